@@ -0,0 +1,88 @@
+// Throughput of the paths through which the server encrypts and decrypts
+// uploads, with each cipher it can use.
+//
+// Run with `cargo bench`.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use chrono::{Duration, Local};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use transpo2::constants::FORM_READ_BUFFER_SIZE;
+use transpo2::files::*;
+
+
+const UPLOAD_SIZE: usize = 8 * 1024 * 1024;
+const ID: i64 = 1;
+
+fn bench_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("transpo-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Encrypt `plaintext` to `path` in the format the server currently uses, and
+// return the key, encrypted name and encrypted mime type
+fn write_upload(path: &PathBuf, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let _ = fs::remove_file(path);
+    let (mut writer, key, name, mime) = EncryptedFileWriter::new(
+        path, usize::MAX, Some(plaintext.len() as u64), ID, false, None,
+        "bench", "application/octet-stream").unwrap();
+
+    // (written in pieces as large as the server reads from the form)
+    for chunk in plaintext.chunks(FORM_READ_BUFFER_SIZE) {
+        writer.write_all(chunk).unwrap();
+    }
+    writer.finish().unwrap();
+    writer.flush().unwrap();
+
+    (key, name, mime)
+}
+
+fn read_upload(path: &PathBuf, key: &[u8], name: &[u8], mime: &[u8], buffer: &mut [u8]) {
+    let expire_after = Local::now().naive_utc() + Duration::days(1);
+    let (mut reader, _, _) = EncryptedFileReader::new(
+        path, 0, 0, expire_after, true,
+        ChunkFormat::new(ID, server_format_version(false)), key, name, mime).unwrap();
+
+    while reader.read(buffer).unwrap() > 0 {}
+}
+
+fn ciphers(c: &mut Criterion) {
+    let dir = bench_dir();
+    let path = dir.join("upload");
+    let plaintext = vec![7; UPLOAD_SIZE];
+    let mut buffer = vec![0; FORM_READ_BUFFER_SIZE];
+
+    let mut group = c.benchmark_group("upload");
+    group.throughput(Throughput::Bytes(UPLOAD_SIZE as u64));
+    group.sample_size(20);
+
+    for (cipher, prefer_chacha20) in [("aes-256-gcm", false), ("chacha20-poly1305", true)] {
+        set_prefer_chacha20(prefer_chacha20);
+
+        group.bench_function(format!("write/{}", cipher), |b| b.iter(|| {
+            write_upload(&path, &plaintext);
+        }));
+
+        let (key, name, mime) = write_upload(&path, &plaintext);
+        group.bench_function(format!("read/{}", cipher), |b| b.iter(|| {
+            read_upload(&path, &key, &name, &mime, &mut buffer);
+        }));
+    }
+
+    group.finish();
+    set_prefer_chacha20(false);
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn hardware_aes(_: &mut Criterion) {
+    println!(
+        "CPU has AES instructions: {}, AES-256-GCM uses them: {}",
+        cpu_has_aes(), has_hardware_aes());
+}
+
+criterion_group!(benches, hardware_aes, ciphers);
+criterion_main!(benches);
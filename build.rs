@@ -0,0 +1,26 @@
+use std::env;
+use std::process::Command;
+
+
+// Record the git commit Transpo is built from. Builds outside of a git
+// checkout (e.g. in Docker) can set `TRANSPO_GIT_COMMIT` instead.
+fn main() {
+    let commit = env::var("TRANSPO_GIT_COMMIT").ok()
+        .or_else(|| Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output().ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok()))
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=TRANSPO_GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-env-changed=TRANSPO_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    // The gRPC service and its messages are generated from the definition
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/transpo.proto")
+        .expect("Compiling proto/transpo.proto");
+}
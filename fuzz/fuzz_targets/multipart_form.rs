@@ -0,0 +1,70 @@
+// Feed arbitrary request bodies to the multipart parser, split into reads of
+// arbitrary sizes. Transpo is a binary, so the parser and the modules it uses
+// are included here directly.
+//
+// Run with `cargo fuzz run multipart_form` (requires cargo-fuzz and a nightly
+// toolchain).
+
+#![no_main]
+#![allow(dead_code)]
+
+#[path = "../../src/b64.rs"]
+mod b64;
+#[path = "../../src/constants.rs"]
+mod constants;
+#[path = "../../src/multipart_form.rs"]
+mod multipart_form;
+
+use libfuzzer_sys::fuzz_target;
+
+use multipart_form::{MultipartParser, ParseResult};
+
+
+const BOUNDARY: &[u8] = b"\r\n--boundary";
+
+// content-disposition, content-type, content-transfer-encoding, value,
+// whether the field is in a nested form
+type Field = (String, String, String, Vec<u8>, bool);
+
+// Parse `body`, which is pushed to the parser `read_size` bytes at a time.
+// Return the fields, or None if the body isn't a whole, valid form.
+fn parse_in_reads(body: &[u8], read_size: usize) -> Option<Vec<Field>> {
+    let mut parser = MultipartParser::new(BOUNDARY);
+    let mut reads = body.chunks(read_size);
+    let mut fields: Vec<Field> = Vec::new();
+
+    loop {
+        match parser.next() {
+            ParseResult::NewField(cd, ct, cte) => {
+                fields.push((cd.to_owned(), ct.to_owned(), cte.to_owned(), Vec::new(), false));
+            },
+            ParseResult::NestedField(cd, ct, cte) => {
+                fields.push((cd.to_owned(), ct.to_owned(), cte.to_owned(), Vec::new(), true));
+            },
+            ParseResult::Value(val) => {
+                // (a value never comes before the headers of its field)
+                fields.last_mut().unwrap().3.extend_from_slice(val);
+            },
+            ParseResult::NeedMoreData => parser.push(reads.next()?),
+            ParseResult::Finished => return Some(fields),
+            ParseResult::Error => return None
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte is the size of the reads
+    let (read_size, body) = match data.split_first() {
+        Some((read_size, body)) => (*read_size as usize + 1, body),
+        None => return
+    };
+
+    // However the body is split, it is parsed the same
+    let fields = parse_in_reads(body, read_size);
+    assert_eq!(fields, parse_in_reads(body, body.len().max(1)));
+
+    // and the values which are parsed don't contain the boundary
+    for (_, _, _, value, _) in fields.iter().flatten() {
+        assert!(!value.windows(BOUNDARY.len()).any(|w| w == BOUNDARY));
+    }
+});
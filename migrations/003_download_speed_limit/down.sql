@@ -0,0 +1 @@
+ALTER TABLE uploads DROP COLUMN max_download_bytes_per_second;
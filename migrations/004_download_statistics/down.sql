@@ -0,0 +1,3 @@
+ALTER TABLE uploads DROP COLUMN num_downloads;
+ALTER TABLE uploads DROP COLUMN num_completed_downloads;
+ALTER TABLE uploads DROP COLUMN bytes_downloaded;
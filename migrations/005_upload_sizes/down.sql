@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN plaintext_size;
+ALTER TABLE uploads DROP COLUMN ciphertext_size;
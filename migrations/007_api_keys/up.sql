@@ -0,0 +1,9 @@
+-- keys which can be presented to authenticate automated clients
+CREATE TABLE IF NOT EXISTS api_keys (
+    id BIGINT PRIMARY KEY,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    label TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    max_upload_size_bytes BIGINT,
+    created_at TIMESTAMP NOT NULL
+);
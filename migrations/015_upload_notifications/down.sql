@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN expiry_notified;
+ALTER TABLE uploads DROP COLUMN notify_topic;
@@ -0,0 +1,4 @@
+-- ntfy topic which the uploader asked to be notified on, and whether they
+-- have been told that the upload expires soon
+ALTER TABLE uploads ADD COLUMN notify_topic VARCHAR(64);
+ALTER TABLE uploads ADD COLUMN expiry_notified BOOLEAN NOT NULL DEFAULT FALSE;
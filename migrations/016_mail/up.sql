@@ -0,0 +1,12 @@
+-- address to which expiry reminders for a user's uploads are sent
+ALTER TABLE users ADD COLUMN email VARCHAR(255);
+
+-- mails waiting to be sent (or retried)
+CREATE TABLE IF NOT EXISTS mail_queue (
+    id BIGINT PRIMARY KEY,
+    recipient VARCHAR(255) NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    send_after TIMESTAMP NOT NULL
+);
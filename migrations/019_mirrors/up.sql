@@ -0,0 +1,3 @@
+-- set for uploads which were copied from a peer instance, and which are
+-- served read-only
+ALTER TABLE uploads ADD COLUMN is_mirror BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- version of the format in which the upload is encrypted. Chunks of uploads
+-- since version 2 are bound to the upload and their position in it.
+ALTER TABLE uploads ADD COLUMN format_version SMALLINT NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN wrapped_key;
+ALTER TABLE uploads DROP COLUMN key_salt;
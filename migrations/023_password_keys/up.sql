@@ -0,0 +1,4 @@
+-- set for uploads whose key is derived from their password: the salt of the
+-- key derived from the password, and the key of the upload encrypted with it
+ALTER TABLE uploads ADD COLUMN key_salt VARCHAR(32);
+ALTER TABLE uploads ADD COLUMN wrapped_key VARCHAR(128);
@@ -0,0 +1,3 @@
+-- set for uploads with a title (pastes): the title, encrypted with the key of
+-- the upload like the file name
+ALTER TABLE uploads ADD COLUMN title TEXT;
@@ -0,0 +1 @@
+ALTER TABLE uploads ADD COLUMN max_download_bytes_per_second BIGINT;
@@ -0,0 +1,4 @@
+-- counters for how often an upload has been downloaded
+ALTER TABLE uploads ADD COLUMN num_downloads INT NOT NULL DEFAULT 0;
+ALTER TABLE uploads ADD COLUMN num_completed_downloads INT NOT NULL DEFAULT 0;
+ALTER TABLE uploads ADD COLUMN bytes_downloaded BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- sizes recorded when an upload completes
+-- (existing rows are backfilled from the storage directory on startup)
+ALTER TABLE uploads ADD COLUMN plaintext_size BIGINT;
+ALTER TABLE uploads ADD COLUMN ciphertext_size BIGINT;
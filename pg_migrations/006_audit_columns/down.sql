@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN uploader_ip;
+ALTER TABLE uploads DROP COLUMN created_at;
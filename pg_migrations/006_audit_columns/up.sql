@@ -0,0 +1,3 @@
+-- optional data about who made an upload, scrubbed after the retention window
+ALTER TABLE uploads ADD COLUMN uploader_ip VARCHAR(45);
+ALTER TABLE uploads ADD COLUMN created_at TIMESTAMP;
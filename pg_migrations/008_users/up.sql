@@ -0,0 +1,16 @@
+-- registered users, who own the uploads they make while logged in
+CREATE TABLE IF NOT EXISTS users (
+    id BIGINT PRIMARY KEY,
+    username VARCHAR(64) NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+-- sessions of logged in users
+CREATE TABLE IF NOT EXISTS sessions (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    expire_after TIMESTAMP NOT NULL
+);
+
+ALTER TABLE uploads ADD COLUMN owner_id BIGINT;
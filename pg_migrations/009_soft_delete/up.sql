@@ -0,0 +1,2 @@
+-- uploads are only purged some time after being deleted
+ALTER TABLE uploads ADD COLUMN deleted_at TIMESTAMP;
@@ -0,0 +1,8 @@
+-- total size of all stored uploads, kept so that the storage directory doesn't
+-- have to be scanned during uploads
+CREATE TABLE IF NOT EXISTS storage_usage (
+    id INT PRIMARY KEY,
+    bytes_used BIGINT NOT NULL
+);
+
+INSERT INTO storage_usage (id, bytes_used) VALUES (1, 0);
@@ -0,0 +1,7 @@
+-- bytes uploaded by each user, counted per calendar month
+CREATE TABLE IF NOT EXISTS account_transfer (
+    user_id BIGINT NOT NULL,
+    month DATE NOT NULL,
+    bytes_uploaded BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, month)
+);
@@ -0,0 +1,3 @@
+-- time at which each upload was completed or last downloaded, used to pick
+-- which uploads to evict when storage is full
+ALTER TABLE uploads ADD COLUMN last_used_at TIMESTAMP;
@@ -0,0 +1,8 @@
+-- users who log in with an OpenID Connect provider, identified by the
+-- provider's issuer and their subject with it
+CREATE TABLE IF NOT EXISTS oidc_identities (
+    issuer VARCHAR(255) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    user_id BIGINT NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN is_disabled;
+DROP TABLE reports;
@@ -0,0 +1,11 @@
+-- reports of uploads which break the rules, kept until an admin reviews them
+CREATE TABLE IF NOT EXISTS reports (
+    id BIGINT PRIMARY KEY,
+    upload_id BIGINT NOT NULL,
+    reason TEXT NOT NULL,
+    reporter_ip VARCHAR(45),
+    created_at TIMESTAMP NOT NULL
+);
+
+-- set once an upload has been reported too often, until an admin reviews it
+ALTER TABLE uploads ADD COLUMN is_disabled BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,14 @@
+-- networks from which uploads are refused
+CREATE TABLE IF NOT EXISTS blocked_networks (
+    id BIGINT PRIMARY KEY,
+    network VARCHAR(49) NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP NOT NULL
+);
+
+-- uploads which can't be downloaded (or restored) any more
+CREATE TABLE IF NOT EXISTS blocked_uploads (
+    upload_id BIGINT PRIMARY KEY,
+    reason TEXT,
+    created_at TIMESTAMP NOT NULL
+);
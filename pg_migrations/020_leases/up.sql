@@ -0,0 +1,7 @@
+-- leases with which Transpo processes sharing the database decide which one
+-- of them runs background jobs
+CREATE TABLE IF NOT EXISTS leases (
+    name VARCHAR(64) PRIMARY KEY,
+    holder VARCHAR(64) NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
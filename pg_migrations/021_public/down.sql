@@ -0,0 +1,2 @@
+ALTER TABLE uploads DROP COLUMN published_at;
+ALTER TABLE uploads DROP COLUMN public_key;
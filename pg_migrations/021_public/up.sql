@@ -0,0 +1,4 @@
+-- set for uploads listed in the public gallery. The key is stored so that the
+-- gallery can show their names and link to them.
+ALTER TABLE uploads ADD COLUMN public_key VARCHAR(64);
+ALTER TABLE uploads ADD COLUMN published_at TIMESTAMP;
@@ -0,0 +1,3 @@
+-- set for uploads whose key is wrapped for recipients' public keys: the key
+-- of the upload wrapped for each recipient
+ALTER TABLE uploads ADD COLUMN recipient_keys TEXT;
@@ -0,0 +1,306 @@
+use crate::api_keys::*;
+use crate::b64::*;
+use crate::client_ip::*;
+use crate::config::*;
+use crate::db::*;
+use crate::http_errors::*;
+use crate::logging::{log_security_event, SecurityEvent};
+use crate::mail::is_valid_address;
+use crate::random_bytes::*;
+use crate::translations::*;
+
+use std::sync::Arc;
+
+use blocking::unblock;
+use trillium::{Conn, Headers};
+use smol::io::AsyncReadExt;
+use urlencoding::decode;
+use chrono::{Local, Duration};
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+
+
+pub const SESSION_COOKIE: &'static str = "transpo_session";
+const SESSION_AGE_DAYS: i64 = 30;
+const SESSION_TOKEN_LENGTH: usize = 32;
+
+const MAX_USERNAME_LENGTH: usize = 64;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_FORM_BODY_SIZE: u64 = 4096;
+
+
+#[derive(Default)]
+struct Credentials {
+    username: Option<String>,
+    password: Option<String>,
+    email: Option<String>
+}
+
+#[derive(Serialize)]
+struct Account {
+    username: String
+}
+
+// What a user has stored and uploaded, along with their limits (if any)
+#[derive(Serialize)]
+struct AccountUsage {
+    username: String,
+    bytes_stored: i64,
+    max_bytes_stored: Option<usize>,
+    bytes_uploaded_this_month: i64,
+    max_bytes_uploaded_per_month: Option<usize>
+}
+
+// Parse an `application/x-www-form-urlencoded` request body containing
+// `username` and `password` (and, optionally, `email`)
+async fn parse_credentials(conn: &mut Conn) -> Option<Credentials> {
+    let mut body = String::new();
+    conn.request_body().await
+        .take(MAX_FORM_BODY_SIZE)
+        .read_to_string(&mut body).await
+        .ok()?;
+
+    let mut credentials = Credentials::default();
+    for field in body.split('&') {
+        if let Some((key, value)) = field.split_once('=') {
+            let value = decode(&value.replace('+', "%20")).ok()?.into_owned();
+            match key {
+                "username" => credentials.username = Some(value),
+                "password" => credentials.password = Some(value),
+                "email" => credentials.email = Some(value).filter(|e| !e.is_empty()),
+                _ => {}
+            }
+        }
+    }
+
+    Some(credentials)
+}
+
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+    && username.len() <= MAX_USERNAME_LENGTH
+    && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Return the value of the cookie with the given name
+pub fn cookie_from_headers(headers: &Headers, name: &str) -> Option<String> {
+    let cookie = headers.get_str("Cookie")?;
+    cookie.split(';')
+        .filter_map(|arg| arg.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| value.trim().to_owned())
+}
+
+fn session_from_headers(headers: &Headers) -> Option<String> {
+    cookie_from_headers(headers, SESSION_COOKIE)
+}
+
+// Return the ID of the user who is logged in (if any)
+pub async fn get_user_id(
+    headers: &Headers, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<i64>
+{
+    let token_hash = hash_key(&session_from_headers(headers)?);
+
+    unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        Session::select_with_hash(&token_hash, &db_connection)
+            .map(|s| s.user_id)
+    }).await
+}
+
+// Create a session for the given user and return its token
+pub fn create_session(user_id: i64, db_connection: &DbConnection) -> Option<String> {
+    let mut token_bytes = [0; SESSION_TOKEN_LENGTH];
+    random_bytes(&mut token_bytes);
+    let token = String::from_utf8(base64_encode(&token_bytes)).unwrap();
+
+    let session = Session {
+        token_hash: hash_key(&token),
+        user_id,
+        expire_after: Local::now().naive_utc() + Duration::days(SESSION_AGE_DAYS)
+    };
+    session.insert(db_connection)?;
+
+    Some(token)
+}
+
+// Return the `Set-Cookie` header which stores the session with the given token
+pub fn session_cookie(config: &TranspoConfig, token: &str) -> String {
+    format!(
+        "{}={}; Path={}/; Max-Age={}; HttpOnly; SameSite=Lax",
+        SESSION_COOKIE, token, config.base_path,
+        Duration::days(SESSION_AGE_DAYS).num_seconds())
+}
+
+fn session_response(
+    conn: Conn, config: &TranspoConfig, token: String, username: String) -> Conn
+{
+    let account = Account { username };
+
+    conn
+        .with_header("Set-Cookie", session_cookie(config, &token))
+        .with_header("Content-Type", "application/json")
+        .with_body(serde_json::to_string(&account).unwrap())
+        .halt()
+}
+
+pub async fn signup(
+    mut conn: Conn, config: Arc<TranspoConfig>,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    let (username, password, email) = match parse_credentials(&mut conn).await {
+        Some(Credentials { username: Some(u), password: Some(p), email: e })
+        if is_valid_username(&u) && p.len() >= MIN_PASSWORD_LENGTH
+            && e.as_deref().is_none_or(is_valid_address) => (u, p, e),
+        _ => return error_400(conn, config, translation)
+    };
+
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => hash.to_string(),
+        Err(_) => return error_400(conn, config, translation)
+    };
+
+    let user = User {
+        id: thread_rng().gen(),
+        username: username.clone(),
+        password_hash,
+        created_at: Local::now().naive_utc(),
+        email
+    };
+
+    let config_ = config.clone();
+    let token = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config_.db_url)?;
+
+        // The username is unique, so this fails if it is already taken
+        user.insert(&db_connection)?;
+        create_session(user.id, &db_connection)
+    }).await;
+
+    match token {
+        Some(token) => session_response(conn.with_status(201), &config, token, username),
+        None => conn.with_status(409).halt()
+    }
+}
+
+pub async fn login(
+    mut conn: Conn, config: Arc<TranspoConfig>,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    let (username, password) = match parse_credentials(&mut conn).await {
+        Some(Credentials { username: Some(u), password: Some(p), .. }) => (u, p),
+        _ => return error_400(conn, config, translation)
+    };
+
+    let client_ip = ClientIp::of(&conn);
+    let username_ = username.clone();
+    let config_ = config.clone();
+    let token = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config_.db_url)?;
+        let user = User::select_with_username(&username_, &db_connection);
+
+        let hash = user.as_ref().and_then(|u| PasswordHash::new(&u.password_hash).ok());
+        let is_valid = hash
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false);
+        if !is_valid {
+            log_security_event(SecurityEvent::PasswordFailure, client_ip);
+            return None;
+        }
+
+        create_session(user?.id, &db_connection)
+    }).await;
+
+    match token {
+        Some(token) => session_response(conn.with_status(200), &config, token, username),
+        None => conn.with_status(401).halt()
+    }
+}
+
+// Respond with the usage of the user who is logged in
+pub async fn usage(
+    conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn
+{
+    let user_id = match get_user_id(conn.headers(), db_backend, config.clone()).await {
+        Some(user_id) => user_id,
+        None => return conn.with_status(401).halt()
+    };
+
+    let config_ = config.clone();
+    let usage = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config_.db_url)?;
+        let user = User::select_with_id(user_id, &db_connection)?;
+
+        Some(AccountUsage {
+            username: user.username,
+            bytes_stored: Upload::bytes_stored_by_owner(user_id, &db_connection)?,
+            max_bytes_stored: Some(config_.account_storage_bytes)
+                .filter(|&b| b > 0),
+            bytes_uploaded_this_month: AccountTransfer::select_this_month(
+                user_id, &db_connection)?,
+            max_bytes_uploaded_per_month: Some(config_.account_transfer_bytes_per_month)
+                .filter(|&b| b > 0)
+        })
+    }).await;
+
+    match usage {
+        Some(usage) => conn
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(serde_json::to_string(&usage).unwrap())
+            .halt(),
+        None => conn.with_status(500).halt()
+    }
+}
+
+// Set the email address to which expiry reminders are sent for the user who
+// is logged in, or remove it if `email` is empty
+pub async fn set_email(
+    mut conn: Conn, config: Arc<TranspoConfig>,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    let user_id = match get_user_id(conn.headers(), db_backend, config.clone()).await {
+        Some(user_id) => user_id,
+        None => return conn.with_status(401).halt()
+    };
+
+    let email = match parse_credentials(&mut conn).await {
+        Some(Credentials { email, .. }) if email.as_deref().is_none_or(is_valid_address) => email,
+        _ => return error_400(conn, config, translation)
+    };
+
+    let updated = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        User::set_email(user_id, email, &db_connection)
+    }).await;
+
+    match updated {
+        Some(_) => conn.with_status(204).halt(),
+        None => conn.with_status(500).halt()
+    }
+}
+
+pub async fn logout(
+    conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn
+{
+    if let Some(token) = session_from_headers(conn.headers()) {
+        let token_hash = hash_key(&token);
+        let config = config.clone();
+        unblock(move || {
+            if let Some(db_connection) = establish_connection(db_backend, &config.db_url) {
+                Session::delete_with_hash(&token_hash, &db_connection);
+            }
+        }).await;
+    }
+
+    let cookie = format!("{}=; Path={}/; Max-Age=0", SESSION_COOKIE, config.base_path);
+    conn
+        .with_status(204)
+        .with_header("Set-Cookie", cookie)
+        .halt()
+}
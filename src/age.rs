@@ -0,0 +1,475 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::cmp;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, AeadInPlace, NewAead};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::b64;
+use crate::random_bytes::*;
+
+
+// Uploads encrypted by the server for recipients can be stored in the age
+// format (https://age-encryption.org/v1) instead of as length-prefixed chunks,
+// so that recipients can decrypt the file itself with `age`. The file key is
+// derived from the key of the upload, so the server can still decrypt the
+// file for downloads which give it the key (see CRYPTO.md).
+
+const MAGIC: &[u8] = b"age-encryption.org/v1\n";
+const X25519_STANZA: &str = "X25519";
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const FILE_KEY_INFO: &[u8] = b"transpo2 age file key";
+const FILE_KEY_SIZE: usize = 16;
+const NONCE_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+// (the stanzas written by the server are much shorter than this)
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+fn other_error(message: &'static str) -> Error {
+    Error::other(message)
+}
+
+// age uses the standard base64 alphabet without padding, while `b64` uses the
+// URL-safe one
+fn encode(bytes: &[u8]) -> String {
+    String::from_utf8(b64::base64_encode(bytes)).unwrap()
+        .replace('-', "+")
+        .replace('_', "/")
+}
+
+fn decode(encoded: &str) -> Option<Vec<u8>> {
+    if !encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/') {
+        return None;
+    }
+
+    b64::base64_decode_lenient(encoded.as_bytes())
+}
+
+fn hkdf(ikm: &[u8], salt: &[u8], info: &[u8], okm: &mut [u8]) -> Result<()> {
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, okm)
+        .or(Err(other_error("expand")))
+}
+
+// Return whether `prefix` (the start of a file) is the start of an age file.
+// The first two bytes of the magic string are too large for the length
+// prefix of a chunk, so files in the chunked format never match.
+pub fn has_magic(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+// Return the file key for an upload with the b64 encoded `key`
+pub fn file_key(key: &[u8]) -> Result<[u8; FILE_KEY_SIZE]> {
+    let key_slice = b64::base64_decode_lenient(key).ok_or(other_error("base64_decode"))?;
+    let mut file_key = [0; FILE_KEY_SIZE];
+    hkdf(&key_slice, &[], FILE_KEY_INFO, &mut file_key)?;
+    Ok(file_key)
+}
+
+fn header_mac(file_key: &[u8; FILE_KEY_SIZE], header: &[u8]) -> Result<Hmac<Sha256>> {
+    let mut mac_key = [0; 32];
+    hkdf(file_key, &[], b"header", &mut mac_key)?;
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key)
+        .or(Err(other_error("new_from_slice")))?;
+    mac.update(header);
+    Ok(mac)
+}
+
+// Return the stanza which wraps `file_key` for `recipient`
+fn x25519_stanza(file_key: &[u8; FILE_KEY_SIZE], recipient: &[u8; 32]) -> Result<String> {
+    let recipient = PublicKey::from(*recipient);
+    let mut ephemeral_bytes = [0; 32];
+    random_bytes(&mut ephemeral_bytes);
+    let ephemeral_secret = StaticSecret::from(ephemeral_bytes);
+    let ephemeral = PublicKey::from(&ephemeral_secret);
+
+    let shared_secret = ephemeral_secret.diffie_hellman(&recipient);
+    if !shared_secret.was_contributory() {
+        return Err(other_error("Invalid public key"));
+    }
+
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+    let mut wrap_key = [0; 32];
+    hkdf(shared_secret.as_bytes(), &salt, X25519_INFO, &mut wrap_key)?;
+
+    let body = ChaCha20Poly1305::new(Key::from_slice(&wrap_key))
+        .encrypt(Nonce::from_slice(&[0; 12]), file_key.as_slice())
+        .or(Err(other_error("encrypt")))?;
+
+    // (the body is short enough to fit on one line)
+    Ok(format!("-> {} {}\n{}\n", X25519_STANZA, encode(ephemeral.as_bytes()), encode(&body)))
+}
+
+// Write the header of an age file whose file key is wrapped for each of
+// `recipients`, followed by the nonce of the payload. Return the stream the
+// payload is written with.
+pub fn write_header<W>(
+    file_key: &[u8; FILE_KEY_SIZE], recipients: &[[u8; 32]], mut writer: W) -> Result<AgeStream>
+where W: Write
+{
+    if recipients.is_empty() {
+        return Err(other_error("No recipients"));
+    }
+
+    let mut header = MAGIC.to_vec();
+    for recipient in recipients {
+        header.extend_from_slice(x25519_stanza(file_key, recipient)?.as_bytes());
+    }
+    header.extend_from_slice(b"---");
+
+    let mac = header_mac(file_key, &header)?.finalize().into_bytes();
+    header.extend_from_slice(format!(" {}\n", encode(&mac)).as_bytes());
+
+    let mut nonce = [0; NONCE_SIZE];
+    random_bytes(&mut nonce);
+    header.extend_from_slice(&nonce);
+
+    writer.write_all(&header)?;
+    AgeStream::new(file_key, &nonce)
+}
+
+fn read_line<R>(reader: &mut R, header_len: &mut usize) -> Result<String>
+where R: Read
+{
+    let mut line = Vec::new();
+    let mut byte = [0];
+
+    loop {
+        reader.read_exact(&mut byte)?;
+        *header_len += 1;
+
+        if *header_len > MAX_HEADER_SIZE {
+            return Err(other_error("Header too large"));
+        } else if byte[0] == b'\n' {
+            return String::from_utf8(line).or(Err(other_error("Invalid header")));
+        }
+        line.push(byte[0]);
+    }
+}
+
+// Read the header of an age file up to the nonce of the payload. Return the
+// part of the header covered by its MAC, the MAC and the length of the header.
+// The stanzas are only checked for their shape, since the server knows the
+// file key of the files it wrote.
+fn read_header<R>(mut reader: R) -> Result<(Vec<u8>, Vec<u8>, usize)>
+where R: Read
+{
+    let mut header_len = 0;
+    if read_line(&mut reader, &mut header_len)?.as_bytes() != &MAGIC[..MAGIC.len() - 1] {
+        return Err(other_error("Not an age file"));
+    }
+
+    let mut header = MAGIC.to_vec();
+    let mut num_stanzas = 0;
+
+    loop {
+        let line = read_line(&mut reader, &mut header_len)?;
+
+        if let Some(mac) = line.strip_prefix("--- ") {
+            let mac = decode(mac).filter(|m| m.len() == 32)
+                .ok_or(other_error("Invalid header MAC"))?;
+            if num_stanzas == 0 {
+                return Err(other_error("No recipients"));
+            }
+
+            header.extend_from_slice(b"---");
+            return Ok((header, mac, header_len));
+        } else if line.starts_with("-> ") {
+            num_stanzas += 1;
+        } else if num_stanzas == 0 {
+            return Err(other_error("Invalid header"));
+        }
+
+        header.extend_from_slice(line.as_bytes());
+        header.push(b'\n');
+    }
+}
+
+// Read the header of an age file with the given file key, checking its MAC.
+// Return the stream the payload is read with.
+pub fn open_header<R>(mut reader: R, file_key: &[u8; FILE_KEY_SIZE]) -> Result<AgeStream>
+where R: Read
+{
+    let (header, mac, _) = read_header(&mut reader)?;
+    header_mac(file_key, &header)?
+        .verify_slice(&mac)
+        .or(Err(other_error("Invalid header MAC")))?;
+
+    let mut nonce = [0; NONCE_SIZE];
+    reader.read_exact(&mut nonce)?;
+    AgeStream::new(file_key, &nonce)
+}
+
+// Check that the age file of `file_size` bytes read by `reader` has a
+// well-formed header and a payload made of chunks of valid sizes. If
+// `file_key` is given, also check the MAC of the header and that the first
+// chunk can be decrypted. Return the length of the plaintext, the number of
+// chunks and whether the first chunk was decrypted.
+pub fn check_file<R>(
+    mut reader: R, file_size: u64,
+    file_key: Option<[u8; FILE_KEY_SIZE]>) -> Result<(u64, u64, bool)>
+where R: Read
+{
+    let (header, mac, header_len) = read_header(&mut reader)?;
+    let payload_len = file_size
+        .checked_sub((header_len + NONCE_SIZE) as u64)
+        .filter(|len| *len >= TAG_SIZE as u64)
+        .ok_or(Error::new(ErrorKind::UnexpectedEof, "Ciphertext truncated"))?;
+
+    // Every chunk but the last one is full, and the last one is only empty
+    // if the whole plaintext is
+    let full_chunk_len = (CHUNK_SIZE + TAG_SIZE) as u64;
+    let num_chunks = cmp::max(1, payload_len.div_ceil(full_chunk_len));
+    let last_chunk_len = payload_len - (num_chunks - 1) * full_chunk_len;
+    if last_chunk_len == TAG_SIZE as u64 && num_chunks > 1 {
+        return Err(other_error("Empty last chunk"));
+    }
+    let plaintext_size = payload_len - num_chunks * TAG_SIZE as u64;
+
+    let is_sample_decrypted = match file_key {
+        Some(file_key) => {
+            header_mac(&file_key, &header)?
+                .verify_slice(&mac)
+                .or(Err(other_error("Invalid header MAC")))?;
+
+            let mut nonce = [0; NONCE_SIZE];
+            reader.read_exact(&mut nonce)?;
+            AgeStream::new(&file_key, &nonce)?
+                .read_chunk(&mut reader)
+                .map_err(|_| other_error("Decrypting the first chunk failed"))?;
+            true
+        },
+        None => false
+    };
+
+    Ok((plaintext_size, num_chunks, is_sample_decrypted))
+}
+
+// Read until `buffer` is full or the end of the file
+fn read_full<R>(reader: &mut R, buffer: &mut [u8]) -> Result<usize>
+where R: Read
+{
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => len += bytes_read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(len)
+}
+
+// The payload of an age file: chunks of `CHUNK_SIZE` bytes of plaintext (the
+// last one may be shorter) encrypted with ChaCha20-Poly1305 under a key
+// derived from the file key and the nonce of the payload. The nonce of each
+// chunk is its index as an 11-byte big-endian integer, followed by 1 for the
+// last chunk and 0 otherwise, so a file which was cut short can't pass for a
+// complete one.
+pub struct AgeStream {
+    cipher: ChaCha20Poly1305,
+    count: u64,
+    // plaintext of the chunk being written, or of the chunk being read (each
+    // chunk is encrypted and decrypted in place, so that the buffer is reused)
+    buffer: Vec<u8>,
+    read_start: usize,
+    // whether the last chunk was written or read
+    is_finished: bool
+}
+
+impl AgeStream {
+    fn new(file_key: &[u8; FILE_KEY_SIZE], nonce: &[u8; NONCE_SIZE]) -> Result<Self> {
+        let mut payload_key = [0; 32];
+        hkdf(file_key, nonce, b"payload", &mut payload_key)?;
+
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&payload_key)),
+            count: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            read_start: 0,
+            is_finished: false
+        })
+    }
+
+    fn nonce(&self, is_last: bool) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[3..11].copy_from_slice(&self.count.to_be_bytes());
+        nonce[11] = is_last as u8;
+        nonce
+    }
+
+    fn write_chunk<W>(&mut self, is_last: bool, mut writer: W) -> Result<()>
+    where W: Write
+    {
+        let nonce = self.nonce(is_last);
+        self.cipher
+            .encrypt_in_place(Nonce::from_slice(&nonce), &[], &mut self.buffer)
+            .or(Err(other_error("encrypt")))?;
+        writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.count += 1;
+        Ok(())
+    }
+
+    // The chunk is left as it is if it can't be decrypted, since
+    // ChaCha20-Poly1305 checks the tag before decrypting
+    fn open_chunk(&mut self, is_last: bool) -> Result<()> {
+        let nonce = self.nonce(is_last);
+        self.cipher
+            .decrypt_in_place(Nonce::from_slice(&nonce), &[], &mut self.buffer)
+            .or(Err(other_error("decrypt")))?;
+        if is_last && self.buffer.is_empty() && self.count > 0 {
+            return Err(other_error("Empty last chunk"));
+        }
+
+        self.read_start = 0;
+        self.is_finished = is_last;
+        self.count += 1;
+        Ok(())
+    }
+
+    // A full chunk is only written once more plaintext follows it, since the
+    // last chunk is sealed differently
+    pub fn write<W>(&mut self, plaintext: &[u8], mut writer: W) -> Result<usize>
+    where W: Write
+    {
+        if plaintext.is_empty() {
+            return Ok(0);
+        } else if self.is_finished {
+            return Err(other_error("Write after the last chunk"));
+        }
+
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk(false, &mut writer)?;
+        }
+
+        let len = cmp::min(plaintext.len(), CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&plaintext[..len]);
+        Ok(len)
+    }
+
+    // Write the last chunk
+    pub fn finish<W>(&mut self, mut writer: W) -> Result<()>
+    where W: Write
+    {
+        if !self.is_finished {
+            self.write_chunk(true, &mut writer)?;
+            self.is_finished = true;
+        }
+        Ok(())
+    }
+
+    // Read and decrypt the next chunk into `buffer`
+    fn read_chunk<R>(&mut self, reader: &mut R) -> Result<()>
+    where R: Read
+    {
+        self.buffer.resize(CHUNK_SIZE + TAG_SIZE, 0);
+        let len = read_full(reader, &mut self.buffer)?;
+        self.buffer.truncate(len);
+
+        if len < TAG_SIZE {
+            Err(other_error("Ciphertext truncated"))
+        } else if len < CHUNK_SIZE + TAG_SIZE {
+            self.open_chunk(true)
+        } else {
+            // A full chunk may also be the last one
+            self.open_chunk(false).or_else(|_| self.open_chunk(true))
+        }
+    }
+
+    pub fn read<R>(&mut self, plaintext: &mut [u8], mut reader: R) -> Result<usize>
+    where R: Read
+    {
+        if plaintext.is_empty() {
+            return Ok(0);
+        }
+
+        if self.read_start == self.buffer.len() {
+            // Like `encrypted_read`, keep returning Ok(0) once the end is
+            // reached
+            if self.is_finished {
+                return Ok(0);
+            }
+
+            self.read_chunk(&mut reader)?;
+            if self.is_finished && reader.read(&mut [0])? > 0 {
+                return Err(other_error("Data after the last chunk"));
+            }
+        }
+
+        let len = cmp::min(plaintext.len(), self.buffer.len() - self.read_start);
+        plaintext[..len].copy_from_slice(&self.buffer[self.read_start..][..len]);
+        self.read_start += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_KEY: [u8; FILE_KEY_SIZE] = [1; FILE_KEY_SIZE];
+
+    fn write_file(plaintext: &[u8]) -> Vec<u8> {
+        let recipient = PublicKey::from(&StaticSecret::from([7; 32])).to_bytes();
+        let mut file = Vec::new();
+        let mut stream = write_header(&FILE_KEY, &[recipient], &mut file).unwrap();
+
+        let mut written = 0;
+        while written < plaintext.len() {
+            written += stream.write(&plaintext[written..], &mut file).unwrap();
+        }
+        stream.finish(&mut file).unwrap();
+        file
+    }
+
+    fn read_file(mut file: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = open_header(&mut file, &FILE_KEY)?;
+        let mut plaintext = Vec::new();
+        let mut buf = [0; 4096];
+
+        loop {
+            let len = stream.read(&mut buf, &mut file)?;
+            if len == 0 {
+                return Ok(plaintext);
+            }
+            plaintext.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for len in [0, 100, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let file = write_file(&plaintext);
+
+            assert!(has_magic(&file));
+            assert_eq!(read_file(&file).unwrap(), plaintext);
+
+            let (plaintext_size, _, is_sample_decrypted) =
+                check_file(file.as_slice(), file.len() as u64, Some(FILE_KEY)).unwrap();
+            assert_eq!(plaintext_size, len as u64);
+            assert!(is_sample_decrypted);
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let file = write_file(&[0; CHUNK_SIZE + 1]);
+
+        // without the last chunk, the first one isn't the last
+        let truncated = &file[..file.len() - 1 - TAG_SIZE];
+        assert!(read_file(truncated).is_err());
+        // a last chunk can't be empty
+        let truncated = &file[..file.len() - 1];
+        assert!(check_file(truncated, truncated.len() as u64, None).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::accounts::*;
+use crate::api_keys::*;
+use crate::b64::*;
+use crate::concurrency::*;
+use crate::config::*;
+use crate::constants::*;
+use crate::db::*;
+use crate::download::{self, UploadInfo};
+use crate::federation::delete_on_peers;
+use crate::http_errors::*;
+use crate::tokens::*;
+
+use std::sync::Arc;
+
+use blocking::unblock;
+use serde::Serialize;
+use trillium::Conn;
+use tracing::info;
+
+
+// An upload owned by the user, as listed by `GET /api/v1/uploads`
+#[derive(Serialize)]
+struct OwnedUpload {
+    id: String,
+    #[serde(flatten)]
+    info: UploadInfo
+}
+
+fn json_response(conn: Conn, status: u16, body: String) -> Conn {
+    conn
+        .with_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_body(body)
+        .halt()
+}
+
+// `GET /api/v1/uploads/:file_id`
+pub async fn info(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens, db_backend: DbBackend) -> Conn
+{
+    let info = download::get_info(
+        &mut conn, id_string, config, accessors, tokens, db_backend).await;
+
+    match info {
+        Ok(info) => json_response(conn, 200, info),
+        Err(404) => api_error(conn, 404, "The upload does not exist"),
+        Err(413) => api_error(conn, 413, "The request body is too large"),
+        Err(status) => api_error(
+            conn, status, "The upload does not exist, or the password is wrong")
+    }
+}
+
+// `GET /api/v1/uploads`: list the uploads of the user who is logged in
+pub async fn list(conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn {
+    let user_id = match get_user_id(conn.headers(), db_backend, config.clone()).await {
+        Some(user_id) => user_id,
+        None => return api_error(conn, 401, "Not logged in")
+    };
+
+    let uploads = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        let uploads = Upload::select_with_owner(user_id, &db_connection)?
+            .into_iter()
+            .filter(|upload| !upload.is_expired())
+            .map(|upload| OwnedUpload {
+                id: String::from_utf8(i64_to_b64_bytes(upload.id)).unwrap(),
+                info: {
+                    let size = upload.ciphertext_size.unwrap_or(0) as u64;
+                    UploadInfo::new(upload, size)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_string(&uploads).ok()
+    }).await;
+
+    match uploads {
+        Some(uploads) => json_response(conn, 200, uploads),
+        None => api_error(conn, 500, "Listing uploads failed")
+    }
+}
+
+// `DELETE /api/v1/uploads/:file_id`: delete an upload owned by the user who
+// is logged in, or any upload with an API key which has the admin scope
+pub async fn delete(
+    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, db_backend: DbBackend) -> Conn
+{
+    let id = match i64_from_b64_bytes(id_string.as_bytes()) {
+        Some(id) if id_string.len() == base64_encode_length(ID_LENGTH) => id,
+        _ => return api_error(conn, 404, "The upload does not exist")
+    };
+
+    let is_admin = check_admin(conn.headers(), config.clone(), db_backend).await.is_none();
+    let user_id = get_user_id(conn.headers(), db_backend, config.clone()).await;
+    if !is_admin && user_id.is_none() {
+        return api_error(conn, 401, "Not logged in");
+    }
+
+    let peers_config = config.clone();
+    let deleted = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url).ok_or(500u16)?;
+
+        let accessor_mutex = accessors.access(id).ok_or(500u16)?;
+        let _accessor = accessor_mutex.lock();
+
+        match Upload::select_with_id(id, &db_connection) {
+            Some(upload) if !upload.is_deleted() => {
+                if !is_admin && upload.owner_id != user_id {
+                    return Err(403);
+                }
+                Upload::mark_deleted(id, &db_connection).ok_or(500u16)?;
+                // Nothing is left to review
+                Report::delete_with_upload(id, &db_connection);
+                Ok(())
+            },
+            _ => Err(404)
+        }
+    }).await;
+
+    match deleted {
+        Ok(()) => {
+            info!(id = %id_string, "Upload deleted");
+            delete_on_peers(id, peers_config);
+            conn.with_status(204).halt()
+        },
+        Err(403) => api_error(conn, 403, "The upload belongs to someone else"),
+        Err(404) => api_error(conn, 404, "The upload does not exist"),
+        Err(status) => api_error(conn, status, "Deleting the upload failed")
+    }
+}
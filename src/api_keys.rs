@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use blocking::unblock;
+use chrono::Local;
+use rand::{thread_rng, Rng};
+use sha2::{Sha256, Digest};
+use trillium::Headers;
+use urlencoding::decode;
+
+use crate::b64::*;
+use crate::config::*;
+use crate::db::*;
+use crate::random_bytes::*;
+
+
+pub const API_KEY_HEADER: &'static str = "X-Transpo-Api-Key";
+// Uploads may pass the key in the query string instead, since browsers can't
+// set headers when opening a websocket
+pub const API_KEY_QUERY: &'static str = "api-key";
+
+pub const UPLOAD_SCOPE: &'static str = "upload";
+pub const ADMIN_SCOPE: &'static str = "admin";
+const SCOPES: &'static [&'static str] = &[UPLOAD_SCOPE, ADMIN_SCOPE];
+
+const KEY_LENGTH: usize = 32;
+
+
+// Keys (and session tokens) are only stored as hashes
+pub fn hash_key(key: &str) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+    String::from_utf8(base64_encode(&hash)).unwrap()
+}
+
+// Look up the API key presented in the request headers (if any). Return
+// Err if a key was presented, but it does not exist.
+pub async fn resolve_api_key(
+    headers: &Headers, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Result<Option<ApiKey>, ()>
+{
+    let key = headers.get_str(API_KEY_HEADER).map(|k| k.trim().to_owned());
+    select_api_key(key, db_backend, config).await
+}
+
+// Like `resolve_api_key`, but the key may also be given in the query string
+pub async fn resolve_upload_api_key(
+    headers: &Headers, query: &str, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Result<Option<ApiKey>, ()>
+{
+    let key = headers.get_str(API_KEY_HEADER)
+        .map(|k| k.trim().to_owned())
+        .or_else(|| query.split('&')
+            .filter_map(|field| field.split_once('='))
+            .find(|(key, _)| *key == API_KEY_QUERY)
+            .and_then(|(_, value)| decode(value).ok())
+            .map(|value| value.into_owned()));
+    select_api_key(key, db_backend, config).await
+}
+
+pub async fn select_api_key(
+    key: Option<String>, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Result<Option<ApiKey>, ()>
+{
+    let key_hash = match key {
+        Some(key) => hash_key(&key),
+        None => return Ok(None)
+    };
+
+    unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url).ok_or(())?;
+        ApiKey::select_with_hash(&key_hash, &db_connection)
+            .map(Some)
+            .ok_or(())
+    }).await
+}
+
+// Return the status to respond with if the request isn't made with an API
+// key which has the admin scope
+pub async fn check_admin(
+    headers: &Headers, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Option<u16>
+{
+    match resolve_api_key(headers, db_backend, config).await {
+        Ok(Some(api_key)) if api_key.has_scope(ADMIN_SCOPE) => None,
+        Ok(None) => Some(401),
+        _ => Some(403)
+    }
+}
+
+// Return a copy of the configuration with the limits of the given key applied
+pub fn apply_limits(config: Arc<TranspoConfig>, api_key: &ApiKey) -> Arc<TranspoConfig> {
+    match api_key.max_upload_size_bytes {
+        Some(max_upload_size_bytes) => {
+            let mut config = config.as_ref().clone();
+            config.max_upload_size_bytes = max_upload_size_bytes as usize;
+            Arc::new(config)
+        },
+        None => config
+    }
+}
+
+
+// Run `api-key <args>`. Return the exit code.
+pub fn run_command(args: &[String], db_connection: &DbConnection) -> i32 {
+    match args {
+        [cmd, label, rest @ ..] if cmd == "create" && rest.len() <= 2 => {
+            let scopes = rest.first().map(|s| s.as_str()).unwrap_or(UPLOAD_SCOPE);
+            if let Some(scope) = scopes.split(',').find(|s| !SCOPES.contains(s)) {
+                eprintln!("Unknown scope `{}`", scope);
+                return 1;
+            }
+
+            let max_upload_size_bytes = match rest.get(1).map(|s| s.parse::<i64>()) {
+                Some(Ok(size)) => Some(size),
+                Some(Err(_)) => {
+                    eprintln!("Invalid maximum upload size");
+                    return 1;
+                },
+                None => None
+            };
+
+            create(label, scopes, max_upload_size_bytes, db_connection)
+        },
+        [cmd, id] if cmd == "revoke" => {
+            let deleted = i64_from_b64_bytes(id.as_bytes())
+                .and_then(|id| ApiKey::delete_with_id(id, db_connection));
+
+            match deleted {
+                Some(1) => 0,
+                _ => {
+                    eprintln!("No API key with ID `{}`", id);
+                    1
+                }
+            }
+        },
+        [cmd] if cmd == "list" => {
+            match ApiKey::select_all(db_connection) {
+                Some(keys) => {
+                    for key in keys {
+                        let max_upload_size = key.max_upload_size_bytes
+                            .map(|s| s.to_string())
+                            .unwrap_or("-".to_string());
+
+                        println!("{}\t{}\t{}\t{}\t{}",
+                            String::from_utf8(i64_to_b64_bytes(key.id)).unwrap(),
+                            key.created_at, key.scopes, max_upload_size, key.label);
+                    }
+                    0
+                },
+                None => 1
+            }
+        },
+        _ => {
+            eprintln!("Usage: api-key create <label> [scopes] [max upload size] | revoke <id> | list");
+            1
+        }
+    }
+}
+
+fn create(
+    label: &str, scopes: &str, max_upload_size_bytes: Option<i64>,
+    db_connection: &DbConnection) -> i32
+{
+    let mut key_bytes = [0; KEY_LENGTH];
+    random_bytes(&mut key_bytes);
+    let key = String::from_utf8(base64_encode(&key_bytes)).unwrap();
+
+    let api_key = ApiKey {
+        id: thread_rng().gen(),
+        key_hash: hash_key(&key),
+        label: label.to_owned(),
+        scopes: scopes.to_owned(),
+        max_upload_size_bytes,
+        created_at: Local::now().naive_utc()
+    };
+
+    match api_key.insert(db_connection) {
+        Some(_) => {
+            // The key itself is not stored, so this is the only chance to see it
+            println!("ID:  {}", String::from_utf8(i64_to_b64_bytes(api_key.id)).unwrap());
+            println!("Key: {}", key);
+            0
+        },
+        None => {
+            eprintln!("Failed to store API key");
+            1
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs::{self, File, OpenOptions, Metadata};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use aes::Aes256Ctr;
+use aes::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, NewAead};
+
+use crate::constants::*;
+use crate::random_bytes::*;
+
+
+// At-rest encryption wraps the files of uploads stored while a storage key is
+// set in an extra layer of encryption, so that a leak of the storage directory
+// exposes neither the files (even those which are already encrypted by the
+// client) nor their metadata.
+//
+// Each upload has a random data key, which is stored in a marker file in its
+// directory, encrypted with AES-256-GCM under the storage key. The file of
+// the upload is encrypted with AES-256-CTR under the data key, so that it
+// keeps its size and can be read from any offset (e.g. to resume a download).
+// Since an upload's file is written only once, its keystream is never reused
+// for different data. Metadata files are rewritten, so they are encrypted
+// with AES-256-GCM under the data key with a new nonce each time, which is
+// stored before the ciphertext.
+//
+// Uploads without a marker were stored before a key was set and are read as
+// they are. Since only the marker depends on the storage key, the key is
+// rotated by wrapping each data key again (see `rekey_dir`), during which the
+// old key is still accepted.
+
+static STORAGE_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+// the previous storage key while it is being rotated
+static OLD_STORAGE_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+const NONCE_SIZE: usize = 12;
+
+// Set the storage keys for the rest of the process (only the first call has an
+// effect)
+pub fn set_storage_keys(key: [u8; 32], old_key: Option<[u8; 32]>) {
+    let _ = STORAGE_KEY.set(key);
+    if let Some(old_key) = old_key {
+        let _ = OLD_STORAGE_KEY.set(old_key);
+    }
+}
+
+pub fn has_storage_key() -> bool {
+    STORAGE_KEY.get().is_some()
+}
+
+fn other_error(message: &'static str) -> Error {
+    Error::other(message)
+}
+
+// Encrypt with AES-256-GCM under `key`, prepending a new nonce
+fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let mut nonce = [0; NONCE_SIZE];
+    random_bytes(&mut nonce);
+
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| other_error("encrypt"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn decrypt(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE {
+        return None;
+    }
+
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+// Write the data key of the upload in `upload_dir` wrapped with `storage_key`.
+// The marker is replaced in one step, so it is never read half-written.
+fn write_marker(upload_dir: &Path, storage_key: &[u8; 32], data_key: &[u8]) -> Result<()> {
+    let tmp_path = upload_dir.join(format!("{}.tmp", AT_REST_MARKER_FILE_NAME));
+    fs::write(&tmp_path, encrypt(storage_key, data_key)?)?;
+    fs::rename(tmp_path, upload_dir.join(AT_REST_MARKER_FILE_NAME))
+}
+
+// Mark a new upload directory as encrypted at rest with a new data key if a
+// storage key is set. This MUST be called before anything is written to the
+// directory.
+pub fn mark_new_dir(upload_dir: &Path) -> Result<()> {
+    match STORAGE_KEY.get() {
+        Some(storage_key) => {
+            let mut data_key = [0; 32];
+            random_bytes(&mut data_key);
+            write_marker(upload_dir, storage_key, &data_key)
+        },
+        None => Ok(())
+    }
+}
+
+// Return the data key of the upload in `upload_dir` and whether it is wrapped
+// with the current storage key, if the upload is encrypted at rest
+fn read_data_key(upload_dir: &Path) -> Result<Option<(Vec<u8>, bool)>> {
+    let wrapped_key = match fs::read(upload_dir.join(AT_REST_MARKER_FILE_NAME)) {
+        Ok(wrapped_key) => wrapped_key,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+
+    let keys = [(STORAGE_KEY.get(), true), (OLD_STORAGE_KEY.get(), false)];
+    for (storage_key, is_current) in keys {
+        if let Some(data_key) = storage_key.and_then(|k| decrypt(k, &wrapped_key)) {
+            return Ok(Some((data_key, is_current)));
+        }
+    }
+
+    Err(other_error("The upload is encrypted at rest with a storage key which is not set"))
+}
+
+fn data_key(upload_dir: &Path) -> Result<Option<Vec<u8>>> {
+    Ok(read_data_key(upload_dir)?.map(|(data_key, _)| data_key))
+}
+
+// Wrap the data key of the upload in `upload_dir` with the current storage
+// key if it is wrapped with the old one. Return whether it was.
+pub fn rekey_dir(upload_dir: &Path) -> Result<bool> {
+    match (read_data_key(upload_dir)?, STORAGE_KEY.get()) {
+        (Some((data_key, false)), Some(storage_key)) => {
+            write_marker(upload_dir, storage_key, &data_key)?;
+            Ok(true)
+        },
+        _ => Ok(false)
+    }
+}
+
+// Return the cipher for the file at `path` in the directory of an upload
+fn file_cipher(path: &Path) -> Result<Option<Aes256Ctr>> {
+    let upload_dir = path.parent().ok_or(other_error("Invalid upload path"))?;
+
+    match data_key(upload_dir)? {
+        Some(data_key) => {
+            let cipher = Aes256Ctr::new_from_slices(&data_key, &[0; 16])
+                .map_err(|_| other_error("Invalid key length"))?;
+            Ok(Some(cipher))
+        },
+        None => Ok(None)
+    }
+}
+
+// A file of an upload which is transparently encrypted at rest if its upload
+// is. Reads and writes at any position are supported, as long as the
+// position is only changed by seeking.
+pub struct StoredFile {
+    file: File,
+    cipher: Option<Aes256Ctr>,
+    position: u64,
+    buffer: Vec<u8>
+}
+
+impl StoredFile {
+    fn new(file: File, path: &Path) -> Result<Self> {
+        let new = Self {
+            file,
+            cipher: file_cipher(path)?,
+            position: 0,
+            buffer: Vec::new()
+        };
+
+        Ok(new)
+    }
+
+    pub fn open<P>(path: P) -> Result<Self>
+    where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        Self::new(File::open(path)?, path)
+    }
+
+    // This must never be used to write the file of an upload again: its
+    // keystream only depends on the data key (with a zero IV), so rewriting
+    // it would encrypt different data with the same keystream. Use
+    // `create_new` for files of uploads.
+    pub fn create<P>(path: P) -> Result<Self>
+    where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        Self::new(File::create(path)?, path)
+    }
+
+    // Fail if the file exists already
+    pub fn create_new<P>(path: P) -> Result<Self>
+    where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        Self::new(file, path)
+    }
+
+    // The underlying file, e.g. to reserve space for it
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn metadata(&self) -> Result<Metadata> {
+        self.file.metadata()
+    }
+}
+
+impl Read for StoredFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let bytes_read = self.file.read(buf)?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.seek(self.position);
+            cipher.apply_keystream(&mut buf[..bytes_read]);
+        }
+        self.position += bytes_read as u64;
+
+        Ok(bytes_read)
+    }
+}
+
+impl Write for StoredFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match &mut self.cipher {
+            Some(cipher) => {
+                self.buffer.clear();
+                self.buffer.extend_from_slice(buf);
+                cipher.seek(self.position);
+                cipher.apply_keystream(&mut self.buffer);
+                // A partial write would leave the keystream out of step
+                self.file.write_all(&self.buffer)?;
+            },
+            None => self.file.write_all(buf)?
+        }
+        self.position += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for StoredFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+// Return the contents of a metadata file in `upload_dir` as they are stored
+pub fn seal(upload_dir: &Path, plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    match data_key(upload_dir)? {
+        Some(data_key) => encrypt(&data_key, &plaintext),
+        None => Ok(plaintext)
+    }
+}
+
+// Return the contents of a metadata file in `upload_dir` stored with `seal`
+pub fn unseal(upload_dir: &Path, sealed: Vec<u8>) -> Result<Vec<u8>> {
+    match data_key(upload_dir)? {
+        Some(data_key) => decrypt(&data_key, &sealed).ok_or(other_error("decrypt")),
+        None => Ok(sealed)
+    }
+}
@@ -8,21 +8,25 @@ pub const BASE64_TABLE: &[u8] = &[
     b'8', b'9', b'-', b'_'
 ];
 
-const fn map_b64(digit: u8) -> u8 {
-    match digit {
-        b'A' => 0, b'B' => 1, b'C' => 2, b'D' => 3, b'E' => 4, b'F' => 5, b'G' => 6,
-        b'H' => 7, b'I' => 8, b'J' => 9, b'K' => 10, b'L' => 11, b'M' => 12,
-        b'N' => 13, b'O' => 14, b'P' => 15, b'Q' => 16, b'R' => 17, b'S' => 18,
-        b'T' => 19, b'U' => 20, b'V' => 21, b'W' => 22, b'X' => 23, b'Y' => 24,
-        b'Z' => 25, b'a' => 26, b'b' => 27, b'c' => 28, b'd' => 29, b'e' => 30,
-        b'f' => 31, b'g' => 32, b'h' => 33, b'i' => 34, b'j' => 35, b'k' => 36,
-        b'l' => 37, b'm' => 38, b'n' => 39, b'o' => 40, b'p' => 41, b'q' => 42,
-        b'r' => 43, b's' => 44, b't' => 45, b'u' => 46, b'v' => 47, b'w' => 48,
-        b'x' => 49, b'y' => 50, b'z' => 51, b'0' => 52, b'1' => 53, b'2' => 54,
-        b'3' => 55, b'4' => 56, b'5' => 57, b'6' => 58, b'7' => 59, b'8' => 60,
-        b'9' => 61, b'-' => 62, b'_' => 63,
-        _ => u8::MAX
-    }
+// Return the value of a base64 digit, or -1 if it isn't one. This doesn't
+// branch on the digit or use it as an index, so that decoding a key doesn't
+// leak it through timing. The digits of the standard alphabet ('+' and '/')
+// are only accepted if `is_lenient`.
+fn digit_value(digit: u8, is_lenient: bool) -> i16 {
+    let c = digit as i16;
+    let lenient_mask = -(is_lenient as i16);
+    // -1 if `lo` <= c <= `hi`, 0 otherwise
+    let in_range = |lo: u8, hi: u8| ((lo as i16 - 1 - c) & (c - hi as i16 - 1)) >> 8;
+
+    let mut value = -1;
+    value += in_range(b'A', b'Z') & (c - b'A' as i16 + 1);
+    value += in_range(b'a', b'z') & (c - b'a' as i16 + 27);
+    value += in_range(b'0', b'9') & (c - b'0' as i16 + 53);
+    value += in_range(b'-', b'-') & 63;
+    value += in_range(b'_', b'_') & 64;
+    value += in_range(b'+', b'+') & lenient_mask & 63;
+    value += in_range(b'/', b'/') & lenient_mask & 64;
+    value
 }
 
 // Return the number of bytes required to store the base64-encoded form of a
@@ -50,71 +54,185 @@ pub const fn base64_decode_length(num_bytes: usize) -> Option<usize> {
     })
 }
 
-// encode the input bytes into URL-safe base64
-pub fn base64_encode(bytes: &[u8]) -> Vec<u8> {
-    let mut vec = Vec::with_capacity(base64_encode_length(bytes.len()));
+// Encode a 1 to 3 byte group into 2 to 4 digits
+fn encode_group(group: &[u8], out: &mut Vec<u8>) {
+    let mut bytes = [0; 4];
+    bytes[1..1 + group.len()].copy_from_slice(group);
+    let bits = u32::from_be_bytes(bytes);
 
-    let len = (bytes.len() + 2) / 3;
+    for i in 0..=group.len() {
+        out.push(BASE64_TABLE[(bits >> (18 - 6 * i) & 0b00111111) as usize]);
+    }
+}
 
-    for i in 0..len {
-        let i = i * 3;
+// Encodes into URL-safe base64 over several calls. The bytes which don't fill
+// a group of 3 are carried over to the next call, so the input doesn't have
+// to be in memory all at once.
+pub struct Base64Encoder {
+    pending: [u8; 3],
+    pending_len: usize
+}
 
-        let first = bytes[i] >> 2;
-        vec.push(BASE64_TABLE[first as usize] as u8);
+impl Base64Encoder {
+    pub fn new() -> Self {
+        Self { pending: [0; 3], pending_len: 0 }
+    }
 
-        let mut second = bytes[i] << 4 & 0b00110000;
-        if i + 1 < bytes.len() {
-            second += bytes[i + 1] >> 4 & 0b00001111;
-        }
-        vec.push(BASE64_TABLE[second as usize] as u8);
+    // Append the encoding of `bytes` to `out`, apart from up to 2 bytes which
+    // are held back until more follow or `finish` is called
+    pub fn update(&mut self, mut bytes: &[u8], out: &mut Vec<u8>) {
+        if self.pending_len > 0 {
+            let num_bytes = (3 - self.pending_len).min(bytes.len());
+            self.pending[self.pending_len..self.pending_len + num_bytes]
+                .copy_from_slice(&bytes[..num_bytes]);
+            self.pending_len += num_bytes;
+            bytes = &bytes[num_bytes..];
 
-        let mut third = bytes.get(i + 1).map(|b| b << 2 & 0b00111100);
-        if let Some(b) = bytes.get(i + 2) {
-            *third.as_mut().unwrap() += b >> 6 & 0b00000011;
+            if self.pending_len < 3 {
+                return;
+            }
+            encode_group(&self.pending, out);
+            self.pending_len = 0;
         }
-        match third {
-            Some(third) => vec.push(BASE64_TABLE[third as usize] as u8),
-            None => {}
+
+        let mut groups = bytes.chunks_exact(3);
+        for group in &mut groups {
+            encode_group(group, out);
         }
 
-        let fourth = bytes.get(i + 2).map(|b| b & 0b00111111);
-        match fourth {
-            Some(fourth) => vec.push(BASE64_TABLE[fourth as usize] as u8),
-            None => {}
+        let remainder = groups.remainder();
+        self.pending[..remainder.len()].copy_from_slice(remainder);
+        self.pending_len = remainder.len();
+    }
+
+    // Append the encoding of the bytes which were held back to `out`
+    pub fn finish(self, out: &mut Vec<u8>) {
+        if self.pending_len > 0 {
+            encode_group(&self.pending[..self.pending_len], out);
         }
     }
+}
 
-    vec
+impl Default for Base64Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-// decode the input bytes from URL-safe base64 into an unencoded form
-pub fn base64_decode(b64: &[u8]) -> Option<Vec<u8>> {
-    let mut vec = Vec::with_capacity(base64_decode_length(b64.len())?);
+// Decodes base64 over several calls, like `Base64Encoder`. Errors are
+// collected over the whole input instead of being returned as soon as they
+// are found, so that the time taken doesn't depend on where they are, and are
+// only reported by `finish`. Until then, the output may hold garbage.
+pub struct Base64Decoder {
+    is_lenient: bool,
+    group: [u8; 4],
+    group_len: usize,
+    padding: usize,
+    invalid: i16
+}
+
+impl Base64Decoder {
+    // Return a decoder for URL-safe base64 without padding
+    pub fn new() -> Self {
+        Self { is_lenient: false, group: [0; 4], group_len: 0, padding: 0, invalid: 0 }
+    }
 
-    let len = (b64.len() + 3) / 4;
+    // Return a decoder which also accepts the standard alphabet and padding
+    pub fn lenient() -> Self {
+        Self { is_lenient: true, ..Self::new() }
+    }
+
+    // Append the decoded form of `b64` to `out`, apart from up to 3 digits
+    // which are held back until more follow or `finish` is called
+    pub fn update(&mut self, b64: &[u8], out: &mut Vec<u8>) {
+        for digit in b64 {
+            if self.is_lenient && *digit == b'=' {
+                self.padding += 1;
+                continue;
+            }
+            // (padding can only come at the end)
+            if self.padding > 0 {
+                self.invalid = -1;
+            }
 
-    for i in 0..len {
-        let i = i * 4;
+            self.group[self.group_len] = *digit;
+            self.group_len += 1;
+            if self.group_len == 4 {
+                self.decode_group(out);
+                self.group_len = 0;
+            }
+        }
+    }
 
-        let b64_1 = map_b64(*b64.get(i)?);
-        let b64_2 = map_b64(*b64.get(i + 1)?);
-        let b64_3 = b64.get(i + 2).map(|b| map_b64(*b));
-        let b64_4 = b64.get(i + 3).map(|b| map_b64(*b));
+    // Append the decoded form of the digits which were held back to `out`.
+    // Return None if the input as a whole wasn't valid.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> Option<()> {
+        let leftover = match self.group_len {
+            0 => 0,
+            1 => { return None }
+            _ => self.decode_group(out)
+        };
 
-        let first = (b64_1 << 2) + (b64_2 >> 4);
-        vec.push(first);
+        // padding has to fill out the last group, and nothing else
+        let is_padding_valid = self.padding == 0
+            || (self.group_len > 0 && self.group_len + self.padding == 4);
 
-        if let Some(b64_3) = b64_3 {
-            let second = (b64_2 << 4) + (b64_3 >> 2); 
-            vec.push(second);
+        if self.invalid == 0 && leftover == 0 && is_padding_valid {
+            Some(())
+        } else {
+            None
         }
+    }
 
-        if let Some(b64_4) = b64_4 {
-            let third = (b64_3.unwrap() << 6) + b64_4;
-            vec.push(third);
+    // Decode the group of 2 to 4 digits being held into 1 to 3 bytes. Return
+    // the bits after them, which have to be zero so that every byte string
+    // has one encoding.
+    fn decode_group(&mut self, out: &mut Vec<u8>) -> u32 {
+        let mut bits: u32 = 0;
+        for (i, digit) in self.group[..self.group_len].iter().enumerate() {
+            let value = digit_value(*digit, self.is_lenient);
+            self.invalid |= value >> 8;
+            bits |= ((value & 0b00111111) as u32) << (18 - 6 * i);
         }
+
+        let num_bytes = self.group_len - 1;
+        out.extend_from_slice(&bits.to_be_bytes()[1..1 + num_bytes]);
+        bits & (0x00ffffff >> (8 * num_bytes))
+    }
+}
+
+impl Default for Base64Decoder {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
+// encode the input bytes into URL-safe base64
+pub fn base64_encode(bytes: &[u8]) -> Vec<u8> {
+    let mut vec = Vec::with_capacity(base64_encode_length(bytes.len()));
+    let mut encoder = Base64Encoder::new();
+    encoder.update(bytes, &mut vec);
+    encoder.finish(&mut vec);
+    vec
+}
+
+// decode the input bytes from URL-safe base64 into an unencoded form. Return
+// None if the input isn't valid URL-safe base64 without padding.
+pub fn base64_decode(b64: &[u8]) -> Option<Vec<u8>> {
+    let mut vec = Vec::with_capacity(base64_decode_length(b64.len())?);
+    let mut decoder = Base64Decoder::new();
+    decoder.update(b64, &mut vec);
+    decoder.finish(&mut vec)?;
+    Some(vec)
+}
+
+// decode the input bytes like `base64_decode`, but also accept the standard
+// alphabet and padding, for keys which were pasted from other tools
+pub fn base64_decode_lenient(b64: &[u8]) -> Option<Vec<u8>> {
+    let mut vec = Vec::with_capacity(b64.len() / 4 * 3 + 2);
+    let mut decoder = Base64Decoder::lenient();
+    decoder.update(b64, &mut vec);
+    decoder.finish(&mut vec)?;
     Some(vec)
 }
 
@@ -158,4 +276,90 @@ mod tests {
 
         assert_eq!(expected_msg.as_bytes(), msg);
     }
+
+    #[test]
+    fn test_base64_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+
+        for len in 0..bytes.len() {
+            let b64 = base64_encode(&bytes[..len]);
+            assert_eq!(base64_decode(&b64).unwrap(), &bytes[..len]);
+            assert_eq!(base64_decode_lenient(&b64).unwrap(), &bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn test_base64_decode_invalid() {
+        // invalid characters
+        assert_eq!(base64_decode(b"YSBza W1w"), None);
+        assert_eq!(base64_decode(b"YSBz\xffW1w"), None);
+        assert_eq!(base64_decode(b"YSBz\0W1w"), None);
+        // impossible length
+        assert_eq!(base64_decode(b"YSBza"), None);
+        // trailing bits which aren't zero ("YQ" is the encoding of "a")
+        assert_eq!(base64_decode(b"YR"), None);
+        // standard alphabet and padding
+        assert_eq!(base64_decode(b"+/+/"), None);
+        assert_eq!(base64_decode(b"YQ=="), None);
+    }
+
+    #[test]
+    fn test_base64_decode_lenient() {
+        let expected = base64_decode(b"-_-_").unwrap();
+        assert_eq!(base64_decode_lenient(b"+/+/").unwrap(), expected);
+        assert_eq!(base64_decode_lenient(b"-/+_").unwrap(), expected);
+
+        assert_eq!(base64_decode_lenient(b"YQ==").unwrap(), b"a");
+        assert_eq!(base64_decode_lenient(b"YWI=").unwrap(), b"ab");
+        assert_eq!(base64_decode_lenient(b"YQ").unwrap(), b"a");
+
+        // padding has to fill out the last group, and nothing else
+        assert_eq!(base64_decode_lenient(b"YQ="), None);
+        assert_eq!(base64_decode_lenient(b"YQ==="), None);
+        assert_eq!(base64_decode_lenient(b"YWJj===="), None);
+        assert_eq!(base64_decode_lenient(b"Y=Q="), None);
+        assert_eq!(base64_decode_lenient(b"===="), None);
+    }
+
+    #[test]
+    fn test_base64_streaming() {
+        let bytes: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let expected_b64 = base64_encode(&bytes);
+
+        for chunk_size in [1, 2, 3, 4, 5, 7, 64, 1000] {
+            let mut b64 = Vec::new();
+            let mut encoder = Base64Encoder::new();
+            for chunk in bytes.chunks(chunk_size) {
+                encoder.update(chunk, &mut b64);
+            }
+            encoder.finish(&mut b64);
+            assert_eq!(b64, expected_b64);
+
+            let mut decoded = Vec::new();
+            let mut decoder = Base64Decoder::new();
+            for chunk in b64.chunks(chunk_size) {
+                decoder.update(chunk, &mut decoded);
+            }
+            decoder.finish(&mut decoded).unwrap();
+            assert_eq!(decoded, bytes);
+        }
+    }
+
+    #[test]
+    fn test_base64_streaming_invalid() {
+        // an invalid digit in an earlier call is still reported by `finish`
+        let mut decoder = Base64Decoder::new();
+        let mut decoded = Vec::new();
+        decoder.update(b"YS*z", &mut decoded);
+        decoder.update(b"aW1w", &mut decoded);
+        assert_eq!(decoder.finish(&mut decoded), None);
+
+        // padding split across calls
+        let mut decoder = Base64Decoder::lenient();
+        let mut decoded = Vec::new();
+        decoder.update(b"YQ=", &mut decoded);
+        decoder.update(b"=", &mut decoded);
+        assert_eq!(decoder.finish(&mut decoded), Some(()));
+        assert_eq!(decoded, b"a");
+    }
 }
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder, Header};
+use tracing::warn;
+
+use crate::b64::*;
+use crate::db::*;
+
+
+// Incremented whenever the layout of the archive changes
+const BACKUP_VERSION: u32 = 1;
+// The manifest is the first entry of the archive, followed by the uploads
+// stored at `STORAGE_DIR_NAME/<id>/upload`
+const MANIFEST_NAME: &'static str = "manifest.json";
+const STORAGE_DIR_NAME: &'static str = "storage";
+
+
+// The database rows contained in a backup
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    created_at: NaiveDateTime,
+    // for an incremental backup, only uploads used since this time are
+    // included
+    since: Option<NaiveDateTime>,
+    users: Vec<User>,
+    api_keys: Vec<ApiKey>,
+    uploads: Vec<Upload>
+}
+
+pub struct BackupReport {
+    pub created_at: NaiveDateTime,
+    pub num_uploads: usize
+}
+
+#[derive(Default)]
+pub struct RestoreReport {
+    pub num_users: usize,
+    pub num_api_keys: usize,
+    pub num_uploads: usize,
+    // uploads which had expired, which are restored as deleted
+    pub num_expired: usize
+}
+
+fn id_string(id: i64) -> String {
+    String::from_utf8(i64_to_b64_bytes(id)).unwrap()
+}
+
+fn other_error(message: &'static str) -> Error {
+    Error::new(ErrorKind::Other, message)
+}
+
+// Write every user, API key and completed upload (or only the uploads used
+// since `since`) into a tar archive at `path`.
+pub fn backup(
+    path: &Path, since: Option<NaiveDateTime>, storage_path: &PathBuf,
+    db_connection: &DbConnection) -> Result<BackupReport>
+{
+    let created_at = Local::now().naive_utc();
+
+    let users = User::select_all(db_connection)
+        .ok_or(other_error("Selecting users"))?;
+    let api_keys = ApiKey::select_all(db_connection)
+        .ok_or(other_error("Selecting API keys"))?;
+    let uploads = Upload::select_for_backup(since, db_connection)
+        .ok_or(other_error("Selecting uploads"))?
+        .into_iter()
+        .filter(|upload| {
+            let is_stored = storage_path.join(id_string(upload.id)).join("upload").is_file();
+            if !is_stored {
+                warn!(id = upload.id, "Skipping upload whose file is missing");
+            }
+            is_stored
+        })
+        .collect::<Vec<_>>();
+
+    let manifest = Manifest {
+        version: BACKUP_VERSION,
+        created_at,
+        since,
+        users,
+        api_keys,
+        uploads
+    };
+
+    let mut builder = Builder::new(File::create(path)?);
+
+    let manifest_bytes = serde_json::to_vec(&manifest)?;
+    let mut header = Header::new_gnu();
+    header.set_size(manifest_bytes.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(created_at.timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_NAME, manifest_bytes.as_slice())?;
+
+    for upload in &manifest.uploads {
+        let id_string = id_string(upload.id);
+        builder.append_path_with_name(
+            storage_path.join(&id_string).join("upload"),
+            Path::new(STORAGE_DIR_NAME).join(&id_string).join("upload"))?;
+    }
+
+    builder.into_inner()?;
+
+    Ok(BackupReport {
+        created_at,
+        num_uploads: manifest.uploads.len()
+    })
+}
+
+// Return the ID of the upload stored at `path` inside a backup, if it is an
+// upload in the expected place (and not e.g. a path outside the storage
+// directory)
+fn upload_id_from_entry_path(path: &Path) -> Option<i64> {
+    let mut components = path.components();
+
+    match (components.next(), components.next(), components.next(), components.next()) {
+        (Some(Component::Normal(dir)), Some(Component::Normal(id)), Some(Component::Normal(name)), None)
+            if dir == STORAGE_DIR_NAME && name == "upload" =>
+        {
+            i64_from_b64_bytes(id.to_str()?.as_bytes())
+        },
+        _ => None
+    }
+}
+
+// Import the backup at `path`. Users and API keys which already exist are
+// kept, while uploads replace any upload with the same ID (so incremental
+// backups can be restored on top of a full one). Uploads which have expired
+// since the backup was made are restored as deleted, so they are purged after
+// the deletion grace period unless they are restored with `upload restore`.
+pub fn restore(
+    path: &Path, storage_path: &PathBuf,
+    db_connection: &DbConnection) -> Result<RestoreReport>
+{
+    let mut archive = Archive::new(File::open(path)?);
+    let mut entries = archive.entries()?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.as_ref() != Path::new(MANIFEST_NAME) {
+                return Err(other_error("The archive does not start with a manifest"));
+            }
+            let mut manifest_bytes = Vec::new();
+            entry.read_to_end(&mut manifest_bytes)?;
+            serde_json::from_slice(&manifest_bytes)?
+        },
+        None => return Err(other_error("The archive is empty"))
+    };
+
+    if manifest.version != BACKUP_VERSION {
+        return Err(other_error("Unsupported backup version"));
+    }
+
+    let upload_ids = manifest.uploads.iter()
+        .map(|upload| upload.id)
+        .collect::<HashSet<_>>();
+
+    // Store the files before the rows, so that no upload is ever served
+    // without its file
+    for entry in entries {
+        let mut entry = entry?;
+        let id = match upload_id_from_entry_path(&entry.path()?) {
+            Some(id) if upload_ids.contains(&id) => id,
+            _ => {
+                warn!(path = %entry.path()?.display(), "Skipping unexpected file in backup");
+                continue;
+            }
+        };
+
+        let upload_dir = storage_path.join(id_string(id));
+        fs::create_dir_all(&upload_dir)?;
+        entry.unpack(upload_dir.join("upload"))?;
+    }
+
+    let mut report = RestoreReport::default();
+
+    for user in &manifest.users {
+        if User::select_with_id(user.id, db_connection).is_none() {
+            match user.insert(db_connection) {
+                Some(_) => report.num_users += 1,
+                None => warn!(username = %user.username, "Restoring user failed")
+            }
+        }
+    }
+
+    for api_key in &manifest.api_keys {
+        if ApiKey::select_with_hash(&api_key.key_hash, db_connection).is_none() {
+            match api_key.insert(db_connection) {
+                Some(_) => report.num_api_keys += 1,
+                None => warn!(id = api_key.id, "Restoring API key failed")
+            }
+        }
+    }
+
+    let now = Local::now().naive_utc();
+    for mut upload in manifest.uploads {
+        // Nobody is accessing the restored upload yet
+        upload.num_accessors = 0;
+
+        if upload.deleted_at.is_none() && upload.is_expired() {
+            upload.deleted_at = Some(now);
+            report.num_expired += 1;
+        }
+
+        match upload.replace(db_connection) {
+            Some(_) => report.num_uploads += 1,
+            None => warn!(id = upload.id, "Restoring upload failed")
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,340 @@
+use crate::api_keys::*;
+use crate::b64::*;
+use crate::client_ip::*;
+use crate::config::*;
+use crate::constants::*;
+use crate::db::*;
+use crate::federation::delete_on_peers;
+use crate::http_errors::*;
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use blocking::unblock;
+use chrono::Local;
+use ipnet::IpNet;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use serde_json::json;
+use smol::io::AsyncReadExt;
+use trillium::Conn;
+use tracing::{info, info_span, warn};
+use urlencoding::decode;
+
+
+// Number of seconds between reloads of the blocklists, so that changes made
+// through other Transpo processes are picked up
+const RELOAD_DELAY_SECS: u64 = 60;
+const MAX_FORM_BODY_SIZE: u64 = 4096;
+const MAX_REASON_LENGTH: usize = 1000;
+
+
+// The blocklists are consulted for every upload and download, so they are
+// kept in memory and reloaded from the database periodically
+#[derive(Clone)]
+pub struct Blocklist {
+    networks: Arc<RwLock<Vec<IpNet>>>,
+    uploads: Arc<RwLock<HashSet<i64>>>
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Self {
+            networks: Arc::new(RwLock::new(Vec::new())),
+            uploads: Arc::new(RwLock::new(HashSet::new()))
+        }
+    }
+
+    // Replace the blocklists with those in the database. They are kept as
+    // they are if the database can't be read.
+    pub fn reload(&self, db_connection: &DbConnection) {
+        let networks = BlockedNetwork::select_all(db_connection);
+        let uploads = BlockedUpload::select_all(db_connection);
+
+        match (networks, uploads) {
+            (Some(networks), Some(uploads)) => {
+                *self.networks.write().unwrap() = networks.iter()
+                    .filter_map(|n| parse_network(&n.network))
+                    .collect();
+                *self.uploads.write().unwrap() = uploads.iter()
+                    .map(|u| u.upload_id)
+                    .collect();
+            },
+            _ => warn!("Reading the blocklists failed")
+        }
+    }
+
+    pub fn is_ip_blocked(&self, ip: &IpAddr) -> bool {
+        self.networks.read().unwrap().iter().any(|n| n.contains(ip))
+    }
+
+    pub fn is_upload_blocked(&self, id: i64) -> bool {
+        self.uploads.read().unwrap().contains(&id)
+    }
+}
+
+pub fn spawn_blocklist_thread(blocklist: Blocklist, db_backend: DbBackend, db_url: String) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(RELOAD_DELAY_SECS));
+
+        let _span = info_span!("blocklist").entered();
+
+        // Keep the current blocklists until the database is available again
+        if let Some(db_connection) = establish_connection(db_backend, &db_url) {
+            blocklist.reload(&db_connection);
+        }
+    });
+}
+
+
+#[derive(Serialize)]
+struct NetworkInfo {
+    id: String,
+    network: String,
+    reason: Option<String>,
+    // Unix timestamp
+    created_at: i64
+}
+
+#[derive(Serialize)]
+struct UploadInfo {
+    id: String,
+    reason: Option<String>,
+    // Unix timestamp
+    created_at: i64
+}
+
+fn id_to_string(id: i64) -> String {
+    String::from_utf8(i64_to_b64_bytes(id)).unwrap()
+}
+
+fn parse_id(id_string: &str) -> Option<i64> {
+    match i64_from_b64_bytes(id_string.as_bytes()) {
+        Some(id) if id_string.len() == base64_encode_length(ID_LENGTH) => Some(id),
+        _ => None
+    }
+}
+
+// Parse an `application/x-www-form-urlencoded` request body
+async fn parse_form_body(conn: &mut Conn) -> Option<Vec<(String, String)>> {
+    let mut body = String::new();
+    conn.request_body().await
+        .take(MAX_FORM_BODY_SIZE)
+        .read_to_string(&mut body).await
+        .ok()?;
+
+    body.split('&')
+        .filter_map(|field| field.split_once('='))
+        .map(|(key, value)| Some((
+            key.to_string(),
+            decode(&value.replace('+', "%20")).ok()?.trim().to_string())))
+        .collect()
+}
+
+fn form_value(form: &[(String, String)], key: &str) -> Option<String> {
+    form.iter()
+        .find(|(k, v)| k == key && !v.is_empty())
+        .map(|(_, v)| v.clone())
+}
+
+// Reload the blocklists if a change to them succeeded, so that it applies
+// right away in this process. Return the result of the change.
+fn reloaded<T>(result: Option<T>, blocklist: &Blocklist, db_connection: &DbConnection) -> Option<T> {
+    let result = result?;
+    blocklist.reload(db_connection);
+    Some(result)
+}
+
+// `GET /api/v1/blocklist`
+pub async fn list(conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn {
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let body = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+
+        let networks = BlockedNetwork::select_all(&db_connection)?
+            .into_iter()
+            .map(|n| NetworkInfo {
+                id: id_to_string(n.id),
+                network: n.network,
+                reason: n.reason,
+                created_at: n.created_at.timestamp()
+            })
+            .collect::<Vec<_>>();
+        let uploads = BlockedUpload::select_all(&db_connection)?
+            .into_iter()
+            .map(|u| UploadInfo {
+                id: id_to_string(u.upload_id),
+                reason: u.reason,
+                created_at: u.created_at.timestamp()
+            })
+            .collect::<Vec<_>>();
+
+        Some(json!({ "networks": networks, "uploads": uploads }).to_string())
+    }).await;
+
+    match body {
+        Some(body) => conn
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(body)
+            .halt(),
+        None => api_error(conn, 500, "Listing the blocklists failed")
+    }
+}
+
+// `POST /api/v1/blocklist/networks`: refuse uploads from a network
+pub async fn block_network(
+    mut conn: Conn, config: Arc<TranspoConfig>, blocklist: Blocklist,
+    db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let form = parse_form_body(&mut conn).await.unwrap_or_default();
+    let network = match form_value(&form, "network").as_deref().and_then(parse_network) {
+        Some(network) => network,
+        None => return api_error(conn, 400, "`network` must be an address or a network in CIDR notation")
+    };
+    let reason = form_value(&form, "reason");
+    if reason.as_ref().map(|r| r.chars().count() > MAX_REASON_LENGTH).unwrap_or(false) {
+        return api_error(conn, 400, "`reason` is too long");
+    }
+
+    let blocked = BlockedNetwork {
+        id: thread_rng().gen(),
+        network: network.to_string(),
+        reason,
+        created_at: Local::now().naive_utc()
+    };
+    let id = blocked.id;
+
+    let inserted = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        reloaded(blocked.insert(&db_connection), &blocklist, &db_connection)
+    }).await;
+
+    match inserted {
+        Some(_) => {
+            info!(%network, "Network blocked");
+            conn
+                .with_status(201)
+                .with_header("Content-Type", "application/json")
+                .with_body(json!({ "id": id_to_string(id) }).to_string())
+                .halt()
+        },
+        None => api_error(conn, 500, "Blocking the network failed")
+    }
+}
+
+// `DELETE /api/v1/blocklist/networks/:id`
+pub async fn unblock_network(
+    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    blocklist: Blocklist, db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return api_error(conn, 404, "The network is not blocked")
+    };
+
+    let deleted = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        reloaded(BlockedNetwork::delete_with_id(id, &db_connection), &blocklist, &db_connection)
+    }).await;
+
+    match deleted {
+        Some(0) => api_error(conn, 404, "The network is not blocked"),
+        Some(_) => {
+            info!(id = %id_string, "Network unblocked");
+            conn.with_status(204).halt()
+        },
+        None => api_error(conn, 500, "Unblocking the network failed")
+    }
+}
+
+// `POST /api/v1/blocklist/uploads`: make an upload expire now, and keep it
+// from being downloaded or restored
+pub async fn block_upload(
+    mut conn: Conn, config: Arc<TranspoConfig>, blocklist: Blocklist,
+    db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let form = parse_form_body(&mut conn).await.unwrap_or_default();
+    let id_string = form_value(&form, "id").unwrap_or_default();
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return api_error(conn, 400, "`id` must be the ID of an upload")
+    };
+    let reason = form_value(&form, "reason");
+    if reason.as_ref().map(|r| r.chars().count() > MAX_REASON_LENGTH).unwrap_or(false) {
+        return api_error(conn, 400, "`reason` is too long");
+    }
+
+    let blocked = BlockedUpload {
+        upload_id: id,
+        reason,
+        created_at: Local::now().naive_utc()
+    };
+
+    let peers_config = config.clone();
+    let inserted = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        // (blocking an upload twice is fine)
+        if !blocklist.is_upload_blocked(id) {
+            blocked.insert(&db_connection)?;
+        }
+        let result = Upload::expire_now(id, &db_connection);
+        reloaded(result, &blocklist, &db_connection)
+    }).await;
+
+    match inserted {
+        Some(_) => {
+            info!(id = %id_string, "Upload blocked");
+            delete_on_peers(id, peers_config);
+            conn.with_status(204).halt()
+        },
+        None => api_error(conn, 500, "Blocking the upload failed")
+    }
+}
+
+// `DELETE /api/v1/blocklist/uploads/:file_id`. (The upload stays expired.)
+pub async fn unblock_upload(
+    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    blocklist: Blocklist, db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return api_error(conn, 404, "The upload is not blocked")
+    };
+
+    let deleted = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        reloaded(BlockedUpload::delete_with_upload(id, &db_connection), &blocklist, &db_connection)
+    }).await;
+
+    match deleted {
+        Some(0) => api_error(conn, 404, "The upload is not blocked"),
+        Some(_) => {
+            info!(id = %id_string, "Upload unblocked");
+            conn.with_status(204).halt()
+        },
+        None => api_error(conn, 500, "Unblocking the upload failed")
+    }
+}
@@ -0,0 +1,99 @@
+use crate::constants::FORM_READ_BUFFER_SIZE;
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+
+// Every stream of an upload (each upload, download and archived file) holds
+// a buffer for the chunk being encrypted or decrypted. When hundreds of
+// streams are active, these come and go all the time, so the buffers of
+// finished streams are kept for the next ones instead of being freed.
+
+// capacity of the buffers, which fits a chunk of plaintext and its ciphertext
+pub const BUFFER_CAPACITY: usize = FORM_READ_BUFFER_SIZE * 2;
+// number of buffers kept at most, beyond which returned buffers are freed
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+// A buffer which goes back to the pool when it is dropped
+pub struct PooledBuffer {
+    buffer: Vec<u8>
+}
+
+impl PooledBuffer {
+    pub fn new() -> Self {
+        let buffer = POOL.lock().ok()
+            .and_then(|mut pool| pool.pop())
+            .unwrap_or_else(|| Vec::with_capacity(BUFFER_CAPACITY));
+
+        Self { buffer }
+    }
+}
+
+impl Default for PooledBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        // Buffers which grew past a chunk aren't kept, so that the pool
+        // doesn't hold on to more memory than it was meant to
+        let capacity = self.buffer.capacity();
+        if !(BUFFER_CAPACITY..=BUFFER_CAPACITY * 2).contains(&capacity) {
+            return;
+        }
+
+        if let Ok(mut pool) = POOL.lock() {
+            if pool.len() < MAX_POOLED_BUFFERS {
+                let mut buffer = std::mem::take(&mut self.buffer);
+                buffer.clear();
+                pool.push(buffer);
+            }
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_buffers_are_empty() {
+        let mut buffer = PooledBuffer::new();
+        buffer.extend_from_slice(&[1; 100]);
+        drop(buffer);
+
+        // (other tests may take buffers from the pool at the same time, but
+        // none of them are handed out with contents)
+        let buffer = PooledBuffer::new();
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= BUFFER_CAPACITY);
+    }
+
+    #[test]
+    fn oversized_buffers_are_freed() {
+        let mut buffer = PooledBuffer::new();
+        buffer.reserve(BUFFER_CAPACITY * 4);
+        drop(buffer);
+
+        assert!(POOL.lock().unwrap().iter().all(|b| b.capacity() <= BUFFER_CAPACITY * 2));
+    }
+}
@@ -1,75 +1,453 @@
+use crate::api_keys::*;
+use crate::cluster::Leader;
+use crate::concurrency::*;
+use crate::config::*;
 use crate::db::*;
 use crate::files::*;
 use crate::b64::*;
+use crate::metrics::count_cleanup;
 use std::thread;
 use std::time::{Duration, SystemTime};
 use std::path::PathBuf;
+use std::sync::Arc;
+use blocking::unblock;
+use chrono::{Local, Duration as ChronoDuration};
+use serde::Serialize;
+use tracing::{debug, error, info, info_span, warn};
+use trillium::Conn;
 
 const CLEANUP_DELAY_SECS: u64 = 60 * 60;
+// Uploads which fail verification are moved into this subdirectory of the
+// storage directory
+pub const QUARANTINE_DIR_NAME: &'static str = "quarantine";
 
 pub fn spawn_cleanup_thread(
-    read_timeout_ms: usize, storage_path: PathBuf,
-    db_backend: DbBackend, db_url: String)
+    read_timeout_ms: usize, audit_retention_minutes: usize,
+    deletion_grace_minutes: usize,
+    storage_path: PathBuf, db_backend: DbBackend, db_url: String, leader: Leader)
 {
-    thread::spawn(move || cleanup_thread(read_timeout_ms, storage_path, db_backend, db_url));
+    thread::spawn(move || cleanup_thread(
+            read_timeout_ms, audit_retention_minutes, deletion_grace_minutes,
+            storage_path, db_backend, db_url, leader));
 }
 
 fn cleanup_thread(
-    read_timeout_ms: usize, storage_path: PathBuf,
-    db_backend: DbBackend, db_url: String)
+    read_timeout_ms: usize, audit_retention_minutes: usize,
+    deletion_grace_minutes: usize,
+    storage_path: PathBuf, db_backend: DbBackend, db_url: String, leader: Leader)
 {
     loop {
         thread::sleep(Duration::from_secs(CLEANUP_DELAY_SECS));
 
+        // Replicas would delete the same uploads at once
+        if !leader.is_leader() {
+            continue;
+        }
+
         let storage_path = storage_path.clone();
         let db_url = db_url.clone();
 
-        thread::spawn(move || cleanup(read_timeout_ms, storage_path, db_backend, db_url));
+        thread::spawn(move || cleanup(
+                read_timeout_ms, audit_retention_minutes, deletion_grace_minutes,
+                storage_path, db_backend, db_url));
     }
 }
 
+// What a cleanup deleted (or would delete, for a dry run), by upload ID
+#[derive(Default, Serialize)]
+pub struct CleanupReport {
+    // uploads which expired and were marked as deleted
+    pub expired: Vec<String>,
+    // deleted uploads whose grace period is over
+    pub purged: Vec<String>,
+    // upload directories without a database record
+    pub broken: Vec<String>,
+    // database records whose upload is missing from the storage directory
+    pub missing: Vec<String>,
+    pub dry_run: bool
+}
+
+// Uploads which failed verification and what was wrong with them
+#[derive(Default)]
+pub struct VerifyReport {
+    pub num_verified: usize,
+    // uploads whose first chunk was decrypted as well, since their key is
+    // known
+    pub num_decrypted: usize,
+    pub failed: Vec<(String, String)>,
+    pub quarantined: usize
+}
+
+fn id_string(id: i64) -> String {
+    String::from_utf8(i64_to_b64_bytes(id)).unwrap()
+}
+
 fn cleanup(
-    read_timeout_ms: usize, storage_path: PathBuf, db_backend: DbBackend, db_url: String)
+    read_timeout_ms: usize, audit_retention_minutes: usize,
+    deletion_grace_minutes: usize,
+    storage_path: PathBuf, db_backend: DbBackend, db_url: String)
+{
+    let _span = info_span!("cleanup").entered();
+
+    // Try again on the next cleanup if the database is unavailable
+    let db_connection = match establish_connection(db_backend, &db_url) {
+        Some(db_connection) => db_connection,
+        None => {
+            warn!("Skipping cleanup, the database is unavailable");
+            return;
+        }
+    };
+
+    run_cleanup(
+        read_timeout_ms, audit_retention_minutes, deletion_grace_minutes,
+        &storage_path, &db_connection, false);
+}
+
+// Delete expired, purgeable and broken uploads. If `dry_run` is set, only
+// report what would be deleted without changing anything.
+pub fn run_cleanup(
+    read_timeout_ms: usize, audit_retention_minutes: usize,
+    deletion_grace_minutes: usize,
+    storage_path: &PathBuf, db_connection: &DbConnection,
+    dry_run: bool) -> CleanupReport
 {
-    let db_connection = establish_connection(db_backend, &db_url);
+    let mut report = CleanupReport { dry_run, ..CleanupReport::default() };
+
+    if !dry_run {
+        // Scrub audit data which is past its retention window. If recording
+        // audit data has been disabled, all of it is scrubbed.
+        let created_before = Local::now().naive_utc()
+            - ChronoDuration::minutes(audit_retention_minutes as i64);
+        Upload::scrub_audit_data(created_before, db_connection);
+
+        Session::delete_expired(db_connection);
+    }
 
-    if let Some(expired_upload_ids) = Upload::select_expired(&db_connection) {
+    if let Some(expired_upload_ids) = Upload::select_expired(db_connection) {
         for id in expired_upload_ids {
-            Upload::delete_with_id(id, &db_connection);
-            delete_upload_dir(&storage_path, id);
+            debug!(id, dry_run, "Upload expired");
+            if !dry_run {
+                Upload::mark_deleted(id, db_connection);
+            }
+            report.expired.push(id_string(id));
         }
     }
 
-    // Detect broken uploads by the following criteria:
-    // - There is a directory for the upload whose name is a valid ID.
-    // - There is no record of an upload with said ID in the database.
-    // - The time since the upload was modified *exceeds* the maximum
-    //   amount of time Transpo permits between writes, i.e. we can be
-    //   reasonably sure that the upload is not currently in progress.
-    if let Ok(dir_entries) = std::fs::read_dir(&storage_path) {
+    // Purge uploads whose deletion grace period is over
+    let deleted_before = Local::now().naive_utc()
+        - ChronoDuration::minutes(deletion_grace_minutes as i64);
+    if let Some(purgeable_upload_ids) = Upload::select_purgeable(deleted_before, db_connection) {
+        for id in purgeable_upload_ids {
+            // Note: ID generation avoids collisions by checking the
+            // filesystem, so we remove the upload directory last.
+            debug!(id, dry_run, "Purging deleted upload");
+            if !dry_run {
+                Upload::delete_with_id(id, db_connection);
+                delete_upload_dir(storage_path, id);
+            }
+            report.purged.push(id_string(id));
+        }
+    }
+
+    // Detect broken uploads: directories whose name is a valid ID but for
+    // which there is no record of an upload in the database, and which are
+    // either completed or no longer in progress.
+    if let Ok(dir_entries) = std::fs::read_dir(storage_path) {
         for entry in dir_entries {
             let entry_data = entry.ok()
-                .and_then(|e| Some((e.path(), std::fs::metadata(e.path().join("upload")).ok()?)))
-                .and_then(|(p, m)| Some((p, m.modified().ok()?)))
-                .and_then(|(p, m)| Some((i64_from_b64_bytes(p.file_name()?.to_str()?.as_bytes())?, p, m)));
-
-            if let Some((id, path, modified_time)) = entry_data {
-                if path.is_dir() && Upload::select_with_id(id, &db_connection).is_none() {
-                    let now = SystemTime::now();
-                    if let Ok(age_millis) = now.duration_since(modified_time).map(|d| d.as_millis()) {
-                        // Depending on various factors, the modified_time
-                        // which gets reported can be slightly behind,
-                        // so we give at least 5 seconds of wiggle room.
-                        let write_deadline = 5000 + read_timeout_ms;
-
-                        if age_millis as usize > write_deadline
-                            && Upload::select_with_id(id, &db_connection).is_none()
-                        {
-                            delete_upload_dir(&storage_path, id);
-                        }
+                .map(|e| e.path())
+                .filter(|p| p.is_dir())
+                .and_then(|p| Some((i64_from_b64_bytes(p.file_name()?.to_str()?.as_bytes())?, p)));
+
+            if let Some((id, path)) = entry_data {
+                if Upload::select_with_id(id, db_connection).is_some() {
+                    continue;
+                }
+
+                let is_broken = match read_upload_metadata(&path) {
+                    Some(metadata) if metadata.completed => true,
+                    metadata => is_idle(&path, metadata.as_ref(), read_timeout_ms)
+                };
+
+                // Check again, in case the record was written in the meantime
+                if is_broken && Upload::select_with_id(id, db_connection).is_none() {
+                    info!(id, dry_run, "Deleting broken upload");
+                    if !dry_run {
+                        delete_upload_dir(storage_path, id);
                     }
+                    report.broken.push(id_string(id));
                 }
             }
         }
     }
+
+    // Detect uploads whose files have vanished from the storage directory
+    // (e.g. deleted by hand), which could otherwise never be downloaded but
+    // would be served as broken downloads until they expire. The directory
+    // is created before the record, but the upload file may only be created
+    // after it, so a missing upload file only counts for completed uploads.
+    if let Some(upload_ids) = Upload::select_all(db_connection) {
+        for id in upload_ids {
+            let upload_dir = storage_path.join(id_string(id));
+            let is_missing = !upload_dir.is_dir() || (
+                !upload_dir.join("upload").exists()
+                && Upload::select_with_id(id, db_connection)
+                    .map(|upload| upload.is_completed)
+                    .unwrap_or(false));
+
+            if is_missing {
+                warn!(id, dry_run, "Deleting upload whose file is missing");
+                if !dry_run {
+                    Upload::delete_with_id(id, db_connection);
+                    delete_upload_dir(storage_path, id);
+                }
+                report.missing.push(id_string(id));
+            }
+        }
+    }
+
+    if !dry_run {
+        reconcile_storage_usage(storage_path, db_connection);
+        count_cleanup(
+            report.expired.len(), report.purged.len(),
+            report.broken.len(), report.missing.len());
+    }
+
+    report
+}
+
+// Return whether the time since an upload was last written to *exceeds* the
+// maximum amount of time Transpo permits between writes, i.e. we can be
+// reasonably sure that the upload is not currently in progress. If its file
+// hasn't been created yet, the time at which it was started (according to its
+// metadata) counts instead.
+fn is_idle(
+    upload_dir: &PathBuf, metadata: Option<&UploadMetadata>,
+    read_timeout_ms: usize) -> bool
+{
+    let started_at = metadata.map(|m| SystemTime::UNIX_EPOCH
+        + Duration::from_millis(m.started_at.timestamp_millis().max(0) as u64));
+    let last_write_time = std::fs::metadata(upload_dir.join("upload"))
+        .and_then(|m| m.modified())
+        .ok()
+        .or(started_at);
+
+    let last_write_time = match last_write_time {
+        Some(last_write_time) => last_write_time,
+        None => return false
+    };
+
+    match SystemTime::now().duration_since(last_write_time).map(|d| d.as_millis()) {
+        // Depending on various factors, the modified_time which gets
+        // reported can be slightly behind, so we give at least 5 seconds of
+        // wiggle room.
+        Ok(age_millis) => age_millis as usize > 5000 + read_timeout_ms,
+        Err(_) => false
+    }
+}
+
+// Delete uploads which were still in progress when Transpo last stopped (e.g.
+// because it crashed), along with their records. Uploads can't be resumed, so
+// these would otherwise be served as incomplete until they expire. Return the
+// number of uploads deleted.
+pub fn recover_interrupted_uploads(
+    read_timeout_ms: usize, storage_path: &PathBuf,
+    db_connection: &DbConnection) -> usize
+{
+    let mut num_deleted = 0;
+
+    let dir_entries = match std::fs::read_dir(storage_path) {
+        Ok(dir_entries) => dir_entries,
+        Err(e) => {
+            error!("Reading storage directory: {}", e);
+            return num_deleted;
+        }
+    };
+
+    for entry in dir_entries {
+        let entry_data = entry.ok()
+            .map(|e| e.path())
+            .and_then(|p| Some((i64_from_b64_bytes(p.file_name()?.to_str()?.as_bytes())?, p)))
+            .and_then(|(id, p)| Some((id, read_upload_metadata(&p)?)));
+
+        if let Some((id, metadata)) = entry_data {
+            // Another Transpo process sharing the storage directory may
+            // still be receiving the upload
+            let upload_dir = storage_path.join(id_string(id));
+            if !metadata.completed && is_idle(&upload_dir, Some(&metadata), read_timeout_ms) {
+                warn!(id, started_at = %metadata.started_at, "Deleting interrupted upload");
+                Upload::delete_with_id(id, db_connection);
+                delete_upload_dir(storage_path, id);
+                num_deleted += 1;
+            }
+        }
+    }
+
+    num_deleted
+}
+
+// Run a cleanup on demand, or only report what it would delete if the query
+// contains `dry_run`. Only available with an API key which has the admin
+// scope.
+pub async fn handle(
+    conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return conn.with_status(status).halt();
+    }
+
+    let dry_run = conn.querystring().split('&')
+        .any(|field| field == "dry_run" || field == "dry_run=true");
+
+    let report = unblock(move || {
+        let _span = info_span!("cleanup").entered();
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+
+        Some(run_cleanup(
+            config.read_timeout_milliseconds, config.audit_retention_minutes,
+            config.deletion_grace_minutes, &config.storage_dir,
+            &db_connection, dry_run))
+    }).await;
+
+    match report {
+        Some(report) => conn
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(serde_json::to_string(&report).unwrap())
+            .halt(),
+        None => conn.with_status(500).halt()
+    }
+}
+
+// Evict the least recently used uploads which haven't been used within the
+// last `protected_minutes` (and which nobody is accessing) until at least
+// `bytes_needed` bytes are freed. Return the number of bytes freed.
+pub fn evict_uploads(
+    bytes_needed: i64, protected_minutes: usize, storage_path: &PathBuf,
+    accessors: &Accessors, db_connection: &DbConnection) -> i64
+{
+    let used_before = Local::now().naive_utc()
+        - ChronoDuration::minutes(protected_minutes as i64);
+    let evictable = match Upload::select_evictable(used_before, db_connection) {
+        Some(evictable) => evictable,
+        None => return 0
+    };
+
+    let mut bytes_freed = 0;
+    for (id, size) in evictable {
+        if bytes_freed >= bytes_needed {
+            break;
+        }
+
+        let accessor_mutex = match accessors.access(id) {
+            Some(accessor_mutex) => accessor_mutex,
+            None => continue
+        };
+        let accessor = accessor_mutex.lock();
+
+        if accessor.is_only_accessor() {
+            info!(id, size, "Evicting upload to make room");
+            Upload::delete_with_id(id, db_connection);
+            delete_upload_dir(storage_path, id);
+            bytes_freed += size;
+        }
+    }
+
+    if bytes_freed > 0 {
+        StorageUsage::add(-bytes_freed, db_connection);
+    }
+
+    bytes_freed
+}
+
+// Check that every completed upload is stored as well-formed chunks which add
+// up to the sizes recorded when it completed, and that the first chunk of
+// uploads in the public gallery (whose key is stored) can be decrypted. If `quarantine` is set, uploads
+// which fail are marked as deleted so they are no longer served, and moved
+// into the quarantine directory so they can still be inspected.
+pub fn verify_uploads(
+    storage_path: &PathBuf, db_connection: &DbConnection,
+    quarantine: bool) -> VerifyReport
+{
+    let mut report = VerifyReport::default();
+
+    let uploads = match Upload::select_completed(db_connection) {
+        Some(uploads) => uploads,
+        None => return report
+    };
+
+    for (id, recorded_plaintext_size, recorded_ciphertext_size, format_version, public_key) in uploads {
+        let upload_path = storage_path.join(id_string(id)).join("upload");
+        report.num_verified += 1;
+
+        let sample = public_key.as_ref()
+            .map(|key| (ChunkFormat::new(id, format_version), key.as_bytes()));
+
+        let problem = match check_file(&upload_path, sample) {
+            Err(e) => e.to_string(),
+            Ok(check) if recorded_ciphertext_size.map(|s| s as u64 != check.file_size).unwrap_or(false) =>
+                format!("Stored size {} differs from recorded size {}",
+                    check.file_size, recorded_ciphertext_size.unwrap()),
+            Ok(check) if recorded_plaintext_size.map(|s| s as u64 != check.plaintext_size).unwrap_or(false) =>
+                format!("Plaintext size {} differs from recorded size {}",
+                    check.plaintext_size, recorded_plaintext_size.unwrap()),
+            Ok(check) => {
+                if check.is_sample_decrypted {
+                    report.num_decrypted += 1;
+                }
+                continue;
+            }
+        };
+
+        warn!(id, problem = %problem, "Upload failed verification");
+
+        if quarantine {
+            Upload::mark_deleted(id, db_connection);
+            match quarantine_upload_dir(storage_path, id) {
+                Ok(()) => report.quarantined += 1,
+                Err(e) => error!(id, "Quarantining upload: {}", e)
+            }
+        }
+
+        report.failed.push((id_string(id), problem));
+    }
+
+    report
+}
+
+fn quarantine_upload_dir(storage_path: &PathBuf, id: i64) -> std::io::Result<()> {
+    let quarantine_path = storage_path.join(QUARANTINE_DIR_NAME);
+    std::fs::create_dir_all(&quarantine_path)?;
+    std::fs::rename(
+        storage_path.join(id_string(id)),
+        quarantine_path.join(id_string(id)))
+}
+
+// Correct the storage usage recorded in the database (which is only updated
+// periodically while uploads are in progress) by measuring the storage
+// directory.
+pub fn reconcile_storage_usage(storage_path: &PathBuf, db_connection: &DbConnection) {
+    match get_storage_size(storage_path) {
+        Ok(storage_size) => {
+            StorageUsage::set(storage_size as i64, db_connection);
+        },
+        Err(e) => error!("Measuring storage usage: {}", e)
+    }
+}
+
+// Record the sizes of completed uploads which were stored before sizes were
+// tracked in the database.
+pub fn backfill_upload_sizes(storage_path: &PathBuf, db_connection: &DbConnection) {
+    if let Some(ids) = Upload::select_missing_sizes(db_connection) {
+        for id in ids {
+            let upload_path = storage_path.join(id_string(id)).join("upload");
+
+            let sizes = get_plaintext_size(&upload_path)
+                .and_then(|p| Ok((p, get_file_size(&upload_path)?)));
+
+            if let Ok((plaintext_size, ciphertext_size)) = sizes {
+                Upload::set_sizes(id, plaintext_size, ciphertext_size, db_connection);
+            }
+        }
+    }
 }
@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use trillium::{Conn, Headers};
+
+
+const X_REAL_IP: &'static str = "X-Real-IP";
+const X_FORWARDED_FOR: &'static str = "X-Forwarded-For";
+
+// Address of the client making a request, stored in the conn's state
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    // Return the address stored in the conn's state, if it was looked up
+    pub fn of(conn: &Conn) -> Option<IpAddr> {
+        conn.state::<ClientIp>().and_then(|ClientIp(ip)| *ip)
+    }
+}
+
+// Return the address of the client. Forwarding headers are only believed when
+// the request comes from a trusted proxy, since anyone else could set them to
+// get around quotas.
+pub fn client_ip(
+    peer_ip: Option<IpAddr>, headers: &Headers,
+    trusted_proxies: &[IpNet]) -> Option<IpAddr>
+{
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    match peer_ip {
+        Some(peer_ip) if is_trusted(&peer_ip) => {
+            let real_ip = headers
+                .get_str(X_REAL_IP)
+                .and_then(|a| a.trim().parse().ok());
+
+            // Each proxy appends the address it received the request from, so
+            // the last untrusted entry is the first one which can't be forged
+            let forwarded_ip = || headers
+                .get_str(X_FORWARDED_FOR)?
+                .rsplit(',')
+                .filter_map(|a| a.trim().parse().ok())
+                .find(|ip| !is_trusted(ip));
+
+            real_ip.or_else(forwarded_ip).or(Some(peer_ip))
+        },
+        _ => peer_ip
+    }
+}
+
+// Parse a network in CIDR notation or a single address
+pub fn parse_network(s: &str) -> Option<IpNet> {
+    s.parse().ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
@@ -0,0 +1,105 @@
+use crate::config::*;
+use crate::db::*;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, Duration as ChronoDuration};
+use rand::{thread_rng, Rng};
+use tracing::{info, info_span, warn};
+
+
+const LEADER_LEASE_NAME: &'static str = "leader";
+// The leader renews its lease this often. If it stops (e.g. because it
+// crashed), another replica takes over once the lease expires.
+const RENEW_DELAY_SECS: u64 = 20;
+const LEASE_DURATION_SECS: i64 = 60;
+
+
+// Whether this process runs the background jobs (cleanup, moving cold
+// uploads, notifications, mail) and the corrections made on start. Without
+// `--cluster`, it always does.
+#[derive(Clone)]
+pub struct Leader {
+    // None unless running as part of a cluster
+    holder: Option<String>,
+    is_leader: Arc<AtomicBool>
+}
+
+impl Leader {
+    pub fn new(config: &TranspoConfig) -> Self {
+        let holder = if config.cluster {
+            Some(format!("{:016x}", thread_rng().gen::<u64>()))
+        } else {
+            None
+        };
+
+        Self {
+            is_leader: Arc::new(AtomicBool::new(holder.is_none())),
+            holder
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::Relaxed)
+    }
+
+    // Try to take (or keep) the lease of the leader. Return whether this
+    // process is the leader now.
+    pub fn elect(&self, db_connection: &DbConnection) -> bool {
+        let holder = match &self.holder {
+            Some(holder) => holder,
+            None => return true
+        };
+
+        let expires_at = Local::now().naive_utc()
+            + ChronoDuration::seconds(LEASE_DURATION_SECS);
+        // Step down if the database can't be reached, since another replica
+        // may take over in the meantime
+        let is_leader = Lease::acquire(LEADER_LEASE_NAME, holder, expires_at, db_connection)
+            .unwrap_or(false);
+
+        let was_leader = self.is_leader.swap(is_leader, Ordering::Relaxed);
+        if is_leader && !was_leader {
+            info!(holder = %holder, "Elected leader");
+        } else if !is_leader && was_leader {
+            warn!(holder = %holder, "No longer the leader");
+        }
+
+        is_leader
+    }
+
+    // Give up the lease on shutdown, so that another replica takes over
+    // without waiting for it to expire
+    pub fn resign(&self, db_connection: &DbConnection) {
+        if let Some(holder) = &self.holder {
+            if self.is_leader.swap(false, Ordering::Relaxed) {
+                Lease::release(LEADER_LEASE_NAME, holder, db_connection);
+                info!(holder = %holder, "Resigned as leader");
+            }
+        }
+    }
+}
+
+pub fn spawn_election_thread(leader: Leader, db_backend: DbBackend, db_url: String) {
+    if leader.holder.is_none() {
+        return;
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(RENEW_DELAY_SECS));
+
+        let _span = info_span!("election").entered();
+
+        match establish_connection(db_backend, &db_url) {
+            Some(db_connection) => {
+                leader.elect(&db_connection);
+            },
+            None => if leader.is_leader.swap(false, Ordering::Relaxed) {
+                warn!("No longer the leader, the database is unavailable");
+            }
+        }
+    });
+}
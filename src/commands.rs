@@ -0,0 +1,341 @@
+use crate::api_keys;
+use crate::at_rest;
+use crate::backup;
+use crate::import;
+use crate::b64::*;
+use crate::cleanup::*;
+use crate::config::*;
+use crate::db::*;
+use crate::onion::onion_link;
+
+use chrono::{Local, Duration, NaiveDateTime};
+use std::path::Path;
+
+
+// Run the command given on the command line instead of the server. Return the
+// exit code.
+pub fn run_command(config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    let command = config.command.as_slice();
+    match command {
+        [name, args @ ..] if name == "api-key" => api_keys::run_command(args, db_connection),
+        [name, args @ ..] if name == "db" => run_db_command(args, config, db_connection),
+        [name, args @ ..] if name == "upload" => run_upload_command(args, db_connection),
+        [name, args @ ..] if name == "cleanup" => run_cleanup_command(args, config, db_connection),
+        [name, args @ ..] if name == "verify" => run_verify_command(args, config, db_connection),
+        [name, args @ ..] if name == "backup" => run_backup_command(args, config, db_connection),
+        [name, args @ ..] if name == "restore" => run_restore_command(args, config, db_connection),
+        [name, args @ ..] if name == "import" => run_import_command(args, config, db_connection),
+        [name, args @ ..] if name == "rekey" => run_rekey_command(args, config),
+        _ => {
+            eprintln!("Unknown command `{}`. Run with `-h` for a list of commands.",
+                command.join(" "));
+            1
+        }
+    }
+}
+
+// Run `db <args>`. Return the exit code.
+fn run_db_command(args: &[String], config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    println!("Backend: {}", backend_name(db_connection));
+
+    match args {
+        [cmd] if cmd == "migrate" => {
+            run_migrations(db_connection, &config.migrations_dir);
+            backfill_upload_sizes(&config.storage_dir, db_connection);
+            reconcile_storage_usage(&config.storage_dir, db_connection);
+            0
+        },
+        [cmd] if cmd == "status" => {
+            match migration_status(db_connection, &config.migrations_dir) {
+                Some(migrations) => {
+                    for (version, is_applied) in migrations {
+                        let status = if is_applied { "applied" } else { "pending" };
+                        println!("{}\t{}", version, status);
+                    }
+                    0
+                },
+                None => {
+                    eprintln!("Reading database migrations failed");
+                    1
+                }
+            }
+        },
+        [cmd] if cmd == "rollback" => {
+            match revert_latest_migration(db_connection, &config.migrations_dir) {
+                Some(version) => {
+                    println!("Reverted {}", version);
+                    0
+                },
+                None => {
+                    eprintln!("Reverting database migration failed");
+                    1
+                }
+            }
+        },
+        _ => {
+            eprintln!("Usage: db migrate | status | rollback");
+            1
+        }
+    }
+}
+
+// Run `upload <args>`. Return the exit code.
+fn run_upload_command(args: &[String], db_connection: &DbConnection) -> i32 {
+    match args {
+        [cmd, id, rest @ ..] if cmd == "restore" && rest.len() <= 1 => {
+            let expire_after = match rest.first().map(|m| m.parse::<i64>()) {
+                Some(Ok(minutes)) => Some(Local::now().naive_utc() + Duration::minutes(minutes)),
+                Some(Err(_)) => {
+                    eprintln!("Invalid number of minutes");
+                    return 1;
+                },
+                None => None
+            };
+
+            let is_blocked = i64_from_b64_bytes(id.as_bytes())
+                .zip(BlockedUpload::select_all(db_connection))
+                .map(|(id, blocked)| blocked.iter().any(|b| b.upload_id == id))
+                .unwrap_or(false);
+            if is_blocked {
+                eprintln!("The upload with ID `{}` is blocked", id);
+                return 1;
+            }
+
+            let restored = i64_from_b64_bytes(id.as_bytes())
+                .and_then(|id| Upload::restore(id, expire_after, db_connection));
+
+            match restored {
+                Some(1) => 0,
+                _ => {
+                    eprintln!("No deleted upload with ID `{}`", id);
+                    1
+                }
+            }
+        },
+        _ => {
+            eprintln!("Usage: upload restore <id> [minutes]");
+            1
+        }
+    }
+}
+
+// Run `cleanup [--dry-run]`. Return the exit code.
+fn run_cleanup_command(args: &[String], config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    let dry_run = match args {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => {
+            eprintln!("Usage: cleanup [--dry-run]");
+            return 1;
+        }
+    };
+
+    let report = run_cleanup(
+        config.read_timeout_milliseconds, config.audit_retention_minutes,
+        config.deletion_grace_minutes, &config.storage_dir,
+        db_connection, dry_run);
+
+    let verb = if dry_run { "Would clean up" } else { "Cleaned up" };
+    for (kind, ids) in [
+        ("expired", &report.expired),
+        ("purged", &report.purged),
+        ("broken", &report.broken),
+        ("missing", &report.missing)]
+    {
+        for id in ids {
+            println!("{}\t{}", kind, id);
+        }
+    }
+    println!("{} {} expired, {} purged, {} broken and {} missing uploads",
+        verb, report.expired.len(), report.purged.len(), report.broken.len(),
+        report.missing.len());
+
+    0
+}
+
+// Run `verify [--quarantine]`. Return the exit code, which is 1 if any upload
+// failed verification.
+fn run_verify_command(args: &[String], config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    let quarantine = match args {
+        [] => false,
+        [flag] if flag == "--quarantine" => true,
+        _ => {
+            eprintln!("Usage: verify [--quarantine]");
+            return 1;
+        }
+    };
+
+    let report = verify_uploads(&config.storage_dir, db_connection, quarantine);
+
+    for (id, problem) in &report.failed {
+        println!("{}\t{}", id, problem);
+    }
+    println!("Verified {} uploads ({} decrypted), {} failed",
+        report.num_verified, report.num_decrypted, report.failed.len());
+    if quarantine {
+        println!("Quarantined {} uploads", report.quarantined);
+    }
+
+    if report.failed.is_empty() { 0 } else { 1 }
+}
+
+// Run `backup <path> [--since <time>]`. Return the exit code.
+fn run_backup_command(args: &[String], config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    let (path, since) = match args {
+        [path] => (path, None),
+        [path, flag, time] if flag == "--since" => match time.parse::<NaiveDateTime>() {
+            Ok(since) => (path, Some(since)),
+            Err(_) => {
+                eprintln!("Invalid time `{}`, expected e.g. 2024-01-31T12:00:00", time);
+                return 1;
+            }
+        },
+        _ => {
+            eprintln!("Usage: backup <path> [--since <time>]");
+            return 1;
+        }
+    };
+
+    match backup::backup(Path::new(path), since, &config.storage_dir, db_connection) {
+        Ok(report) => {
+            println!("Backed up {} uploads", report.num_uploads);
+            // Passing this to `--since` makes the next backup incremental
+            println!("Backup started at {}", report.created_at.format("%Y-%m-%dT%H:%M:%S"));
+            0
+        },
+        Err(e) => {
+            eprintln!("Backup failed: {}", e);
+            1
+        }
+    }
+}
+
+// Run `restore <path>`. Return the exit code.
+fn run_restore_command(args: &[String], config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    let path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("Usage: restore <path>");
+            return 1;
+        }
+    };
+
+    match backup::restore(Path::new(path), &config.storage_dir, db_connection) {
+        Ok(report) => {
+            reconcile_storage_usage(&config.storage_dir, db_connection);
+            println!("Restored {} users, {} API keys and {} uploads ({} of which had expired)",
+                report.num_users, report.num_api_keys, report.num_uploads, report.num_expired);
+            0
+        },
+        Err(e) => {
+            eprintln!("Restore failed: {}", e);
+            1
+        }
+    }
+}
+
+// Run `import <dir> [minutes] [max downloads]`. Return the exit code.
+fn run_import_command(args: &[String], config: &TranspoConfig, db_connection: &DbConnection) -> i32 {
+    let (dir, minutes, max_downloads) = match args {
+        [dir, rest @ ..] if rest.len() <= 2 => {
+            let minutes = match rest.first().map(|m| m.parse::<usize>()) {
+                Some(Ok(minutes)) => minutes,
+                Some(Err(_)) => {
+                    eprintln!("Invalid number of minutes");
+                    return 1;
+                },
+                None => config.max_upload_age_minutes
+            };
+            let max_downloads = match rest.get(1).map(|d| d.parse::<i32>()) {
+                Some(Ok(max_downloads)) if max_downloads > 0 => Some(max_downloads),
+                Some(_) => {
+                    eprintln!("Invalid maximum number of downloads");
+                    return 1;
+                },
+                None => None
+            };
+            (dir, minutes, max_downloads)
+        },
+        _ => {
+            eprintln!("Usage: import <dir> [minutes] [max downloads]");
+            return 1;
+        }
+    };
+
+    let imported = import::import_dir(
+        Path::new(dir), minutes, max_downloads, config.max_upload_age_minutes,
+        config.pad_uploads, &config.storage_dir, db_connection);
+
+    match imported {
+        Ok(imported) => {
+            // Print the link to each imported file (and its link on the onion
+            // service, if there is one), so that links to the old files can
+            // be redirected
+            for file in &imported {
+                let link = format!("{}?nopass#{}", file.id_string, file.key);
+                match onion_link(config, &link) {
+                    Some(onion_link) => println!("{}\t{}\t{}", file.path.display(), link, onion_link),
+                    None => println!("{}\t{}", file.path.display(), link)
+                }
+            }
+            println!("Imported {} files", imported.len());
+            0
+        },
+        Err(e) => {
+            eprintln!("Import failed: {}", e);
+            1
+        }
+    }
+}
+
+// Run `rekey`. Return the exit code.
+fn run_rekey_command(args: &[String], config: &TranspoConfig) -> i32 {
+    if !args.is_empty() {
+        eprintln!("Usage: rekey");
+        return 1;
+    }
+
+    if !at_rest::has_storage_key() {
+        eprintln!("No storage key is set");
+        return 1;
+    }
+
+    let mut num_rekeyed = 0;
+    let mut num_failed = 0;
+
+    // (the keys of uploads in cold storage stay in their directories here)
+    let dirs = [config.storage_dir.clone(), config.storage_dir.join(QUARANTINE_DIR_NAME)];
+    for dir in &dirs {
+        let dir_entries = match std::fs::read_dir(dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(_) => continue
+        };
+
+        for entry in dir_entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue
+            };
+
+            let id = path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| i64_from_b64_bytes(n.as_bytes()));
+            if !path.is_dir() || id.is_none() {
+                continue;
+            }
+
+            match at_rest::rekey_dir(&path) {
+                Ok(true) => num_rekeyed += 1,
+                Ok(false) => (),
+                Err(e) => {
+                    println!("{}\t{}", path.display(), e);
+                    num_failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("Moved {} uploads to the new storage key, {} failed", num_rekeyed, num_failed);
+
+    if num_failed == 0 { 0 } else { 1 }
+}
@@ -1,23 +1,68 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 use crate::db::*;
+use crate::metrics::{count_rejection, Rejection};
+
+use tracing::{error, warn};
 
 
 // Count the number of concurrent accessors to files to make sure that they
 // aren't deleted while being downloaded over a different connection.
 
+// Storage for the number of accessors of each upload, shared by every Transpo
+// process using the same database. The count is kept in the database by
+// default.
+pub trait AccessCounter: Send + Sync {
+    // Return None if the count could not be changed
+    fn increment(&self, id: i64) -> Option<()>;
+    fn decrement(&self, id: i64) -> Option<()>;
+    // Return None if the count is unavailable
+    fn count(&self, id: i64) -> Option<i64>;
+}
+
+pub struct DbAccessCounter(DbConnectionInfo);
+
+impl DbAccessCounter {
+    pub fn new(db_connection_info: DbConnectionInfo) -> Self {
+        Self(db_connection_info)
+    }
+}
+
+impl AccessCounter for DbAccessCounter {
+    fn increment(&self, id: i64) -> Option<()> {
+        let db_connection = establish_connection_info(&self.0)?;
+        Upload::access(&db_connection, id).map(|_| ())
+    }
+
+    fn decrement(&self, id: i64) -> Option<()> {
+        let db_connection = establish_connection_info(&self.0)?;
+        Upload::revoke(&db_connection, id).map(|_| ())
+    }
+
+    fn count(&self, id: i64) -> Option<i64> {
+        let db_connection = establish_connection_info(&self.0)?;
+        Upload::num_accessors(&db_connection, id).map(|n| n as i64)
+    }
+}
+
+
 pub struct Accessor {
     pub id: i64,
     rc: usize,
-    db_connection_info: DbConnectionInfo
+    counter: Arc<dyn AccessCounter>
 }
 
 impl Accessor {
     // Return whether or not there are other accessors on the same ID as is
     // possessed by this instance
     pub fn is_only_accessor(&self) -> bool {
-        let db_connection = establish_connection_info(&self.db_connection_info);
-        self.rc == 1 && Upload::num_accessors(&db_connection, self.id) == Some(1)
+        // If the count is unavailable, assume there are other accessors so
+        // that nothing is deleted
+        self.rc == 1 && self.counter.count(self.id) == Some(1)
     }
 }
 
@@ -34,13 +79,12 @@ impl AccessorMutex {
 
 impl Drop for AccessorMutex {
     fn drop(&mut self) {
-        let mut map = self.parent.0.lock().unwrap();
+        let mut map = self.parent.map.lock().unwrap();
         let mut accessor = self.lock();
 
-        let db_connection = establish_connection_info(
-            &accessor.db_connection_info);
-        Upload::revoke(&db_connection, accessor.id)
-            .expect("Revoking access in DB");
+        if accessor.counter.decrement(accessor.id).is_none() {
+            error!(id = accessor.id, "Revoking access failed");
+        }
 
         accessor.rc -= 1;
         if accessor.rc == 0 {
@@ -51,19 +95,24 @@ impl Drop for AccessorMutex {
 
 
 #[derive(Clone)]
-pub struct Accessors (Arc<Mutex<HashMap<i64, Arc<Mutex<Accessor>>>>>);
+pub struct Accessors {
+    map: Arc<Mutex<HashMap<i64, Arc<Mutex<Accessor>>>>>,
+    counter: Arc<dyn AccessCounter>
+}
 
 impl Accessors {
-    pub fn new() -> Self {
-        Self (Arc::new(Mutex::new(HashMap::new())))
+    pub fn new(counter: Arc<dyn AccessCounter>) -> Self {
+        Self {
+            map: Arc::new(Mutex::new(HashMap::new())),
+            counter
+        }
     }
 
-    pub fn access(&self, id: i64, db_connection_info: DbConnectionInfo) -> AccessorMutex {
-        let db_connection = establish_connection_info(&db_connection_info);
-        Upload::access(&db_connection, id)
-            .expect("Gaining access in DB");
+    // Return None if access could not be recorded
+    pub fn access(&self, id: i64) -> Option<AccessorMutex> {
+        self.counter.increment(id)?;
 
-        let mut map = self.0.lock().unwrap();
+        let mut map = self.map.lock().unwrap();
 
         // Get the existing mutex, or create it if it does not exist (or is poisoned)
         let accessor_mutex = match map.get(&id) {
@@ -78,7 +127,7 @@ impl Accessors {
                         let accessor = Accessor {
                             id,
                             rc: 1,
-                            db_connection_info
+                            counter: self.counter.clone()
                         };
                         let accessor_mutex = Arc::new(Mutex::new(accessor));
                         accessor_mutex
@@ -89,7 +138,7 @@ impl Accessors {
                 let accessor = Accessor {
                     id,
                     rc: 1,
-                    db_connection_info
+                    counter: self.counter.clone()
                 };
                 let accessor_mutex = Arc::new(Mutex::new(accessor));
                 accessor_mutex
@@ -98,9 +147,192 @@ impl Accessors {
 
         map.insert(id, accessor_mutex.clone());
 
-        AccessorMutex {
+        Some(AccessorMutex {
             mtx: accessor_mutex,
             parent: self.clone()
+        })
+    }
+}
+
+
+// Count the number of uploads and downloads each client has open at once, so
+// that a single host can't occupy every worker thread.
+pub struct ClientConnection {
+    addr: IpAddr,
+    parent: ClientConnections
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        let mut map = self.parent.map.lock().unwrap();
+
+        if let Some(count) = map.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientConnections {
+    map: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_connections: usize
+}
+
+impl ClientConnections {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            map: Arc::new(Mutex::new(HashMap::new())),
+            max_connections
+        }
+    }
+
+    // Return None if the client already has the maximum number of
+    // connections open. The connection is counted until the returned value is
+    // dropped.
+    pub fn open(&self, addr: IpAddr) -> Option<ClientConnection> {
+        let mut map = self.map.lock().unwrap();
+        let count = map.entry(addr).or_insert(0);
+
+        if *count >= self.max_connections {
+            return None;
         }
+        *count += 1;
+
+        Some(ClientConnection {
+            addr,
+            parent: self.clone()
+        })
+    }
+}
+
+
+// The transfer rate is checked over windows of this many seconds spent
+// waiting for the client
+const MIN_RATE_WINDOW_SECS: u64 = 30;
+
+// Abort uploads and downloads which are slower than a minimum rate or take
+// longer than a deadline, so that clients can't hold connections open by
+// trickling bytes (slow-loris). Only the time spent waiting for the client
+// counts towards the rate, so that waiting for the disk, a speed limit or an
+// upload in progress doesn't.
+pub struct MinTransferRate {
+    // minimum number of bytes per window (0 if there is no minimum)
+    bytes_per_window: u64,
+    window_bytes: u64,
+    window_elapsed: Duration,
+    started_at: Instant,
+    deadline: Option<Duration>
+}
+
+impl MinTransferRate {
+    // (either limit is disabled by setting it to 0)
+    pub fn new(min_bytes_per_second: usize, deadline_minutes: usize) -> Self {
+        Self {
+            bytes_per_window: min_bytes_per_second as u64 * MIN_RATE_WINDOW_SECS,
+            window_bytes: 0,
+            window_elapsed: Duration::ZERO,
+            started_at: Instant::now(),
+            deadline: match deadline_minutes {
+                0 => None,
+                minutes => Some(Duration::from_secs(minutes as u64 * 60))
+            }
+        }
+    }
+
+    // Record that `bytes` were transferred after waiting `waited` for the
+    // client. Return an error if the client is too slow.
+    pub fn record(&mut self, bytes: usize, waited: Duration) -> Result<()> {
+        if let Some(deadline) = self.deadline {
+            if self.started_at.elapsed() > deadline {
+                return Err(Self::too_slow("Transfer deadline exceeded"));
+            }
+        }
+        if self.bytes_per_window == 0 {
+            return Ok(());
+        }
+
+        self.window_bytes += bytes as u64;
+        self.window_elapsed += waited;
+
+        if self.window_elapsed >= Duration::from_secs(MIN_RATE_WINDOW_SECS) {
+            if self.window_bytes < self.bytes_per_window {
+                return Err(Self::too_slow("Transfer rate below the minimum"));
+            }
+            self.window_bytes = 0;
+            self.window_elapsed = Duration::ZERO;
+        }
+
+        Ok(())
+    }
+
+    fn too_slow(message: &str) -> Error {
+        warn!("Closing the connection of a slow client: {}", message);
+        count_rejection(Rejection::SlowClient);
+        Error::new(ErrorKind::TimedOut, message.to_owned())
+    }
+}
+
+// Run the future and return its output along with how long it took
+pub async fn timed<F>(future: F) -> (F::Output, Duration)
+where F: Future
+{
+    let started_at = Instant::now();
+    let output = future.await;
+    (output, started_at.elapsed())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_over_the_limit_are_refused() {
+        let connections = ClientConnections::new(2);
+        let addr = "192.0.2.1".parse().unwrap();
+        let other_addr = "192.0.2.2".parse().unwrap();
+
+        let first = connections.open(addr).unwrap();
+        let second = connections.open(addr).unwrap();
+        assert!(connections.open(addr).is_none());
+        // Other clients have limits of their own
+        let other = connections.open(other_addr).unwrap();
+
+        // Closing a connection makes room for another one
+        drop(first);
+        let third = connections.open(addr).unwrap();
+        assert!(connections.open(addr).is_none());
+
+        // ...and clients are forgotten once they have none open
+        drop((second, third, other));
+        assert!(connections.map.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_connections_stop_at_the_limit() {
+        let connections = ClientConnections::new(3);
+        let addr: IpAddr = "2001:db8::1".parse().unwrap();
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+
+        // Every connection is held until all of them have been tried
+        let threads: Vec<_> = (0..8).map(|_| {
+            let connections = connections.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let connection = connections.open(addr);
+                barrier.wait();
+                connection.is_some()
+            })
+        }).collect();
+        let num_opened = threads.into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|is_opened| *is_opened)
+            .count();
+
+        assert_eq!(num_opened, 3);
+        assert!(connections.open(addr).is_some());
     }
 }
@@ -2,3 +2,12 @@ pub const FORM_READ_BUFFER_SIZE: usize = 10240;
 pub const FORM_FIELD_BUFFER_SIZE: usize = 512;
 pub const MAX_FORM_BOUNDARY_LENGTH: usize = 70;
 pub const ID_LENGTH: usize = 8;
+// file in the storage directory to which quotas are saved
+pub const QUOTAS_FILE_NAME: &'static str = "quotas.json";
+// file in each upload's directory which describes the state of the upload
+pub const UPLOAD_METADATA_FILE_NAME: &'static str = "meta.json";
+// file in the directory of each upload which is encrypted at rest
+pub const AT_REST_MARKER_FILE_NAME: &'static str = "encrypted";
+// file next to an archive created by the server which lists its entries,
+// encrypted with the key of the upload
+pub const ARCHIVE_ENTRIES_FILE_NAME: &'static str = "entries";
@@ -1,10 +1,43 @@
 use diesel::prelude::*;
 use diesel_migrations::*;
-use chrono::{NaiveDateTime, Local};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Local};
 use std::path::Path;
+use serde::{Deserialize, Serialize};
 
+use crate::metrics::*;
+use std::ops::Deref;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
 
+
+// Number of attempts made at connecting to the database before giving up and
+// the delay before the first retry (doubled after every failed attempt)
+const CONNECT_ATTEMPTS: u32 = 5;
+const CONNECT_RETRY_DELAY_MS: u64 = 50;
+
+// Number of idle connections kept at most, beyond which returned connections
+// are closed
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+// Whether or not the last attempt at connecting to the database succeeded
+static DB_IS_AVAILABLE: AtomicBool = AtomicBool::new(true);
+static IDLE_CONNECTIONS: Mutex<Vec<DbConnection>> = Mutex::new(Vec::new());
+
+
+// Run `$e` on the connection of whichever backend is in use. If a query kind
+// is given, the duration of the query is recorded.
 macro_rules! conn {
+    ($kind:expr, $dbc:expr, $e:expr) => {
+        {
+            let start = Instant::now();
+            let result = conn!($dbc, $e);
+            observe_query($kind, start.elapsed());
+            result
+        }
+    };
     ($dbc:expr, $e:expr) => {
         {
             let dbc = $dbc;
@@ -45,6 +78,7 @@ pub enum DbBackend {
 #[derive(Debug)]
 #[derive(Queryable)]
 #[derive(Insertable)]
+#[derive(Serialize, Deserialize)]
 #[table_name="uploads"]
 pub struct Upload {
     // unique identifier for this upload
@@ -62,7 +96,70 @@ pub struct Upload {
     pub expire_after: NaiveDateTime,
     // whether or not the upload has fully completed
     // used when reporting file size
-    pub is_completed: bool
+    pub is_completed: bool,
+    // maximum speed at which this upload may be downloaded (if it has a limit
+    // of its own)
+    pub max_download_bytes_per_second: Option<i64>,
+    // number of downloads which have been started
+    pub num_downloads: i32,
+    // number of downloads which reached the end of the upload
+    pub num_completed_downloads: i32,
+    // total number of bytes served by all downloads (as sent, after compression)
+    pub bytes_downloaded: i64,
+    // size of the upload before encryption (set when the upload completes)
+    pub plaintext_size: Option<i64>,
+    // size of the stored upload (set when the upload completes)
+    pub ciphertext_size: Option<i64>,
+    // address of the uploader (only if audit data is enabled)
+    pub uploader_ip: Option<String>,
+    // time at which the upload was made (only if audit data is enabled)
+    pub created_at: Option<NaiveDateTime>,
+    // user who made the upload (if they were logged in)
+    pub owner_id: Option<i64>,
+    // time at which the upload was deleted (it is purged after a grace period)
+    pub deleted_at: Option<NaiveDateTime>,
+    // time at which the upload was completed or last downloaded
+    pub last_used_at: Option<NaiveDateTime>,
+    // ntfy topic on which the uploader is notified of downloads and expiry
+    pub notify_topic: Option<String>,
+    // whether the uploader has been told that the upload expires soon
+    #[serde(default)]
+    pub expiry_notified: bool,
+    // whether downloads are disabled because the upload was reported
+    #[serde(default)]
+    pub is_disabled: bool,
+    // whether the upload was copied from a peer instance
+    #[serde(default)]
+    pub is_mirror: bool,
+    // key of the upload, if the uploader listed it in the public gallery
+    #[serde(default)]
+    pub public_key: Option<String>,
+    // time at which the upload was listed in the public gallery
+    #[serde(default)]
+    pub published_at: Option<NaiveDateTime>,
+    // version of the format in which the upload is encrypted (see `files.rs`)
+    #[serde(default = "default_format_version")]
+    pub format_version: i16,
+    // if the key is derived from the password: the base64-encoded salt of the
+    // key derived from it, and the key of the upload encrypted with that key
+    #[serde(default)]
+    pub key_salt: Option<String>,
+    #[serde(default)]
+    pub wrapped_key: Option<String>,
+    // if the key is wrapped for recipients: the key of the upload wrapped for
+    // each recipient's public key (see `recipients.rs`)
+    #[serde(default)]
+    pub recipient_keys: Option<String>,
+    // the b64 encoded title of a paste, encrypted with its key (see
+    // `files::seal_title`)
+    #[serde(default)]
+    pub title: Option<String>
+}
+
+// Uploads backed up before the format version was recorded are all in the
+// first format
+pub fn default_format_version() -> i16 {
+    1
 }
 
 table! {
@@ -75,6 +172,28 @@ table! {
         num_accessors -> Integer,
         expire_after -> Timestamp,
         is_completed -> Bool,
+        max_download_bytes_per_second -> Nullable<BigInt>,
+        num_downloads -> Integer,
+        num_completed_downloads -> Integer,
+        bytes_downloaded -> BigInt,
+        plaintext_size -> Nullable<BigInt>,
+        ciphertext_size -> Nullable<BigInt>,
+        uploader_ip -> Nullable<Text>,
+        created_at -> Nullable<Timestamp>,
+        owner_id -> Nullable<BigInt>,
+        deleted_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        notify_topic -> Nullable<Text>,
+        expiry_notified -> Bool,
+        is_disabled -> Bool,
+        is_mirror -> Bool,
+        public_key -> Nullable<Text>,
+        published_at -> Nullable<Timestamp>,
+        format_version -> SmallInt,
+        key_salt -> Nullable<Text>,
+        wrapped_key -> Nullable<Text>,
+        recipient_keys -> Nullable<Text>,
+        title -> Nullable<Text>,
     }
 }
 
@@ -84,8 +203,50 @@ impl Upload {
     pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
         let insert = diesel::insert_into(uploads::table)
             .values(self);
-       
-        conn!(db_connection, |c| insert.execute(c)).ok()
+
+        let today = DailyStats::create_today(db_connection);
+        let stats_update = diesel::update(daily_stats::table.find(today))
+            .set(daily_stats::uploads_created.eq(daily_stats::uploads_created + 1));
+
+        conn!(QueryKind::Insert, db_connection, |c| Connection::transaction::<_, diesel::result::Error, _>(c, || {
+            stats_update.execute(c)?;
+            insert.execute(c)
+        })).ok()
+    }
+
+    // Insert into DB, replacing any row with the same ID (without counting
+    // it as a new upload). Return the number of inserted rows, or None if
+    // there was a problem.
+    pub fn replace(&self, db_connection: &DbConnection) -> Option<usize> {
+        let delete = diesel::delete(uploads::table.filter(uploads::id.eq(self.id)));
+        let insert = diesel::insert_into(uploads::table)
+            .values(self);
+
+        conn!(QueryKind::Insert, db_connection, |c| Connection::transaction::<_, diesel::result::Error, _>(c, || {
+            delete.execute(c)?;
+            insert.execute(c)
+        })).ok()
+    }
+
+    // Return every completed upload, or only those used since `since`
+    pub fn select_for_backup(
+        since: Option<NaiveDateTime>, db_connection: &DbConnection) -> Option<Vec<Self>>
+    {
+        match since {
+            Some(since) => {
+                let select = uploads::table
+                    .filter(uploads::is_completed.eq(true)
+                        .and(uploads::last_used_at.ge(since)));
+
+                conn!(db_connection, |c| select.load::<Upload>(c)).ok()
+            },
+            None => {
+                let select = uploads::table
+                    .filter(uploads::is_completed.eq(true));
+
+                conn!(db_connection, |c| select.load::<Upload>(c)).ok()
+            }
+        }
     }
 
     // Return whether or not an Upload has expired, either based on time or
@@ -94,6 +255,11 @@ impl Upload {
         self.is_expired_time() || self.is_expired_downloads()
     }
 
+    // Return whether or not the upload has been deleted (but not yet purged)
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
     // Return whether or not the expiry date for an upload has been reached
     pub fn is_expired_time(&self) -> bool {
         let now = Local::now().naive_utc();
@@ -116,50 +282,347 @@ impl Upload {
             .filter(uploads::id.eq(id))
             .limit(1);
 
-        conn!(db_connection, |c| select.load::<Upload>(c)).ok()?.pop()
+        conn!(QueryKind::SelectWithId, db_connection, |c| select.load::<Upload>(c)).ok()?.pop()
     }
 
-    // Decrement the number of remaining downloads on the row with the given ID. Return
-    // the number of modified rows.
-    pub fn decrement_remaining_downloads(id: i64, db_connection: &DbConnection) -> Option<usize> {
+    // Claim one of the remaining downloads of the row with the given ID. The
+    // check and the decrement are a single statement so that concurrent
+    // downloads can't exceed the limit. Return whether or not a download was
+    // claimed. (Only call this for rows with a download limit)
+    pub fn claim_download(id: i64, db_connection: &DbConnection) -> Option<bool> {
         let target = uploads::table
             .filter(uploads::id.eq(id)
-                .and(uploads::remaining_downloads.is_not_null()));
+                .and(uploads::remaining_downloads.gt(0)));
         let update = diesel::update(target)
             .set(uploads::remaining_downloads.eq(uploads::remaining_downloads - 1));
 
+        conn!(QueryKind::Decrement, db_connection, |c| update.execute(c)).ok()
+            .map(|num_modified_rows| num_modified_rows == 1)
+    }
+
+    // Give back a download claimed on the row with the given ID. Return the
+    // number of modified rows.
+    pub fn refund_download(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let target = uploads::table
+            .filter(uploads::id.eq(id)
+                .and(uploads::remaining_downloads.is_not_null()));
+        let update = diesel::update(target)
+            .set(uploads::remaining_downloads.eq(uploads::remaining_downloads + 1));
+
         conn!(db_connection, |c| update.execute(c)).ok()
     }
 
-    pub fn set_is_completed(id: i64, is_completed: bool, db_connection: &DbConnection) -> Option<usize> {
+    // Add a finished (or aborted) download to the download statistics of the
+    // row with the given ID. Return the number of modified rows.
+    pub fn record_download(
+        id: i64, bytes_downloaded: u64, is_completed: bool,
+        db_connection: &DbConnection) -> Option<usize>
+    {
+        let target = uploads::table
+            .filter(uploads::id.eq(id));
+        let update = diesel::update(target)
+            .set((
+                uploads::num_downloads.eq(uploads::num_downloads + 1),
+                uploads::num_completed_downloads.eq(
+                    uploads::num_completed_downloads + is_completed as i32),
+                uploads::bytes_downloaded.eq(
+                    uploads::bytes_downloaded + bytes_downloaded as i64),
+                uploads::last_used_at.eq(Local::now().naive_utc())));
+
+        let today = DailyStats::create_today(db_connection);
+        let stats_update = diesel::update(daily_stats::table.find(today))
+            .set((
+                daily_stats::downloads_served.eq(daily_stats::downloads_served + 1),
+                daily_stats::bytes_served.eq(
+                    daily_stats::bytes_served + bytes_downloaded as i64)));
+
+        conn!(db_connection, |c| Connection::transaction::<_, diesel::result::Error, _>(c, || {
+            stats_update.execute(c)?;
+            update.execute(c)
+        })).ok()
+    }
+
+    // Mark the row with the given ID as completed, record its sizes and count
+    // it towards today's statistics. Return the number of modified rows.
+    pub fn set_completed(
+        id: i64, plaintext_size: u64, ciphertext_size: u64,
+        db_connection: &DbConnection) -> Option<usize>
+    {
+        let target = uploads::table
+            .filter(uploads::id.eq(id));
+
+        let update = diesel::update(target)
+            .set((
+                uploads::is_completed.eq(true),
+                uploads::plaintext_size.eq(plaintext_size as i64),
+                uploads::ciphertext_size.eq(ciphertext_size as i64),
+                uploads::last_used_at.eq(Local::now().naive_utc())));
+
+        let today = DailyStats::create_today(db_connection);
+        let stats_update = diesel::update(daily_stats::table.find(today))
+            .set(daily_stats::bytes_stored.eq(
+                daily_stats::bytes_stored + ciphertext_size as i64));
+
+        conn!(db_connection, |c| Connection::transaction::<_, diesel::result::Error, _>(c, || {
+            stats_update.execute(c)?;
+            update.execute(c)
+        })).ok()
+    }
+
+    // Record the sizes of the row with the given ID without counting it
+    // towards today's statistics. Return the number of modified rows.
+    pub fn set_sizes(
+        id: i64, plaintext_size: u64, ciphertext_size: u64,
+        db_connection: &DbConnection) -> Option<usize>
+    {
         let target = uploads::table
             .filter(uploads::id.eq(id));
 
         let update = diesel::update(target)
-            .set(uploads::is_completed.eq(is_completed));
+            .set((
+                uploads::plaintext_size.eq(plaintext_size as i64),
+                uploads::ciphertext_size.eq(ciphertext_size as i64)));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Return the completed uploads which expire before `deadline` and whose
+    // uploaders haven't been told so yet
+    pub fn select_expiring(
+        deadline: NaiveDateTime, db_connection: &DbConnection) -> Option<Vec<Self>>
+    {
+        let select = uploads::table
+            .filter(uploads::is_completed.eq(true)
+                .and(uploads::deleted_at.is_null())
+                .and(uploads::expiry_notified.eq(false))
+                .and(uploads::expire_after.lt(deadline)));
+
+        conn!(db_connection, |c| select.load::<Upload>(c)).ok()
+    }
+
+    // Disable or enable downloads of the row with the given ID. Return the
+    // number of modified rows.
+    pub fn set_disabled(id: i64, is_disabled: bool, db_connection: &DbConnection) -> Option<usize> {
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set(uploads::is_disabled.eq(is_disabled));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Store the key of the upload wrapped with a key derived from its password
+    // (see `files::wrap_key`). Return the number of modified rows.
+    pub fn set_wrapped_key(
+        id: i64, key_salt: &str, wrapped_key: &str,
+        db_connection: &DbConnection) -> Option<usize>
+    {
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set((
+                uploads::key_salt.eq(key_salt),
+                uploads::wrapped_key.eq(wrapped_key)));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Store the key of the upload wrapped for its recipients (see
+    // `recipients::wrap_key_for_recipients`). Return the number of modified
+    // rows.
+    pub fn set_recipient_keys(
+        id: i64, recipient_keys: &str, db_connection: &DbConnection) -> Option<usize>
+    {
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set(uploads::recipient_keys.eq(recipient_keys));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Store the encrypted title of the upload. Return the number of modified
+    // rows.
+    pub fn set_title(id: i64, title: &str, db_connection: &DbConnection) -> Option<usize> {
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set(uploads::title.eq(title));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // List the upload in the public gallery. Return the number of modified
+    // rows.
+    pub fn publish(id: i64, key: &str, db_connection: &DbConnection) -> Option<usize> {
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set((
+                uploads::public_key.eq(key),
+                uploads::published_at.eq(Local::now().naive_utc())));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Make the row with the given ID expire now, so that it is deleted by
+    // the next cleanup. Return the number of modified rows.
+    pub fn expire_now(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let now = Local::now().naive_utc();
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set(uploads::expire_after.eq(now));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Record that the uploader of the row with the given ID has been told
+    // that it expires soon. Return the number of modified rows.
+    pub fn set_expiry_notified(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let update = diesel::update(uploads::table.filter(uploads::id.eq(id)))
+            .set(uploads::expiry_notified.eq(true));
 
         conn!(db_connection, |c| update.execute(c)).ok()
     }
 
+    // Return a list of IDs for completed uploads whose sizes were not recorded
+    // Return the IDs of completed uploads which haven't been used since
+    // `used_before` (including those completed before use was tracked)
+    pub fn select_cold(used_before: NaiveDateTime, db_connection: &DbConnection) -> Option<Vec<i64>> {
+        let select = uploads::table
+            .filter(uploads::is_completed.eq(true)
+                .and(uploads::deleted_at.is_null())
+                .and(uploads::last_used_at.lt(used_before)
+                    .or(uploads::last_used_at.is_null())))
+            .select(uploads::id);
+
+        conn!(db_connection, |c| select.load::<i64>(c)).ok()
+    }
+
+    // Return the ID and recorded plaintext and ciphertext sizes of every
+    // completed upload which hasn't been deleted
+    // Return the ID, the recorded sizes, the format version and the key (if it
+    // is public) of each completed upload
+    pub fn select_completed(db_connection: &DbConnection) -> Option<Vec<(i64, Option<i64>, Option<i64>, i16, Option<String>)>> {
+        let select = uploads::table
+            .filter(uploads::is_completed.eq(true)
+                .and(uploads::deleted_at.is_null()))
+            .select((
+                uploads::id, uploads::plaintext_size, uploads::ciphertext_size,
+                uploads::format_version, uploads::public_key));
+
+        conn!(db_connection, |c| select.load::<(i64, Option<i64>, Option<i64>, i16, Option<String>)>(c)).ok()
+    }
+
+    pub fn select_missing_sizes(db_connection: &DbConnection) -> Option<Vec<i64>> {
+        let select = uploads::table
+            .filter(uploads::is_completed.eq(true)
+                .and(uploads::ciphertext_size.is_null()))
+            .select(uploads::id);
+
+        conn!(db_connection, |c| select.load::<i64>(c)).ok()
+    }
+
     // Delete the row with the given ID
     pub fn delete_with_id(id: i64, db_connection: &DbConnection) -> Option<usize> {
         let target = uploads::table
             .filter(uploads::id.eq(id));
         let delete = diesel::delete(target);
 
-        conn!(db_connection, |c| delete.execute(c)).ok()
+        conn!(QueryKind::Delete, db_connection, |c| delete.execute(c)).ok()
+    }
+
+    // Mark the row with the given ID as deleted. The upload is purged by the
+    // cleanup thread once the grace period is over. Return the number of
+    // modified rows.
+    pub fn mark_deleted(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let now = Local::now().naive_utc();
+        let target = uploads::table
+            .filter(uploads::id.eq(id)
+                .and(uploads::deleted_at.is_null()));
+        let update = diesel::update(target)
+            .set(uploads::deleted_at.eq(now));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Undo the deletion of the row with the given ID, optionally giving it a
+    // new expiry date. Return the number of modified rows.
+    pub fn restore(
+        id: i64, expire_after: Option<NaiveDateTime>,
+        db_connection: &DbConnection) -> Option<usize>
+    {
+        let target = uploads::table
+            .filter(uploads::id.eq(id)
+                .and(uploads::deleted_at.is_not_null()));
+
+        match expire_after {
+            Some(expire_after) => {
+                let update = diesel::update(target)
+                    .set((
+                        uploads::deleted_at.eq(None::<NaiveDateTime>),
+                        uploads::expire_after.eq(expire_after)));
+                conn!(db_connection, |c| update.execute(c)).ok()
+            },
+            None => {
+                let update = diesel::update(target)
+                    .set(uploads::deleted_at.eq(None::<NaiveDateTime>));
+                conn!(db_connection, |c| update.execute(c)).ok()
+            }
+        }
     }
 
-    // Return a list of IDs for expired (time-based) uploads
+    // Return a list of IDs for expired (time-based) uploads which have not
+    // been deleted yet
     pub fn select_expired(db_connection: &DbConnection) -> Option<Vec<i64>> {
         let now = Local::now().naive_utc();
         let select = uploads::table
-            .filter(uploads::expire_after.lt(now))
+            .filter(uploads::expire_after.lt(now)
+                .and(uploads::deleted_at.is_null()))
+            .select(uploads::id);
+
+        conn!(db_connection, |c| select.load::<i64>(c)).ok()
+    }
+
+    // Return the ID and size of each completed upload which has not been used
+    // since the given time, least recently used first
+    pub fn select_evictable(
+        used_before: NaiveDateTime, db_connection: &DbConnection) -> Option<Vec<(i64, i64)>>
+    {
+        let select = uploads::table
+            .filter(uploads::is_completed.eq(true)
+                .and(uploads::deleted_at.is_null())
+                .and(uploads::ciphertext_size.is_not_null())
+                .and(uploads::last_used_at.lt(used_before)
+                    .or(uploads::last_used_at.is_null())))
+            .select((uploads::id, uploads::ciphertext_size, uploads::last_used_at));
+
+        let mut rows = conn!(db_connection, |c| select
+            .load::<(i64, Option<i64>, Option<NaiveDateTime>)>(c)).ok()?;
+        // Backends disagree on where NULLs are ordered, so sort here. Uploads
+        // which were never used count as the oldest.
+        rows.sort_by_key(|(_, _, last_used_at)| *last_used_at);
+
+        Some(rows.into_iter()
+            .filter_map(|(id, size, _)| Some((id, size?)))
+            .collect())
+    }
+
+    // Return a list of IDs for uploads which were deleted before the given time
+    pub fn select_purgeable(
+        deleted_before: NaiveDateTime, db_connection: &DbConnection) -> Option<Vec<i64>>
+    {
+        let select = uploads::table
+            .filter(uploads::deleted_at.lt(deleted_before))
             .select(uploads::id);
 
         conn!(db_connection, |c| select.load::<i64>(c)).ok()
     }
 
+    // Remove the audit data from rows created before the given time. Return
+    // the number of modified rows.
+    pub fn scrub_audit_data(
+        created_before: NaiveDateTime, db_connection: &DbConnection) -> Option<usize>
+    {
+        let target = uploads::table
+            .filter(uploads::created_at.lt(created_before));
+        let update = diesel::update(target)
+            .set((
+                uploads::uploader_ip.eq(None::<String>),
+                uploads::created_at.eq(None::<NaiveDateTime>)));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
     pub fn select_all(db_connection: &DbConnection) -> Option<Vec<i64>> {
         let select = uploads::table.select(uploads::id);
 
@@ -186,6 +649,50 @@ impl Upload {
         conn!(db_connection, |c| update.execute(c)).ok()
     }
 
+    // Return the total size of the completed uploads owned by the given user
+    // which have not been deleted
+    pub fn bytes_stored_by_owner(owner_id: i64, db_connection: &DbConnection) -> Option<i64> {
+        let select = uploads::table
+            .filter(uploads::owner_id.eq(owner_id)
+                .and(uploads::deleted_at.is_null()))
+            .select(uploads::ciphertext_size);
+
+        let sizes = conn!(db_connection, |c| select.load::<Option<i64>>(c)).ok()?;
+        Some(sizes.into_iter().flatten().sum())
+    }
+
+    // Return the uploads owned by the given user which have not been deleted,
+    // newest first
+    pub fn select_with_owner(owner_id: i64, db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = uploads::table
+            .filter(uploads::owner_id.eq(owner_id)
+                .and(uploads::deleted_at.is_null()))
+            .order(uploads::created_at.desc());
+
+        conn!(db_connection, |c| select.load::<Upload>(c)).ok()
+    }
+
+    // Return up to `limit` uploads listed in the public gallery which can be
+    // downloaded, skipping the first `offset`, newest first
+    pub fn select_public(
+        offset: i64, limit: i64, db_connection: &DbConnection) -> Option<Vec<Self>>
+    {
+        let select = uploads::table
+            .filter(uploads::public_key.is_not_null()
+                .and(uploads::is_completed.eq(true))
+                .and(uploads::is_disabled.eq(false))
+                .and(uploads::deleted_at.is_null())
+                .and(uploads::password_hash.is_null())
+                .and(uploads::expire_after.gt(Local::now().naive_utc()))
+                .and(uploads::remaining_downloads.is_null()
+                    .or(uploads::remaining_downloads.gt(0))))
+            .order(uploads::published_at.desc())
+            .offset(offset)
+            .limit(limit);
+
+        conn!(db_connection, |c| select.load::<Upload>(c)).ok()
+    }
+
     pub fn num_accessors(db_connection: &DbConnection, id: i64) -> Option<i32> {
         let select = uploads::table
             .filter(uploads::dsl::id.eq(id))
@@ -196,82 +703,1003 @@ impl Upload {
 }
 
 
-fn get_migrations<C, P>(db_connection: &C, path: P) -> Vec<Box<dyn Migration + 'static>>
-where C: connection::MigrationConnection,
-      P: AsRef<Path>
-{
-    mark_migrations_in_directory(db_connection, path.as_ref())
-        .expect("Marking database migrations")
-        .into_iter()
-        .filter_map(|(m, is_applied)| if is_applied { None } else { Some(m) })
-        .collect()
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[derive(Serialize, Deserialize)]
+#[table_name="api_keys"]
+pub struct ApiKey {
+    // unique identifier for this key
+    pub id: i64,
+    // base64-encoded SHA-256 hash of the key
+    pub key_hash: String,
+    // description of who/what the key is for
+    pub label: String,
+    // comma-separated list of what the key may be used for
+    pub scopes: String,
+    // maximum size of a single upload made with this key (overrides the
+    // configured maximum)
+    pub max_upload_size_bytes: Option<i64>,
+    pub created_at: NaiveDateTime
 }
 
-pub fn run_migrations<P>(db_connection: &DbConnection, path: P)
-where P: AsRef<Path>
-{
-    let path = path.as_ref();
-    let stdout = &mut std::io::stdout();
-    match db_connection {
-        #[cfg(feature = "mysql")]
-        DbConnection::Mysql(c) => {
-            let migrations: Vec<_> = get_migrations(c, path.join("migrations"));
-            diesel_migrations::run_migrations(c, migrations, stdout)
-        },
-        #[cfg(feature = "postgres")]
-        DbConnection::Pg(c) => {
-            let migrations: Vec<_> = get_migrations(c, path.join("pg_migrations"));
-            diesel_migrations::run_migrations(c, migrations, stdout)
-        },
-        #[cfg(feature = "sqlite")]
-        DbConnection::Sqlite(c) => {
-            let migrations: Vec<_> = get_migrations(c, path.join("migrations"));
-            diesel_migrations::run_migrations(c, migrations, stdout)
-        }
-    }.expect("Running database migrations");
+table! {
+    api_keys (id) {
+        id -> BigInt,
+        key_hash -> Text,
+        label -> Text,
+        scopes -> Text,
+        max_upload_size_bytes -> Nullable<BigInt>,
+        created_at -> Timestamp,
+    }
 }
 
-pub fn parse_db_backend(db_url: &str) -> Option<DbBackend> {
-    if db_url.starts_with("mysql://") {
-        #[cfg(feature = "mysql")]
-        return Some(DbBackend::Mysql);
-    } else if db_url.starts_with("postgresql://") {
-        #[cfg(feature = "postgres")]
-        return Some(DbBackend::Pg);
-    } else {
-        #[cfg(feature = "sqlite")]
-        return Some(DbBackend::Sqlite);
+impl ApiKey {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(api_keys::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
     }
 
-    #[cfg(not(all(feature = "mysql", feature = "postgres", feature = "sqlite")))]
-    None
+    // Return whether or not the key may be used for the given scope
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.split(',').any(|s| s.trim() == scope)
+    }
+
+    // Return the key with the given hash
+    pub fn select_with_hash(key_hash: &str, db_connection: &DbConnection) -> Option<Self> {
+        let select = api_keys::table
+            .filter(api_keys::key_hash.eq(key_hash))
+            .limit(1);
+
+        conn!(db_connection, |c| select.load::<ApiKey>(c)).ok()?.pop()
+    }
+
+    pub fn select_all(db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = api_keys::table.order(api_keys::created_at);
+
+        conn!(db_connection, |c| select.load::<ApiKey>(c)).ok()
+    }
+
+    // Delete the key with the given ID
+    pub fn delete_with_id(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let target = api_keys::table
+            .filter(api_keys::id.eq(id));
+        let delete = diesel::delete(target);
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
 }
 
-pub fn establish_connection(db_backend: DbBackend, db_url: &str) -> DbConnection {
-    match db_backend {
-        #[cfg(feature = "mysql")]
-        DbBackend::Mysql => DbConnection::Mysql(
-            MysqlConnection::establish(&db_url)
-            .expect("Establishing MySQL connection")),
 
-            #[cfg(feature = "postgres")]
-        DbBackend::Pg => DbConnection::Pg(
-            PgConnection::establish(&db_url)
-            .expect("Establishing PostgreSQL connection")),
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[derive(Serialize, Deserialize)]
+#[table_name="users"]
+pub struct User {
+    // unique identifier for this user
+    pub id: i64,
+    pub username: String,
+    // argon2 hash of the user's password
+    pub password_hash: String,
+    pub created_at: NaiveDateTime,
+    // address to which expiry reminders are sent (if the user gave one)
+    pub email: Option<String>
+}
 
-            #[cfg(feature = "sqlite")]
-        DbBackend::Sqlite => {
-            let connection = SqliteConnection::establish(&db_url)
-                .expect("Establishing SQLite connection");
-            connection.execute("PRAGMA busy_timeout = 15000;")
-                .expect("Setting busy timeout");
-            DbConnection::Sqlite(connection)
-        }
+table! {
+    users (id) {
+        id -> BigInt,
+        username -> Text,
+        password_hash -> Text,
+        created_at -> Timestamp,
+        email -> Nullable<Text>,
     }
 }
 
-pub type DbConnectionInfo = (DbBackend, String);
+impl User {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem (e.g. the username is taken).
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(users::table)
+            .values(self);
 
-pub fn establish_connection_info(db_connection_info: &DbConnectionInfo) -> DbConnection {
-    establish_connection(db_connection_info.0, &db_connection_info.1)
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    // Return the user with the given username
+    pub fn select_with_username(username: &str, db_connection: &DbConnection) -> Option<Self> {
+        let select = users::table
+            .filter(users::username.eq(username))
+            .limit(1);
+
+        conn!(db_connection, |c| select.load::<User>(c)).ok()?.pop()
+    }
+
+    // Return the user with the given ID
+    pub fn select_with_id(id: i64, db_connection: &DbConnection) -> Option<Self> {
+        let select = users::table
+            .filter(users::id.eq(id))
+            .limit(1);
+
+        conn!(db_connection, |c| select.load::<User>(c)).ok()?.pop()
+    }
+
+    pub fn select_all(db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = users::table.order(users::created_at);
+
+        conn!(db_connection, |c| select.load::<User>(c)).ok()
+    }
+
+    // Set (or, with None, remove) the email address of the user with the
+    // given ID. Return the number of modified rows.
+    pub fn set_email(
+        id: i64, email: Option<String>, db_connection: &DbConnection) -> Option<usize>
+    {
+        let update = diesel::update(users::table.filter(users::id.eq(id)))
+            .set(users::email.eq(email));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="sessions"]
+pub struct Session {
+    // base64-encoded SHA-256 hash of the session token
+    pub token_hash: String,
+    // user to which this session belongs
+    pub user_id: i64,
+    // deadline after which the session expires
+    pub expire_after: NaiveDateTime
+}
+
+table! {
+    sessions (token_hash) {
+        token_hash -> Text,
+        user_id -> BigInt,
+        expire_after -> Timestamp,
+    }
+}
+
+impl Session {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(sessions::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    // Return the unexpired session with the given token hash
+    pub fn select_with_hash(token_hash: &str, db_connection: &DbConnection) -> Option<Self> {
+        let now = Local::now().naive_utc();
+        let select = sessions::table
+            .filter(sessions::token_hash.eq(token_hash))
+            .filter(sessions::expire_after.gt(now))
+            .limit(1);
+
+        conn!(db_connection, |c| select.load::<Session>(c)).ok()?.pop()
+    }
+
+    // Delete the session with the given token hash
+    pub fn delete_with_hash(token_hash: &str, db_connection: &DbConnection) -> Option<usize> {
+        let target = sessions::table
+            .filter(sessions::token_hash.eq(token_hash));
+        let delete = diesel::delete(target);
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+
+    // Delete all expired sessions
+    pub fn delete_expired(db_connection: &DbConnection) -> Option<usize> {
+        let now = Local::now().naive_utc();
+        let target = sessions::table
+            .filter(sessions::expire_after.lt(now));
+        let delete = diesel::delete(target);
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="oidc_identities"]
+pub struct OidcIdentity {
+    // issuer of the OpenID Connect provider
+    pub issuer: String,
+    // identifier of the user with the provider
+    pub subject: String,
+    // user as whom the subject is logged in
+    pub user_id: i64
+}
+
+table! {
+    oidc_identities (issuer, subject) {
+        issuer -> Text,
+        subject -> Text,
+        user_id -> BigInt,
+    }
+}
+
+impl OidcIdentity {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(oidc_identities::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    // Return the identity of the given subject with the given issuer
+    pub fn select(issuer: &str, subject: &str, db_connection: &DbConnection) -> Option<Self> {
+        let select = oidc_identities::table
+            .filter(oidc_identities::issuer.eq(issuer)
+                .and(oidc_identities::subject.eq(subject)))
+            .limit(1);
+
+        conn!(db_connection, |c| select.load::<OidcIdentity>(c)).ok()?.pop()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="reports"]
+pub struct Report {
+    pub id: i64,
+    // upload which was reported
+    pub upload_id: i64,
+    pub reason: String,
+    pub reporter_ip: Option<String>,
+    pub created_at: NaiveDateTime
+}
+
+table! {
+    reports (id) {
+        id -> BigInt,
+        upload_id -> BigInt,
+        reason -> Text,
+        reporter_ip -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+impl Report {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(reports::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    // Return every report, oldest first
+    pub fn select_all(db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = reports::table.order(reports::created_at);
+
+        conn!(db_connection, |c| select.load::<Report>(c)).ok()
+    }
+
+    // Return the reports of the upload with the given ID
+    pub fn select_with_upload(upload_id: i64, db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = reports::table
+            .filter(reports::upload_id.eq(upload_id));
+
+        conn!(db_connection, |c| select.load::<Report>(c)).ok()
+    }
+
+    // Delete the reports of the upload with the given ID. Return the number
+    // of modified rows.
+    pub fn delete_with_upload(upload_id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let delete = diesel::delete(reports::table.filter(reports::upload_id.eq(upload_id)));
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="blocked_networks"]
+pub struct BlockedNetwork {
+    pub id: i64,
+    // in CIDR notation
+    pub network: String,
+    pub reason: Option<String>,
+    pub created_at: NaiveDateTime
+}
+
+table! {
+    blocked_networks (id) {
+        id -> BigInt,
+        network -> Text,
+        reason -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+impl BlockedNetwork {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(blocked_networks::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    pub fn select_all(db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = blocked_networks::table.order(blocked_networks::created_at);
+
+        conn!(db_connection, |c| select.load::<BlockedNetwork>(c)).ok()
+    }
+
+    // Delete the row with the given ID, return the number of modified rows
+    pub fn delete_with_id(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let delete = diesel::delete(blocked_networks::table.filter(blocked_networks::id.eq(id)));
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="blocked_uploads"]
+pub struct BlockedUpload {
+    pub upload_id: i64,
+    pub reason: Option<String>,
+    pub created_at: NaiveDateTime
+}
+
+table! {
+    blocked_uploads (upload_id) {
+        upload_id -> BigInt,
+        reason -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+impl BlockedUpload {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(blocked_uploads::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    pub fn select_all(db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = blocked_uploads::table.order(blocked_uploads::created_at);
+
+        conn!(db_connection, |c| select.load::<BlockedUpload>(c)).ok()
+    }
+
+    // Delete the row for the upload with the given ID, return the number of
+    // modified rows
+    pub fn delete_with_upload(upload_id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let delete = diesel::delete(
+            blocked_uploads::table.filter(blocked_uploads::upload_id.eq(upload_id)));
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="leases"]
+pub struct Lease {
+    pub name: String,
+    // ID of the Transpo process which holds the lease
+    pub holder: String,
+    pub expires_at: NaiveDateTime
+}
+
+table! {
+    leases (name) {
+        name -> Text,
+        holder -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+impl Lease {
+    // Take (or renew) the lease with the given name until `expires_at`, unless
+    // another holder has it and it hasn't expired. Return whether `holder`
+    // has the lease now, or None if there was a problem.
+    pub fn acquire(
+        name: &str, holder: &str, expires_at: NaiveDateTime,
+        db_connection: &DbConnection) -> Option<bool>
+    {
+        let now = Local::now().naive_utc();
+
+        // Create the row separately like `DailyStats::create_today`. This
+        // fails if it already exists, which is fine.
+        let lease = Lease {
+            name: name.to_string(),
+            holder: holder.to_string(),
+            expires_at
+        };
+        let insert = diesel::insert_into(leases::table)
+            .values(&lease);
+        drop(conn!(db_connection, |c| insert.execute(c)));
+
+        let target = leases::table
+            .filter(leases::name.eq(name))
+            .filter(leases::holder.eq(holder).or(leases::expires_at.lt(now)));
+        let update = diesel::update(target)
+            .set((leases::holder.eq(holder), leases::expires_at.eq(expires_at)));
+
+        conn!(db_connection, |c| update.execute(c)).ok().map(|n| n == 1)
+    }
+
+    // Give up the lease if `holder` has it, so that another process can take
+    // it right away. Return the number of modified rows.
+    pub fn release(name: &str, holder: &str, db_connection: &DbConnection) -> Option<usize> {
+        let delete = diesel::delete(leases::table
+            .filter(leases::name.eq(name))
+            .filter(leases::holder.eq(holder)));
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+}
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="mail_queue"]
+pub struct QueuedMail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    // number of times sending the mail has failed
+    pub attempts: i32,
+    // the mail is not (re)tried before this time
+    pub send_after: NaiveDateTime
+}
+
+table! {
+    mail_queue (id) {
+        id -> BigInt,
+        recipient -> Text,
+        subject -> Text,
+        body -> Text,
+        attempts -> Integer,
+        send_after -> Timestamp,
+    }
+}
+
+impl QueuedMail {
+    // Insert into DB, return number of modified rows, or None if there
+    // was a problem.
+    pub fn insert(&self, db_connection: &DbConnection) -> Option<usize> {
+        let insert = diesel::insert_into(mail_queue::table)
+            .values(self);
+
+        conn!(db_connection, |c| insert.execute(c)).ok()
+    }
+
+    // Return up to `limit` mails which are due to be sent
+    pub fn select_due(limit: i64, db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = mail_queue::table
+            .filter(mail_queue::send_after.le(Local::now().naive_utc()))
+            .order(mail_queue::send_after)
+            .limit(limit);
+
+        conn!(db_connection, |c| select.load::<QueuedMail>(c)).ok()
+    }
+
+    // Record a failed attempt to send the mail with the given ID, which is
+    // retried after `retry_after`. Return the number of modified rows.
+    pub fn set_failed(
+        id: i64, retry_after: NaiveDateTime, db_connection: &DbConnection) -> Option<usize>
+    {
+        let update = diesel::update(mail_queue::table.filter(mail_queue::id.eq(id)))
+            .set((
+                mail_queue::attempts.eq(mail_queue::attempts + 1),
+                mail_queue::send_after.eq(retry_after)));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+
+    // Remove the mail with the given ID from the queue. Return the number of
+    // modified rows.
+    pub fn delete(id: i64, db_connection: &DbConnection) -> Option<usize> {
+        let delete = diesel::delete(mail_queue::table.filter(mail_queue::id.eq(id)));
+
+        conn!(db_connection, |c| delete.execute(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="daily_stats"]
+pub struct DailyStats {
+    pub day: NaiveDate,
+    // number of uploads started
+    pub uploads_created: i64,
+    // total size of the uploads which completed
+    pub bytes_stored: i64,
+    // number of downloads which finished (or were aborted)
+    pub downloads_served: i64,
+    // total number of bytes served by all downloads
+    pub bytes_served: i64
+}
+
+table! {
+    daily_stats (day) {
+        day -> Date,
+        uploads_created -> BigInt,
+        bytes_stored -> BigInt,
+        downloads_served -> BigInt,
+        bytes_served -> BigInt,
+    }
+}
+
+impl DailyStats {
+    // Create today's row if it doesn't exist yet and return today's date.
+    // Creating the row is done separately from updating it so that two
+    // connections racing to create it can't fail the transaction the update
+    // is part of.
+    fn create_today(db_connection: &DbConnection) -> NaiveDate {
+        let today = Local::now().naive_utc().date();
+
+        let stats = DailyStats {
+            day: today,
+            uploads_created: 0,
+            bytes_stored: 0,
+            downloads_served: 0,
+            bytes_served: 0
+        };
+        let insert = diesel::insert_into(daily_stats::table)
+            .values(&stats);
+        // This fails if the row already exists, which is fine.
+        drop(conn!(db_connection, |c| insert.execute(c)));
+
+        today
+    }
+
+    // Return the statistics for the given number of most recent days
+    pub fn select_recent(num_days: i64, db_connection: &DbConnection) -> Option<Vec<Self>> {
+        let select = daily_stats::table
+            .order(daily_stats::day.desc())
+            .limit(num_days);
+
+        conn!(db_connection, |c| select.load::<DailyStats>(c)).ok()
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Queryable)]
+#[derive(Insertable)]
+#[table_name="account_transfer"]
+pub struct AccountTransfer {
+    pub user_id: i64,
+    // first day of the month
+    pub month: NaiveDate,
+    pub bytes_uploaded: i64
+}
+
+table! {
+    account_transfer (user_id, month) {
+        user_id -> BigInt,
+        month -> Date,
+        bytes_uploaded -> BigInt,
+    }
+}
+
+impl AccountTransfer {
+    fn this_month() -> NaiveDate {
+        let today = Local::now().naive_utc().date();
+        NaiveDate::from_ymd_opt(today.year(), today.month(), 1).unwrap()
+    }
+
+    // Add the given number of bytes to this month's transfer of the given
+    // user. Return this month's transfer.
+    pub fn add(user_id: i64, bytes: i64, db_connection: &DbConnection) -> Option<i64> {
+        let month = Self::this_month();
+
+        // Create the row separately like `DailyStats::create_today`. This
+        // fails if it already exists, which is fine.
+        let transfer = AccountTransfer { user_id, month, bytes_uploaded: 0 };
+        let insert = diesel::insert_into(account_transfer::table)
+            .values(&transfer);
+        drop(conn!(db_connection, |c| insert.execute(c)));
+
+        let target = account_transfer::table.find((user_id, month));
+        let update = diesel::update(target)
+            .set(account_transfer::bytes_uploaded.eq(
+                account_transfer::bytes_uploaded + bytes));
+        let select = account_transfer::table.find((user_id, month))
+            .select(account_transfer::bytes_uploaded);
+
+        conn!(db_connection, |c| Connection::transaction::<_, diesel::result::Error, _>(c, || {
+            update.execute(c)?;
+            select.first::<i64>(c)
+        })).ok()
+    }
+
+    // Return this month's transfer of the given user
+    pub fn select_this_month(user_id: i64, db_connection: &DbConnection) -> Option<i64> {
+        let select = account_transfer::table.find((user_id, Self::this_month()))
+            .select(account_transfer::bytes_uploaded);
+
+        conn!(db_connection, |c| select.load::<i64>(c)).ok()
+            .map(|bytes| bytes.first().copied().unwrap_or(0))
+    }
+}
+
+
+// The storage usage table only has a single row
+const STORAGE_USAGE_ID: i32 = 1;
+
+// The total size of all stored uploads (including ones in progress)
+pub struct StorageUsage;
+
+table! {
+    storage_usage (id) {
+        id -> Integer,
+        bytes_used -> BigInt,
+    }
+}
+
+impl StorageUsage {
+    // Add the given number of bytes (which may be negative) to the storage
+    // usage. Return the new storage usage.
+    pub fn add(bytes: i64, db_connection: &DbConnection) -> Option<i64> {
+        let target = storage_usage::table.find(STORAGE_USAGE_ID);
+        let update = diesel::update(target)
+            .set(storage_usage::bytes_used.eq(storage_usage::bytes_used + bytes));
+        let select = storage_usage::table.find(STORAGE_USAGE_ID)
+            .select(storage_usage::bytes_used);
+
+        conn!(db_connection, |c| Connection::transaction::<_, diesel::result::Error, _>(c, || {
+            update.execute(c)?;
+            select.first::<i64>(c)
+        })).ok()
+    }
+
+    pub fn select(db_connection: &DbConnection) -> Option<i64> {
+        let select = storage_usage::table.find(STORAGE_USAGE_ID)
+            .select(storage_usage::bytes_used);
+
+        conn!(db_connection, |c| select.first::<i64>(c)).ok()
+    }
+
+    // Replace the storage usage with the given number of bytes. Return the
+    // number of modified rows.
+    pub fn set(bytes: i64, db_connection: &DbConnection) -> Option<usize> {
+        let target = storage_usage::table.find(STORAGE_USAGE_ID);
+        let update = diesel::update(target)
+            .set(storage_usage::bytes_used.eq(bytes));
+
+        conn!(db_connection, |c| update.execute(c)).ok()
+    }
+}
+
+fn get_migrations<C, P>(db_connection: &C, path: P) -> Vec<Box<dyn Migration + 'static>>
+where C: connection::MigrationConnection,
+      P: AsRef<Path>
+{
+    mark_migrations_in_directory(db_connection, path.as_ref())
+        .expect("Marking database migrations")
+        .into_iter()
+        .filter_map(|(m, is_applied)| if is_applied { None } else { Some(m) })
+        .collect()
+}
+
+pub fn run_migrations<P>(db_connection: &DbConnection, path: P)
+where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    let stdout = &mut std::io::stdout();
+    match db_connection {
+        #[cfg(feature = "mysql")]
+        DbConnection::Mysql(c) => {
+            let migrations: Vec<_> = get_migrations(c, path.join("migrations"));
+            diesel_migrations::run_migrations(c, migrations, stdout)
+        },
+        #[cfg(feature = "postgres")]
+        DbConnection::Pg(c) => {
+            let migrations: Vec<_> = get_migrations(c, path.join("pg_migrations"));
+            diesel_migrations::run_migrations(c, migrations, stdout)
+        },
+        #[cfg(feature = "sqlite")]
+        DbConnection::Sqlite(c) => {
+            let migrations: Vec<_> = get_migrations(c, path.join("migrations"));
+            diesel_migrations::run_migrations(c, migrations, stdout)
+        }
+    }.expect("Running database migrations");
+}
+
+// Return the version of every migration along with whether or not it has
+// been applied
+pub fn migration_status<P>(db_connection: &DbConnection, path: P) -> Option<Vec<(String, bool)>>
+where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    match db_connection {
+        #[cfg(feature = "mysql")]
+        DbConnection::Mysql(c) => mark_migrations_in_directory(c, &path.join("migrations")),
+        #[cfg(feature = "postgres")]
+        DbConnection::Pg(c) => mark_migrations_in_directory(c, &path.join("pg_migrations")),
+        #[cfg(feature = "sqlite")]
+        DbConnection::Sqlite(c) => mark_migrations_in_directory(c, &path.join("migrations")),
+    }
+    .ok()
+    .map(|migrations| migrations.into_iter()
+        .map(|(m, is_applied)| (m.version().to_string(), is_applied))
+        .collect())
+}
+
+// Revert the most recently applied migration. Return its version.
+pub fn revert_latest_migration<P>(db_connection: &DbConnection, path: P) -> Option<String>
+where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    match db_connection {
+        #[cfg(feature = "mysql")]
+        DbConnection::Mysql(c) => revert_latest_migration_in_directory(c, &path.join("migrations")),
+        #[cfg(feature = "postgres")]
+        DbConnection::Pg(c) => revert_latest_migration_in_directory(c, &path.join("pg_migrations")),
+        #[cfg(feature = "sqlite")]
+        DbConnection::Sqlite(c) => revert_latest_migration_in_directory(c, &path.join("migrations")),
+    }.ok()
+}
+
+// Return the name of the database backend in use
+pub fn backend_name(db_connection: &DbConnection) -> &'static str {
+    match db_connection {
+        #[cfg(feature = "mysql")]
+        DbConnection::Mysql(_) => "MySQL",
+        #[cfg(feature = "postgres")]
+        DbConnection::Pg(_) => "PostgreSQL",
+        #[cfg(feature = "sqlite")]
+        DbConnection::Sqlite(_) => "SQLite",
+    }
+}
+
+pub fn parse_db_backend(db_url: &str) -> Option<DbBackend> {
+    if db_url.starts_with("mysql://") {
+        #[cfg(feature = "mysql")]
+        return Some(DbBackend::Mysql);
+    } else if db_url.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        return Some(DbBackend::Pg);
+    } else {
+        #[cfg(feature = "sqlite")]
+        return Some(DbBackend::Sqlite);
+    }
+
+    #[cfg(not(all(feature = "mysql", feature = "postgres", feature = "sqlite")))]
+    None
+}
+
+// Make a single attempt at connecting to the database
+fn try_establish_connection(db_backend: DbBackend, db_url: &str) -> Result<DbConnection, String> {
+    match db_backend {
+        #[cfg(feature = "mysql")]
+        DbBackend::Mysql => MysqlConnection::establish(&db_url)
+            .map(DbConnection::Mysql)
+            .map_err(|e| e.to_string()),
+
+        #[cfg(feature = "postgres")]
+        DbBackend::Pg => PgConnection::establish(&db_url)
+            .map(DbConnection::Pg)
+            .map_err(|e| e.to_string()),
+
+        #[cfg(feature = "sqlite")]
+        DbBackend::Sqlite => {
+            let connection = SqliteConnection::establish(&db_url)
+                .map_err(|e| e.to_string())?;
+            connection.execute("PRAGMA busy_timeout = 15000;")
+                .map_err(|e| e.to_string())?;
+            Ok(DbConnection::Sqlite(connection))
+        }
+    }
+}
+
+// Return whether or not the last attempt at connecting to the database
+// succeeded
+pub fn is_db_available() -> bool {
+    DB_IS_AVAILABLE.load(Ordering::Relaxed)
+}
+
+// Make a single attempt at connecting to the database and record whether or
+// not it is available
+pub fn check_connection(db_backend: DbBackend, db_url: &str) -> bool {
+    let is_available = try_establish_connection(db_backend, db_url).is_ok();
+    DB_IS_AVAILABLE.store(is_available, Ordering::Relaxed);
+    is_available
+}
+
+// Connect to the database, retrying with exponential backoff if it is
+// unavailable. Return None if every attempt failed.
+pub fn establish_connection(db_backend: DbBackend, db_url: &str) -> Option<DbConnection> {
+    let mut delay = Duration::from_millis(CONNECT_RETRY_DELAY_MS);
+
+    for attempt in 1..=CONNECT_ATTEMPTS {
+        match try_establish_connection(db_backend, db_url) {
+            Ok(connection) => {
+                DB_IS_AVAILABLE.store(true, Ordering::Relaxed);
+                return Some(connection);
+            },
+            Err(e) => {
+                warn!("Connecting to database (attempt {}/{}): {}",
+                    attempt, CONNECT_ATTEMPTS, e);
+                if attempt < CONNECT_ATTEMPTS {
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    DB_IS_AVAILABLE.store(false, Ordering::Relaxed);
+    None
+}
+
+// Every download records its end in the database, so the connections used
+// for this are kept for the next ones instead of being closed.
+
+// A connection which goes back to the pool when it is dropped
+pub struct PooledConnection {
+    connection: Option<DbConnection>
+}
+
+impl PooledConnection {
+    // Take an idle connection which still works, or connect to the database
+    // if there is none
+    pub fn get(db_backend: DbBackend, db_url: &str) -> Option<Self> {
+        loop {
+            let idle = IDLE_CONNECTIONS.lock().ok().and_then(|mut idle| idle.pop());
+            let connection = match idle {
+                Some(connection) => connection,
+                None => break
+            };
+
+            // (the database may have closed it in the meantime)
+            if conn!(&connection, |c| Connection::execute(c, "SELECT 1")).is_ok() {
+                return Some(Self { connection: Some(connection) });
+            }
+        }
+
+        establish_connection(db_backend, db_url)
+            .map(|connection| Self { connection: Some(connection) })
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Ok(mut idle) = IDLE_CONNECTIONS.lock() {
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.extend(self.connection.take());
+            }
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = DbConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+pub type DbConnectionInfo = (DbBackend, String);
+
+pub fn establish_connection_info(db_connection_info: &DbConnectionInfo) -> Option<DbConnection> {
+    establish_connection(db_connection_info.0, &db_connection_info.1)
+}
+
+
+#[cfg(all(test, feature = "sqlite"))]
+pub mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    // Create an empty database (with every migration applied) for a test
+    pub fn test_db(name: &str) -> DbConnectionInfo {
+        let path = std::env::temp_dir()
+            .join(format!("transpo_test_{}_{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db_url = path.to_string_lossy().into_owned();
+
+        let db_connection = establish_connection(DbBackend::Sqlite, &db_url).unwrap();
+        run_migrations(&db_connection, env!("CARGO_MANIFEST_DIR"));
+
+        (DbBackend::Sqlite, db_url)
+    }
+
+    pub fn test_upload(id: i64, remaining_downloads: Option<i32>) -> Upload {
+        Upload {
+            id,
+            file_name: String::new(),
+            mime_type: String::new(),
+            password_hash: None,
+            remaining_downloads,
+            num_accessors: 0,
+            expire_after: Local::now().naive_utc() + ChronoDuration::minutes(10),
+            is_completed: true,
+            max_download_bytes_per_second: None,
+            num_downloads: 0,
+            num_completed_downloads: 0,
+            bytes_downloaded: 0,
+            plaintext_size: None,
+            ciphertext_size: None,
+            uploader_ip: None,
+            created_at: None,
+            owner_id: None,
+            deleted_at: None,
+            last_used_at: None,
+            notify_topic: None,
+            expiry_notified: false,
+            is_disabled: false,
+            is_mirror: false,
+            public_key: None,
+            published_at: None,
+            format_version: 1,
+            key_salt: None,
+            wrapped_key: None,
+            recipient_keys: None,
+            title: None
+        }
+    }
+
+    fn remaining_downloads(id: i64, db_connection: &DbConnection) -> Option<i32> {
+        Upload::select_with_id(id, db_connection).unwrap().remaining_downloads
+    }
+
+    #[test]
+    fn claims_stop_at_the_limit() {
+        let db_connection = establish_connection_info(&test_db("claims")).unwrap();
+        test_upload(1, Some(2)).insert(&db_connection).unwrap();
+
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(true));
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(true));
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(false));
+        assert_eq!(remaining_downloads(1, &db_connection), Some(0));
+    }
+
+    #[test]
+    fn refunds_give_back_a_claim() {
+        let db_connection = establish_connection_info(&test_db("refunds")).unwrap();
+        test_upload(1, Some(1)).insert(&db_connection).unwrap();
+        test_upload(2, None).insert(&db_connection).unwrap();
+
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(true));
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(false));
+        assert_eq!(Upload::refund_download(1, &db_connection), Some(1));
+        assert_eq!(remaining_downloads(1, &db_connection), Some(1));
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(true));
+
+        // Uploads without a limit are left without one
+        assert_eq!(Upload::refund_download(2, &db_connection), Some(0));
+        assert_eq!(remaining_downloads(2, &db_connection), None);
+    }
 }
@@ -1,3 +1,5 @@
+use crate::accounts::get_user_id;
+use crate::api_keys::check_admin;
 use crate::concurrency::*;
 use crate::db::*;
 use crate::b64::*;
@@ -6,52 +8,425 @@ use crate::config::*;
 use crate::files::*;
 use crate::http_errors::*;
 use crate::translations::*;
+use crate::tokens::*;
+use crate::metrics::*;
+use crate::notify;
+use crate::client_ip::*;
+use crate::recipients::*;
+use crate::federation::restore_from_peers;
+use crate::logging::{log_security_event, SecurityEvent};
 
-use std::io::{Read, Result};
-use std::sync::Arc;
+use std::io::{Read, Write, Result, Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::collections::HashSet;
+use std::future::Future;
+use std::mem;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Instant, Duration as StdDuration};
 
 use blocking::*;
-use trillium::{Conn, Body};
+use trillium::{Conn, Body, Headers, Method};
+
+use smol::{ready, Timer};
+use smol::channel::{bounded, Sender, Receiver};
+use smol::io::{AsyncRead, AsyncReadExt};
+use smol::stream::Stream;
 
 use urlencoding::{decode, encode};
 
+use chrono::{Local, Duration};
+
+use serde::Serialize;
+
+use streaming_zip::{Archive, CompressionMode};
+
+use flate2::{read::GzEncoder, Compression};
+use brotli::CompressorReader;
+
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 
+use tracing::{debug, info, warn};
+
+
+pub const PASSWORD_HEADER: &'static str = "X-Transpo-Password";
+const AUTHORIZATION_HEADER: &'static str = "Authorization";
+const BEARER_PREFIX: &'static str = "Bearer ";
+
+// Form bodies sent to the download endpoints only ever contain a key and a
+// password, so anything longer than this is not a legitimate request.
+const MAX_FORM_BODY_SIZE: u64 = 4096;
+
+// Uploads stored in the age format are downloaded without the key as .age
+// files of this type
+const AGE_MIME_TYPE: &'static str = "application/octet-stream";
+
+// Mime types (other than text/*) which are worth compressing
+const COMPRESSIBLE_MIME_TYPES: &'static [&'static str] = &[
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "image/svg+xml"
+];
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+
+// Maximum number of uploads which can be downloaded as one zip archive
+const MAX_ZIP_UPLOADS: usize = 64;
+// The form listing the uploads holds their keys and passwords
+const MAX_ZIP_FORM_BODY_SIZE: u64 = 64 * 1024;
+// Number of chunks of a zip archive which may be buffered before the writer
+// has to wait for the client to catch up
+const ZIP_CHANNEL_CAPACITY: usize = 4;
+
+// Signed download tokens are meant to be short-lived
+pub const DEFAULT_TOKEN_AGE_MINUTES: u32 = 10;
+pub const MAX_TOKEN_AGE_MINUTES: u32 = 60;
+
+#[derive(Clone, Copy)]
+enum ContentEncoding {
+    Brotli,
+    Gzip
+}
+
+impl ContentEncoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip"
+        }
+    }
+}
+
+// Pick a content encoding supported by the client, preferring brotli
+fn negotiate_encoding(headers: &Headers) -> Option<ContentEncoding> {
+    let accept_encoding = headers.get_str("Accept-Encoding")?;
+    let mut accepts_gzip = false;
+
+    for encoding in accept_encoding.split(',') {
+        let (name, params) = encoding.split_once(';').unwrap_or((encoding, ""));
+        // An encoding with a weight of 0 is explicitly not acceptable
+        let is_refused = params.trim().strip_prefix("q=")
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .map(|q| q == 0.0)
+            .unwrap_or(false);
+
+        match name.trim() {
+            "br" if !is_refused => return Some(ContentEncoding::Brotli),
+            "gzip" if !is_refused => accepts_gzip = true,
+            _ => {}
+        }
+    }
+
+    if accepts_gzip {
+        Some(ContentEncoding::Gzip)
+    } else {
+        None
+    }
+}
+
+fn is_compressible(mime_type: &str) -> bool {
+    let mime_type = mime_type.split(';').next().unwrap_or("").trim();
+    mime_type.starts_with("text/") || COMPRESSIBLE_MIME_TYPES.contains(&mime_type)
+}
+
+// Limit the rate at which data is read to a number of bytes per second
+struct Throttle {
+    bytes_per_second: u64,
+    start: Instant,
+    bytes_read: u64
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            start: Instant::now(),
+            bytes_read: 0
+        }
+    }
+
+    // Return how many bytes may be read at once
+    fn max_read_len(&self, buf_len: usize) -> usize {
+        cmp::max(cmp::min(buf_len as u64, self.bytes_per_second), 1) as usize
+    }
+
+    // Record that `bytes_read` bytes were read and return how long to wait
+    // until reading them no longer exceeds the limit
+    fn throttle(&mut self, bytes_read: usize) -> StdDuration {
+        self.bytes_read += bytes_read as u64;
+
+        let target = StdDuration::from_secs_f64(
+            self.bytes_read as f64 / self.bytes_per_second as f64);
+
+        target.saturating_sub(self.start.elapsed())
+    }
+}
+
+// Limit the combined rate of all downloads to a number of bytes per second.
+// Downloads take the bytes they read from a shared token bucket holding up to
+// a second's worth of bytes, and wait for any bytes which weren't available.
+#[derive(Clone)]
+pub struct Bandwidth {
+    bytes_per_second: u64,
+    // bytes available (negative if downloads are waiting for bytes) and the
+    // time at which they were last refilled
+    bucket: Arc<Mutex<(f64, Instant)>>
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            bucket: Arc::new(Mutex::new((bytes_per_second as f64, Instant::now())))
+        }
+    }
+
+    // Return how many bytes may be read at once
+    fn max_read_len(&self, buf_len: usize) -> usize {
+        cmp::min(buf_len as u64, cmp::max(self.bytes_per_second, 1)) as usize
+    }
+
+    // Take `bytes_read` bytes from the bucket and return how long to wait
+    // until they would have been available
+    fn take(&self, bytes_read: usize) -> StdDuration {
+        let rate = self.bytes_per_second as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let (available, updated_at) = &mut *bucket;
+        let now = Instant::now();
+
+        let refill = now.duration_since(*updated_at).as_secs_f64() * rate;
+        *available = (*available + refill).min(rate) - bytes_read as f64;
+        *updated_at = now;
+
+        StdDuration::from_secs_f64((-*available / rate).max(0.0))
+    }
+}
+
+// The limits on how fast a download is sent: its own speed limit, the
+// bandwidth shared by all downloads and the rate at which the client has to
+// receive it. Waiting is left to the caller, so that HTTP downloads don't
+// hold a thread while they are throttled.
+struct Limits {
+    throttle: Option<Throttle>,
+    bandwidth: Option<Bandwidth>,
+    transfer_rate: MinTransferRate,
+    // when the download could continue after the last read, after which the
+    // client was being waited for until the next read
+    resume_at: Option<Instant>,
+    waited: StdDuration
+}
+
+impl Limits {
+    fn new(
+        speed_limit: Option<u64>, bandwidth: Option<Bandwidth>,
+        config: &TranspoConfig) -> Self
+    {
+        Self {
+            throttle: speed_limit.map(Throttle::new),
+            bandwidth,
+            transfer_rate: MinTransferRate::new(
+                config.min_transfer_bytes_per_second, config.download_deadline_minutes),
+            resume_at: None,
+            waited: StdDuration::ZERO
+        }
+    }
+
+    // Record that a read was started and return how many bytes it may read
+    fn start_read(&mut self, buf_len: usize) -> usize {
+        if let Some(resume_at) = self.resume_at.take() {
+            self.waited = Instant::now().saturating_duration_since(resume_at);
+        }
+
+        let len = match &self.throttle {
+            Some(throttle) => throttle.max_read_len(buf_len),
+            None => buf_len
+        };
+        match &self.bandwidth {
+            Some(bandwidth) => bandwidth.max_read_len(len),
+            None => len
+        }
+    }
+
+    // Record that the read returned `bytes_read` bytes and return how long
+    // to wait before the next one. Return an error if the client is too slow.
+    fn finish_read(&mut self, bytes_read: usize) -> Result<StdDuration> {
+        self.transfer_rate.record(bytes_read, mem::take(&mut self.waited))?;
+
+        let throttle_delay = self.throttle.as_mut()
+            .map(|throttle| throttle.throttle(bytes_read))
+            .unwrap_or_default();
+        let bandwidth_delay = self.bandwidth.as_ref()
+            .map(|bandwidth| bandwidth.take(bytes_read))
+            .unwrap_or_default();
+        let delay = cmp::max(throttle_delay, bandwidth_delay);
+
+        // (time spent throttled isn't time spent waiting for the client)
+        self.resume_at = Some(Instant::now() + delay);
+        Ok(delay)
+    }
+}
+
+// The body of a download, which waits on a timer instead of a thread while it
+// is throttled
+struct ThrottledBody<R> {
+    reader: R,
+    limits: Limits,
+    timer: Option<Timer>
+}
+
+impl<R> AsyncRead for ThrottledBody<R>
+where R: AsyncRead + Unpin
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>>
+    {
+        let this = &mut *self;
+
+        if let Some(timer) = &mut this.timer {
+            ready!(Pin::new(timer).poll(cx));
+            this.timer = None;
+        }
+
+        let len = this.limits.start_read(buf.len());
+        let bytes_read = ready!(Pin::new(&mut this.reader).poll_read(cx, &mut buf[..len]))?;
+        let delay = this.limits.finish_read(bytes_read)?;
+        if !delay.is_zero() {
+            this.timer = Some(Timer::after(delay));
+        }
+
+        Poll::Ready(Ok(bytes_read))
+    }
+}
+
+// Return the download speed limit to apply to an upload, taking the lower of
+// the global limit and the upload's own limit. (0 means there is no limit)
+pub fn get_speed_limit(config: &TranspoConfig, upload: &Upload) -> Option<u64> {
+    let global_limit = match config.max_download_bytes_per_second {
+        0 => None,
+        limit => Some(limit as u64)
+    };
+    let upload_limit = upload.max_download_bytes_per_second
+        .filter(|l| *l > 0)
+        .map(|l| l as u64);
+
+    match (global_limit, upload_limit) {
+        (Some(g), Some(u)) => Some(cmp::min(g, u)),
+        (g, u) => g.or(u)
+    }
+}
 
 struct Reader<R>
 where R: Read {
     reader: R,
+    // only set if the reader itself has to wait (otherwise the limits are
+    // applied to the body)
+    limits: Option<Limits>,
+    // used to keep download statistics. These are the bytes of the body as it
+    // is sent, so a compressed body counts its compressed size.
+    bytes_read: u64,
+    is_finished: bool,
+    // whether the download started part way through the file
+    is_resumed: bool,
     accessor_mutex: AccessorMutex,
+    // the token the download was made with, which is used up once it finishes
+    redeemed_token: Option<RedeemedToken>,
     db_backend: DbBackend,
-    config: Arc<TranspoConfig>
+    config: Arc<TranspoConfig>,
+    _active: ActiveTransfer
 }
 
 impl<R> Reader<R>
 where R: Read
 {
     fn cleanup(&mut self) {
+        // The token can only be used to resume the download once something
+        // was sent (otherwise dropping it gives it back)
+        if let Some(redeemed_token) = self.redeemed_token.take() {
+            if self.is_finished {
+                redeemed_token.finish();
+            } else if self.bytes_read > 0 {
+                redeemed_token.interrupt();
+            }
+        }
+
         let accessor = self.accessor_mutex.lock();
+        // A download which failed before anything was sent doesn't count
+        // towards the download limit
+        let should_refund = self.bytes_read == 0 && !self.is_finished;
+        let is_only_accessor = accessor.is_only_accessor();
+        if !self.is_finished && !should_refund && !is_only_accessor {
+            return;
+        }
+
+        let db_connection = match PooledConnection::get(self.db_backend, &self.config.db_url) {
+            Some(db_connection) => db_connection,
+            None => return
+        };
+
+        // Downloads are only recorded once they reach the end of the file, so
+        // that a download which is interrupted and resumed counts once. It
+        // only counts as complete if it wasn't resumed part way through.
+        if self.is_finished {
+            Upload::record_download(
+                accessor.id, self.bytes_read, !self.is_resumed, &db_connection);
+
+            if notify::is_enabled(&self.config) {
+                if let Some(upload) = Upload::select_with_id(accessor.id, &db_connection) {
+                    notify::notify(&upload, notify::Event::Downloaded, &self.config);
+                }
+            }
+        }
+
+        if should_refund {
+            Upload::refund_download(accessor.id, &db_connection);
+        }
 
         // If we're the last accessor, then it's our responsibility to
         // clean up the upload if it is now invalid!
-        if accessor.is_only_accessor() {
-            let db_connection = establish_connection(self.db_backend, &self.config.db_url);
-
+        if is_only_accessor {
             let should_delete = match Upload::select_with_id(accessor.id, &db_connection) {
                 Some(upload) => upload.is_expired(),
-                None => true
+                None => false
             };
 
+            // The upload itself is purged by the cleanup thread once the
+            // deletion grace period is over.
             if should_delete {
-                // Note: ID generation avoids collisions by checking the
-                // filesystem, so we remove the upload directory last.
-                Upload::delete_with_id(accessor.id, &db_connection);
-                delete_upload_dir(&self.config.storage_dir, accessor.id);
+                Upload::mark_deleted(accessor.id, &db_connection);
             }
         }
     }
 }
 
+// Wrap a reader of an upload so that the download is limited and recorded
+// like one made over HTTP (e.g. through the gRPC API or in a zip archive). The
+// reader sleeps while it is throttled, so it should be read on a thread of its
+// own.
+pub fn tracked_reader<R>(
+    reader: R, speed_limit: Option<u64>, bandwidth: Option<Bandwidth>,
+    accessor_mutex: AccessorMutex, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> impl Read + Send
+where R: Read + Send
+{
+    Reader {
+        reader,
+        limits: Some(Limits::new(speed_limit, bandwidth, &config)),
+        bytes_read: 0,
+        is_finished: false,
+        is_resumed: false,
+        accessor_mutex,
+        redeemed_token: None,
+        db_backend,
+        config,
+        _active: ActiveTransfer::new(Transfer::Download)
+    }
+}
+
 impl<R> Drop for Reader<R> 
 where R: Read
 {
@@ -63,17 +438,64 @@ where R: Read
 impl<R> Read for Reader<R>
 where R: Read {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.reader.read(buf)
+        let bytes_read = match self.limits.as_mut() {
+            Some(limits) => {
+                let len = limits.start_read(buf.len());
+                let bytes_read = self.reader.read(&mut buf[..len])?;
+                thread::sleep(limits.finish_read(bytes_read)?);
+                bytes_read
+            },
+            None => self.reader.read(buf)?
+        };
+
+        if bytes_read == 0 && !buf.is_empty() {
+            self.is_finished = true;
+        }
+        self.bytes_read += bytes_read as u64;
+        add_bytes_sent(bytes_read);
+
+        Ok(bytes_read)
     }
 }
 
+// Why a download was refused
+enum Refusal {
+    NotFound,
+    Invalid,
+    // `start_index` isn't the start of a chunk, the one it is in starts at
+    // the given index
+    NotChunkStart(u64)
+}
+
 #[derive(Default)]
 struct DownloadQuery {
     crypto_key: Option<Vec<u8>>,
     password: Option<Vec<u8>>,
+    token: Option<String>,
+    // secret key of a recipient of the upload
+    identity: Option<String>,
+    minutes: Option<u32>,
     start_index: u64
 }
 
+impl DownloadQuery {
+    // Fill in any values which are missing from `self` with those in `other`
+    fn or(self, other: Self) -> Self {
+        Self {
+            crypto_key: self.crypto_key.or(other.crypto_key),
+            password: self.password.or(other.password),
+            token: self.token.or(other.token),
+            identity: self.identity.or(other.identity),
+            minutes: self.minutes.or(other.minutes),
+            start_index: if self.start_index == 0 {
+                other.start_index
+            } else {
+                self.start_index
+            }
+        }
+    }
+}
+
 fn parse_query(query: &str) -> DownloadQuery {
     let mut parsed = DownloadQuery::default();
 
@@ -88,6 +510,13 @@ fn parse_query(query: &str) -> DownloadQuery {
                 "password" => parsed.password = decode(value)
                     .ok()
                     .and_then(|s| Some(s.into_owned().into_bytes())),
+                "token" => parsed.token = decode(value)
+                    .ok()
+                    .map(|s| s.into_owned()),
+                "identity" => parsed.identity = decode(value)
+                    .ok()
+                    .map(|s| s.into_owned()),
+                "minutes" => parsed.minutes = value.parse().ok(),
                 "start_index" => if let Ok(start_index) = value.parse() {
                     parsed.start_index = start_index;
                 }
@@ -99,21 +528,81 @@ fn parse_query(query: &str) -> DownloadQuery {
     parsed
 }
 
-fn get_upload(
-    id: i64, config: &TranspoConfig,
-    accessors: &Accessors, db_backend: DbBackend,
+// The password may be sent in a header so that it doesn't end up in the logs
+// of proxies along the way. The value is percent-decoded in case the client
+// needs to send characters which aren't allowed in headers.
+fn password_from_headers(headers: &Headers) -> Option<Vec<u8>> {
+    headers.get_str(PASSWORD_HEADER)
+        .or_else(|| headers
+            .get_str(AUTHORIZATION_HEADER)
+            .and_then(|a| a.strip_prefix(BEARER_PREFIX)))
+        .and_then(|p| decode(p.trim()).ok())
+        .map(|p| p.into_owned().into_bytes())
+}
+
+// Read a request body of at most `limit` bytes, or return the status to
+// respond with if it is too large (413) or can't be read (400). One byte more
+// than the limit is read, so that a body which is too large is refused rather
+// than cut short.
+async fn read_form_body(conn: &mut Conn, limit: u64) -> std::result::Result<String, u16> {
+    let mut body = Vec::new();
+    conn.request_body().await
+        .take(limit + 1)
+        .read_to_end(&mut body).await
+        .or(Err(400u16))?;
+
+    if body.len() as u64 > limit {
+        return Err(413);
+    }
+
+    String::from_utf8(body).or(Err(400))
+}
+
+// Parse an `application/x-www-form-urlencoded` request body. This is the same
+// format as the query string, except that spaces are encoded as '+'.
+async fn parse_form_body(conn: &mut Conn) -> std::result::Result<DownloadQuery, u16> {
+    let body = read_form_body(conn, MAX_FORM_BODY_SIZE).await?;
+
+    Ok(parse_query(&body.replace('+', "%20")))
+}
+
+// Collect the download parameters from the request, or return the status to
+// respond with if the form body can't be read.
+// header -> form body -> query
+async fn get_download_query(conn: &mut Conn) -> std::result::Result<DownloadQuery, u16> {
+    let query = parse_query(conn.querystring());
+
+    let query = if conn.method() == Method::Post {
+        parse_form_body(conn).await?.or(query)
+    } else {
+        query
+    };
+
+    let header_query = DownloadQuery {
+        password: password_from_headers(conn.headers()),
+        ..DownloadQuery::default()
+    };
+
+    Ok(header_query.or(query))
+}
+
+pub fn get_upload(
+    id: i64, accessors: &Accessors,
     db_connection: &DbConnection) -> Option<Upload>
 {
-    let accessor_mutex = accessors.access(id, (db_backend, config.db_url.to_owned()));
+    let accessor_mutex = accessors.access(id)?;
     let accessor = accessor_mutex.lock();
 
     let row = Upload::select_with_id(id, &db_connection)?;
 
-    // If the row is expired and we are the only accessor, clean it up!
-    let upload = if row.is_expired() {
+    // If the row is expired and we are the only accessor, delete it!
+    // Uploads disabled because of reports can't be downloaded until an admin
+    // reviews them
+    let upload = if row.is_deleted() || row.is_disabled {
+        None
+    } else if row.is_expired() {
         if accessor.is_only_accessor() {
-            Upload::delete_with_id(accessor.id, &db_connection);
-            delete_upload_dir(&config.storage_dir, accessor.id);
+            Upload::mark_deleted(accessor.id, &db_connection);
         }
         None
     } else {
@@ -123,6 +612,83 @@ fn get_upload(
     upload
 }
 
+// The metadata of an upload, as returned by `/info`
+#[derive(Serialize)]
+pub struct UploadInfo {
+    // base64-encoded ciphertext of the file name
+    name: String,
+    // base64-encoded ciphertext of the mime type
+    mime: String,
+    // size of the ciphertext (0 if the upload is still in progress)
+    size: u64,
+    // size of the plaintext (0 if the upload is still in progress), including
+    // the padding of padded uploads
+    plaintext_size: u64,
+    // UNIX timestamp after which the upload expires
+    expire_after: i64,
+    remaining_downloads: Option<i32>,
+    is_completed: bool,
+    downloads: i32,
+    completed_downloads: i32,
+    bytes_downloaded: i64,
+    // version of the format in which the upload is encrypted
+    format_version: i16,
+    // whether the key is derived from the password, so the server has to
+    // decrypt the upload
+    derived_key: bool,
+    // whether the key is wrapped for recipients, who send their secret key so
+    // the server can decrypt the upload
+    recipients: bool,
+    // base64-encoded ciphertext of the title, if the upload has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>
+}
+
+impl UploadInfo {
+    pub fn new(upload: Upload, size: u64) -> Self {
+        // The size of uploads whose sizes were never recorded is worked out
+        // from the size of their file
+        let plaintext_size = match (upload.is_completed, upload.plaintext_size) {
+            (true, Some(plaintext_size)) => plaintext_size as u64,
+            (true, None) => ChunkFormat::new(upload.id, upload.format_version).plaintext_len(size),
+            (false, _) => 0
+        };
+
+        Self {
+            name: upload.file_name,
+            mime: upload.mime_type,
+            size,
+            plaintext_size,
+            expire_after: upload.expire_after.timestamp(),
+            remaining_downloads: upload.remaining_downloads,
+            is_completed: upload.is_completed,
+            downloads: upload.num_downloads,
+            completed_downloads: upload.num_completed_downloads,
+            bytes_downloaded: upload.bytes_downloaded,
+            format_version: upload.format_version,
+            derived_key: upload.wrapped_key.is_some(),
+            recipients: upload.recipient_keys.is_some(),
+            title: upload.title,
+            // Uploads do not store a checksum (yet)
+            checksum: None
+        }
+    }
+}
+
+// Like `check_password`, but log a security event if a wrong password is
+// given
+pub fn verify_password(
+    password: &Option<Vec<u8>>, upload: &Upload, client_ip: Option<IpAddr>) -> bool
+{
+    let is_valid = check_password(password, upload);
+    if !is_valid && password.is_some() {
+        log_security_event(SecurityEvent::PasswordFailure, client_ip);
+    }
+    is_valid
+}
+
 fn check_password(password: &Option<Vec<u8>>, upload: &Upload) -> bool {
     let hash_string = upload.password_hash.as_ref()
         .map(|h| String::from_utf8_lossy(h).to_string());
@@ -143,60 +709,165 @@ fn check_password(password: &Option<Vec<u8>>, upload: &Upload) -> bool {
 }
 
 
-pub async fn info(
-    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
-    accessors: Accessors, translation: Translation, db_backend: DbBackend) -> Conn
+// Return the metadata of the upload as JSON, or the status to respond with if
+// it can't be shown
+pub async fn get_info(
+    conn: &mut Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens,
+    db_backend: DbBackend) -> std::result::Result<String, u16>
 {
     if id_string.len() != base64_encode_length(ID_LENGTH) {
-        return error_404(conn, config, translation);
+        return Err(404);
     }
 
     let id = i64_from_b64_bytes(id_string.as_bytes()).unwrap();
 
-    let query = parse_query(conn.querystring());
+    restore_from_peers(id_string.clone(), config.clone(), db_backend).await;
+
+    let query = get_download_query(conn).await?;
     let password = query.password;
+    let token = query.token;
+    let client_ip = ClientIp::of(conn);
 
-    let config_ = config.clone();
     let info = unblock(move || {
-        let db_connection = establish_connection(db_backend, &config_.db_url);
-        let upload = get_upload(id, &config_, &accessors, db_backend, &db_connection)?;
-        let upload_path = config_.storage_dir.join(&id_string).join("upload");
-        let ciphertext_size = if upload.is_completed {
-            get_file_size(&upload_path).ok()?
-        } else {
-            0
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        let upload = get_upload(id, &accessors, &db_connection)?;
+        let upload_path = config.storage_dir.join(&id_string).join("upload");
+        let ciphertext_size = match (upload.is_completed, upload.ciphertext_size) {
+            (true, Some(size)) => size as u64,
+            (true, None) => get_file_size(&upload_path).ok()?,
+            (false, _) => 0
         };
 
-        if !check_password(&password, &upload) {
+        // The token is only checked here, it is used up by the download
+        let has_valid_token = token.map(|t| tokens.verify(id, &t)).unwrap_or(false);
+
+        if !has_valid_token && !verify_password(&password, &upload, client_ip) {
             None
         } else {
-            Some((upload.file_name, upload.mime_type, ciphertext_size))
+            serde_json::to_string(&UploadInfo::new(upload, ciphertext_size)).ok()
         }
     }).await;
 
+    info.ok_or(400)
+}
+
+pub async fn info(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    let info = get_info(
+        &mut conn, id_string, config.clone(), accessors, tokens, db_backend).await;
+
     match info {
-        Some((file_name, mime_type, file_size)) => {
+        Ok(info) => {
             conn
                 .with_status(200)
                 .with_header("Content-Type", "application/json")
-                .with_body(format!("{{ \
-                        \"name\": \"{}\", \
-                        \"mime\": \"{}\", \
-                        \"size\": {} \
-                    }}",
-                    file_name, mime_type, file_size))
+                .with_body(info)
                 .halt()
         },
-        None => {
-            error_400(conn, config, translation)
-        }
+        Err(404) => error_404(conn, config, translation),
+        Err(413) => error_413(conn, config, translation),
+        Err(_) => error_400(conn, config, translation)
     }
 }
 
 
+// Return the key with which the server decrypts the upload, or None (in the
+// option) if it doesn't. Return None if a key can't be unwrapped.
+fn resolve_key(
+    crypto_key: Option<Vec<u8>>, upload: &Upload, password: &Option<Vec<u8>>,
+    identity: &Option<String>) -> Option<Option<Vec<u8>>>
+{
+    // If the key of the upload is derived from its password, the server
+    // decrypts it with the key unwrapped with the password (unless the
+    // client sent the key itself)
+    let crypto_key = match (crypto_key, &upload.key_salt, &upload.wrapped_key, password) {
+        (None, Some(key_salt), Some(wrapped_key), Some(password)) => Some(
+            unwrap_key(wrapped_key.as_bytes(), key_salt.as_bytes(), password).ok()?),
+        (crypto_key, ..) => crypto_key
+    };
+    // Likewise if its key is wrapped for the recipient who sent their
+    // secret key
+    let crypto_key = match (crypto_key, &upload.recipient_keys, identity) {
+        (None, Some(recipient_keys), Some(identity)) => Some(
+            unwrap_key_for_recipient(recipient_keys, &parse_identity(identity)?).ok()?),
+        (crypto_key, ..) => crypto_key
+    };
+
+    Some(crypto_key)
+}
+
+// Return the entries of an archive created by the server without streaming
+// the archive. They are decrypted if the server has the key, and are sent
+// encrypted otherwise (see `files::read_archive_entries`), so that the
+// browser can decrypt them with the key in the link.
+pub async fn entries(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    if id_string.len() != base64_encode_length(ID_LENGTH) {
+        return error_404(conn, config, translation);
+    }
+
+    let id = i64_from_b64_bytes(id_string.as_bytes()).unwrap();
+
+    let query = match get_download_query(&mut conn).await {
+        Ok(query) => query,
+        Err(413) => return error_413(conn, config, translation),
+        Err(_) => return error_400(conn, config, translation)
+    };
+    let client_ip = ClientIp::of(&conn);
+
+    let entries = {
+        let config = config.clone();
+        unblock(move || {
+            let db_connection = establish_connection(db_backend, &config.db_url)?;
+            let upload = get_upload(id, &accessors, &db_connection)?;
+
+            // The token is only checked here, it is used up by the download
+            let has_valid_token = query.token
+                .map(|t| tokens.verify(id, &t))
+                .unwrap_or(false);
+            if !has_valid_token && !verify_password(&query.password, &upload, client_ip) {
+                return None;
+            }
+
+            let crypto_key = resolve_key(
+                query.crypto_key, &upload, &query.password, &query.identity)?;
+            let entries_cipher = read_archive_entries(
+                &config.storage_dir.join(&id_string))?;
+
+            match crypto_key {
+                Some(key) => {
+                    let entries = decrypt_archive_entries(
+                        &key, &entries_cipher,
+                        ChunkFormat::new(upload.id, upload.format_version)).ok()?;
+                    Some(("application/json", serde_json::to_vec(&entries).ok()?))
+                },
+                None => Some(("application/octet-stream", entries_cipher))
+            }
+        }).await
+    };
+
+    match entries {
+        Some((content_type, body)) => conn
+            .with_status(200)
+            .with_header("Cache-Control", "no-cache")
+            .with_header("Content-Type", content_type)
+            .with_body(body)
+            .halt(),
+        None => error_404(conn, config, translation)
+    }
+}
+
 pub async fn handle(
-    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
-    accessors: Accessors, translation: Translation, db_backend: DbBackend) -> Conn
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens, bandwidth: Option<Bandwidth>,
+    translation: Translation, db_backend: DbBackend) -> Conn
 {
     if id_string.len() != base64_encode_length(ID_LENGTH) {
         return error_404(conn, config, translation);
@@ -204,95 +875,679 @@ pub async fn handle(
 
     let id = i64_from_b64_bytes(id_string.as_bytes()).unwrap();
 
-    let query = parse_query(conn.querystring());
+    restore_from_peers(id_string.clone(), config.clone(), db_backend).await;
+
+    let query = match get_download_query(&mut conn).await {
+        Ok(query) => query,
+        Err(413) => return error_413(conn, config, translation),
+        Err(_) => return error_400(conn, config, translation)
+    };
     let crypto_key = query.crypto_key;
+    let crypto_key_given = crypto_key.is_some();
     let password = query.password;
+    let token = query.token;
+    let identity = query.identity;
     let start_index = query.start_index;
+    let client_ip = ClientIp::of(&conn);
+    // Compressed downloads can't be resumed, since the offsets would not
+    // line up with the ciphertext (the encoding is only used if the server
+    // decrypts the upload)
+    let encoding = if start_index == 0 {
+        negotiate_encoding(conn.headers())
+    } else {
+        None
+    };
 
     let response = {
         let config = config.clone();
         unblock(move || {
-            let db_connection = establish_connection(db_backend, &config.db_url);
+            let db_connection = establish_connection(db_backend, &config.db_url)
+                .ok_or(Refusal::Invalid)?;
 
-            let upload = get_upload(id, &config, &accessors, db_backend, &db_connection)?;
+            let upload = get_upload(id, &accessors, &db_connection)
+                .ok_or(Refusal::NotFound)?;
 
-            // validate password
-            if !check_password(&password, &upload) {
-                return None;
+            let is_resumed = start_index > 0;
+
+            // validate password (a signed token can be used in its place)
+            let redeemed_token = token.and_then(|t| tokens.redeem(id, &t, is_resumed));
+            if redeemed_token.is_none() && !verify_password(&password, &upload, client_ip) {
+                return Err(Refusal::Invalid);
             }
 
-            let accessor_mutex = accessors.access(id, (db_backend, config.db_url.to_owned()));
-            Upload::decrement_remaining_downloads(id, &db_connection)?;
+            let crypto_key = resolve_key(crypto_key, &upload, &password, &identity)
+                .ok_or(Refusal::Invalid)?;
 
-            let upload_path = config.storage_dir.join(&id_string).join("upload");
-            let ciphertext_size = get_file_size(&upload_path).ok()?;
+            // A download can only be resumed at the start of a chunk, otherwise
+            // the client gets garbage (or a decryption error) in the middle of
+            // the stream. (This is only checked once the client is allowed to
+            // download the upload, since it reveals where its chunks start.)
+            let start_chunk = if start_index == 0 {
+                0
+            } else {
+                let upload_path = config.storage_dir.join(&id_string).join("upload");
+                match find_chunk(&upload_path, start_index) {
+                    Ok((chunk_start, chunk_index)) if chunk_start == start_index => chunk_index,
+                    Ok((chunk_start, _)) => return Err(Refusal::NotChunkStart(chunk_start)),
+                    Err(_) => return Err(Refusal::Invalid)
+                }
+            };
 
-            let (body, file_name, mime_type) = match crypto_key {
-                // server-side decryption
-                Some(key) => {
-                    let (reader, mut file_name, mime_type) =
-                        EncryptedFileReader::new(
-                            &upload_path, start_index, upload.expire_after, upload.is_completed,
-                            &key, upload.file_name.as_bytes(), upload.mime_type.as_bytes()).ok()?;
+            let accessor_mutex = accessors.access(id).ok_or(Refusal::Invalid)?;
+            if upload.remaining_downloads.is_some()
+            && !Upload::claim_download(id, &db_connection).ok_or(Refusal::Invalid)?
+            {
+                return Err(Refusal::Invalid);
+            }
+
+            // Give the claimed download back if the response can't be created
+            let create_response = move || {
+                let upload_path = config.storage_dir.join(&id_string).join("upload");
+                let ciphertext_size = match upload.ciphertext_size {
+                    Some(size) => size as u64,
+                    None => get_file_size(&upload_path).ok()?
+                };
+                let speed_limit = get_speed_limit(&config, &upload);
+
+                let (body, file_name, mime_type, encoding, title) = match crypto_key {
+                    // server-side decryption
+                    Some(key) => {
+                        let title = upload.title.as_ref()
+                            .and_then(|title| decrypt_title(
+                                &key, title.as_bytes(),
+                                ChunkFormat::new(upload.id, upload.format_version)).ok())
+                            .map(|title| encode(&title).into_owned());
 
-                    // If file name is missing, assign one based on the app name and upload ID
-                    if file_name.is_empty() {
-                        file_name = format!("{}_{}", config.app_name, id_string);
+                        let (reader, mut file_name, mime_type) =
+                            EncryptedFileReader::new(
+                                &upload_path, start_index, start_chunk,
+                                upload.expire_after, upload.is_completed,
+                                ChunkFormat::new(upload.id, upload.format_version),
+                                &key, upload.file_name.as_bytes(), upload.mime_type.as_bytes()).ok()?;
 
-                        if mime_type == "application/zip" {
-                            file_name.push_str(".zip");
+                        // If file name is missing, assign one based on the app name and upload ID
+                        if file_name.is_empty() {
+                            file_name = format!("{}_{}", config.app_name, id_string);
+
+                            if mime_type == "application/zip" {
+                                file_name.push_str(".zip");
+                            }
                         }
-                    }
 
-                    file_name = encode(&file_name).into_owned();
+                        file_name = encode(&file_name).into_owned();
 
-                    let body = create_body_for(
-                        reader, accessor_mutex, db_backend, config);
+                        let encoding = encoding.filter(|_| is_compressible(&mime_type));
+                        let body = match encoding {
+                            Some(ContentEncoding::Brotli) => create_body_for(
+                                CompressorReader::new(
+                                    reader, BROTLI_BUFFER_SIZE,
+                                    BROTLI_QUALITY, BROTLI_WINDOW_SIZE),
+                                None, speed_limit, bandwidth, accessor_mutex, is_resumed,
+                                redeemed_token, db_backend, config),
+                            Some(ContentEncoding::Gzip) => create_body_for(
+                                GzEncoder::new(reader, Compression::default()),
+                                None, speed_limit, bandwidth, accessor_mutex, is_resumed,
+                                redeemed_token, db_backend, config),
+                            None => {
+                                // The length of the plaintext is only known
+                                // when the whole upload is downloaded
+                                // (the plaintext size of padded uploads
+                                // includes the padding)
+                                let len = reader.plaintext_len()
+                                    .or(upload.plaintext_size.map(|l| l as u64))
+                                    .filter(|_| upload.is_completed && start_chunk == 0);
 
-                    (body, file_name, mime_type)
-                },
-                // no server-side decryption
-                None => {
-                    let reader = FileReader::new(
-                        &upload_path, start_index, upload.expire_after,
-                        upload.is_completed).ok()?;
-                    let body = create_body_for(
-                        reader, accessor_mutex, db_backend, config);
-                    (body, upload.file_name, upload.mime_type)
-                }
+                                create_body_for(
+                                    reader, len, speed_limit, bandwidth, accessor_mutex,
+                                    is_resumed, redeemed_token, db_backend, config)
+                            }
+                        };
+
+                        (body, file_name, mime_type, encoding, title)
+                    },
+                    // no server-side decryption
+                    None => {
+                        let reader = FileReader::new(
+                            &upload_path, start_index, upload.expire_after,
+                            upload.is_completed).ok()?;
+                        let len = upload.ciphertext_size
+                            .filter(|_| upload.is_completed)
+                            .map(|l| (l as u64).saturating_sub(start_index));
+                        let body = create_body_for(
+                            reader, len, speed_limit, bandwidth, accessor_mutex, is_resumed,
+                            redeemed_token, db_backend, config);
+
+                        // Age files are served as such, so that they can be
+                        // decrypted with `age`
+                        if upload.format_version == AGE_FORMAT_VERSION {
+                            let file_name = format!("{}.age", id_string);
+                            (body, file_name, AGE_MIME_TYPE.to_owned(), None, None)
+                        } else {
+                            (body, upload.file_name, upload.mime_type, None, None)
+                        }
+                    }
+                };
+
+                Some((body, file_name, mime_type, encoding, title, ciphertext_size))
             };
 
-            Some((body, file_name, mime_type, ciphertext_size))
+            let response = create_response();
+            if response.is_none() {
+                Upload::refund_download(id, &db_connection);
+            }
+
+            response.ok_or(Refusal::Invalid)
         }).await
     };
 
     match response {
-        Some((body, file_name, mime_type, ciphertext_size)) => {
-            conn
+        Ok((body, file_name, mime_type, encoding, title, ciphertext_size)) => {
+            info!(start_index, server_side_decryption = crypto_key_given, "Serving download");
+            let conn = conn
                 .with_status(200)
                 .with_body(body)
                 .with_header("Cache-Control", "no-cache")
+                .with_header("Vary", "Accept-Encoding")
                 .with_header("Content-Type", mime_type)
                 .with_header("Transpo-Ciphertext-Length", format!("{}", ciphertext_size))
                 .with_header("Content-Disposition",
-                             format!("attachment; filename=\"{}\"", file_name))
+                             format!("attachment; filename=\"{}\"", file_name));
+
+            // (percent-encoded like the file name)
+            let conn = match title {
+                Some(title) => conn.with_header("Transpo-Title", title),
+                None => conn
+            };
+
+            match encoding {
+                Some(encoding) => conn.with_header("Content-Encoding", encoding.as_str()),
+                None => conn
+            }.halt()
+        },
+        // Tell the client where it can resume instead
+        Err(Refusal::NotChunkStart(chunk_start)) => conn
+            .with_status(416)
+            .with_header("Transpo-Chunk-Start", format!("{}", chunk_start))
+            .with_body("start_index does not point to the start of a chunk")
+            .halt(),
+        Err(Refusal::NotFound) => error_404(conn, config, translation),
+        Err(Refusal::Invalid) => {
+            debug!("Download refused");
+            error_400(conn, config, translation)
+        }
+    }
+}
+
+// Return a signed token which can be used in place of the password to
+// download the upload once within the given number of minutes. Only the owner
+// of the upload (or an admin) can create tokens for it.
+pub async fn token(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    if id_string.len() != base64_encode_length(ID_LENGTH) {
+        return error_404(conn, config, translation);
+    }
+
+    let id = i64_from_b64_bytes(id_string.as_bytes()).unwrap();
+
+    let query = match get_download_query(&mut conn).await {
+        Ok(query) => query,
+        Err(413) => return error_413(conn, config, translation),
+        Err(_) => return error_400(conn, config, translation)
+    };
+    let minutes = cmp::min(
+        query.minutes.unwrap_or(DEFAULT_TOKEN_AGE_MINUTES),
+        MAX_TOKEN_AGE_MINUTES);
+
+    let is_admin = check_admin(conn.headers(), config.clone(), db_backend).await.is_none();
+    let user_id = get_user_id(conn.headers(), db_backend, config.clone()).await;
+    if !is_admin && user_id.is_none() {
+        return api_error(conn, 401, "Not logged in");
+    }
+
+    let config_ = config.clone();
+    let token = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config_.db_url).ok_or(500u16)?;
+        let upload = get_upload(id, &accessors, &db_connection)
+            .ok_or(404u16)?;
+
+        if !is_admin && (upload.owner_id.is_none() || upload.owner_id != user_id) {
+            return Err(403);
+        }
+
+        let expire_after = cmp::min(
+            Local::now().naive_utc() + Duration::minutes(minutes as i64),
+            upload.expire_after);
+
+        Ok(tokens.mint(id, expire_after))
+    }).await;
+
+    match token {
+        Ok(token) => {
+            conn
+                .with_status(200)
+                .with_header("Content-Type", "application/json")
+                .with_body(serde_json::to_string(&token).unwrap())
                 .halt()
         },
-        None => error_400(conn, config, translation)
+        Err(404) => error_404(conn, config, translation),
+        Err(403) => api_error(conn, 403, "Only the owner of the upload can create tokens"),
+        Err(_) => error_400(conn, config, translation)
     }
 }
 
+// `len` is the exact number of bytes the body will contain, if it is known.
+// `reader` is read for the body as it is, so an encoder has to wrap the file
+// reader rather than the body for the compressed bytes to be counted.
 fn create_body_for<R>(
-    reader: R, accessor_mutex: AccessorMutex,
-    db_backend: DbBackend, config: Arc<TranspoConfig>) -> Body
+    reader: R, len: Option<u64>, speed_limit: Option<u64>,
+    bandwidth: Option<Bandwidth>, accessor_mutex: AccessorMutex, is_resumed: bool,
+    redeemed_token: Option<RedeemedToken>, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Body
 where R: Read + Sync + Send + 'static
 {
+    let limits = Limits::new(speed_limit, bandwidth, &config);
     let reader = Reader {
         reader,
+        limits: None,
+        bytes_read: 0,
+        is_finished: false,
+        is_resumed,
         accessor_mutex,
+        redeemed_token,
         db_backend,
-        config
+        config,
+        _active: ActiveTransfer::new(Transfer::Download)
+    };
+
+    let body = ThrottledBody {
+        reader: Unblock::with_capacity(FORM_READ_BUFFER_SIZE, reader),
+        limits,
+        timer: None
+    };
+
+    Body::new_streaming(body, len)
+}
+
+
+// The writing half of a pipe which carries a zip archive from the thread
+// creating it to the response body
+struct ChannelWriter(Sender<Result<Vec<u8>>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.send_blocking(Ok(buf.to_owned()))
+            .or(Err(Error::new(ErrorKind::BrokenPipe, "Download closed")))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// The reading half of the pipe. If writing the archive fails, so does reading
+// it, so that the client doesn't take a truncated archive for a whole one.
+// (the uploads in the archive are counted as downloads by their own readers)
+struct ChannelReader {
+    receiver: Receiver<Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    read_start: usize
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>>
+    {
+        let this = &mut *self;
+
+        if this.read_start == this.buffer.len() {
+            match ready!(Pin::new(&mut this.receiver).poll_next(cx)) {
+                Some(buffer) => {
+                    this.buffer = buffer?;
+                    this.read_start = 0;
+                },
+                // The writer is done
+                None => return Poll::Ready(Ok(0))
+            }
+        }
+
+        let len = cmp::min(buf.len(), this.buffer.len() - this.read_start);
+        buf[..len].copy_from_slice(&this.buffer[this.read_start..][..len]);
+        this.read_start += len;
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+// An upload to add to a zip archive
+struct ZipUpload {
+    id_string: String,
+    key: Vec<u8>,
+    password: Option<Vec<u8>>
+}
+
+// Parse an `application/x-www-form-urlencoded` body with an `upload` field
+// for each upload to add to the archive, in the form `<upload ID>:<key>` or
+// `<upload ID>:<key>:<password>`
+fn parse_zip_form(body: &str) -> Option<Vec<ZipUpload>> {
+    let mut uploads = Vec::new();
+    let mut id_strings = HashSet::new();
+
+    for (name, value) in body.split('&').filter_map(|f| f.split_once('=')) {
+        if name != "upload" {
+            continue;
+        }
+
+        let value = decode(&value.replace('+', "%20")).ok()?.into_owned();
+        let mut parts = value.splitn(3, ':');
+        let id_string = parts.next()?;
+        let key = parts.next()?;
+        let password = parts.next();
+
+        if id_string.len() != base64_encode_length(ID_LENGTH)
+            || key.len() != base64_encode_length(256 / 8)
+            || !id_strings.insert(id_string.to_owned())
+        {
+            return None;
+        }
+
+        uploads.push(ZipUpload {
+            id_string: id_string.to_owned(),
+            key: key.to_owned().into_bytes(),
+            password: password.map(|p| p.to_owned().into_bytes())
+        });
+    }
+
+    if uploads.is_empty() || uploads.len() > MAX_ZIP_UPLOADS {
+        None
+    } else {
+        Some(uploads)
+    }
+}
+
+// Return a name for an entry of a zip archive with the given file name (which
+// is chosen by the uploader) that doesn't lead out of the directory the
+// archive is extracted to, and that differs from the names in `taken`
+fn zip_entry_name(file_name: &str, taken: &mut HashSet<String>) -> String {
+    // Only the last component of a path is kept, without characters which
+    // aren't allowed in file names on some systems
+    let name: String = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or("")
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c == ':' { '_' } else { c })
+        .collect();
+    let name = match name.trim() {
+        "" | "." | ".." => "file",
+        name => name
     };
 
-    Body::new_streaming(Unblock::with_capacity(FORM_READ_BUFFER_SIZE, reader), None)
+    // `name.ext`, `name (1).ext`, `name (2).ext`, ...
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, "")
+    };
+    let mut entry_name = name.to_owned();
+    let mut count = 0;
+    // (some file systems ignore case)
+    while !taken.insert(entry_name.to_lowercase()) {
+        count += 1;
+        entry_name = format!("{} ({}){}", stem, count, extension);
+    }
+
+    entry_name
+}
+
+fn write_zip<W, R>(writer: W, readers: Vec<(R, String)>) -> Result<()>
+where W: Write, R: Read
+{
+    let mut archive = Archive::new(writer);
+    let mut buf = vec![0; FORM_READ_BUFFER_SIZE];
+    let mut taken_names = HashSet::new();
+
+    for (mut reader, file_name) in readers {
+        let now = Local::now().naive_utc();
+        let entry_name = zip_entry_name(&file_name, &mut taken_names);
+        archive.start_new_file(
+            entry_name.into_bytes(), now, CompressionMode::Store, true)?;
+
+        loop {
+            let bytes_read = reader.read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            archive.append_data(&buf[..bytes_read])?;
+        }
+
+        archive.finish_file()?;
+    }
+
+    archive.finish()?;
+    Ok(())
+}
+
+// Download several uploads (decrypted server-side) as a single zip archive
+// which is built on the fly. The uploads are listed in the body of the
+// request, so that their keys and passwords don't end up in logs.
+pub async fn handle_zip(
+    mut conn: Conn, config: Arc<TranspoConfig>, accessors: Accessors,
+    bandwidth: Option<Bandwidth>, translation: Translation, db_backend: DbBackend) -> Conn
+{
+    let uploads = match read_form_body(&mut conn, MAX_ZIP_FORM_BODY_SIZE).await {
+        Ok(body) => parse_zip_form(&body),
+        Err(413) => return error_413(conn, config, translation),
+        Err(_) => None
+    };
+    let uploads = match uploads {
+        Some(uploads) => uploads,
+        None => return error_400(conn, config, translation)
+    };
+    let num_uploads = uploads.len();
+    let client_ip = ClientIp::of(&conn);
+
+    let config_ = config.clone();
+    let readers = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config_.db_url)?;
+        let mut opened = Vec::with_capacity(uploads.len());
+        let mut claimed_ids = Vec::with_capacity(uploads.len());
+
+        let open_readers = || {
+            for ZipUpload { id_string, key, password } in uploads {
+                let id = i64_from_b64_bytes(id_string.as_bytes())?;
+                let upload = get_upload(id, &accessors, &db_connection)?;
+
+                // Each upload is checked against its own password
+                if !verify_password(&password, &upload, client_ip) {
+                    return None;
+                }
+
+                let accessor_mutex = accessors.access(id)?;
+                if upload.remaining_downloads.is_some() {
+                    if !Upload::claim_download(id, &db_connection)? {
+                        return None;
+                    }
+                    claimed_ids.push(id);
+                }
+
+                let upload_path = config_.storage_dir.join(&id_string).join("upload");
+                let (reader, mut file_name, mime_type) = EncryptedFileReader::new(
+                    &upload_path, 0, 0, upload.expire_after, upload.is_completed,
+                    ChunkFormat::new(upload.id, upload.format_version),
+                    &key, upload.file_name.as_bytes(), upload.mime_type.as_bytes()).ok()?;
+
+                if file_name.is_empty() {
+                    file_name = format!("{}_{}", config_.app_name, id_string);
+
+                    if mime_type == "application/zip" {
+                        file_name.push_str(".zip");
+                    }
+                }
+
+                let speed_limit = get_speed_limit(&config_, &upload);
+                opened.push((reader, file_name, speed_limit, accessor_mutex));
+            }
+
+            Some(())
+        };
+
+        // Give back the downloads claimed so far if any of the uploads can't
+        // be opened
+        if open_readers().is_none() {
+            for id in claimed_ids {
+                Upload::refund_download(id, &db_connection);
+            }
+            return None;
+        }
+
+        // Each upload is limited and recorded like a download of its own. The
+        // bandwidth is shared by the whole archive, so it limits the body.
+        let readers = opened.into_iter()
+            .map(|(reader, file_name, speed_limit, accessor_mutex)| {
+                let reader = tracked_reader(
+                    reader, speed_limit, None, accessor_mutex, db_backend, config_.clone());
+                (reader, file_name)
+            })
+            .collect::<Vec<_>>();
+
+        Some(readers)
+    }).await;
+
+    match readers {
+        Some(readers) => {
+            let (sender, receiver) = bounded(ZIP_CHANNEL_CAPACITY);
+
+            thread::spawn(move || {
+                if let Err(e) = write_zip(ChannelWriter(sender.clone()), readers) {
+                    warn!("Writing zip archive: {}", e);
+                    let _ = sender.send_blocking(Err(e));
+                }
+            });
+
+            info!(uploads = num_uploads, "Serving zip download");
+            let reader = ChannelReader {
+                receiver,
+                buffer: Vec::new(),
+                read_start: 0
+            };
+            let body = Body::new_streaming(ThrottledBody {
+                reader,
+                limits: Limits::new(None, bandwidth, &config),
+                timer: None
+            }, None);
+            let file_name = encode(&format!("{}.zip", config.app_name)).into_owned();
+
+            conn
+                .with_status(200)
+                .with_body(body)
+                .with_header("Cache-Control", "no-cache")
+                .with_header("Content-Type", "application/zip")
+                .with_header("Content-Disposition",
+                             format!("attachment; filename=\"{}\"", file_name))
+                .halt()
+        },
+        None => {
+            debug!("Zip download refused");
+            error_400(conn, config, translation)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The reader of an upload which fails before anything is read
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+            Err(Error::other("Reading failed"))
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn only_downloads_which_send_nothing_are_refunded() {
+        use crate::db::tests::{test_db, test_upload};
+
+        let db_connection_info = test_db("refunded_downloads");
+        let db_connection = establish_connection_info(&db_connection_info).unwrap();
+        let accessors = Accessors::new(
+            Arc::new(DbAccessCounter::new(db_connection_info.clone())));
+        let config = Arc::new(TranspoConfig {
+            db_url: db_connection_info.1.clone(),
+            ..TranspoConfig::default()
+        });
+        let mut buf = [0; 16];
+
+        // A download which fails before any bytes are sent gives its claim back
+        test_upload(1, Some(1)).insert(&db_connection).unwrap();
+        assert_eq!(Upload::claim_download(1, &db_connection), Some(true));
+        let mut reader = tracked_reader(
+            FailingReader, None, None, accessors.access(1).unwrap(), db_connection_info.0,
+            config.clone());
+        assert!(reader.read(&mut buf).is_err());
+        drop(reader);
+        let upload = Upload::select_with_id(1, &db_connection).unwrap();
+        assert_eq!(upload.remaining_downloads, Some(1));
+        assert_eq!(upload.num_downloads, 0);
+
+        // One which reaches the end of the upload keeps it and is recorded
+        test_upload(2, Some(1)).insert(&db_connection).unwrap();
+        assert_eq!(Upload::claim_download(2, &db_connection), Some(true));
+        let mut reader = tracked_reader(
+            &b"upload"[..], None, None, accessors.access(2).unwrap(), db_connection_info.0,
+            config);
+        while reader.read(&mut buf).unwrap() > 0 {}
+        drop(reader);
+        let upload = Upload::select_with_id(2, &db_connection).unwrap();
+        assert_eq!(upload.remaining_downloads, Some(0));
+        assert_eq!(upload.num_downloads, 1);
+        assert_eq!(upload.bytes_downloaded, 6);
+    }
+
+    #[test]
+    fn zip_entry_names_stay_in_the_archive() {
+        let mut taken = HashSet::new();
+        assert_eq!(zip_entry_name("../../etc/passwd", &mut taken), "passwd");
+        assert_eq!(zip_entry_name("..\\..\\boot.ini", &mut taken), "boot.ini");
+        assert_eq!(zip_entry_name("/", &mut taken), "file");
+        assert_eq!(zip_entry_name("..", &mut taken), "file (1)");
+        assert_eq!(zip_entry_name("C:evil\n.txt", &mut taken), "C_evil.txt");
+    }
+
+    #[test]
+    fn zip_entry_names_are_unique() {
+        let mut taken = HashSet::new();
+        assert_eq!(zip_entry_name("photo.jpg", &mut taken), "photo.jpg");
+        assert_eq!(zip_entry_name("photo.jpg", &mut taken), "photo (1).jpg");
+        assert_eq!(zip_entry_name("a/Photo.JPG", &mut taken), "Photo (2).JPG");
+        assert_eq!(zip_entry_name(".bashrc", &mut taken), ".bashrc");
+        assert_eq!(zip_entry_name(".bashrc", &mut taken), ".bashrc (1)");
+    }
+
+    #[test]
+    fn zip_form() {
+        let id = "A".repeat(base64_encode_length(ID_LENGTH));
+        let other_id = "B".repeat(base64_encode_length(ID_LENGTH));
+        let key = "k".repeat(base64_encode_length(256 / 8));
+
+        let uploads = parse_zip_form(&format!(
+            "upload={}:{}&upload={}%3A{}%3Apass%3Aword+1", id, key, other_id, key)).unwrap();
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0].password, None);
+        assert_eq!(uploads[1].id_string, other_id);
+        assert_eq!(uploads[1].password.as_deref(), Some(&b"pass:word 1"[..]));
+
+        // Repeated, malformed and missing uploads are refused
+        assert!(parse_zip_form(&format!("upload={}:{}&upload={}:{}", id, key, id, key)).is_none());
+        assert!(parse_zip_form(&format!("upload={}:short", id)).is_none());
+        assert!(parse_zip_form("").is_none());
+    }
 }
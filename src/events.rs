@@ -0,0 +1,229 @@
+use crate::b64::*;
+use crate::concurrency::*;
+use crate::config::*;
+use crate::db::*;
+use crate::download::get_info;
+use crate::files::*;
+use crate::http_errors::*;
+use crate::shutdown::Shutdown;
+use crate::tokens::*;
+use crate::translations::*;
+
+use std::cmp;
+use std::io::{Read, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use blocking::Unblock;
+use serde_json::json;
+use trillium::{Body, Conn};
+
+
+// How often the upload is checked for changes
+const POLL_INTERVAL_MS: u64 = 1000;
+// Send a comment if nothing else was sent for this long, so that proxies
+// don't close the stream
+const KEEPALIVE_SECS: u64 = 15;
+// Streams are ended after this long (or when the server shuts down), and
+// `EventSource` reconnects after the delay given by `retry`
+const MAX_STREAM_SECS: u64 = 5 * 60;
+const RETRY_MS: u64 = 5000;
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+
+// What the events report about an upload
+#[derive(Clone, Copy, PartialEq)]
+struct UploadState {
+    // size of the ciphertext stored so far
+    size: u64,
+    is_completed: bool,
+    remaining_downloads: Option<i32>,
+    downloads: i32,
+    completed_downloads: i32
+}
+
+impl UploadState {
+    fn new(upload: &Upload, upload_path: &PathBuf) -> Self {
+        let size = match (upload.is_completed, upload.ciphertext_size) {
+            (true, Some(size)) => size as u64,
+            _ => get_file_size(upload_path).unwrap_or(0)
+        };
+
+        Self {
+            size,
+            is_completed: upload.is_completed,
+            remaining_downloads: upload.remaining_downloads,
+            downloads: upload.num_downloads,
+            completed_downloads: upload.num_completed_downloads
+        }
+    }
+}
+
+fn event(name: &str, data: serde_json::Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+// Checks the upload for changes whenever the response body is read, and
+// turns them into events. Reads block until there is something to send.
+struct EventStream {
+    id: i64,
+    upload_path: PathBuf,
+    // (kept open for the whole stream, rather than connecting every second)
+    db_connection: Mutex<Option<DbConnection>>,
+    db_backend: DbBackend,
+    config: Arc<TranspoConfig>,
+    shutdown: Shutdown,
+    last_state: Option<UploadState>,
+    pending: Vec<u8>,
+    started_at: Instant,
+    last_sent_at: Instant,
+    is_finished: bool
+}
+
+impl EventStream {
+    fn new(
+        id: i64, id_string: &str, config: Arc<TranspoConfig>,
+        shutdown: Shutdown, db_backend: DbBackend) -> Self
+    {
+        Self {
+            id,
+            upload_path: config.storage_dir.join(id_string).join("upload"),
+            db_connection: Mutex::new(None),
+            db_backend,
+            config,
+            shutdown,
+            last_state: None,
+            pending: format!("retry: {}\n\n", RETRY_MS).into_bytes(),
+            started_at: Instant::now(),
+            last_sent_at: Instant::now(),
+            is_finished: false
+        }
+    }
+
+    fn select_upload(&self) -> Option<Option<Upload>> {
+        let mut db_connection = self.db_connection.lock().unwrap();
+        if db_connection.is_none() {
+            *db_connection = establish_connection(self.db_backend, &self.config.db_url);
+        }
+
+        let upload = Upload::select_with_id(self.id, db_connection.as_ref()?);
+        Some(upload)
+    }
+
+    // Queue the events for whatever changed since the last poll
+    fn poll(&mut self) {
+        if self.shutdown.is_draining()
+        || self.started_at.elapsed() >= Duration::from_secs(MAX_STREAM_SECS)
+        {
+            self.is_finished = true;
+            return;
+        }
+
+        // End the stream if the database is unavailable; the client
+        // reconnects later
+        let upload = match self.select_upload() {
+            Some(upload) => upload,
+            None => {
+                self.is_finished = true;
+                return;
+            }
+        };
+
+        let mut events = String::new();
+        match upload {
+            Some(upload) if !upload.is_deleted() && !upload.is_disabled => {
+                let state = UploadState::new(&upload, &self.upload_path);
+                let last_state = self.last_state.replace(state);
+
+                if !state.is_completed && last_state.map(|s| s.size != state.size).unwrap_or(true) {
+                    events.push_str(&event("progress", json!({ "size": state.size })));
+                }
+                if state.is_completed && last_state.map(|s| !s.is_completed).unwrap_or(true) {
+                    events.push_str(&event("completed", json!({ "size": state.size })));
+                }
+                let downloads_changed = last_state.map(|s| {
+                    s.remaining_downloads != state.remaining_downloads
+                        || s.downloads != state.downloads
+                        || s.completed_downloads != state.completed_downloads
+                }).unwrap_or(true);
+                if downloads_changed {
+                    events.push_str(&event("downloads", json!({
+                        "remaining_downloads": state.remaining_downloads,
+                        "downloads": state.downloads,
+                        "completed_downloads": state.completed_downloads
+                    })));
+                }
+
+                if upload.is_expired() {
+                    events.push_str(&event("gone", json!({})));
+                    self.is_finished = true;
+                }
+            },
+            _ => {
+                events.push_str(&event("gone", json!({})));
+                self.is_finished = true;
+            }
+        }
+
+        if events.is_empty() && self.last_sent_at.elapsed() >= Duration::from_secs(KEEPALIVE_SECS) {
+            events.push_str(": keepalive\n\n");
+        }
+        if !events.is_empty() {
+            self.pending.extend(events.into_bytes());
+            self.last_sent_at = Instant::now();
+        }
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.pending.is_empty() {
+            if self.is_finished {
+                return Ok(0);
+            }
+            // The first poll reports the current state right away
+            if self.last_state.is_some() {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+            self.poll();
+        }
+
+        let len = cmp::min(buf.len(), self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+// `GET /:file_id/events`: stream the progress of the upload, its completion
+// and changes to its downloads as server-sent events, until it is gone
+pub async fn handle(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, tokens: DownloadTokens, shutdown: Shutdown,
+    translation: Translation, db_backend: DbBackend) -> Conn
+{
+    // The same password (or token) as for `/info` is required
+    let info = get_info(
+        &mut conn, id_string.clone(), config.clone(), accessors, tokens, db_backend).await;
+    match info {
+        Ok(_) => {},
+        Err(404) => return error_404(conn, config, translation),
+        Err(413) => return error_413(conn, config, translation),
+        Err(_) => return error_400(conn, config, translation)
+    }
+
+    let id = i64_from_b64_bytes(id_string.as_bytes()).unwrap();
+    let stream = EventStream::new(id, &id_string, config, shutdown, db_backend);
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+        // Keep nginx from buffering the events
+        .with_header("X-Accel-Buffering", "no")
+        .with_body(Body::new_streaming(
+            Unblock::with_capacity(EVENT_BUFFER_SIZE, stream), None))
+        .halt()
+}
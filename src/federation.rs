@@ -0,0 +1,606 @@
+use crate::api_keys::*;
+use crate::at_rest::{self, StoredFile};
+use crate::b64::*;
+use crate::blocklist::*;
+use crate::concurrency::*;
+use crate::config::*;
+use crate::constants::*;
+use crate::db::*;
+use crate::files::*;
+use crate::http_errors::*;
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use blocking::{unblock, Unblock};
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use trillium::{Body, Conn};
+use tracing::{info, info_span, warn};
+
+
+// Uploads are sent to and fetched from peers at this path, followed by the ID
+const MIRROR_PATH: &'static str = "api/federation/uploads";
+// The metadata of an upload is sent as JSON in this header, next to the
+// ciphertext in the body
+const METADATA_HEADER: &'static str = "Transpo-Mirror-Metadata";
+// Uploads can take long to transfer, so only connecting and each read and
+// write are limited
+const TIMEOUT_SECS: u64 = 30;
+// Each upload is looked up on peers at most once in this many seconds, so
+// downloads of an upload which the peers don't have don't all wait on them
+const LOOKUP_INTERVAL_SECS: u64 = 10 * 60;
+
+// When each upload whose file was lost was last looked up on peers
+static LOOKUPS: Mutex<BTreeMap<i64, Instant>> = Mutex::new(BTreeMap::new());
+
+
+// What a peer needs to serve an upload. Nothing about the uploader (their
+// address, account or ntfy topic) leaves the instance they uploaded to.
+#[derive(Serialize, Deserialize)]
+struct MirrorMetadata {
+    file_name: String,
+    mime_type: String,
+    password_hash: Option<Vec<u8>>,
+    // (always empty, but sent by peers running versions which mirrored
+    // uploads with a download limit)
+    remaining_downloads: Option<i32>,
+    // Unix timestamp
+    expire_after: i64,
+    max_download_bytes_per_second: Option<i64>,
+    plaintext_size: i64,
+    ciphertext_size: i64,
+    // (not sent by peers running versions from before it was recorded)
+    #[serde(default = "default_format_version")]
+    format_version: i16,
+    // (needed to download uploads whose key is derived from their password)
+    #[serde(default)]
+    key_salt: Option<String>,
+    #[serde(default)]
+    wrapped_key: Option<String>,
+    // (needed to download uploads whose key is wrapped for recipients)
+    #[serde(default)]
+    recipient_keys: Option<String>,
+    #[serde(default)]
+    title: Option<String>
+}
+
+impl MirrorMetadata {
+    // Return None for uploads with a download limit: each copy would count
+    // its downloads separately, so they aren't mirrored
+    fn new(upload: &Upload) -> Option<Self> {
+        if upload.remaining_downloads.is_some() {
+            return None;
+        }
+
+        Some(Self {
+            file_name: upload.file_name.clone(),
+            mime_type: upload.mime_type.clone(),
+            password_hash: upload.password_hash.clone(),
+            remaining_downloads: None,
+            expire_after: upload.expire_after.timestamp(),
+            max_download_bytes_per_second: upload.max_download_bytes_per_second,
+            plaintext_size: upload.plaintext_size?,
+            ciphertext_size: upload.ciphertext_size?,
+            format_version: upload.format_version,
+            key_salt: upload.key_salt.clone(),
+            wrapped_key: upload.wrapped_key.clone(),
+            recipient_keys: upload.recipient_keys.clone(),
+            title: upload.title.clone()
+        })
+    }
+
+    fn into_upload(self, id: i64) -> Option<Upload> {
+        Some(Upload {
+            id,
+            file_name: self.file_name,
+            mime_type: self.mime_type,
+            password_hash: self.password_hash,
+            remaining_downloads: None,
+            num_accessors: 0,
+            expire_after: NaiveDateTime::from_timestamp_opt(self.expire_after, 0)?,
+            is_completed: true,
+            max_download_bytes_per_second: self.max_download_bytes_per_second,
+            num_downloads: 0,
+            num_completed_downloads: 0,
+            bytes_downloaded: 0,
+            plaintext_size: Some(self.plaintext_size),
+            ciphertext_size: Some(self.ciphertext_size),
+            uploader_ip: None,
+            created_at: None,
+            owner_id: None,
+            deleted_at: None,
+            last_used_at: Some(Local::now().naive_utc()),
+            notify_topic: None,
+            expiry_notified: false,
+            is_disabled: false,
+            is_mirror: true,
+            // (only listed in the gallery of the instance it was uploaded to)
+            public_key: None,
+            published_at: None,
+            format_version: self.format_version,
+            key_salt: self.key_salt,
+            wrapped_key: self.wrapped_key,
+            recipient_keys: self.recipient_keys,
+            title: self.title
+        })
+    }
+}
+
+pub fn is_enabled(config: &TranspoConfig) -> bool {
+    !config.peers.is_empty()
+}
+
+fn id_to_string(id: i64) -> String {
+    String::from_utf8(i64_to_b64_bytes(id)).unwrap()
+}
+
+fn parse_id(id_string: &str) -> Option<i64> {
+    match i64_from_b64_bytes(id_string.as_bytes()) {
+        Some(id) if id_string.len() == base64_encode_length(ID_LENGTH) => Some(id),
+        _ => None
+    }
+}
+
+fn other_error(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+fn agent() -> ureq::Agent {
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout)
+        .build()
+}
+
+fn bearer(config: &TranspoConfig) -> String {
+    format!("Bearer {}", config.peer_token.as_deref().unwrap_or_default())
+}
+
+// Compare hashes, so the comparison takes the same time however much of the
+// token is right
+fn is_peer(conn: &Conn, token: &str) -> bool {
+    conn.headers().get_str("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|presented| hash_key(presented.trim()) == hash_key(token))
+        .unwrap_or(false)
+}
+
+// Check the ciphertext the same way `verify` does, and that its sizes match
+// what the peer claimed
+fn check_mirrored_file(upload_path: &Path, metadata: &MirrorMetadata) -> io::Result<()> {
+    let (plaintext_size, ciphertext_size) = verify_chunks(upload_path)?;
+    if plaintext_size as i64 == metadata.plaintext_size
+    && ciphertext_size as i64 == metadata.ciphertext_size
+    {
+        Ok(())
+    } else {
+        Err(other_error("Sizes do not match the metadata"))
+    }
+}
+
+// Record a mirrored upload whose file has been written to `upload_dir`, as
+// long as there is room for it
+fn store_mirror(
+    id: i64, upload_dir: &Path, metadata: MirrorMetadata,
+    config: &TranspoConfig, db_connection: &DbConnection) -> io::Result<()>
+{
+    check_mirrored_file(&upload_dir.join("upload"), &metadata)?;
+    mark_upload_metadata_completed(upload_dir)?;
+
+    let size = metadata.ciphertext_size;
+    let used = StorageUsage::add(size, db_connection)
+        .ok_or(other_error("Updating storage usage"))?;
+    if used > config.max_storage_size_bytes as i64 {
+        StorageUsage::add(-size, db_connection);
+        return Err(other_error("Storage capacity exceeded"));
+    }
+
+    let upload = metadata.into_upload(id).ok_or(other_error("Invalid expiry time"))?;
+    if upload.insert(db_connection).is_none() {
+        StorageUsage::add(-size, db_connection);
+        return Err(other_error("Inserting upload"));
+    }
+
+    Ok(())
+}
+
+// Create the directory of a mirrored upload. This fails if this instance
+// already has an upload with the same ID.
+fn create_mirror_dir(config: &TranspoConfig, id_string: &str) -> io::Result<PathBuf> {
+    let upload_dir = config.storage_dir.join(id_string);
+    fs::create_dir(&upload_dir)?;
+    at_rest::mark_new_dir(&upload_dir)?;
+
+    let metadata = UploadMetadata {
+        declared_size: None,
+        started_at: Local::now().naive_utc(),
+        client: UploadClient::Mirror,
+        completed: false
+    };
+    write_upload_metadata(&upload_dir, &metadata)?;
+
+    Ok(upload_dir)
+}
+
+
+// Send a completed upload to every peer in the background. Uploads which
+// were themselves mirrored from a peer are not sent on.
+pub fn mirror_to_peers(id: i64, config: Arc<TranspoConfig>, db_backend: DbBackend) {
+    if !is_enabled(&config) {
+        return;
+    }
+
+    thread::spawn(move || {
+        let id_string = id_to_string(id);
+        let _span = info_span!("mirror", id = %id_string).entered();
+
+        let upload = match establish_connection(db_backend, &config.db_url)
+            .and_then(|db_connection| Upload::select_with_id(id, &db_connection))
+        {
+            Some(upload) if !upload.is_mirror => upload,
+            _ => return
+        };
+        let metadata = match MirrorMetadata::new(&upload)
+            .and_then(|m| serde_json::to_string(&m).ok())
+        {
+            Some(metadata) => metadata,
+            None => return
+        };
+        let upload_path = config.storage_dir.join(&id_string).join("upload");
+
+        let agent = agent();
+        for peer in &config.peers {
+            let result = StoredFile::open(&upload_path)
+                .map_err(|e| e.to_string())
+                .and_then(|file| {
+                    agent.put(&format!("{}/{}/{}", peer, MIRROR_PATH, id_string))
+                        .set("Authorization", &bearer(&config))
+                        .set(METADATA_HEADER, &metadata)
+                        .set("Content-Length", &upload.ciphertext_size.unwrap_or(0).to_string())
+                        .send(file)
+                        .map_err(|e| e.to_string())
+                });
+
+            match result {
+                Ok(_) => info!(peer = %peer, "Upload mirrored"),
+                Err(e) => warn!(peer = %peer, "Mirroring upload: {}", e)
+            }
+        }
+    });
+}
+
+// Send the deletion of an upload to every peer in the background, so that
+// they delete their copies of it
+pub fn delete_on_peers(id: i64, config: Arc<TranspoConfig>) {
+    if !is_enabled(&config) {
+        return;
+    }
+
+    thread::spawn(move || {
+        let id_string = id_to_string(id);
+        let _span = info_span!("mirror", id = %id_string).entered();
+
+        let agent = agent();
+        for peer in &config.peers {
+            let result = agent.delete(&format!("{}/{}/{}", peer, MIRROR_PATH, id_string))
+                .set("Authorization", &bearer(&config))
+                .call();
+
+            match result {
+                Ok(_) | Err(ureq::Error::Status(404, _)) =>
+                    info!(peer = %peer, "Deletion sent to peer"),
+                Err(e) => warn!(peer = %peer, "Sending deletion: {}", e)
+            }
+        }
+    });
+}
+
+// Record a lookup of the upload with the given ID on peers. Return false if
+// it was looked up less than `LOOKUP_INTERVAL_SECS` ago.
+fn begin_lookup(id: i64) -> bool {
+    let now = Instant::now();
+    let interval = Duration::from_secs(LOOKUP_INTERVAL_SECS);
+
+    let mut lookups = LOOKUPS.lock().unwrap();
+    lookups.retain(|_, looked_up_at| now.duration_since(*looked_up_at) < interval);
+    match lookups.entry(id) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(now);
+            true
+        }
+    }
+}
+
+// Fetch the lost file of an upload from the first peer which has a copy of
+// it. Return whether it was restored.
+fn fetch_from_peers(upload: &Upload, id_string: &str, config: &TranspoConfig) -> bool {
+    let upload_dir = config.storage_dir.join(id_string);
+    let upload_path = upload_dir.join("upload");
+
+    let agent = agent();
+    for peer in &config.peers {
+        let response = agent.get(&format!("{}/{}/{}", peer, MIRROR_PATH, id_string))
+            .set("Authorization", &bearer(config))
+            .call();
+        let response = match response {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => continue,
+            Err(e) => {
+                warn!(peer = %peer, "Fetching upload: {}", e);
+                continue;
+            }
+        };
+
+        // The copy must be of the same upload
+        let metadata: MirrorMetadata = match response.header(METADATA_HEADER)
+            .and_then(|m| serde_json::from_str::<MirrorMetadata>(m).ok())
+        {
+            Some(metadata) if Some(metadata.plaintext_size) == upload.plaintext_size
+                && Some(metadata.ciphertext_size) == upload.ciphertext_size => metadata,
+            _ => {
+                warn!(peer = %peer, "Fetching upload: missing or mismatched metadata");
+                continue;
+            }
+        };
+
+        // (the whole directory may have been lost along with the file)
+        let is_new_dir = !upload_dir.exists();
+        if is_new_dir && create_mirror_dir(config, id_string).is_err() {
+            return false;
+        }
+
+        let result = StoredFile::create_new(&upload_path)
+            .and_then(|mut file| io::copy(
+                &mut response.into_reader().take(metadata.ciphertext_size as u64 + 1),
+                &mut file))
+            .and_then(|_| check_mirrored_file(&upload_path, &metadata))
+            .and_then(|_| if is_new_dir {
+                mark_upload_metadata_completed(&upload_dir)
+            } else {
+                Ok(())
+            });
+
+        match result {
+            Ok(()) => {
+                info!(peer = %peer, "Upload fetched from peer");
+                return true;
+            },
+            // (e.g. another download is fetching it already)
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return false,
+            Err(e) => {
+                warn!(peer = %peer, "Fetching upload: {}", e);
+                let _ = fs::remove_file(&upload_path);
+            }
+        }
+    }
+
+    false
+}
+
+// Before an upload is downloaded, fetch its file from a peer if this instance
+// has lost it. Only uploads which this instance knows about and which can
+// still be downloaded are looked up, each at most once per
+// `LOOKUP_INTERVAL_SECS`, so requests for other IDs never reach the peers.
+pub async fn restore_from_peers(
+    id_string: String, config: Arc<TranspoConfig>, db_backend: DbBackend)
+{
+    if !is_enabled(&config) {
+        return;
+    }
+
+    unblock(move || {
+        let id = parse_id(&id_string)?;
+        if config.storage_dir.join(&id_string).join("upload").exists() {
+            return None;
+        }
+
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        let upload = Upload::select_with_id(id, &db_connection)?;
+        if upload.is_deleted() || upload.is_expired() || upload.is_disabled
+        || !upload.is_completed || upload.remaining_downloads.is_some()
+        || !begin_lookup(id)
+        {
+            return None;
+        }
+
+        warn!(id = %id_string, "Upload file is missing, fetching it from peers");
+        fetch_from_peers(&upload, &id_string, &config);
+        Some(())
+    }).await;
+}
+
+
+// `PUT /api/federation/uploads/:file_id`: store an upload sent by a peer
+pub async fn receive(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    blocklist: Blocklist, db_backend: DbBackend) -> Conn
+{
+    // Instances without a peer token don't take part in federation
+    match &config.peer_token {
+        Some(token) if is_peer(&conn, token) => {},
+        Some(_) => return api_error(conn, 401, "A peer token is required"),
+        None => return conn.with_status(404).halt()
+    }
+
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return api_error(conn, 400, "Invalid upload ID")
+    };
+    if blocklist.is_upload_blocked(id) {
+        return api_error(conn, 403, "The upload is blocked");
+    }
+
+    let metadata: MirrorMetadata = match conn.headers().get_str(METADATA_HEADER)
+        .and_then(|m| serde_json::from_str(m).ok())
+    {
+        Some(metadata) => metadata,
+        None => return api_error(conn, 400, "Missing or invalid metadata")
+    };
+    if metadata.ciphertext_size < 0
+    || metadata.plaintext_size > config.max_upload_size_bytes as i64
+    {
+        return api_error(conn, 413, "The upload is too large");
+    }
+    if metadata.remaining_downloads.is_some() {
+        return api_error(conn, 400, "Uploads with a download limit are not mirrored");
+    }
+
+    let upload_dir = {
+        let config = config.clone();
+        let id_string = id_string.clone();
+        match unblock(move || create_mirror_dir(&config, &id_string)).await {
+            Ok(upload_dir) => upload_dir,
+            Err(_) => return api_error(conn, 409, "An upload with this ID exists already")
+        }
+    };
+
+    // Write the body, then check and record it like an upload made here
+    let written = match StoredFile::create_new(upload_dir.join("upload")) {
+        Ok(file) => {
+            let mut file = Unblock::with_capacity(FORM_READ_BUFFER_SIZE, file);
+            let body = conn.request_body().await
+                .take(metadata.ciphertext_size as u64 + 1);
+            // (the file is only written once it is flushed)
+            smol::io::copy(body, &mut file).await.is_ok() && file.flush().await.is_ok()
+        },
+        Err(_) => false
+    };
+
+    let stored = unblock(move || {
+        let result = if written {
+            establish_connection(db_backend, &config.db_url)
+                .ok_or(other_error("Connecting to the database"))
+                .and_then(|db_connection| store_mirror(
+                    id, &upload_dir, metadata, &config, &db_connection))
+        } else {
+            Err(other_error("Writing the upload"))
+        };
+
+        if let Err(e) = &result {
+            warn!(id = %id_string, "Receiving mirrored upload: {}", e);
+            delete_upload_dir(&config.storage_dir, id);
+        } else {
+            info!(id = %id_string, "Mirrored upload received");
+        }
+        result.is_ok()
+    }).await;
+
+    if stored {
+        conn.with_status(201).halt()
+    } else {
+        api_error(conn, 400, "Storing the upload failed")
+    }
+}
+
+// `GET /api/federation/uploads/:file_id`: send an upload to a peer which lost
+// its copy
+pub async fn send(
+    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    blocklist: Blocklist, db_backend: DbBackend) -> Conn
+{
+    // Instances without a peer token don't take part in federation
+    match &config.peer_token {
+        Some(token) if is_peer(&conn, token) => {},
+        Some(_) => return api_error(conn, 401, "A peer token is required"),
+        None => return conn.with_status(404).halt()
+    }
+
+    let id = match parse_id(&id_string) {
+        Some(id) if !blocklist.is_upload_blocked(id) => id,
+        _ => return api_error(conn, 404, "The upload does not exist")
+    };
+
+    let found = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        let upload = Upload::select_with_id(id, &db_connection)?;
+        if upload.is_deleted() || upload.is_expired() || upload.is_disabled {
+            return None;
+        }
+
+        let metadata = MirrorMetadata::new(&upload)?;
+        let upload_path = config.storage_dir.join(&id_string).join("upload");
+        let file = StoredFile::open(upload_path).ok()?;
+        Some((metadata, file))
+    }).await;
+
+    match found {
+        Some((metadata, file)) => {
+            let len = metadata.ciphertext_size as u64;
+            let body = Body::new_streaming(
+                Unblock::with_capacity(FORM_READ_BUFFER_SIZE, file), Some(len));
+            conn
+                .with_status(200)
+                .with_header("Content-Type", "application/octet-stream")
+                .with_header(METADATA_HEADER, serde_json::to_string(&metadata).unwrap())
+                .with_body(body)
+                .halt()
+        },
+        None => api_error(conn, 404, "The upload does not exist")
+    }
+}
+
+// `DELETE /api/federation/uploads/:file_id`: delete this instance's copy of
+// an upload which was deleted on a peer. Uploads made here are left alone.
+pub async fn remove(
+    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    accessors: Accessors, db_backend: DbBackend) -> Conn
+{
+    // Instances without a peer token don't take part in federation
+    match &config.peer_token {
+        Some(token) if is_peer(&conn, token) => {},
+        Some(_) => return api_error(conn, 401, "A peer token is required"),
+        None => return conn.with_status(404).halt()
+    }
+
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return api_error(conn, 404, "The upload does not exist")
+    };
+
+    let deleted = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+
+        let accessor_mutex = accessors.access(id)?;
+        let _accessor = accessor_mutex.lock();
+
+        match Upload::select_with_id(id, &db_connection) {
+            Some(upload) if upload.is_mirror && !upload.is_deleted() => {
+                Upload::mark_deleted(id, &db_connection)?;
+                Some(true)
+            },
+            _ => Some(false)
+        }
+    }).await;
+
+    match deleted {
+        Some(true) => {
+            info!(id = %id_string, "Mirrored upload deleted by peer");
+            conn.with_status(204).halt()
+        },
+        Some(false) => api_error(conn, 404, "The upload does not exist"),
+        None => api_error(conn, 500, "Deleting the upload failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_are_limited() {
+        assert!(begin_lookup(-1));
+        assert!(!begin_lookup(-1));
+        assert!(begin_lookup(-2));
+    }
+}
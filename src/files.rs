@@ -1,20 +1,70 @@
 use std::io::{
     Result, Error, ErrorKind, BufWriter, Write,
-    Read, BufReader, Seek, SeekFrom};
+    Read, BufRead, BufReader, Seek, SeekFrom};
 use std::path::{PathBuf, Path};
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use aes_gcm::aead::{AeadInPlace, Aead, NewAead};
+use chacha20poly1305::ChaCha20Poly1305;
+use sha2::{Digest, Sha256};
+use argon2::Argon2;
+use crate::at_rest::{self, StoredFile};
+use crate::age::{self, AgeStream};
 use crate::b64;
+use crate::buffer_pool::PooledBuffer;
 use crate::random_bytes::*;
 use crate::constants::*;
 use chrono::*;
 use std::time::Duration;
 use std::cmp;
+use ::serde::{Deserialize, Serialize};
 use streaming_zip::*;
-
-const MAX_CHUNK_SIZE: usize = FORM_READ_BUFFER_SIZE + 16;
+use tracing::error;
+
+const TAG_SIZE: usize = 16;
+const MAX_CHUNK_SIZE: usize = FORM_READ_BUFFER_SIZE + TAG_SIZE;
+// Version of the format in which new uploads are encrypted. In version 1,
+// chunks have no associated data. Since version 2, the associated data of
+// each chunk of the file is the upload's ID followed by the chunk's count
+// (both in big-endian byte order), so that chunks can't be moved between
+// uploads sharing a key or reordered. The name and mime type have no
+// associated data in any version, since the browser client encrypts them
+// before it is told the ID. Since version 3, the last chunk before the
+// terminating zero bytes is a manifest (see `write_manifest`).
+pub const FORMAT_VERSION: i16 = 3;
+// Version of the format in which uploads are encrypted by the server if
+// padding is enabled. It is version 3, except that every chunk of the file
+// holds `FORM_READ_BUFFER_SIZE` bytes of plaintext (the last one is padded
+// with zeros), and chunks of zeros are added to round the number of chunks
+// up (see `padded_chunk_count`). The length in the manifest tells where the
+// plaintext ends.
+pub const PADDED_FORMAT_VERSION: i16 = 4;
+// Version of uploads encrypted by the server which are stored in the age
+// format for their recipients (see `age.rs`). The name and mime type are
+// encrypted as in version 3, but the file is a standard age file.
+pub const AGE_FORMAT_VERSION: i16 = 5;
+// Version of uploads encrypted by the server on hosts without hardware AES if
+// `prefer_chacha20` is set. It is version 3, except that everything (including
+// the name and mime type) is encrypted with ChaCha20-Poly1305 instead of
+// AES-256-GCM, with the same nonces. Browsers can't decrypt it, so they let
+// the server decrypt these uploads like padded ones.
+pub const CHACHA20_FORMAT_VERSION: i16 = 6;
+// Set in the length prefix of the manifest, which chunk sizes never reach, so
+// that it can be told apart from the other chunks without the key
+const MANIFEST_FLAG: u16 = 0x8000;
+// length of the plaintext, followed by its SHA-256
+const MANIFEST_SIZE: usize = 8 + 32;
+// The title of an upload (used by pastes) is encrypted like the name and mime
+// type, but with a count which no chunk reaches, so that it can be added
+// without moving the chunks (it is also exact as a JavaScript number)
+const TITLE_COUNT: u64 = 1 << 48;
+// longest title (in bytes) the server encrypts
+pub const MAX_TITLE_LENGTH: usize = 256;
+// The entries of an archive created by the server are encrypted with the
+// count after the title's
+const ARCHIVE_ENTRIES_COUNT: u64 = TITLE_COUNT + 1;
 
 
 fn nonce_bytes_from_count(count: &u64) -> [u8; 12] {
@@ -23,23 +73,289 @@ fn nonce_bytes_from_count(count: &u64) -> [u8; 12] {
     nonce_bytes
 }
 
+// whether the server encrypts new uploads in `CHACHA20_FORMAT_VERSION`
+static PREFER_CHACHA20: AtomicBool = AtomicBool::new(false);
+
+// Set whether the server encrypts new uploads with ChaCha20-Poly1305 (padded
+// uploads are still encrypted with AES-256-GCM, since there is no padded
+// version of that format)
+pub fn set_prefer_chacha20(prefer_chacha20: bool) {
+    PREFER_CHACHA20.store(prefer_chacha20, Ordering::Relaxed);
+}
+
+// Return the version of the format in which the server encrypts new uploads
+pub fn server_format_version(is_padded: bool) -> i16 {
+    if is_padded {
+        PADDED_FORMAT_VERSION
+    } else if PREFER_CHACHA20.load(Ordering::Relaxed) {
+        CHACHA20_FORMAT_VERSION
+    } else {
+        FORMAT_VERSION
+    }
+}
+
+// Return whether the CPU has the instructions with which AES-256-GCM can be
+// computed in hardware
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn cpu_has_aes() -> bool {
+    is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn cpu_has_aes() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+        && std::arch::is_aarch64_feature_detected!("pmull")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn cpu_has_aes() -> bool {
+    false
+}
+
+// Return whether AES-256-GCM is computed in hardware. The `aes` crate detects
+// AES-NI at runtime, but only uses the ARMv8 instructions if it is built with
+// its `armv8` feature (which requires nightly Rust), so it is computed in
+// software on ARM hosts.
+pub fn has_hardware_aes() -> bool {
+    cfg!(any(target_arch = "x86", target_arch = "x86_64")) && cpu_has_aes()
+}
+
+// Round the number of chunks of a padded file up with Padmé, so that the size
+// of the file only reveals O(log log n) bits about the size of the plaintext
+// while adding at most ~12% to it. Even an empty file has one chunk.
+fn padded_chunk_count(count: u64) -> u64 {
+    if count < 2 {
+        return 1;
+    }
+
+    let exponent = 63 - count.leading_zeros() as u64;
+    let exponent_bits = 64 - exponent.leading_zeros() as u64;
+    let mask = (1 << (exponent - exponent_bits)) - 1;
+    (count + mask) & !mask
+}
+
+// Return the size of the chunk whose length prefix is `size_buf` and whether
+// it is the manifest
+fn parse_chunk_prefix(size_buf: [u8; 2]) -> (usize, bool) {
+    let prefix = u16::from_be_bytes(size_buf);
+    ((prefix & !MANIFEST_FLAG) as usize, prefix & MANIFEST_FLAG != 0)
+}
+
+// How the chunks of an upload are authenticated, which depends on the version
+// of the format it is encrypted in
+#[derive(Clone, Copy)]
+pub struct ChunkFormat {
+    // the ID the chunks are bound to (since version 2)
+    binding: Option<i64>,
+    // whether the file ends with a manifest (since version 3)
+    has_manifest: bool,
+    // whether the chunks are padded (in `PADDED_FORMAT_VERSION`)
+    is_padded: bool,
+    // whether the file is an age file (in `AGE_FORMAT_VERSION`)
+    is_age: bool,
+    // whether it is encrypted with ChaCha20-Poly1305 (in
+    // `CHACHA20_FORMAT_VERSION`)
+    is_chacha20: bool
+}
+
+impl ChunkFormat {
+    pub fn new(id: i64, format_version: i16) -> Self {
+        let is_age = format_version == AGE_FORMAT_VERSION;
+
+        Self {
+            binding: if format_version >= 2 { Some(id) } else { None },
+            has_manifest: format_version >= 3 && !is_age,
+            is_padded: format_version == PADDED_FORMAT_VERSION,
+            is_age,
+            is_chacha20: format_version == CHACHA20_FORMAT_VERSION
+        }
+    }
+
+    // Return the associated data of a chunk in a fixed buffer (so that none
+    // is allocated per chunk), and how much of it is used
+    //
+    // The manifest is bound to its position like any other chunk, and is
+    // marked so that no other chunk can pass for it.
+    fn aad(&self, count: &u64, is_manifest: bool) -> ([u8; 17], usize) {
+        let mut aad = [0; 17];
+        let mut len = 0;
+        if let Some(id) = self.binding {
+            aad[..8].copy_from_slice(&id.to_be_bytes());
+            aad[8..16].copy_from_slice(&count.to_be_bytes());
+            len = 16;
+        }
+        if is_manifest {
+            aad[len] = 1;
+            len += 1;
+        }
+        (aad, len)
+    }
+
+    // Return the length of the plaintext of a complete file of `file_len`
+    // bytes in this format (see `plaintext_len`)
+    pub fn plaintext_len(&self, file_len: u64) -> u64 {
+        let manifest_len = if self.has_manifest { 2 + MANIFEST_SIZE + TAG_SIZE } else { 0 };
+        plaintext_len(file_len.saturating_sub(manifest_len as u64), FORM_READ_BUFFER_SIZE)
+    }
+}
+
+// An authenticated cipher with which uploads can be encrypted. Every message
+// (the name, the mime type and each chunk) is encrypted with the nonce for its
+// count, so a suite only has to seal and open single messages in place.
+pub trait StreamCipherSuite: Send + Sync {
+    fn seal_in_place(&self, count: u64, aad: &[u8], buffer: &mut Vec<u8>) -> Result<()>;
+    fn open_in_place(&self, count: u64, aad: &[u8], buffer: &mut Vec<u8>) -> Result<()>;
+}
+
+impl StreamCipherSuite for Aes256Gcm {
+    fn seal_in_place(&self, count: u64, aad: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        self.encrypt_in_place(Nonce::from_slice(&nonce_bytes_from_count(&count)), aad, buffer)
+            .map_err(|_| other_error("encrypt_in_place"))
+    }
+
+    fn open_in_place(&self, count: u64, aad: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        self.decrypt_in_place(Nonce::from_slice(&nonce_bytes_from_count(&count)), aad, buffer)
+            .map_err(|_| other_error("decrypt_in_place"))
+    }
+}
+
+impl StreamCipherSuite for ChaCha20Poly1305 {
+    fn seal_in_place(&self, count: u64, aad: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        self.encrypt_in_place(Nonce::from_slice(&nonce_bytes_from_count(&count)), aad, buffer)
+            .map_err(|_| other_error("encrypt_in_place"))
+    }
+
+    fn open_in_place(&self, count: u64, aad: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        self.decrypt_in_place(Nonce::from_slice(&nonce_bytes_from_count(&count)), aad, buffer)
+            .map_err(|_| other_error("decrypt_in_place"))
+    }
+}
+
+// The cipher of an upload, which encrypts its messages the way its format
+// says. Readers and writers only go through this, so a new format only has to
+// choose its suite in `new` and its associated data in `ChunkFormat`.
+pub struct ChunkCipher {
+    suite: Box<dyn StreamCipherSuite>,
+    format: ChunkFormat
+}
+
+impl ChunkCipher {
+    // `key` is the b64 encoded key of the upload
+    pub fn new(key: &[u8], format: ChunkFormat) -> Result<Self> {
+        let key_slice = b64::base64_decode_lenient(key).ok_or(other_error("base64_decode"))?;
+        Self::from_key_slice(&key_slice, format)
+    }
+
+    fn from_key_slice(key_slice: &[u8], format: ChunkFormat) -> Result<Self> {
+        if key_slice.len() != 32 {
+            return Err(other_error("key length"));
+        }
+
+        let suite: Box<dyn StreamCipherSuite> = if format.is_chacha20 {
+            Box::new(ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key_slice)))
+        } else {
+            Box::new(Aes256Gcm::new(Key::from_slice(key_slice)))
+        };
+        let new = Self { suite, format };
+
+        Ok(new)
+    }
+
+    // The name and mime type have no associated data (see `FORMAT_VERSION`)
+    fn seal_string(&self, string: &str, count: &mut u64) -> Result<Vec<u8>> {
+        let mut buffer = string.as_bytes().to_vec();
+        self.suite.seal_in_place(*count, &[], &mut buffer)?;
+        *count += 1;
+        Ok(buffer)
+    }
+
+    fn open_string(&self, bytes: &[u8], count: &mut u64) -> Result<String> {
+        let mut buffer = bytes.to_vec();
+        self.suite.open_in_place(*count, &[], &mut buffer).or(Err(other_error("decrypt")))?;
+        *count += 1;
+        String::from_utf8(buffer).or(Err(other_error("from_utf8")))
+    }
+
+    fn seal_chunk(&self, buffer: &mut Vec<u8>, count: &mut u64, is_manifest: bool) -> Result<()> {
+        let (aad, aad_len) = self.format.aad(count, is_manifest);
+        self.suite.seal_in_place(*count, &aad[..aad_len], buffer)?;
+        *count += 1;
+        Ok(())
+    }
+
+    fn open_chunk(&self, buffer: &mut Vec<u8>, count: &mut u64, is_manifest: bool) -> Result<()> {
+        let (aad, aad_len) = self.format.aad(count, is_manifest);
+        self.suite.open_in_place(*count, &aad[..aad_len], buffer)?;
+        *count += 1;
+        Ok(())
+    }
+}
+
+// The plaintext read so far, to be checked against the manifest
+pub struct PlaintextDigest {
+    hasher: Sha256,
+    len: u64,
+    // false if reading started after the beginning of the file, in which case
+    // only the manifest itself can be checked
+    is_whole: bool,
+    is_verified: bool
+}
+
+impl PlaintextDigest {
+    pub fn new(is_whole: bool) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            len: 0,
+            is_whole,
+            is_verified: false
+        }
+    }
+
+    fn update(&mut self, plaintext: &[u8]) {
+        self.hasher.update(plaintext);
+        self.len += plaintext.len() as u64;
+    }
+
+    fn verify(&mut self, manifest: &[u8]) -> Result<()> {
+        if manifest.len() != MANIFEST_SIZE {
+            return Err(other_error("Invalid manifest"));
+        }
+
+        if self.is_whole {
+            let mut len_bytes = [0; 8];
+            len_bytes.copy_from_slice(&manifest[..8]);
+            if u64::from_be_bytes(len_bytes) != self.len {
+                return Err(other_error("Plaintext length does not match the manifest"));
+            } else if self.hasher.clone().finalize().as_slice() != &manifest[8..] {
+                return Err(other_error("Plaintext hash does not match the manifest"));
+            }
+        }
+
+        self.is_verified = true;
+        Ok(())
+    }
+}
+
 // Writers
 
 // Write to a single file. `start_new_file` can only be called once, calling it
 // multiple times returns an error
 pub struct FileWriter {
-    writer: BufWriter<File>,
+    writer: BufWriter<StoredFile>,
     max_upload_size: usize,
     bytes_written: usize,
 }
 
 impl FileWriter {
-    pub fn new(path: &PathBuf, max_upload_size: usize) -> Result<Self>
+    // If the size of the upload is known in advance, `size_hint` is used to
+    // reserve space for it
+    pub fn new(path: &PathBuf, max_upload_size: usize, size_hint: Option<u64>) -> Result<Self>
     {
-        let file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path)?;
+        let file = StoredFile::create_new(path)?;
+
+        if let Some(size) = size_hint {
+            preallocate(file.file(), cmp::min(size, max_upload_size as u64))?;
+        }
 
         let new = Self {
             writer: BufWriter::new(file),
@@ -68,6 +384,53 @@ impl Write for FileWriter {
 }
 
 
+// Reserve `len` bytes of disk space for `file` without changing its size, so
+// that it can be allocated contiguously and running out of space is detected
+// before the data is sent. Since the size is kept, downloads of the upload
+// while it is in progress are unaffected.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = cmp::min(len, i64::MAX as u64) as libc::off_t;
+    if len == 0 {
+        return Ok(());
+    }
+
+    let result = unsafe {
+        libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len)
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        let e = Error::last_os_error();
+        // Not every filesystem supports preallocation, in which case space
+        // is allocated as the upload is written
+        match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => Ok(()),
+            _ => Err(e)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> Result<()> {
+    Ok(())
+}
+
+// Return whether or not an error was caused by running out of disk space
+#[cfg(unix)]
+pub fn is_out_of_space(e: &Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOSPC)
+}
+
+#[cfg(not(unix))]
+pub fn is_out_of_space(_e: &Error) -> bool {
+    false
+}
+
+
 // Wrap a FileWriter such that the data written is encrypted with the given key.
 // Also encrypts the file name and mime type.
 //
@@ -76,61 +439,136 @@ impl Write for FileWriter {
 //   (but no longer than MAX_CHUNK_SIZE)
 // - Each segment is prefixed by a 16-bit unsigned integer in big-endian byte
 //   order which stores the length of the segment
+// - Since version 3, the last segment is the manifest, whose length prefix
+//   has `MANIFEST_FLAG` set
+// - If the file is padded, every other segment has the same length
 // - The file ends with two zero bytes not belonging to any segment.
 //
 pub struct EncryptedFileWriter {
     writer: FileWriter,
-    cipher: Aes256Gcm,
-    buffer: Vec<u8>,
-    count: u64
+    cipher: ChunkCipher,
+    buffer: PooledBuffer,
+    count: u64,
+    digest: PlaintextDigest,
+    // plaintext of the next chunk, if the file is padded
+    pending: PooledBuffer,
+    // the payload, if the file is an age file
+    age: Option<AgeStream>
 }
 
-fn encrypt_string(cipher: &Aes256Gcm, string: &str, count: &mut u64) -> Result<Vec<u8>> {
-    let nonce_bytes = nonce_bytes_from_count(count);
-    *count += 1;
-
-    match cipher.encrypt(Nonce::from_slice(&nonce_bytes), string.as_bytes()) {
-        Ok(ciphertext) => Ok(ciphertext),
-        Err(_) => Err(other_error("encrypt"))
-    }
-}
+// A new writer along with the b64 encoded key, encrypted file name and
+// encrypted mime type of the upload
+pub type NewEncryptedWriter<W> = (W, Vec<u8>, Vec<u8>, Vec<u8>);
 
 impl EncryptedFileWriter {
     // Return the writer + the b64 encoded key, encrypted file name and encrypted mime type
-    pub fn new(path: &PathBuf, max_upload_size: usize, name: &str, mime: &str) -> Result<(Self, Vec<u8>, Vec<u8>, Vec<u8>)>
+    //
+    // The file is written in the version returned by `server_format_version`,
+    // or as an age file for `age_recipients` if they are given.
+    pub fn new(
+        path: &PathBuf, max_upload_size: usize, size_hint: Option<u64>,
+        id: i64, is_padded: bool, age_recipients: Option<&[[u8; 32]]>,
+        name: &str, mime: &str) -> Result<NewEncryptedWriter<Self>>
     {
         let mut key_slice = [0; 32];
         random_bytes(&mut key_slice);
         let encoded_key = b64::base64_encode(&key_slice);
-        let key = Key::from_slice(&key_slice);
-        let cipher = Aes256Gcm::new(key);
-        let writer = FileWriter::new(path, max_upload_size)?;
+        let format_version = match age_recipients {
+            Some(_) => AGE_FORMAT_VERSION,
+            None => server_format_version(is_padded)
+        };
+        let cipher = ChunkCipher::from_key_slice(&key_slice, ChunkFormat::new(id, format_version))?;
+        let mut writer = FileWriter::new(path, max_upload_size, size_hint)?;
         let mut count = 0;
 
-        let name_cipher = b64::base64_encode(&encrypt_string(&cipher, name, &mut count)?);
-        let mime_cipher = b64::base64_encode(&encrypt_string(&cipher, mime, &mut count)?);
+        let name_cipher = b64::base64_encode(&cipher.seal_string(name, &mut count)?);
+        let mime_cipher = b64::base64_encode(&cipher.seal_string(mime, &mut count)?);
+
+        let age = match age_recipients {
+            Some(recipients) => Some(
+                age::write_header(&age::file_key(&encoded_key)?, recipients, &mut writer)?),
+            None => None
+        };
 
         let new = Self {
-            writer: writer,
-            cipher: cipher,
-            buffer: Vec::with_capacity(FORM_READ_BUFFER_SIZE * 2),
-            count: count
+            writer,
+            cipher,
+            buffer: PooledBuffer::new(),
+            count,
+            digest: PlaintextDigest::new(true),
+            pending: PooledBuffer::new(),
+            age
         };
 
         Ok((new, encoded_key, name_cipher, mime_cipher))
     }
 
     pub fn finish(&mut self) -> Result<()> {
+        if let Some(age) = &mut self.age {
+            return age.finish(&mut self.writer);
+        }
+        if self.cipher.format.is_padded {
+            // (the name and mime type were encrypted with the first two counts)
+            let chunk_count = self.count - 2 + !self.pending.is_empty() as u64;
+            let padded_count = padded_chunk_count(chunk_count);
+            while self.count - 2 < padded_count {
+                self.write_pending_chunk(true)?;
+            }
+        }
+        if self.cipher.format.has_manifest {
+            write_manifest(
+                &self.digest, &mut self.buffer, &mut self.count, &self.cipher,
+                &mut self.writer)?;
+        }
         // Make sure the file is terminated by two zero bytes
         self.writer.write(&0u16.to_be_bytes())?;
         Ok(())
     }
+
+    // Write the pending plaintext of a padded file as a chunk, padded with
+    // zeros. Padding doesn't count towards the maximum upload size, so the
+    // chunks written when finishing the file skip the check.
+    fn write_pending_chunk(&mut self, is_padding: bool) -> Result<()> {
+        self.pending.resize(FORM_READ_BUFFER_SIZE, 0);
+        if is_padding {
+            encrypted_write(
+                &self.pending, &mut self.buffer, &mut self.count, &self.cipher,
+                &mut self.writer.writer)?;
+        } else {
+            encrypted_write(
+                &self.pending, &mut self.buffer, &mut self.count, &self.cipher,
+                &mut self.writer)?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// Write the manifest of the plaintext hashed into `digest`: the length of the
+// plaintext as a big-endian u64, followed by its SHA-256. Since it is
+// authenticated like every other chunk, a file which was cut short can't
+// pass for a complete one.
+fn write_manifest<W>(
+    digest: &PlaintextDigest, buffer: &mut Vec<u8>, count: &mut u64,
+    cipher: &ChunkCipher, mut writer: W) -> Result<()>
+where W: Write
+{
+    buffer.clear();
+    buffer.extend_from_slice(&digest.len.to_be_bytes());
+    buffer.extend_from_slice(&digest.hasher.clone().finalize());
+
+    cipher.seal_chunk(buffer, count, true)?;
+
+    let size_prefix = (buffer.len() as u16 | MANIFEST_FLAG).to_be_bytes();
+    writer.write_all(&size_prefix)?;
+    writer.write_all(&buffer)
 }
 
 // `buffer` is a resizable buffer for intermediate data required by the
 // encryption process.
 pub fn encrypted_write<W>(
-    plaintext: &[u8], buffer: &mut Vec<u8>, count: &mut u64, cipher: &Aes256Gcm, mut writer: W) -> Result<usize>
+    plaintext: &[u8], buffer: &mut Vec<u8>, count: &mut u64, cipher: &ChunkCipher,
+    mut writer: W) -> Result<usize>
 where W: Write
 {
     if plaintext.is_empty() {
@@ -144,27 +582,39 @@ where W: Write
     buffer.clear();
     buffer.extend_from_slice(plaintext);
 
-    let nonce_bytes = nonce_bytes_from_count(count);
-    *count += 1;
+    cipher.seal_chunk(buffer, count, false)?;
 
-    match cipher.encrypt_in_place(Nonce::from_slice(&nonce_bytes), b"", buffer) {
-        Ok(()) => {
-            if buffer.len() <= MAX_CHUNK_SIZE {
-                let size_prefix = (buffer.len() as u16).to_be_bytes();
-                writer.write_all(&size_prefix)?;
-                writer.write_all(&buffer)?;
-                Ok(plaintext.len())
-            } else {
-                Err(other_error("Plaintext too large"))
-            }
-        },
-        Err(_) => Err(other_error("encrypt_in_place"))
+    if buffer.len() <= MAX_CHUNK_SIZE {
+        let size_prefix = (buffer.len() as u16).to_be_bytes();
+        writer.write_all(&size_prefix)?;
+        writer.write_all(&buffer)?;
+        Ok(plaintext.len())
+    } else {
+        Err(other_error("Plaintext too large"))
     }
 }
 
 impl Write for EncryptedFileWriter {
     fn write(&mut self, plaintext: &[u8]) -> Result<usize> {
-        encrypted_write(plaintext, &mut self.buffer, &mut self.count, &self.cipher, &mut self.writer)
+        if let Some(age) = &mut self.age {
+            return age.write(plaintext, &mut self.writer);
+        }
+
+        if self.cipher.format.is_padded {
+            // Fill the pending chunk, and write it once it is full
+            let len = cmp::min(plaintext.len(), FORM_READ_BUFFER_SIZE - self.pending.len());
+            self.pending.extend_from_slice(&plaintext[..len]);
+            self.digest.update(&plaintext[..len]);
+            if self.pending.len() == FORM_READ_BUFFER_SIZE {
+                self.write_pending_chunk(false)?;
+            }
+            return Ok(len);
+        }
+
+        let len = encrypted_write(
+            plaintext, &mut self.buffer, &mut self.count, &self.cipher, &mut self.writer)?;
+        self.digest.update(&plaintext[..len]);
+        Ok(len)
     }
 
     fn flush(&mut self) -> Result<()> {
@@ -175,16 +625,42 @@ impl Write for EncryptedFileWriter {
 
 // Wrap an EncryptedFileWriter such that multiple files can be written into a
 // single archive. 
+// An entry of an archive created by the server, as listed in its entries file
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    // size of the uncompressed contents
+    pub size: u64,
+    // UNIX timestamp at which the entry was added
+    pub mtime: i64
+}
+
 pub struct EncryptedZipWriter {
     writer: Archive<EncryptedFileWriter>,
     compression: CompressionMode,
+    // (written next to the archive once it is finished, see
+    // `write_archive_entries`)
+    entries: Vec<ArchiveEntry>,
+    entries_cipher: ChunkCipher,
+    upload_dir: PathBuf
 }
 
 impl EncryptedZipWriter {
     // Return the writer + the b64 encoded key, encrypted file name and encrypted mime type
-    pub fn new(path: &PathBuf, max_upload_size: usize, level: u8) -> Result<(Self, Vec<u8>, Vec<u8>, Vec<u8>)> {
+    pub fn new(
+        path: &PathBuf, max_upload_size: usize, size_hint: Option<u64>,
+        id: i64, is_padded: bool, age_recipients: Option<&[[u8; 32]]>,
+        level: u8) -> Result<NewEncryptedWriter<Self>>
+    {
         let (inner_writer, key, name, mime) = EncryptedFileWriter::new(
-            path, max_upload_size, "", "application/zip")?;
+            path, max_upload_size, size_hint, id, is_padded, age_recipients, "",
+            "application/zip")?;
+        let format_version = match age_recipients {
+            Some(_) => AGE_FORMAT_VERSION,
+            None => server_format_version(is_padded)
+        };
+        let entries_cipher = ChunkCipher::new(&key, ChunkFormat::new(id, format_version))?;
+        let upload_dir = path.parent().ok_or(other_error("upload path"))?.to_owned();
         if level > 9 {
             return Err(Error::from(ErrorKind::InvalidInput));
         }
@@ -197,7 +673,10 @@ impl EncryptedZipWriter {
 
         let new = Self {
             writer: Archive::new(inner_writer),
-            compression
+            compression,
+            entries: Vec::new(),
+            entries_cipher,
+            upload_dir
         };
 
         Ok((new, key, name, mime))
@@ -205,6 +684,11 @@ impl EncryptedZipWriter {
 
     pub fn start_new_file(&mut self, name: &str) -> Result<()> {
         let now = Local::now().naive_utc();
+        self.entries.push(ArchiveEntry {
+            name: name.to_owned(),
+            size: 0,
+            mtime: now.timestamp()
+        });
         self.writer.start_new_file(name.to_owned().into_bytes(), now, self.compression, true)
     }
 
@@ -215,13 +699,16 @@ impl EncryptedZipWriter {
     pub fn finish(self) -> Result<()> {
         let mut inner_writer = self.writer.finish()?;
         inner_writer.finish()?;
-        Ok(())
+        write_archive_entries(&self.upload_dir, &self.entries, &self.entries_cipher)
     }
 }
 
 impl Write for EncryptedZipWriter {
     fn write(&mut self, bytes: &[u8]) -> Result<usize> {
         self.writer.append_data(bytes)?;
+        if let Some(entry) = self.entries.last_mut() {
+            entry.size += bytes.len() as u64;
+        }
         Ok(bytes.len())
     }
 
@@ -235,7 +722,7 @@ impl Write for EncryptedZipWriter {
 
 // Basic wrapper around a buffered reader for a file.
 pub struct FileReader {
-    reader: BufReader<File>,
+    reader: BufReader<StoredFile>,
     expire_after: NaiveDateTime,
     is_completed: bool
 }
@@ -247,7 +734,7 @@ impl FileReader {
             expire_after: NaiveDateTime,
             is_completed: bool) -> Result<Self>
     {
-        let mut file = File::open(path)?;
+        let mut file = StoredFile::open(path)?;
         file.seek(SeekFrom::Start(start_index))?;
         let reader = BufReader::new(file);
 
@@ -292,53 +779,194 @@ impl Read for FileReader {
 // decrypts the encrypted name and mime type of the file
 pub struct EncryptedFileReader {
     reader: FileReader,
-    cipher: Aes256Gcm,
-    buffer: Vec<u8>,
+    cipher: ChunkCipher,
+    buffer: PooledBuffer,
     read_start: usize,
     read_end: usize,
     count: u64,
+    digest: PlaintextDigest,
+    // whether the two zero bytes which end the file were read
+    is_finished: bool,
+    // length of the plaintext of a padded file, and how much of it is left
+    // to be read
+    plaintext_len: Option<u64>,
+    remaining_plaintext: Option<u64>,
+    // the payload, if the file is an age file
+    age: Option<AgeStream>
 }
 
-fn decrypt_string(cipher: &Aes256Gcm, bytes: &[u8], count: &mut u64) -> Result<String> {
-    let nonce_bytes = nonce_bytes_from_count(count);
-    *count += 1;
+// Decrypt the file name of an upload (the first string encrypted with its key)
+pub fn decrypt_file_name(key: &[u8], name_cipher: &[u8], format: ChunkFormat) -> Result<String> {
+    let cipher = ChunkCipher::new(key, format)?;
+    let name_cipher = b64::base64_decode(name_cipher).ok_or(other_error("decrypt"))?;
 
-    match cipher.decrypt(Nonce::from_slice(&nonce_bytes), bytes) {
-        Ok(plaintext) => String::from_utf8(plaintext).or(Err(other_error("from_utf8"))),
-        Err(_) => Err(other_error("decrypt"))
-    }
+    cipher.open_string(&name_cipher, &mut 0)
+}
+
+// Encrypt the title of an upload with its b64 encoded key. Return the b64
+// encoded ciphertext.
+pub fn seal_title(key: &[u8], title: &str, format: ChunkFormat) -> Result<String> {
+    let cipher = ChunkCipher::new(key, format)?;
+    let title_cipher = cipher.seal_string(title, &mut { TITLE_COUNT })?;
+
+    Ok(String::from_utf8(b64::base64_encode(&title_cipher)).unwrap())
+}
+
+// Decrypt the title encrypted by `seal_title` (or by the client)
+pub fn decrypt_title(key: &[u8], title_cipher: &[u8], format: ChunkFormat) -> Result<String> {
+    let cipher = ChunkCipher::new(key, format)?;
+    let title_cipher = b64::base64_decode(title_cipher).ok_or(other_error("decrypt"))?;
+
+    cipher.open_string(&title_cipher, &mut { TITLE_COUNT })
+}
+
+// The cipher for the key of an upload whose key is derived from its password
+fn password_cipher(password: &[u8], salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key_slice = [0; 32];
+    Argon2::default().hash_password_into(password, salt, &mut key_slice)
+        .or(Err(other_error("hash_password_into")))?;
+
+    Ok(Aes256Gcm::new(Key::from_slice(&key_slice)))
+}
+
+// Encrypt the b64 encoded key of an upload with a key derived from `password`
+// with Argon2id and a new salt. The key of the upload isn't derived from the
+// password directly, since a form may send the password after the file, once
+// the upload is already being encrypted. Return the b64 encoded salt and
+// encrypted key.
+pub fn wrap_key(key: &[u8], password: &[u8]) -> Result<(String, String)> {
+    let key_slice = b64::base64_decode_lenient(key).ok_or(other_error("base64_decode"))?;
+    let mut salt = [0; 16];
+    random_bytes(&mut salt);
+
+    // Every salt gives a new key, so the nonce doesn't need to change
+    let wrapped_key = password_cipher(password, &salt)?
+        .encrypt(Nonce::from_slice(&[0; 12]), key_slice.as_slice())
+        .or(Err(other_error("encrypt")))?;
+
+    Ok((
+        String::from_utf8(b64::base64_encode(&salt)).unwrap(),
+        String::from_utf8(b64::base64_encode(&wrapped_key)).unwrap()))
+}
+
+// Return the b64 encoded key wrapped by `wrap_key`
+pub fn unwrap_key(wrapped_key: &[u8], salt: &[u8], password: &[u8]) -> Result<Vec<u8>> {
+    let salt = b64::base64_decode(salt).ok_or(other_error("base64_decode"))?;
+    let wrapped_key = b64::base64_decode(wrapped_key).ok_or(other_error("base64_decode"))?;
+
+    let key_slice = password_cipher(password, &salt)?
+        .decrypt(Nonce::from_slice(&[0; 12]), wrapped_key.as_slice())
+        .or(Err(other_error("decrypt")))?;
+
+    Ok(b64::base64_encode(&key_slice))
 }
 
 impl EncryptedFileReader {
     // Return the reader + the decrypted file name and decrypted mime type
+    //
+    // `start_index` MUST be the offset of the start of the chunk with index
+    // `start_chunk` (see `find_chunk`). Padded files can only be read once
+    // they are complete, since the length of their plaintext is only known
+    // from the manifest. Age files can only be read from the start.
     pub fn new(
         path: &PathBuf,
         start_index: u64,
+        start_chunk: u64,
         expire_after: NaiveDateTime,
         is_completed: bool,
+        format: ChunkFormat,
         key: &[u8],
         name_cipher: &[u8],
         mime_cipher: &[u8]) -> Result<(Self, String, String)>
     {
-        let key_slice = b64::base64_decode(key).ok_or(other_error("base64_decode"))?;
-        let key = Key::from_slice(&key_slice);
-        let cipher = Aes256Gcm::new(key);
+        let cipher = ChunkCipher::new(key, format)?;
         let mut count = 0;
 
-        let name = decrypt_string(&cipher, &b64::base64_decode(name_cipher).ok_or(other_error("decrypt"))?, &mut count)?;
-        let mime = decrypt_string(&cipher, &b64::base64_decode(mime_cipher).ok_or(other_error("decrypt"))?, &mut count)?;
+        let name = cipher.open_string(&b64::base64_decode(name_cipher).ok_or(other_error("decrypt"))?, &mut count)?;
+        let mime = cipher.open_string(&b64::base64_decode(mime_cipher).ok_or(other_error("decrypt"))?, &mut count)?;
+
+        // The nonce for each chunk depends on its position in the file
+        count += start_chunk;
+
+        let plaintext_len = if format.is_padded {
+            Some(read_padded_plaintext_len(path, &cipher)?)
+        } else {
+            None
+        };
+        // Every chunk before `start_chunk` holds the same amount of plaintext
+        let remaining_plaintext = plaintext_len
+            .map(|len| len.saturating_sub(start_chunk * FORM_READ_BUFFER_SIZE as u64));
+
+        let mut reader = FileReader::new(path, start_index, expire_after, is_completed)?;
+        let age = if format.is_age {
+            if start_index != 0 {
+                return Err(other_error("Age files can only be read from the start"));
+            }
+            Some(age::open_header(&mut reader, &age::file_key(key)?)?)
+        } else {
+            None
+        };
 
         let new = Self {
-            reader: FileReader::new(path, start_index, expire_after, is_completed)?,
-            cipher: cipher,
-            buffer: Vec::with_capacity(FORM_READ_BUFFER_SIZE * 2),
+            reader,
+            cipher,
+            buffer: PooledBuffer::new(),
             read_start: 0,
             read_end: 0,
-            count: count
+            count,
+            // The plaintext before `start_chunk` isn't read, so it can't be
+            // checked against the manifest
+            digest: PlaintextDigest::new(start_chunk == 0),
+            is_finished: false,
+            plaintext_len,
+            remaining_plaintext,
+            age
         };
 
         Ok((new, name, mime))
     }
+
+    // Return the length of the plaintext without the padding, if the file is
+    // padded
+    pub fn plaintext_len(&self) -> Option<u64> {
+        self.plaintext_len
+    }
+}
+
+// Return the length of the plaintext of the complete padded file at `path`
+// from its manifest, which is at a known offset from the end of the file since
+// every other chunk has the same size.
+fn read_padded_plaintext_len(path: &PathBuf, cipher: &ChunkCipher) -> Result<u64>
+{
+    const PADDED_CHUNK_SIZE: u64 = 2 + MAX_CHUNK_SIZE as u64;
+    // the manifest and the terminating zero bytes
+    const TAIL_SIZE: usize = 2 + MANIFEST_SIZE + TAG_SIZE + 2;
+
+    let mut file = StoredFile::open(path)?;
+    let chunks_size = file.metadata()?.len()
+        .checked_sub(TAIL_SIZE as u64)
+        .ok_or(other_error("Missing manifest"))?;
+    if chunks_size % PADDED_CHUNK_SIZE != 0 {
+        return Err(other_error("Invalid padded file size"));
+    }
+
+    let mut tail = [0; TAIL_SIZE];
+    file.seek(SeekFrom::Start(chunks_size))?;
+    file.read_exact(&mut tail)?;
+
+    let (chunk_size, is_manifest) = parse_chunk_prefix([tail[0], tail[1]]);
+    if !is_manifest || chunk_size != MANIFEST_SIZE + TAG_SIZE || tail[TAIL_SIZE - 2..] != [0, 0] {
+        return Err(other_error("Missing manifest"));
+    }
+
+    // (the name and mime type were encrypted with the first two counts)
+    let mut count = 2 + chunks_size / PADDED_CHUNK_SIZE;
+    let mut manifest = tail[2..TAIL_SIZE - 2].to_vec();
+    cipher.open_chunk(&mut manifest, &mut count, true)?;
+
+    let mut len_bytes = [0; 8];
+    len_bytes.copy_from_slice(&manifest[..8]);
+    Ok(u64::from_be_bytes(len_bytes))
 }
 
 // `buffer` is a resizable buffer for intermediate data required by the
@@ -346,12 +974,20 @@ impl EncryptedFileReader {
 // we produce from a single ciphertext segment may exceed the size of the
 // `plaintext` buffer, so it must be stored and returned in a subsequent call
 // to this function.
+//
+// Reaching the end of the file before the two zero bytes which terminate it
+// (or, since version 3, before a manifest matching the plaintext) is an
+// error, since the file was truncated. If the file is padded,
+// `remaining_plaintext` is the length of the plaintext left to be read, and
+// everything after it is dropped.
 pub fn encrypted_read<R>(
     plaintext: &mut[u8], buffer: &mut Vec<u8>, read_start: &mut usize,
-    read_end: &mut usize, count: &mut u64, cipher: &Aes256Gcm, mut reader: R) -> Result<usize>
+    read_end: &mut usize, count: &mut u64, digest: &mut PlaintextDigest,
+    remaining_plaintext: &mut Option<u64>, is_finished: &mut bool,
+    cipher: &ChunkCipher, mut reader: R) -> Result<usize>
 where R: Read
 {
-    if plaintext.is_empty() {
+    if plaintext.is_empty() || *is_finished {
         return Ok(0);
     }
 
@@ -360,48 +996,72 @@ where R: Read
 
         let mut size_buf = 0u16.to_be_bytes();
 
-        if let Err(e) = reader.read_exact(&mut size_buf) {
-            if e.kind() == ErrorKind::UnexpectedEof {
+        // The manifest and chunks which are only padding hold none of the
+        // plaintext, so read on until a chunk which does (or the end)
+        loop {
+            if let Err(e) = reader.read_exact(&mut size_buf) {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    return Err(other_error("Ciphertext truncated"));
+                } else {
+                    return Err(e);
+                }
+            }
+
+            let (chunk_size, is_manifest) = parse_chunk_prefix(size_buf);
+
+            if chunk_size == 0 && !is_manifest {
+                if cipher.format.has_manifest && !digest.is_verified {
+                    return Err(other_error("Missing manifest"));
+                }
                 // Trillium will continue trying to read from us, even after
                 // we reach the end of the file. However, returning an error
                 // in this case will cause Trillium to improperly close the
-                // connection to the client which can break the download.
-                //
-                // It's a bit of a hack, but just returning Ok(0) will make
-                // sure Trillium properly terminates the chunk-encoded body.
-                return Ok(0);
-            } else {
-                return Err(e);
+                // connection to the client which can break the download, so
+                // keep returning Ok(0) from now on.
+                *is_finished = true;
+                return Ok(0); // EOF
+            } else if chunk_size > MAX_CHUNK_SIZE {
+                return Err(other_error("Ciphertext chunk too large"));
+            } else if digest.is_verified {
+                return Err(other_error("Data after the manifest"));
             }
-        }
 
-        let chunk_size = u16::from_be_bytes(size_buf) as usize;
+            buffer.resize(chunk_size, 0);
+            reader.read_exact(buffer)?;
 
-        if chunk_size == 0 {
-            return Ok(0); // EOF
-        } else if chunk_size > MAX_CHUNK_SIZE {
-            return Err(other_error("Ciphertext chunk too large"));
-        }
+            if is_manifest && !cipher.format.has_manifest {
+                return Err(other_error("Unexpected manifest"));
+            }
 
-        buffer.resize(chunk_size, 0);
-        reader.read_exact(buffer)?;
+            cipher.open_chunk(buffer, count, is_manifest)?;
 
-        let nonce_bytes = nonce_bytes_from_count(count);
-        *count += 1;
+            if is_manifest {
+                digest.verify(buffer)?;
+                continue;
+            }
 
-        match cipher.decrypt_in_place(Nonce::from_slice(&nonce_bytes), b"", buffer) {
-            Ok(()) => {
-                let available_plaintext_len = buffer.len();
-                let len = cmp::min(plaintext.len(), available_plaintext_len);
+            // Drop the padding after the end of the plaintext
+            if let Some(remaining) = remaining_plaintext {
+                let len = cmp::min(buffer.len() as u64, *remaining);
+                buffer.truncate(len as usize);
+                *remaining -= len;
+            }
 
-                plaintext[..len].copy_from_slice(&buffer[..len]);
-                *read_start = len;
-                *read_end = available_plaintext_len;
+            digest.update(buffer);
 
-                Ok(len)
-            },
-            Err(_) => Err(other_error("decrypt_in_place"))
+            if !buffer.is_empty() {
+                break;
+            }
         }
+
+        let available_plaintext_len = buffer.len();
+        let len = cmp::min(plaintext.len(), available_plaintext_len);
+
+        plaintext[..len].copy_from_slice(&buffer[..len]);
+        *read_start = len;
+        *read_end = available_plaintext_len;
+
+        Ok(len)
     } else {
         // If there is remaining decrypted data that has yet to be sent
         let len = cmp::min(plaintext.len(), *read_end - *read_start);
@@ -414,22 +1074,316 @@ where R: Read
 
 impl Read for EncryptedFileReader {
     fn read(&mut self, plaintext: &mut [u8]) -> Result<usize> {
+        if let Some(age) = &mut self.age {
+            return age.read(plaintext, &mut self.reader);
+        }
+
         encrypted_read(
             plaintext, &mut self.buffer, &mut self.read_start,
-            &mut self.read_end, &mut self.count, &self.cipher, &mut self.reader)
+            &mut self.read_end, &mut self.count, &mut self.digest,
+            &mut self.remaining_plaintext, &mut self.is_finished, &self.cipher,
+            &mut self.reader)
+    }
+}
+
+// Return the offset and index of the chunk of the encrypted file at `path`
+// which contains the byte at `offset`. The terminating zero-length chunk
+// counts as a chunk.
+pub fn find_chunk<P>(path: P, offset: u64) -> Result<(u64, u64)>
+where P: AsRef<Path>
+{
+    let mut reader = BufReader::new(StoredFile::open(path)?);
+    let mut chunk_start = 0;
+    let mut chunk_index = 0;
+
+    loop {
+        let mut size_buf = 0u16.to_be_bytes();
+        reader.read_exact(&mut size_buf)?;
+        let (chunk_size, is_manifest) = parse_chunk_prefix(size_buf);
+        let chunk_size = chunk_size as u64;
+        let chunk_end = chunk_start + size_buf.len() as u64 + chunk_size;
+
+        if chunk_size as usize > MAX_CHUNK_SIZE {
+            return Err(other_error("Ciphertext chunk too large"));
+        } else if offset < chunk_end {
+            return Ok((chunk_start, chunk_index));
+        } else if chunk_size == 0 && !is_manifest {
+            return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Offset is past the end of the file"));
+        }
+
+        reader.seek_relative(chunk_size as i64)?;
+        chunk_start = chunk_end;
+        chunk_index += 1;
+    }
+}
+
+// Return the size of the plaintext of the encrypted file at `path` by adding
+// up the sizes of its chunks (other than the manifest).
+pub fn get_plaintext_size<P>(path: P) -> Result<u64>
+where P: AsRef<Path>
+{
+    let file = StoredFile::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut plaintext_size = 0;
+
+    if age::has_magic(reader.fill_buf()?) {
+        return age::check_file(reader, file_size, None).map(|(plaintext_size, ..)| plaintext_size);
+    }
+
+    loop {
+        let mut size_buf = 0u16.to_be_bytes();
+        reader.read_exact(&mut size_buf)?;
+        let (chunk_size, is_manifest) = parse_chunk_prefix(size_buf);
+
+        if chunk_size == 0 && !is_manifest {
+            return Ok(plaintext_size);
+        } else if !(TAG_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(other_error("Invalid ciphertext chunk size"));
+        }
+
+        if !is_manifest {
+            plaintext_size += (chunk_size - TAG_SIZE) as u64;
+        }
+        reader.seek_relative(chunk_size as i64)?;
     }
 }
 
+// What `check_file` found out about a file which passed
+#[derive(Serialize)]
+pub struct FileCheck {
+    pub plaintext_size: u64,
+    pub file_size: u64,
+    // number of chunks, including the manifest
+    pub num_chunks: u64,
+    pub has_manifest: bool,
+    // whether the first chunk was decrypted (only if a key was given)
+    pub is_sample_decrypted: bool
+}
+
+// Return the length of the plaintext of a complete file of `ciphertext_len`
+// bytes (without a manifest) whose chunks each hold `chunk_size` bytes of
+// plaintext, except for the last one. Each chunk adds its size prefix and
+// tag, and the file ends with two zero bytes. If chunks were written smaller
+// (e.g. by the server, which writes whatever it was sent at once), the
+// plaintext is shorter than this.
+pub fn plaintext_len(ciphertext_len: u64, chunk_size: usize) -> u64 {
+    const CHUNK_OVERHEAD: u64 = 2 + TAG_SIZE as u64;
+
+    let chunks_len = ciphertext_len.saturating_sub(2);
+    let full_chunk_len = chunk_size as u64 + CHUNK_OVERHEAD;
+    let num_full_chunks = chunks_len / full_chunk_len;
+    let last_chunk_len = chunks_len % full_chunk_len;
+
+    num_full_chunks * chunk_size as u64 + last_chunk_len.saturating_sub(CHUNK_OVERHEAD)
+}
+
+// Check that the encrypted file at `path` consists of complete chunks of valid
+// sizes (with at most one manifest, right before the end) followed by the
+// terminating zero-length chunk and nothing else. If `sample` has the format
+// and the b64 encoded key of the upload, also check that its first chunk can
+// be decrypted. Age files are checked by `age::check_file` instead.
+pub fn check_file<P>(path: P, sample: Option<(ChunkFormat, &[u8])>) -> Result<FileCheck>
+where P: AsRef<Path>
+{
+    let file = StoredFile::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    if age::has_magic(reader.fill_buf()?) {
+        let file_key = match sample {
+            Some((_, key)) => Some(age::file_key(key)?),
+            None => None
+        };
+        let (plaintext_size, num_chunks, is_sample_decrypted) =
+            age::check_file(reader, file_size, file_key)?;
+
+        return Ok(FileCheck {
+            plaintext_size,
+            file_size,
+            num_chunks,
+            has_manifest: false,
+            is_sample_decrypted
+        });
+    }
+    let mut position = 0;
+    let mut plaintext_size = 0;
+    let mut num_chunks = 0;
+    let mut has_manifest = false;
+    let mut is_sample_decrypted = false;
+
+    loop {
+        let mut size_buf = 0u16.to_be_bytes();
+        if position + size_buf.len() as u64 > file_size {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Missing terminating chunk"));
+        }
+        reader.read_exact(&mut size_buf)?;
+        position += size_buf.len() as u64;
+        let (chunk_size, is_manifest) = parse_chunk_prefix(size_buf);
+
+        if chunk_size == 0 && !is_manifest {
+            break;
+        } else if !(TAG_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(other_error("Invalid ciphertext chunk size"));
+        } else if position + chunk_size as u64 > file_size {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated ciphertext chunk"));
+        } else if has_manifest {
+            return Err(other_error("Data after the manifest"));
+        } else if is_manifest && chunk_size != MANIFEST_SIZE + TAG_SIZE {
+            return Err(other_error("Invalid manifest size"));
+        }
+
+        match sample {
+            Some((format, key)) if num_chunks == 0 => {
+                let mut chunk = vec![0; chunk_size];
+                reader.read_exact(&mut chunk)?;
+                decrypt_first_chunk(format, key, is_manifest, &mut chunk)?;
+                is_sample_decrypted = true;
+            },
+            _ => reader.seek_relative(chunk_size as i64)?
+        }
+
+        has_manifest = is_manifest;
+        if !is_manifest {
+            plaintext_size += (chunk_size - TAG_SIZE) as u64;
+        }
+        position += chunk_size as u64;
+        num_chunks += 1;
+    }
+
+    if position < file_size {
+        return Err(other_error("Data after the terminating chunk"));
+    }
+
+    Ok(FileCheck {
+        plaintext_size,
+        file_size,
+        num_chunks,
+        has_manifest,
+        is_sample_decrypted
+    })
+}
+
+fn decrypt_first_chunk(
+    format: ChunkFormat, key: &[u8], is_manifest: bool, chunk: &mut Vec<u8>) -> Result<()>
+{
+    // (the name and mime type were encrypted with the first two counts)
+    ChunkCipher::new(key, format)?.open_chunk(chunk, &mut 2, is_manifest)
+        .map_err(|_| other_error("Decrypting the first chunk failed"))
+}
+
+// Return the size of the plaintext and the size of the file checked by
+// `check_file`
+pub fn verify_chunks<P>(path: P) -> Result<(u64, u64)>
+where P: AsRef<Path>
+{
+    let check = check_file(path, None)?;
+    Ok((check.plaintext_size, check.file_size))
+}
+
 fn other_error(message: &'static str) -> Error {
     Error::new(ErrorKind::Other, message)
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadClient {
+    // the browser client, which encrypts uploads itself
+    Websocket,
+    // a form submitted by a browser without JavaScript or a tool like curl
+    Form,
+    // the `import` command
+    Import,
+    // a peer instance which mirrored the upload here
+    Mirror,
+    // the gRPC API
+    Grpc
+}
+
+// Written next to the file of each upload, so that the state of uploads can
+// be recovered after a crash without relying on the database or on when
+// their files were last modified
+#[derive(Serialize, Deserialize)]
+pub struct UploadMetadata {
+    // size of the upload declared by the client, if any
+    pub declared_size: Option<u64>,
+    pub started_at: NaiveDateTime,
+    pub client: UploadClient,
+    pub completed: bool
+}
+
+// Write the entries of an archive encrypted with its cipher (and sealed like
+// the rest of the upload if it is encrypted at rest)
+fn write_archive_entries(
+    upload_dir: &Path, entries: &[ArchiveEntry], cipher: &ChunkCipher) -> Result<()>
+{
+    let entries = serde_json::to_string(entries)?;
+    let entries_cipher = cipher.seal_string(&entries, &mut { ARCHIVE_ENTRIES_COUNT })?;
+    std::fs::write(
+        upload_dir.join(ARCHIVE_ENTRIES_FILE_NAME),
+        at_rest::seal(upload_dir, entries_cipher)?)
+}
+
+// Return the encrypted entries of an archive created by the server, or None if
+// the upload isn't one (or is not completed yet)
+pub fn read_archive_entries(upload_dir: &Path) -> Option<Vec<u8>> {
+    let bytes = std::fs::read(upload_dir.join(ARCHIVE_ENTRIES_FILE_NAME)).ok()?;
+    at_rest::unseal(upload_dir, bytes).ok()
+}
+
+// Decrypt the entries returned by `read_archive_entries` with the b64 encoded
+// key of the upload
+pub fn decrypt_archive_entries(
+    key: &[u8], entries_cipher: &[u8], format: ChunkFormat) -> Result<Vec<ArchiveEntry>>
+{
+    let cipher = ChunkCipher::new(key, format)?;
+    let entries = cipher.open_string(entries_cipher, &mut { ARCHIVE_ENTRIES_COUNT })?;
+    Ok(serde_json::from_str(&entries)?)
+}
+
+pub fn write_upload_metadata(upload_dir: &Path, metadata: &UploadMetadata) -> Result<()> {
+    // Replace the file in one step, so it is never read half-written
+    let tmp_path = upload_dir.join(format!("{}.tmp", UPLOAD_METADATA_FILE_NAME));
+    std::fs::write(&tmp_path, at_rest::seal(upload_dir, serde_json::to_vec(metadata)?)?)?;
+    std::fs::rename(tmp_path, upload_dir.join(UPLOAD_METADATA_FILE_NAME))
+}
+
+// Return None if the upload has no metadata (e.g. it was stored before
+// metadata was written) or it can't be read
+pub fn read_upload_metadata(upload_dir: &Path) -> Option<UploadMetadata> {
+    let bytes = std::fs::read(upload_dir.join(UPLOAD_METADATA_FILE_NAME)).ok()?;
+    let bytes = at_rest::unseal(upload_dir, bytes).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+pub fn mark_upload_metadata_completed(upload_dir: &Path) -> Result<()> {
+    match read_upload_metadata(upload_dir) {
+        Some(mut metadata) => {
+            metadata.completed = true;
+            write_upload_metadata(upload_dir, &metadata)
+        },
+        None => Err(other_error("Reading upload metadata"))
+    }
+}
+
 pub fn delete_upload_dir(storage_dir: &PathBuf, id: i64) {
     let id_string = String::from_utf8(b64::i64_to_b64_bytes(id)).unwrap();
-    let upload_path = storage_dir.join(id_string);
+    let upload_path = storage_dir.join(&id_string);
     if upload_path.exists() {
+        // The file of an upload which was moved to cold storage is a link to
+        // where it was moved, which has to be removed as well
+        if let Ok(cold_path) = std::fs::read_link(upload_path.join("upload")) {
+            if let Some(cold_dir) = cold_path.parent() {
+                if let Err(e) = std::fs::remove_dir_all(cold_dir) {
+                    error!(id = %id_string, "Deleting cold upload directory: {}", e);
+                }
+            }
+        }
+
         if let Err(e) = std::fs::remove_dir_all(upload_path) {
-            eprintln!("{}", e);
+            error!(id = %id_string, "Deleting upload directory: {}", e);
         }
     }
 }
@@ -463,3 +1417,36 @@ where P: AsRef<Path>
 
     Ok(storage_size)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::files::*;
+
+    #[test]
+    fn test_plaintext_len_empty() {
+        // only the terminating zero bytes
+        assert_eq!(plaintext_len(2, 10240), 0);
+        assert_eq!(plaintext_len(0, 10240), 0);
+    }
+
+    #[test]
+    fn test_plaintext_len_one_chunk() {
+        assert_eq!(plaintext_len(2 + 16 + 5 + 2, 10240), 5);
+        assert_eq!(plaintext_len(2 + 16 + 10240 + 2, 10240), 10240);
+    }
+
+    #[test]
+    fn test_plaintext_len_many_chunks() {
+        let full_chunk = 2 + 16 + 10240;
+        assert_eq!(plaintext_len(3 * full_chunk + 2, 10240), 3 * 10240);
+        assert_eq!(plaintext_len(3 * full_chunk + 2 + 16 + 100 + 2, 10240), 3 * 10240 + 100);
+    }
+
+    #[test]
+    fn test_plaintext_len_manifest() {
+        let format = ChunkFormat::new(1, 3);
+        let manifest = (2 + MANIFEST_SIZE + TAG_SIZE) as u64;
+        assert_eq!(format.plaintext_len(2 + 16 + 5 + manifest + 2), 5);
+        assert_eq!(ChunkFormat::new(1, 2).plaintext_len(2 + 16 + 5 + 2), 5);
+    }
+}
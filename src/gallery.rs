@@ -0,0 +1,205 @@
+use crate::b64::*;
+use crate::config::*;
+use crate::db::*;
+use crate::files::{decrypt_file_name, ChunkFormat};
+use crate::http_errors::*;
+use crate::templates::*;
+use crate::translations::*;
+use crate::uploader_config::instance_url;
+
+use std::sync::Arc;
+
+use blocking::unblock;
+use trillium::Conn;
+use trillium_askama::AskamaConnExt;
+
+
+// Number of uploads on each page of the gallery
+const PAGE_SIZE: i64 = 50;
+// Number of uploads in the feed
+const FEED_SIZE: i64 = 50;
+const PAGE_QUERY: &'static str = "page";
+
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&'static str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// A public upload along with its decrypted name
+struct PublicUpload {
+    id_string: String,
+    name: String,
+    key: String,
+    upload: Upload
+}
+
+impl PublicUpload {
+    fn new(upload: Upload, config: &TranspoConfig) -> Option<Self> {
+        let key = upload.public_key.clone()?;
+        let id_string = String::from_utf8(i64_to_b64_bytes(upload.id)).unwrap();
+        let mut name = decrypt_file_name(
+            key.as_bytes(), upload.file_name.as_bytes(),
+            ChunkFormat::new(upload.id, upload.format_version)).ok()?;
+
+        // Archives made of several files have no name, like on download
+        if name.is_empty() {
+            name = format!("{}_{}.zip", config.app_name, id_string);
+        }
+
+        Some(Self {
+            id_string,
+            name,
+            key,
+            upload
+        })
+    }
+
+    // Uploads in the gallery are never password-protected
+    fn path(&self) -> String {
+        format!("{}?nopass#{}", self.id_string, self.key)
+    }
+
+    fn size(&self) -> String {
+        format_size(self.upload.plaintext_size.unwrap_or(0) as u64)
+    }
+
+    fn expire_after(&self) -> String {
+        format!("{} UTC", self.upload.expire_after.format("%Y-%m-%d %H:%M"))
+    }
+}
+
+async fn select_public_uploads(
+    offset: i64, limit: i64, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<Vec<PublicUpload>>
+{
+    unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        let uploads = Upload::select_public(offset, limit, &db_connection)?;
+
+        Some(uploads.into_iter()
+            .filter_map(|upload| PublicUpload::new(upload, &config))
+            .collect())
+    }).await
+}
+
+fn parse_page(query: &str) -> i64 {
+    query.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| *key == PAGE_QUERY)
+        .and_then(|(_, value)| value.parse::<i64>().ok())
+        .filter(|page| *page >= 0 && *page < i64::MAX / PAGE_SIZE)
+        .unwrap_or(0)
+}
+
+// `GET /gallery?page=<n>`: list the public uploads, newest first
+pub async fn handle(
+    conn: Conn, config: Arc<TranspoConfig>, translations: Arc<Translations>,
+    translation: Translation, lang: String, db_backend: DbBackend) -> Conn
+{
+    if !config.gallery {
+        return error_404(conn, config, translation);
+    }
+
+    let page = parse_page(conn.querystring());
+    // One more than fits on the page is selected to tell if there is a next
+    // page
+    let uploads = select_public_uploads(
+        page * PAGE_SIZE, PAGE_SIZE + 1, db_backend, config.clone()).await;
+    let mut uploads = match uploads {
+        Some(uploads) => uploads,
+        None => return error_400(conn, config, translation)
+    };
+
+    let has_next_page = uploads.len() as i64 > PAGE_SIZE;
+    uploads.truncate(PAGE_SIZE as usize);
+
+    let entries = uploads.iter()
+        .map(|upload| GalleryEntry {
+            name: html_escape(&upload.name),
+            path: upload.path(),
+            size: upload.size(),
+            expire_after: upload.expire_after()
+        })
+        .collect();
+
+    let template = GalleryTemplate {
+        app_name: &config.app_name,
+        selected_lang: &lang,
+        lang_names: translations.names(),
+        entries,
+        previous_page: if page > 0 { Some(page - 1) } else { None },
+        next_page: if has_next_page { Some(page + 1) } else { None },
+        t: translation
+    };
+
+    conn.render(template).halt()
+}
+
+// `GET /gallery/feed`: the newest public uploads as an RSS feed
+pub async fn feed(
+    conn: Conn, config: Arc<TranspoConfig>, translation: Translation,
+    db_backend: DbBackend) -> Conn
+{
+    if !config.gallery {
+        return error_404(conn, config, translation);
+    }
+
+    let uploads = match select_public_uploads(0, FEED_SIZE, db_backend, config.clone()).await {
+        Some(uploads) => uploads,
+        None => return error_400(conn, config, translation)
+    };
+
+    let url = instance_url(&conn, &config);
+    let items = uploads.iter()
+        .map(|upload| {
+            let published_at = upload.upload.published_at
+                .map(|t| format!(
+                    "\n<pubDate>{}</pubDate>", t.format("%a, %d %b %Y %H:%M:%S +0000")))
+                .unwrap_or_default();
+
+            format!(
+                "<item>\n<title>{name}</title>\n<link>{url}/{path}</link>\n\
+                <guid isPermaLink=\"false\">{id}</guid>\n\
+                <description>{size}, {expires} {expire_after}</description>{published_at}\n</item>\n",
+                name = html_escape(&upload.name),
+                url = html_escape(&url),
+                path = html_escape(&upload.path()),
+                id = upload.id_string,
+                size = upload.size(),
+                expires = html_escape(translation.get("gallery/expires")),
+                expire_after = upload.expire_after(),
+                published_at = published_at)
+        })
+        .collect::<String>();
+
+    let feed = format!(r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0">
+<channel>
+<title>{app_name} | {title}</title>
+<link>{url}/gallery</link>
+<description>{title}</description>
+{items}</channel>
+</rss>
+"#, app_name = html_escape(&config.app_name), title = html_escape(translation.get("gallery/title")),
+        url = html_escape(&url), items = items);
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "application/rss+xml; charset=utf-8")
+        .with_header("Cache-Control", "no-cache")
+        .with_body(feed)
+        .halt()
+}
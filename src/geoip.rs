@@ -0,0 +1,60 @@
+use crate::config::*;
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use maxminddb::{geoip2, Reader};
+use tracing::{debug, info};
+
+
+// The country of the client, if it could be looked up
+#[derive(Clone)]
+pub struct ClientCountry(pub Option<String>);
+
+// Looks up the countries of addresses in a MaxMind database, which is read
+// into memory once on start
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        Reader::open_readfile(path)
+            .map(|reader| Self { reader })
+            .map_err(|e| e.to_string())
+    }
+
+    // Return the ISO 3166-1 code of the country of the address, or None if
+    // the database doesn't know (e.g. for private addresses)
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record.country?.iso_code.map(str::to_string)
+    }
+}
+
+// Whether `--geoip-allow` or `--geoip-deny` restrict anything
+pub fn is_restricted(config: &TranspoConfig) -> bool {
+    !config.geoip_allow.is_empty() || !config.geoip_deny.is_empty()
+}
+
+// Decide whether a client from the given country may upload (or download).
+// Clients whose country is unknown are only refused if there is an allow
+// list.
+pub fn is_allowed(country: Option<&str>, action: &str, config: &TranspoConfig) -> bool {
+    let is_allowed = match country {
+        Some(country) => {
+            (config.geoip_allow.is_empty() || config.geoip_allow.iter().any(|c| c == country))
+                && !config.geoip_deny.iter().any(|c| c == country)
+        },
+        None => config.geoip_allow.is_empty()
+    };
+
+    let country = country.unwrap_or("unknown");
+    if is_allowed {
+        debug!(country, action, "Allowed by country");
+    } else {
+        info!(country, action, "Refused by country");
+    }
+
+    is_allowed
+}
@@ -0,0 +1,467 @@
+use crate::api_keys::*;
+use crate::b64::*;
+use crate::blocklist::Blocklist;
+use crate::concurrency::*;
+use crate::config::*;
+use crate::constants::*;
+use crate::db::*;
+use crate::download::{get_speed_limit, get_upload, tracked_reader, verify_password, Bandwidth};
+use crate::federation::{delete_on_peers, mirror_to_peers, restore_from_peers};
+use crate::files::*;
+use crate::metrics::*;
+use crate::shutdown::Shutdown;
+use crate::upload::{
+    charge_storage, create_upload_storage_dir, release_storage, write_is_completed,
+    write_to_db, UploadForm, STORAGE_CHECK_INTERVAL};
+
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use blocking::{unblock, Unblock};
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tonic::transport::Server;
+use tracing::{error, info, info_span, warn, Instrument};
+
+mod proto {
+    tonic::include_proto!("transpo.v1");
+}
+
+use proto::*;
+use proto::transpo_server::{Transpo, TranspoServer};
+
+
+// Number of bytes of the plaintext sent in each message of a download
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+// Number of messages of a download which may be waiting to be sent
+const DOWNLOAD_CHANNEL_SIZE: usize = 4;
+// How often the server checks whether it should shut down
+const SHUTDOWN_POLL_INTERVAL_MS: u64 = 500;
+
+
+fn not_found() -> Status {
+    Status::not_found("The upload does not exist")
+}
+
+fn unavailable() -> Status {
+    Status::unavailable("The database is unavailable")
+}
+
+fn parse_id(id_string: &str) -> Result<i64, Status> {
+    match i64_from_b64_bytes(id_string.as_bytes()) {
+        Some(id) if id_string.len() == base64_encode_length(ID_LENGTH) => Ok(id),
+        _ => Err(not_found())
+    }
+}
+
+// Serves the same uploads as the HTTP server, using the same database and
+// storage directory
+struct TranspoService {
+    config: Arc<TranspoConfig>,
+    accessors: Accessors,
+    bandwidth: Option<Bandwidth>,
+    blocklist: Blocklist,
+    db_backend: DbBackend
+}
+
+impl TranspoService {
+    // Return the API key given in the request metadata if it has the scope
+    async fn check_scope<T>(&self, request: &Request<T>, scope: &str) -> Result<ApiKey, Status> {
+        let key = request.metadata().get(API_KEY_HEADER)
+            .and_then(|k| k.to_str().ok())
+            .map(|k| k.trim().to_owned());
+
+        match select_api_key(key, self.db_backend, self.config.clone()).await {
+            Ok(Some(api_key)) if api_key.has_scope(scope) => Ok(api_key),
+            Ok(None) => Err(Status::unauthenticated("An API key is required")),
+            _ => Err(Status::permission_denied(
+                "The API key is invalid or may not be used for this"))
+        }
+    }
+
+    // Write the contents of an upload as they are received. Return the key
+    // with which they were encrypted.
+    async fn receive_upload(
+        &self, id: i64, upload_path: &PathBuf, options: UploadOptions,
+        stream: &mut Streaming<UploadRequest>, client_ip: Option<IpAddr>,
+        config: Arc<TranspoConfig>) -> Result<String, Status>
+    {
+        let db_backend = self.db_backend;
+        let storage_error = |e: std::io::Error| Status::internal(e.to_string());
+
+        if charge_storage(0, &self.accessors, db_backend, config.clone()).await
+            .map_err(storage_error)?
+        {
+            return Err(Status::resource_exhausted("Storage capacity exceeded"));
+        }
+
+        let mime_type = if options.mime_type.is_empty() {
+            mime_guess::from_path(&options.file_name).first_or_octet_stream().to_string()
+        } else {
+            options.mime_type
+        };
+        // https://datatracker.ietf.org/doc/html/rfc4288#section-4.2
+        if mime_type.len() > 255 {
+            return Err(Status::invalid_argument("Mime type is too long"));
+        }
+
+        let (writer, key, file_name, mime_type) = EncryptedFileWriter::new(
+                upload_path, config.max_upload_size_bytes, options.size, id,
+                config.pad_uploads, None, &options.file_name, &mime_type)
+            .map_err(storage_error)?;
+        let mut writer = Unblock::with_capacity(FORM_READ_BUFFER_SIZE, writer);
+
+        // Like form uploads which give their options in the query string, the
+        // upload can be downloaded while it is in progress
+        let mut form = UploadForm::new(
+            true, options.minutes, options.max_downloads, options.password, None, None);
+        form.set_encrypted_by_server(config.pad_uploads, false);
+        write_to_db(
+                form, id, Some(file_name), Some(mime_type), client_ip, None,
+                db_backend, config.clone()).await
+            .ok_or_else(|| Status::internal("Writing the upload to the database failed"))?;
+
+        let timeout_duration = time::Duration::from_millis(
+            config.read_timeout_milliseconds as u64);
+        let mut bytes_read_interval = 0;
+
+        loop {
+            let message = match stream.message().timeout(timeout_duration).await {
+                Some(message) => message?,
+                None => return Err(Status::deadline_exceeded("Timed out waiting for the upload"))
+            };
+
+            match message.and_then(|m| m.message) {
+                Some(upload_request::Message::Chunk(chunk)) => {
+                    // (fails once the maximum upload size is exceeded)
+                    writer.write_all(&chunk).await
+                        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+                    bytes_read_interval += chunk.len();
+                    if bytes_read_interval > STORAGE_CHECK_INTERVAL {
+                        let bytes = bytes_read_interval;
+                        bytes_read_interval = 0;
+
+                        if charge_storage(bytes, &self.accessors, db_backend, config.clone()).await
+                            .map_err(storage_error)?
+                        {
+                            return Err(Status::resource_exhausted("Storage capacity exceeded"));
+                        }
+                    }
+                },
+                Some(upload_request::Message::Options(_)) => {
+                    return Err(Status::invalid_argument("The options may only be sent once"));
+                },
+                None => break
+            }
+        }
+
+        writer.with_mut(|w| w.finish()).await.map_err(storage_error)?;
+        writer.flush().await.map_err(storage_error)?;
+        // Charge the rest of the upload
+        charge_storage(bytes_read_interval, &self.accessors, db_backend, config.clone()).await
+            .map_err(storage_error)?;
+
+        write_is_completed(id, upload_path.to_owned(), Some(key.clone()), db_backend, config).await
+            .ok_or_else(|| Status::internal("Completing the upload failed"))?;
+
+        Ok(String::from_utf8(key).unwrap())
+    }
+}
+
+#[tonic::async_trait]
+impl Transpo for TranspoService {
+    async fn upload(
+        &self, request: Request<Streaming<UploadRequest>>) -> Result<Response<UploadResponse>, Status>
+    {
+        let client_ip = request.remote_addr().map(|a| a.ip());
+        if client_ip.map(|ip| self.blocklist.is_ip_blocked(&ip)).unwrap_or(false) {
+            count_rejection(Rejection::Blocked);
+            return Err(Status::permission_denied("Uploads from this address are blocked"));
+        }
+
+        let api_key = self.check_scope(&request, UPLOAD_SCOPE).await?;
+        let config = apply_limits(self.config.clone(), &api_key);
+        let mut stream = request.into_inner();
+
+        let options = match stream.message().await?.and_then(|m| m.message) {
+            Some(upload_request::Message::Options(options)) => options,
+            _ => return Err(Status::invalid_argument("The first message must contain the options"))
+        };
+        if options.minutes == 0 {
+            return Err(Status::invalid_argument("A time limit is required"));
+        }
+
+        let _active = ActiveTransfer::new(Transfer::Upload);
+
+        let (id, id_string, upload_dir) = {
+            let storage_path = config.storage_dir.clone();
+            let size_hint = options.size;
+            unblock(move || create_upload_storage_dir(
+                storage_path, UploadClient::Grpc, size_hint))
+        }.await;
+        let upload_path = upload_dir.join("upload");
+
+        let span = info_span!("grpc_upload", id = %id_string);
+        let result = self.receive_upload(
+                id, &upload_path, options, &mut stream, client_ip, config.clone())
+            .instrument(span.clone())
+            .await;
+
+        let _span = span.entered();
+        match result {
+            Ok(key) => {
+                info!("Upload completed");
+                mirror_to_peers(id, config, self.db_backend);
+                Ok(Response::new(UploadResponse { id: id_string, key }))
+            },
+            Err(status) => {
+                warn!(status = %status.message(), "Upload failed");
+                let db_backend = self.db_backend;
+                unblock(move || {
+                    if upload_dir.exists() {
+                        release_storage(&upload_dir.join("upload"), db_backend, &config);
+                        std::fs::remove_dir_all(upload_dir)
+                            .expect("Deleting failed upload");
+                    }
+                    if let Some(db_connection) = establish_connection(db_backend, &config.db_url) {
+                        Upload::delete_with_id(id, &db_connection);
+                    }
+                }).await;
+
+                Err(status)
+            }
+        }
+    }
+
+    type DownloadStream = ReceiverStream<Result<DownloadResponse, Status>>;
+
+    async fn download(
+        &self, request: Request<DownloadRequest>) -> Result<Response<Self::DownloadStream>, Status>
+    {
+        let client_ip = request.remote_addr().map(|a| a.ip());
+        let request = request.into_inner();
+        let id = parse_id(&request.id)?;
+        if self.blocklist.is_upload_blocked(id) {
+            return Err(not_found());
+        }
+
+        let id_string = request.id.clone();
+        let config = self.config.clone();
+        let db_backend = self.db_backend;
+        restore_from_peers(id_string.clone(), config.clone(), db_backend).await;
+
+        let accessors = self.accessors.clone();
+        let bandwidth = self.bandwidth.clone();
+        let (reader, metadata) = unblock(move || {
+            let db_connection = establish_connection(db_backend, &config.db_url)
+                .ok_or_else(unavailable)?;
+
+            let upload = get_upload(id, &accessors, &db_connection).ok_or_else(not_found)?;
+            let password = request.password.map(String::into_bytes);
+            if !verify_password(&password, &upload, client_ip) {
+                return Err(Status::permission_denied("The password is incorrect"));
+            }
+
+            let accessor_mutex = accessors.access(id)
+                .ok_or_else(|| Status::internal("Accessing the upload failed"))?;
+            if upload.remaining_downloads.is_some()
+            && !Upload::claim_download(id, &db_connection).ok_or_else(unavailable)?
+            {
+                return Err(not_found());
+            }
+
+            let upload_path = config.storage_dir.join(&request.id).join("upload");
+            let reader = EncryptedFileReader::new(
+                &upload_path, 0, 0, upload.expire_after, upload.is_completed,
+                ChunkFormat::new(id, upload.format_version),
+                request.key.as_bytes(), upload.file_name.as_bytes(), upload.mime_type.as_bytes());
+
+            // Give the claimed download back if the key is wrong
+            let (reader, mut file_name, mime_type) = match reader {
+                Ok(reader) => reader,
+                Err(_) => {
+                    Upload::refund_download(id, &db_connection);
+                    return Err(Status::invalid_argument("The key is incorrect"));
+                }
+            };
+
+            if file_name.is_empty() {
+                file_name = format!("{}_{}", config.app_name, request.id);
+            }
+
+            let metadata = DownloadMetadata {
+                file_name,
+                mime_type,
+                // (the plaintext size of padded uploads includes the padding)
+                size: reader.plaintext_len()
+                    .or(upload.plaintext_size.map(|s| s as u64))
+                    .filter(|_| upload.is_completed)
+            };
+
+            let speed_limit = get_speed_limit(&config, &upload);
+            let reader = tracked_reader(
+                reader, speed_limit, bandwidth, accessor_mutex, db_backend, config.clone());
+
+            Ok((reader, metadata))
+        }).await?;
+
+        info!(id = %id_string, "Serving download over gRPC");
+
+        // Reading blocks (e.g. while the upload is still in progress), so the
+        // messages are sent from their own thread
+        let (sender, receiver) = mpsc::channel(DOWNLOAD_CHANNEL_SIZE);
+        thread::spawn(move || {
+            let mut reader = reader;
+            let metadata = DownloadResponse {
+                message: Some(download_response::Message::Metadata(metadata))
+            };
+            if sender.blocking_send(Ok(metadata)).is_err() {
+                return;
+            }
+
+            let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
+            loop {
+                let message = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(bytes_read) => Ok(DownloadResponse {
+                        message: Some(download_response::Message::Chunk(buf[..bytes_read].to_vec()))
+                    }),
+                    Err(e) => Err(Status::internal(e.to_string()))
+                };
+
+                let is_error = message.is_err();
+                // (fails once the client has gone away)
+                if sender.blocking_send(message).is_err() || is_error {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn info(&self, request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
+        let client_ip = request.remote_addr().map(|a| a.ip());
+        let request = request.into_inner();
+        let id = parse_id(&request.id)?;
+        if self.blocklist.is_upload_blocked(id) {
+            return Err(not_found());
+        }
+
+        let config = self.config.clone();
+        let db_backend = self.db_backend;
+        restore_from_peers(request.id.clone(), config.clone(), db_backend).await;
+
+        let accessors = self.accessors.clone();
+        let info = unblock(move || {
+            let db_connection = establish_connection(db_backend, &config.db_url)
+                .ok_or_else(unavailable)?;
+
+            let upload = get_upload(id, &accessors, &db_connection).ok_or_else(not_found)?;
+            let password = request.password.map(String::into_bytes);
+            if !verify_password(&password, &upload, client_ip) {
+                return Err(Status::permission_denied("The password is incorrect"));
+            }
+
+            let upload_path = config.storage_dir.join(&request.id).join("upload");
+            let size = match (upload.is_completed, upload.ciphertext_size) {
+                (true, Some(size)) => size as u64,
+                (true, None) => get_file_size(&upload_path)
+                    .map_err(|e| Status::internal(e.to_string()))?,
+                (false, _) => 0
+            };
+
+            Ok(InfoResponse {
+                name: upload.file_name,
+                mime: upload.mime_type,
+                size,
+                expire_after: upload.expire_after.timestamp(),
+                remaining_downloads: upload.remaining_downloads,
+                is_completed: upload.is_completed,
+                downloads: upload.num_downloads,
+                completed_downloads: upload.num_completed_downloads
+            })
+        }).await?;
+
+        Ok(Response::new(info))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        self.check_scope(&request, ADMIN_SCOPE).await?;
+
+        let id_string = request.into_inner().id;
+        let id = parse_id(&id_string)?;
+
+        let config = self.config.clone();
+        let db_backend = self.db_backend;
+        let accessors = self.accessors.clone();
+        unblock(move || {
+            let db_connection = establish_connection(db_backend, &config.db_url)
+                .ok_or_else(unavailable)?;
+
+            let accessor_mutex = accessors.access(id)
+                .ok_or_else(|| Status::internal("Accessing the upload failed"))?;
+            let _accessor = accessor_mutex.lock();
+
+            match Upload::select_with_id(id, &db_connection) {
+                Some(upload) if !upload.is_deleted() => {
+                    Upload::mark_deleted(id, &db_connection).ok_or_else(unavailable)?;
+                    // Nothing is left to review
+                    Report::delete_with_upload(id, &db_connection);
+                    Ok(())
+                },
+                _ => Err(not_found())
+            }
+        }).await?;
+
+        info!(id = %id_string, "Upload deleted");
+        delete_on_peers(id, self.config.clone());
+        Ok(Response::new(DeleteResponse {}))
+    }
+}
+
+async fn wait_for_shutdown(shutdown: Shutdown) {
+    while !shutdown.is_draining() {
+        tokio::time::sleep(time::Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS)).await;
+    }
+}
+
+// Serve the gRPC API on its own thread (and runtime) until the server is
+// shut down
+pub fn spawn_grpc_server(
+    config: Arc<TranspoConfig>, accessors: Accessors, bandwidth: Option<Bandwidth>,
+    blocklist: Blocklist, shutdown: Shutdown, db_backend: DbBackend)
+{
+    let address = SocketAddr::from(([0, 0, 0, 0], config.grpc_port as u16));
+    let service = TranspoService {
+        config,
+        accessors,
+        bandwidth,
+        blocklist,
+        db_backend
+    };
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Starting the gRPC runtime");
+
+        info!(%address, "Serving gRPC");
+        let result = runtime.block_on(Server::builder()
+            .add_service(TranspoServer::new(service))
+            .serve_with_shutdown(address, wait_for_shutdown(shutdown)));
+
+        if let Err(e) = result {
+            error!("Serving gRPC: {}", e);
+        }
+    });
+}
@@ -2,6 +2,7 @@ use trillium::Conn;
 use trillium_askama::AskamaConnExt;
 use std::sync::Arc;
 use crate::config::*;
+use crate::request_id::*;
 use crate::templates::*;
 use crate::translations::*;
 
@@ -17,7 +18,7 @@ fn path_depth(path: &str) -> usize {
     depth
 }
 
-fn path_prefix(path: &str) -> String {
+pub fn path_prefix(path: &str) -> String {
     "../".repeat(path_depth(path))
 }
 
@@ -26,7 +27,8 @@ pub fn error_400(conn: Conn, config: Arc<TranspoConfig>, translation: Translatio
         error_code: 400,
         t: translation,
         app_name: &config.app_name,
-        path_prefix: path_prefix(conn.path())
+        path_prefix: path_prefix(conn.path()),
+        request_id: get_request_id(&conn)
     };
 
     conn.render(template).with_status(400).halt()
@@ -37,8 +39,35 @@ pub fn error_404(conn: Conn, config: Arc<TranspoConfig>, translation: Translatio
         error_code: 404,
         t: translation,
         app_name: &config.app_name,
-        path_prefix: path_prefix(conn.path())
+        path_prefix: path_prefix(conn.path()),
+        request_id: get_request_id(&conn)
     };
 
     conn.render(template).with_status(404).halt()
 }
+
+pub fn error_413(conn: Conn, config: Arc<TranspoConfig>, translation: Translation) -> Conn {
+    let template = ErrorTemplate {
+        error_code: 413,
+        t: translation,
+        app_name: &config.app_name,
+        path_prefix: path_prefix(conn.path()),
+        request_id: get_request_id(&conn)
+    };
+
+    conn.render(template).with_status(413).halt()
+}
+
+// Respond to a request made to `/api/v1` with an error as JSON
+pub fn api_error(conn: Conn, status: u16, message: &str) -> Conn {
+    let body = serde_json::json!({
+        "error": message,
+        "request_id": get_request_id(&conn)
+    });
+
+    conn
+        .with_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_body(body.to_string())
+        .halt()
+}
@@ -0,0 +1,144 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, Local};
+use tracing::warn;
+
+use crate::constants::*;
+use crate::db::*;
+use crate::files::*;
+use crate::upload::create_upload_storage_dir;
+
+
+// An upload created from an imported file
+pub struct ImportedFile {
+    pub path: PathBuf,
+    pub id_string: String,
+    pub key: String
+}
+
+fn other_error(message: &'static str) -> Error {
+    Error::new(ErrorKind::Other, message)
+}
+
+// Encrypt and store the file at `path` as a new upload which expires after
+// `minutes` or `max_downloads` downloads. Return the upload's ID and key.
+fn import_file(
+    path: &Path, minutes: usize, max_downloads: Option<i32>, is_padded: bool,
+    storage_path: &PathBuf, db_connection: &DbConnection) -> Result<(String, String)>
+{
+    let file_name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(other_error("File name is not valid UTF-8"))?;
+    let mime_type = mime_guess::from_path(path).first_or_octet_stream();
+
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let (upload_id, upload_id_string, upload_dir) = create_upload_storage_dir(
+        storage_path.clone(), UploadClient::Import, Some(size));
+    let upload_path = upload_dir.join("upload");
+
+    let result: Result<String> = (|| {
+        let (mut writer, key, name_cipher, mime_cipher) = EncryptedFileWriter::new(
+            &upload_path, usize::MAX, Some(size), upload_id, is_padded, None, file_name,
+            mime_type.essence_str())?;
+
+        // Each write becomes one chunk, which may be no larger than the
+        // buffer used when uploading
+        let mut buf = vec![0; FORM_READ_BUFFER_SIZE];
+        loop {
+            let bytes_read = file.read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            writer.write_all(&buf[..bytes_read])?;
+        }
+        writer.finish()?;
+        writer.flush()?;
+        drop(writer);
+
+        let upload = Upload {
+            id: upload_id,
+            file_name: String::from_utf8(name_cipher).unwrap(),
+            mime_type: String::from_utf8(mime_cipher).unwrap(),
+            password_hash: None,
+            remaining_downloads: max_downloads,
+            num_accessors: 0,
+            expire_after: Local::now().naive_utc() + Duration::minutes(minutes as i64),
+            is_completed: false,
+            max_download_bytes_per_second: None,
+            num_downloads: 0,
+            num_completed_downloads: 0,
+            bytes_downloaded: 0,
+            plaintext_size: None,
+            ciphertext_size: None,
+            uploader_ip: None,
+            created_at: None,
+            owner_id: None,
+            deleted_at: None,
+            last_used_at: None,
+            notify_topic: None,
+            expiry_notified: false,
+            is_disabled: false,
+            is_mirror: false,
+            public_key: None,
+            published_at: None,
+            format_version: server_format_version(is_padded),
+            key_salt: None,
+            wrapped_key: None,
+            recipient_keys: None,
+            title: None
+        };
+        upload.insert(db_connection).ok_or(other_error("Inserting upload"))?;
+
+        let ciphertext_size = get_file_size(&upload_path)?;
+        let plaintext_size = get_plaintext_size(&upload_path)?;
+        Upload::set_completed(upload_id, plaintext_size, ciphertext_size, db_connection)
+            .ok_or(other_error("Completing upload"))?;
+        StorageUsage::add(ciphertext_size as i64, db_connection);
+        mark_upload_metadata_completed(&upload_dir)?;
+
+        Ok(String::from_utf8(key).unwrap())
+    })();
+
+    match result {
+        Ok(key) => Ok((upload_id_string, key)),
+        Err(e) => {
+            Upload::delete_with_id(upload_id, db_connection);
+            delete_upload_dir(storage_path, upload_id);
+            Err(e)
+        }
+    }
+}
+
+// Import every file directly inside `dir` as a new upload. The time limit is
+// capped to the configured maximum upload age.
+pub fn import_dir(
+    dir: &Path, minutes: usize, max_downloads: Option<i32>,
+    max_upload_age_minutes: usize, is_padded: bool, storage_path: &PathBuf,
+    db_connection: &DbConnection) -> Result<Vec<ImportedFile>>
+{
+    let minutes = cmp::min(minutes, max_upload_age_minutes);
+    let mut imported = Vec::new();
+
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    for path in paths {
+        if !path.is_file() {
+            warn!(path = %path.display(), "Skipping, not a file");
+            continue;
+        }
+
+        match import_file(&path, minutes, max_downloads, is_padded, storage_path, db_connection) {
+            Ok((id_string, key)) => imported.push(ImportedFile { path, id_string, key }),
+            Err(e) => warn!(path = %path.display(), "Importing file failed: {}", e)
+        }
+    }
+
+    Ok(imported)
+}
@@ -0,0 +1,10 @@
+// The modules through which uploads are encrypted and stored. They are built
+// as a library so that the benchmarks can use them as well as the server.
+
+pub mod random_bytes;
+pub mod b64;
+pub mod buffer_pool;
+pub mod files;
+pub mod at_rest;
+pub mod age;
+pub mod constants;
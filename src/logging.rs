@@ -0,0 +1,82 @@
+use crate::config::*;
+
+use std::io::IsTerminal;
+use std::net::IpAddr;
+
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+
+// Security events are logged with this target and message, followed by the
+// `event` and `client_ip` fields. Tools like fail2ban depend on this format,
+// so it must not change.
+const SECURITY_TARGET: &'static str = "transpo::security";
+const SECURITY_MESSAGE: &'static str = "Security event";
+
+#[derive(Clone, Copy)]
+pub enum SecurityEvent {
+    // a wrong password for an upload or an account
+    PasswordFailure,
+    // an upload quota or the connection limit was exceeded
+    QuotaExceeded,
+    // an upload from a blocked network or country
+    BlockedUpload
+}
+
+impl SecurityEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            SecurityEvent::PasswordFailure => "password_failure",
+            SecurityEvent::QuotaExceeded => "quota_exceeded",
+            SecurityEvent::BlockedUpload => "blocked_upload"
+        }
+    }
+}
+
+
+// Set up the global subscriber which writes log messages to stderr
+pub fn init_logging(config: &TranspoConfig) {
+    // The level has already been checked while parsing the configuration
+    let filter = EnvFilter::try_new(&config.log_level)
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    // Colours would get in the way of tools which read the log from a file or
+    // the journal
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr);
+
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init()
+    }
+}
+
+// Log a security event on a single line. Events of clients whose address is
+// unknown can't be acted on, so they are not logged.
+pub fn log_security_event(event: SecurityEvent, client_ip: Option<IpAddr>) {
+    if let Some(client_ip) = client_ip {
+        warn!(target: SECURITY_TARGET, event = %event.name(), client_ip = %client_ip, "{}", SECURITY_MESSAGE);
+    }
+}
+
+// Return a fail2ban filter which matches the security events written in the
+// given format
+pub fn fail2ban_filter(log_format: LogFormat) -> String {
+    let failregex = match log_format {
+        LogFormat::Text => format!(
+            r#"^.*\s{}: {}\s.*\bclient_ip=<HOST>\s*$"#,
+            SECURITY_TARGET, SECURITY_MESSAGE),
+        LogFormat::Json => format!(
+            r#"^.*"message":"{}".*"client_ip":"<HOST>".*"target":"{}".*$"#,
+            SECURITY_MESSAGE, SECURITY_TARGET)
+    };
+
+    format!(
+        "# fail2ban filter for Transpo (generated by `--print-fail2ban-filter`)\n\
+        # Matches password failures, quota violations and blocked uploads.\n\
+        [Definition]\n\
+        failregex = {}\n\
+        ignoreregex =\n",
+        failregex)
+}
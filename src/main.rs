@@ -4,19 +4,51 @@ mod multipart_form;
 mod concurrency;
 mod upload;
 mod download;
-mod random_bytes;
-mod b64;
-mod files;
-mod constants;
+mod recipients;
 mod db;
 mod cleanup;
 mod quotas;
 mod http_errors;
 mod translations;
+mod tokens;
+mod client_ip;
+mod api_keys;
+mod accounts;
+mod commands;
+mod stats;
+mod metrics;
+mod logging;
+mod version;
+mod shutdown;
+mod backup;
+mod import;
+mod tiering;
+mod openapi;
+mod api;
+mod request_id;
+mod proxy_protocol;
+mod oidc;
+mod notify;
+mod mail;
+mod uploader_config;
+mod reports;
+mod blocklist;
+mod geoip;
+mod onion;
+mod federation;
+mod cluster;
+mod events;
+mod gallery;
+#[cfg(feature = "redis")]
+mod redis_store;
+#[cfg(feature = "grpc")]
+mod grpc;
 
 #[macro_use]
 extern crate diesel;
 
+use transpo2::{random_bytes, b64, files, at_rest, constants};
+
 use config::*;
 use translations::*;
 use constants::*;
@@ -25,20 +57,33 @@ use templates::*;
 use concurrency::*;
 use cleanup::*;
 use quotas::*;
+use tokens::*;
+use api_keys::*;
+use db::ApiKey;
+use client_ip::*;
+use logging::*;
+use shutdown::*;
+use request_id::*;
+use metrics::{count_rejection, Rejection};
+use blocklist::*;
+use geoip::*;
+use cluster::*;
 
 use std::env;
 use std::fs;
 use std::sync::Arc;
-use std::net::IpAddr;
-use trillium::{Conn, Headers, state};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use blocking::unblock;
+use tracing::{error, info, info_span, warn, field, Instrument};
+use trillium::{Conn, state};
+use trillium_http::Stopper;
 use trillium_websockets::{WebSocketConn, WebSocketConfig, websocket};
 use trillium_router::{Router, RouterConnExt};
 use trillium_askama::AskamaConnExt;
 use trillium_static::{files, crate_relative_path};
 
 
-const X_REAL_IP: &'static str = "X-Real-IP";
-
 const WS_UPLOAD_CONFIG: WebSocketConfig = WebSocketConfig {
     max_send_queue: Some(1),
     max_message_size: Some(FORM_READ_BUFFER_SIZE * 2),
@@ -46,6 +91,14 @@ const WS_UPLOAD_CONFIG: WebSocketConfig = WebSocketConfig {
     accept_unmasked_frames: false
 };
 
+// Number of seconds after which clients should retry while the database is
+// unavailable
+const DB_RETRY_AFTER_SECS: usize = 10;
+
+// Number of seconds after which clients should retry while shutting down
+// (e.g. once a new process has taken over)
+const SHUTDOWN_RETRY_AFTER_SECS: usize = 10;
+
 const ID_STRING_LENGTH: usize = base64_encode_length(ID_LENGTH);
 
 
@@ -54,16 +107,70 @@ struct TranspoState {
     config: Arc<TranspoConfig>,
     translations: Arc<Translations>,
     accessors: Accessors,
-    quotas: Option<Quotas>
+    tokens: DownloadTokens,
+    quotas: Option<Quotas>,
+    upload_counts: Option<UploadCounts>,
+    bandwidth: Option<download::Bandwidth>,
+    connections: Option<ClientConnections>,
+    blocklist: Blocklist,
+    geoip: Option<Arc<GeoIp>>,
+    shutdown: Shutdown
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    let vars: Vec<(String, String)> = env::vars().collect();
+
+    // command line > environment > file > defaults
     let mut config = TranspoConfig::default();
-    config.parse_vars(env::vars());
-    config.parse_args(env::args());
+    let mut errors = Vec::new();
+    if let Some(config_file) = find_config_file(&args, &vars) {
+        errors.append(&mut config.parse_file(config_file));
+    }
+    errors.append(&mut config.parse_vars(vars.into_iter()));
+    errors.append(&mut config.parse_args(args.into_iter()));
+    errors.append(&mut config.validate());
+
+    // Print the configuration even if it is invalid to help find out why
+    if config.print_config {
+        println!("{}", config.to_redacted_json());
+        if errors.is_empty() {
+            std::process::exit(0);
+        }
+    }
+
+    // (only depends on the log format)
+    if config.print_fail2ban_filter {
+        print!("{}", fail2ban_filter(config.log_format));
+        std::process::exit(0);
+    }
+
+    if !errors.is_empty() {
+        exit_with_errors(&errors);
+    }
+
+    init_logging(&config);
+
+    // (before anything is stored, including by commands)
+    if let Some(storage_key) = config.storage_key_bytes() {
+        at_rest::set_storage_keys(storage_key, config.old_storage_key_bytes());
+    }
+
+    // Software AES is an order of magnitude slower, which is easy to miss on
+    // small ARM hosts
+    if files::has_hardware_aes() {
+        info!("Encrypting with hardware-accelerated AES-256-GCM");
+    } else if config.prefer_chacha20 {
+        files::set_prefer_chacha20(true);
+        info!(cpu_has_aes = files::cpu_has_aes(),
+            "AES is computed in software, so uploads are encrypted with ChaCha20-Poly1305");
+    } else {
+        warn!(cpu_has_aes = files::cpu_has_aes(),
+            "AES is computed in software, which is slow (see --prefer-chacha20)");
+    }
 
     if !config.quiet {
-        println!("Running with: {:#?}", &config);
+        info!("Running with: {:#?}", &config);
     }
 
     let translations = translations::Translations::new(
@@ -75,32 +182,393 @@ fn main() {
         .expect("Creating storage directory");
 
     if let Some(db_backend) = db::parse_db_backend(&config.db_url) {
-        let db_connection = db::establish_connection(db_backend, &config.db_url);
-        db::run_migrations(&db_connection, &config.migrations_dir);
+        let db_connection = db::establish_connection(db_backend, &config.db_url)
+            .expect("Establishing database connection");
+
+        // `db` commands manage migrations themselves
+        let is_db_command = config.command.first().map(|c| c == "db").unwrap_or(false);
+        if !config.skip_migrations && !is_db_command {
+            db::run_migrations(&db_connection, &config.migrations_dir);
+        }
+
+        // In a cluster, only the leader corrects the storage usage, since
+        // the other replicas may be in the middle of uploads. Commands never
+        // become the leader.
+        let leader = Leader::new(&config);
+        let is_leader = !config.cluster
+            || (config.command.is_empty() && leader.elect(&db_connection));
+
+        if !config.skip_migrations && !is_db_command && is_leader {
+            backfill_upload_sizes(&config.storage_dir, &db_connection);
+            reconcile_storage_usage(&config.storage_dir, &db_connection);
+        }
+
+        if !config.command.is_empty() {
+            std::process::exit(commands::run_command(&config, &db_connection));
+        }
+
+        if is_leader {
+            let num_interrupted = recover_interrupted_uploads(
+                config.read_timeout_milliseconds, &config.storage_dir, &db_connection);
+            if num_interrupted > 0 {
+                reconcile_storage_usage(&config.storage_dir, &db_connection);
+            }
+        }
 
         let config = Arc::new(config);
         let translations = Arc::new(translations);
 
+        spawn_election_thread(leader.clone(), db_backend, config.db_url.to_owned());
+
         spawn_cleanup_thread(
             config.read_timeout_milliseconds,
+            config.audit_retention_minutes,
+            config.deletion_grace_minutes,
             config.storage_dir.to_owned(),
-            db_backend, config.db_url.to_owned());
+            db_backend, config.db_url.to_owned(), leader.clone());
 
-        trillium_main(config, translations, db_backend);
+        if let Some(cold_storage_dir) = &config.cold_storage_dir {
+            fs::create_dir_all(cold_storage_dir)
+                .expect("Creating cold storage directory");
+            tiering::spawn_tiering_thread(
+                config.cold_after_hours,
+                config.storage_dir.to_owned(),
+                cold_storage_dir.to_owned(),
+                db_backend, config.db_url.to_owned(), leader.clone());
+        }
+
+        // (which also queues expiry reminders by mail)
+        if notify::is_enabled(&config) || mail::is_enabled(&config) {
+            notify::spawn_notify_thread((*config).clone(), db_backend, leader.clone());
+        }
+        if mail::is_enabled(&config) {
+            mail::spawn_mail_thread((*config).clone(), db_backend, leader.clone());
+        }
+
+        trillium_main(config.clone(), translations, db_backend);
+
+        // The server only stops once it has been drained. Clean up after any
+        // uploads which were abandoned then, rather than leaving them for the
+        // next cleanup after restarting. (In a cluster, this is left to
+        // the leader.)
+        if leader.is_leader() {
+            info!("Running final cleanup");
+            run_cleanup(
+                config.read_timeout_milliseconds,
+                config.audit_retention_minutes,
+                config.deletion_grace_minutes,
+                &config.storage_dir, &db_connection, false);
+        }
+        leader.resign(&db_connection);
+        info!("Shut down");
     } else {
-        eprintln!("A database connection is required!");
+        error!("A database connection is required!");
         std::process::exit(1);
     }
 }
 
-fn get_quotas_data(quotas: Option<Quotas>, headers: &Headers) -> Option<(Quotas, IpAddr)> {
-    quotas.and_then(|q| Some((q, addr_from_headers(headers)?)))
+// Halt with 403 if an unknown API key, or one which may not be used for
+// uploading, is presented, or with 401 if no key is presented while one is
+// required (or, with OpenID Connect, while nobody is logged in). Otherwise,
+// keep the key in the connection state.
+async fn check_upload_api_key(conn: Conn, db_backend: db::DbBackend) -> Conn {
+    let config = conn.state::<TranspoState>().unwrap().config.clone();
+    let require_api_key = config.require_api_key;
+    let require_login = config.oidc_issuer.is_some() && accounts::get_user_id(
+        conn.headers(), db_backend, config.clone()).await.is_none();
+
+    let api_key = resolve_upload_api_key(
+        conn.headers(), conn.querystring(), db_backend, config).await;
+
+    match api_key {
+        Ok(Some(api_key)) if api_key.has_scope(UPLOAD_SCOPE) => conn.with_state(api_key),
+        Ok(None) if require_api_key => http_errors::api_error(
+                conn.with_header("WWW-Authenticate", API_KEY_HEADER),
+                401, "An API key is required to upload"),
+        Ok(None) if require_login => http_errors::api_error(
+                conn, 401, "Log in to upload"),
+        Ok(None) => conn,
+        _ => http_errors::api_error(
+            conn, 403, "The API key is invalid or may not be used to upload")
+    }
+}
+
+// With OpenID Connect, send anyone who isn't logged in to the provider before
+// showing them the upload pages
+async fn require_oidc_login(conn: Conn, db_backend: db::DbBackend) -> Conn {
+    let config = conn.state::<TranspoState>().unwrap().config.clone();
+    if config.oidc_issuer.is_none() {
+        return conn;
+    }
+
+    match accounts::get_user_id(conn.headers(), db_backend, config.clone()).await {
+        Some(_) => conn,
+        None => conn
+            .with_status(302)
+            .with_header("Location", format!("{}/oidc/login", config.base_path))
+            .halt()
+    }
+}
+
+// While the database is unavailable, check whether it has come back before
+// handling each request and respond with 503 if it hasn't.
+async fn check_db_health(
+    conn: Conn, db_backend: db::DbBackend, config: Arc<TranspoConfig>) -> Conn
+{
+    let is_available = db::is_db_available() || unblock(move || {
+        db::check_connection(db_backend, &config.db_url)
+    }).await;
+
+    if is_available {
+        conn
+    } else {
+        conn
+            .with_status(503)
+            .with_header("Retry-After", DB_RETRY_AFTER_SECS.to_string())
+            .with_body("Database unavailable")
+            .halt()
+    }
 }
 
-fn addr_from_headers(headers: &Headers) -> Option<IpAddr> {
-    headers
-        .get_str(X_REAL_IP)
-        .and_then(|a| a.parse().ok())
+// Quotas and accessor counts are kept in Redis if it is configured, so that
+// they are shared by every Transpo process
+fn create_quota_store(config: &TranspoConfig) -> Arc<dyn QuotaStore> {
+    match &config.redis_url {
+        #[cfg(feature = "redis")]
+        Some(redis_url) => Arc::new(
+            redis_store::RedisQuotaStore::new(
+                redis_url, config.quota_bytes_total, config.quota_bytes_per_minute)
+                .expect("Connecting to Redis")),
+        _ => Arc::new(MemoryQuotaStore::with_state_file(
+            config.quota_bytes_total, config.quota_bytes_per_minute,
+            config.storage_dir.join(QUOTAS_FILE_NAME)))
+    }
+}
+
+fn create_access_counter(
+    config: &TranspoConfig, db_backend: db::DbBackend) -> Arc<dyn AccessCounter>
+{
+    match &config.redis_url {
+        #[cfg(feature = "redis")]
+        Some(redis_url) => Arc::new(
+            redis_store::RedisAccessCounter::new(redis_url)
+                .expect("Connecting to Redis")),
+        _ => Arc::new(DbAccessCounter::new((db_backend, config.db_url.to_owned())))
+    }
+}
+
+fn get_quotas_data(quotas: Option<Quotas>, client_ip: Option<IpAddr>) -> Option<(Quotas, IpAddr)> {
+    quotas.and_then(|q| Some((q, client_ip?)))
+}
+
+// Only record the address of the uploader if audit data is recorded
+fn get_uploader_ip(config: &TranspoConfig, client_ip: Option<IpAddr>) -> Option<IpAddr> {
+    if config.audit_retention_minutes > 0 {
+        client_ip
+    } else {
+        None
+    }
+}
+
+// Refuse uploads from clients whose quota is already used up before reading
+// anything, so that websocket clients get a proper 429 as well
+async fn check_quota(conn: Conn) -> Conn {
+    let state = conn.state::<TranspoState>().unwrap();
+    let client_ip = conn.state::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+
+    let status = match (&state.quotas, client_ip) {
+        (Some(quotas), Some(ip)) => quotas.use_quota(&ip, 0),
+        _ => None
+    };
+
+    match status {
+        Some(status) if status.remaining == 0 => {
+            count_rejection(Rejection::Quota);
+            log_security_event(SecurityEvent::QuotaExceeded, client_ip);
+            upload::quota_exceeded(conn, status)
+        },
+        _ => conn
+    }
+}
+
+// Refuse uploads from blocked networks
+async fn check_blocked_ip(conn: Conn) -> Conn {
+    let blocklist = &conn.state::<TranspoState>().unwrap().blocklist;
+    let client_ip = conn.state::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+
+    match client_ip {
+        Some(ip) if blocklist.is_ip_blocked(&ip) => {
+            count_rejection(Rejection::Blocked);
+            log_security_event(SecurityEvent::BlockedUpload, client_ip);
+            conn
+                .with_status(403)
+                .with_body("Uploads from your network are blocked")
+                .halt()
+        },
+        _ => conn
+    }
+}
+
+// Respond as if blocked uploads didn't exist
+async fn check_blocked_upload(conn: Conn) -> Conn {
+    let state = conn.state::<TranspoState>().unwrap();
+    let is_blocked = conn.param("file_id")
+        .and_then(|id| i64_from_b64_bytes(id.as_bytes()))
+        .map(|id| state.blocklist.is_upload_blocked(id))
+        .unwrap_or(false);
+
+    if !is_blocked {
+        conn
+    } else if conn.path().starts_with("/api/") {
+        http_errors::api_error(conn, 404, "The upload does not exist")
+    } else {
+        let (config, _, translation, _) = get_config(&conn);
+        http_errors::error_404(conn, config, translation)
+    }
+}
+
+// Count the upload towards the upload quota of the API key it is made with, or
+// of its client if there is none. Refuse it if the quota is used up.
+async fn check_upload_count(conn: Conn) -> Conn {
+    let upload_counts = conn.state::<TranspoState>().unwrap().upload_counts.clone();
+    let uploader = match (conn.state::<ApiKey>(), conn.state::<ClientIp>()) {
+        (Some(api_key), _) => Some(Uploader::ApiKey(api_key.id)),
+        (None, Some(ClientIp(Some(ip)))) => Some(Uploader::Address(*ip)),
+        _ => None
+    };
+
+    let status = match (upload_counts, uploader) {
+        (Some(upload_counts), Some(uploader)) => upload_counts.count_upload(uploader),
+        _ => None
+    };
+
+    match status {
+        Some(status) => {
+            count_rejection(Rejection::UploadCount);
+            // Clients with an API key are known, they aren't banned
+            if let Some(Uploader::Address(ip)) = uploader {
+                log_security_event(SecurityEvent::QuotaExceeded, Some(ip));
+            }
+            upload::quota_exceeded(conn, status)
+        },
+        None => conn
+    }
+}
+
+// Respond with the usage of the quotas which apply to the client (or to the
+// API key it presents), or null for quotas which are disabled
+async fn quota_usage(conn: Conn) -> Conn {
+    let state = conn.state::<TranspoState>().unwrap();
+    let client_ip = conn.state::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+    let api_key_id = conn.state::<ApiKey>().map(|api_key| api_key.id);
+
+    let bytes = match (&state.quotas, client_ip) {
+        (Some(quotas), Some(ip)) => quotas.use_quota(&ip, 0).map(QuotaUsage::from),
+        _ => None
+    };
+    let uploader = match (api_key_id, client_ip) {
+        (Some(id), _) => Some(Uploader::ApiKey(id)),
+        (None, Some(ip)) => Some(Uploader::Address(ip)),
+        _ => None
+    };
+    let uploads = match (&state.upload_counts, uploader) {
+        (Some(upload_counts), Some(uploader)) =>
+            Some(QuotaUsage::from(upload_counts.status(uploader))),
+        _ => None
+    };
+
+    let body = serde_json::json!({
+        "bytes": bytes,
+        "uploads": uploads
+    });
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "application/json")
+        .with_body(body.to_string())
+        .halt()
+}
+
+// Refuse new uploads and downloads while shutting down. Otherwise, count them
+// as in progress for as long as they are kept in the state of the conn.
+async fn track_in_flight(conn: Conn) -> Conn {
+    let shutdown = conn.state::<TranspoState>().unwrap().shutdown.clone();
+
+    match shutdown.begin() {
+        Some(in_flight) => conn.with_state(in_flight),
+        None => conn
+            .with_status(503)
+            .with_header("Retry-After", SHUTDOWN_RETRY_AFTER_SECS.to_string())
+            .with_body("Shutting down")
+            .halt()
+    }
+}
+
+// Count the connection towards the limit of its client, or refuse it if the
+// client has too many open already. The connection is counted for as long as
+// it is kept in the state of the conn.
+async fn limit_connections(conn: Conn) -> Conn {
+    let connections = conn.state::<TranspoState>().unwrap().connections.clone();
+    let client_ip = conn.state::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+
+    match (connections, client_ip) {
+        (Some(connections), Some(ip)) => match connections.open(ip) {
+            Some(connection) => conn.with_state(connection),
+            None => {
+                count_rejection(Rejection::Connections);
+                log_security_event(SecurityEvent::QuotaExceeded, client_ip);
+                conn
+                    .with_status(429)
+                    .with_body("Too many connections")
+                    .halt()
+            }
+        },
+        _ => conn
+    }
+}
+
+// Look up the address (and country) of the client before the conn is turned
+// into a websocket, which doesn't expose its peer
+async fn resolve_client_ip(conn: Conn) -> Conn {
+    let state = conn.state::<TranspoState>().unwrap();
+    let ip = client_ip(conn.inner().peer_ip(), conn.headers(), &state.config.trusted_proxies);
+    let country = match (&state.geoip, ip) {
+        (Some(geoip), Some(ip)) => geoip.country(ip),
+        _ => None
+    };
+    conn.with_state(ClientIp(ip)).with_state(ClientCountry(country))
+}
+
+// Refuse uploads (or downloads, with `--geoip-downloads`) from clients in
+// countries which aren't allowed
+fn check_country(conn: Conn, action: &str) -> Conn {
+    let config = &conn.state::<TranspoState>().unwrap().config;
+    if !is_restricted(config) || (action == "download" && !config.geoip_downloads) {
+        return conn;
+    }
+
+    let country = conn.state::<ClientCountry>().and_then(|ClientCountry(c)| c.as_deref());
+    if is_allowed(country, action, config) {
+        conn
+    } else {
+        count_rejection(Rejection::Country);
+        if action == "upload" {
+            let client_ip = conn.state::<ClientIp>().and_then(|ClientIp(ip)| *ip);
+            log_security_event(SecurityEvent::BlockedUpload, client_ip);
+        }
+        conn
+            .with_status(403)
+            .with_body(format!("This {} is not allowed from your country", action))
+            .halt()
+    }
+}
+
+async fn check_upload_country(conn: Conn) -> Conn {
+    check_country(conn, "upload")
+}
+
+async fn check_download_country(conn: Conn) -> Conn {
+    check_country(conn, "download")
 }
 
 // query -> cookie -> default
@@ -154,23 +622,66 @@ fn trillium_main(
     let quotas = if config.quota_bytes_total == 0 {
         None
     } else {
-        Some(Quotas::from(config.as_ref()))
+        Some(Quotas::new(&config, create_quota_store(&config)))
+    };
+    let accessors = Accessors::new(create_access_counter(&config, db_backend));
+    let tokens = DownloadTokens::new();
+    let connections = match config.max_connections_per_ip {
+        0 => None,
+        n => Some(ClientConnections::new(n))
     };
-    let accessors = Accessors::new();
 
-    if let Some(quotas) = quotas.clone() {
-        spawn_quotas_thread(quotas);
+    let upload_counts = match config.quota_uploads {
+        0 => None,
+        _ => Some(UploadCounts::new(&config))
+    };
+
+    spawn_quotas_thread(quotas.clone(), upload_counts.clone());
+
+    let shutdown = Shutdown::new();
+    spawn_signal_thread(
+        shutdown.clone(),
+        Duration::from_secs(config.drain_timeout_seconds as u64));
+
+    let blocklist = Blocklist::new();
+    if let Some(db_connection) = db::establish_connection(db_backend, &config.db_url) {
+        blocklist.reload(&db_connection);
+    }
+    spawn_blocklist_thread(blocklist.clone(), db_backend, config.db_url.to_owned());
+
+    let geoip = config.geoip_db.as_ref().map(|path| Arc::new(
+        GeoIp::open(path).expect("Reading GeoIP database")));
+
+    let bandwidth = match config.max_bandwidth_bytes_per_second {
+        0 => None,
+        n => Some(download::Bandwidth::new(n as u64))
+    };
+
+    #[cfg(feature = "grpc")]
+    if config.grpc_port != 0 {
+        grpc::spawn_grpc_server(
+            config.clone(), accessors.clone(), bandwidth.clone(),
+            blocklist.clone(), shutdown.clone(), db_backend);
     }
 
     let s = TranspoState {
         config: config.clone(),
         translations: translations.clone(),
         accessors: accessors.clone(),
+        tokens: tokens.clone(),
         quotas: quotas.clone(),
+        upload_counts,
+        bandwidth,
+        connections,
+        blocklist: blocklist.clone(),
+        geoip,
+        shutdown: shutdown.clone()
     };
 
     let router = Router::new()
-        .get("/", (state(s.clone()), move |mut conn: Conn| { async move {
+        .get("/", (state(s.clone()), move |conn: Conn| {
+            require_oidc_login(conn, db_backend)
+        }, move |mut conn: Conn| { async move {
             let (config, translations, translation, lang) = get_config(&conn);
             set_lang_cookie(&mut conn, &lang);
 
@@ -189,36 +700,240 @@ fn trillium_main(
 
             conn.render(about).halt()
         }}))
-        .get("/paste", (state(s.clone()), move |mut conn: Conn| { async move {
+        .get("/gallery", (state(s.clone()), move |mut conn: Conn| { async move {
+            let (config, translations, translation, lang) = get_config(&conn);
+            set_lang_cookie(&mut conn, &lang);
+            gallery::handle(conn, config, translations, translation, lang, db_backend).await
+        }}))
+        .get("/gallery/feed", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            gallery::feed(conn, config, translation, db_backend).await
+        }}))
+        .get("/paste", (state(s.clone()), move |conn: Conn| {
+            require_oidc_login(conn, db_backend)
+        }, move |mut conn: Conn| { async move {
             let (config, translations, translation, lang) = get_config(&conn);
             set_lang_cookie(&mut conn, &lang);
             let paste = PasteTemplate::new(&config, translations.names(), &lang, translation);
 
             conn.render(paste).halt()
         }}))
-        .post("/upload", (state(s.clone()), move |mut conn: Conn| { async move {
+        .post("/upload", (state(s.clone()), track_in_flight, resolve_client_ip, check_upload_country, check_blocked_ip, check_quota, limit_connections, move |conn: Conn| {
+            check_upload_api_key(conn, db_backend)
+        }, check_upload_count, move |mut conn: Conn| { async move {
             let (config, _, translation, _) = get_config(&conn);
             let state = conn.take_state::<TranspoState>().unwrap();
-            let quotas_data = get_quotas_data(state.quotas, conn.headers());
+            let config = match conn.take_state::<ApiKey>() {
+                Some(api_key) => apply_limits(config, &api_key),
+                None => config
+            };
+            let ClientIp(ip) = conn.take_state::<ClientIp>().unwrap();
+            let quotas_data = get_quotas_data(state.quotas, ip);
+            let uploader_ip = get_uploader_ip(&config, ip);
+            let owner_id = accounts::get_user_id(
+                conn.headers(), db_backend, config.clone()).await;
 
-            upload::handle_post(conn, config, translation, db_backend, quotas_data).await
+            upload::handle_post(
+                conn, upload::UploadResponse::Page, config, translation, state.accessors,
+                db_backend, quotas_data, uploader_ip, owner_id)
+                .instrument(info_span!("upload", id = field::Empty))
+                .await
         }}))
-        .get("/upload", (state(s.clone()), websocket(move |mut conn: WebSocketConn| { async move {
+        .get("/upload", (state(s.clone()), track_in_flight, resolve_client_ip, check_upload_country, check_blocked_ip, check_quota, limit_connections, move |conn: Conn| {
+            check_upload_api_key(conn, db_backend)
+        }, check_upload_count, websocket(move |mut conn: WebSocketConn| { async move {
             let state = conn.take_state::<TranspoState>().unwrap();
-            let quotas_data = get_quotas_data(state.quotas, conn.headers());
+            let config = match conn.take_state::<ApiKey>() {
+                Some(api_key) => apply_limits(state.config, &api_key),
+                None => state.config
+            };
+            let ClientIp(ip) = conn.take_state::<ClientIp>().unwrap();
+            let quotas_data = get_quotas_data(state.quotas, ip);
+            let uploader_ip = get_uploader_ip(&config, ip);
+            let owner_id = accounts::get_user_id(
+                conn.headers(), db_backend, config.clone()).await;
+            // The websocket outlives the span of the request which opened it
+            let request_id = conn.take_state::<RequestId>()
+                .map(|RequestId(id)| id)
+                .unwrap_or_default();
 
-            drop(upload::handle_websocket(conn, state.config, db_backend, quotas_data).await)
+            drop(upload::handle_websocket(
+                    conn, config, state.accessors, db_backend, quotas_data,
+                    uploader_ip, owner_id)
+                .instrument(info_span!("upload", id = field::Empty, %request_id))
+                .await)
         }}).with_protocol_config(WS_UPLOAD_CONFIG)))
+        .post("/signup", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            accounts::signup(conn, config, translation, db_backend).await
+        }}))
+        .post("/login", (state(s.clone()), resolve_client_ip, move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            accounts::login(conn, config, translation, db_backend).await
+        }}))
+        .post("/account/email", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            accounts::set_email(conn, config, translation, db_backend).await
+        }}))
+        .post("/logout", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            accounts::logout(conn, config, db_backend).await
+        }}))
+        .get("/oidc/login", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            oidc::login(conn, config, translation).await
+        }}))
+        .get("/oidc/callback", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            oidc::callback(conn, config, translation, db_backend).await
+        }}))
+        .get("/api/v1/quota", (state(s.clone()), resolve_client_ip, move |conn: Conn| {
+            check_upload_api_key(conn, db_backend)
+        }, quota_usage))
+        .post("/api/v1/uploads", (state(s.clone()), track_in_flight, resolve_client_ip, check_upload_country, check_blocked_ip, check_quota, limit_connections, move |conn: Conn| {
+            check_upload_api_key(conn, db_backend)
+        }, check_upload_count, move |mut conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            let state = conn.take_state::<TranspoState>().unwrap();
+            let config = match conn.take_state::<ApiKey>() {
+                Some(api_key) => apply_limits(config, &api_key),
+                None => config
+            };
+            let ClientIp(ip) = conn.take_state::<ClientIp>().unwrap();
+            let quotas_data = get_quotas_data(state.quotas, ip);
+            let uploader_ip = get_uploader_ip(&config, ip);
+            let owner_id = accounts::get_user_id(
+                conn.headers(), db_backend, config.clone()).await;
+
+            upload::handle_post(
+                conn, upload::UploadResponse::Api, config, translation, state.accessors,
+                db_backend, quotas_data, uploader_ip, owner_id)
+                .instrument(info_span!("upload", id = field::Empty))
+                .await
+        }}))
+        .get("/api/v1/uploads", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            api::list(conn, config, db_backend).await
+        }}))
+        .get("/api/v1/uploads/:file_id", (state(s.clone()), check_blocked_upload, resolve_client_ip, move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            api::info(
+                conn, file_id, state.config, state.accessors, state.tokens,
+                db_backend).await
+        }}))
+        .delete("/api/v1/uploads/:file_id", (state(s.clone()), move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            api::delete(conn, file_id, state.config, state.accessors, db_backend).await
+        }}))
+        .get("/api/v1/reports", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            reports::list(conn, config, db_backend).await
+        }}))
+        .delete("/api/v1/reports/:file_id", (state(s.clone()), move |conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let (config, _, _, _) = get_config(&conn);
+            reports::dismiss(conn, file_id, config, db_backend).await
+        }}))
+        .get("/api/v1/blocklist", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            blocklist::list(conn, config, db_backend).await
+        }}))
+        .post("/api/v1/blocklist/networks", (state(s.clone()), move |mut conn: Conn| { async move {
+            let state = conn.take_state::<TranspoState>().unwrap();
+            blocklist::block_network(conn, state.config, state.blocklist, db_backend).await
+        }}))
+        .delete("/api/v1/blocklist/networks/:id", (state(s.clone()), move |mut conn: Conn| { async move {
+            let id = conn.param("id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+            blocklist::unblock_network(conn, id, state.config, state.blocklist, db_backend).await
+        }}))
+        .post("/api/v1/blocklist/uploads", (state(s.clone()), move |mut conn: Conn| { async move {
+            let state = conn.take_state::<TranspoState>().unwrap();
+            blocklist::block_upload(conn, state.config, state.blocklist, db_backend).await
+        }}))
+        .delete("/api/v1/blocklist/uploads/:file_id", (state(s.clone()), move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+            blocklist::unblock_upload(conn, file_id, state.config, state.blocklist, db_backend).await
+        }}))
+        .put("/api/federation/uploads/:file_id", (state(s.clone()), move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+            federation::receive(conn, file_id, state.config, state.blocklist, db_backend).await
+        }}))
+        .get("/api/federation/uploads/:file_id", (state(s.clone()), move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+            federation::send(conn, file_id, state.config, state.blocklist, db_backend).await
+        }}))
+        .delete("/api/federation/uploads/:file_id", (state(s.clone()), move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let state = conn.take_state::<TranspoState>().unwrap();
+            federation::remove(conn, file_id, state.config, state.accessors, db_backend).await
+        }}))
+        .get("/api/openapi.json", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            openapi::handle(conn, config)
+        }}))
+        .get("/api/docs", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            openapi::handle_docs(conn, config, translation)
+        }}))
+        .get("/api/sharex", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            uploader_config::sharex(conn, config)
+        }}))
+        .get("/api/ishare", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            uploader_config::ishare(conn, config)
+        }}))
+        .get("/account", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            accounts::usage(conn, config, db_backend).await
+        }}))
+        .get("/stats", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            stats::handle(conn, config, db_backend).await
+        }}))
+        .post("/api/admin/cleanup", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            cleanup::handle(conn, config, db_backend).await
+        }}))
+        .get("/stats/db", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            stats::handle_db(conn, config, db_backend).await
+        }}))
+        .get("/metrics", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, _, _) = get_config(&conn);
+            stats::handle_metrics(conn, config, db_backend).await
+        }}))
+        .post("/zip", (state(s.clone()), track_in_flight, resolve_client_ip, check_download_country, limit_connections, move |mut conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            download::handle_zip(
+                conn, config, state.accessors, state.bandwidth, translation, db_backend)
+                .instrument(info_span!("zip"))
+                .await
+        }}))
         .get("/:file_id", (state(s.clone()), move |conn: Conn| { async move {
             let file_id = conn.param("file_id").unwrap().to_owned();
             let (config, _, translation, _) = get_config(&conn);
 
             let mut has_password = true;
             let mut is_paste = false;
+            let mut derived_key = false;
+            let mut recipient_key = false;
             for field in conn.querystring().split('&') {
                 match field {
                     "nopass" => has_password = false,
                     "paste" => is_paste = true,
+                    "derived" => derived_key = true,
+                    "recipient" => recipient_key = true,
                     _ => {}
                 }
             }
@@ -236,6 +951,8 @@ fn trillium_main(
                         file_id,
                         app_name: &config.app_name,
                         has_password,
+                        derived_key,
+                        recipient_key,
                         t: translation
                     })
                 };
@@ -245,22 +962,82 @@ fn trillium_main(
                 http_errors::error_404(conn, config, translation)
             }
         }}))
-        .get("/:file_id/info", (state(s.clone()), move |mut conn: Conn| { async move {
+        .get("/:file_id/info", (state(s.clone()), check_blocked_upload, resolve_client_ip, move |mut conn: Conn| { async move {
             let file_id = conn.param("file_id").unwrap().to_owned();
             let (_, _, translation, _) = get_config(&conn);
             let state = conn.take_state::<TranspoState>().unwrap();
 
             download::info(
                 conn, file_id, state.config,
-                state.accessors, translation, db_backend).await
+                state.accessors, state.tokens, translation, db_backend).await
+        }}))
+        .get("/:file_id/entries", (state(s.clone()), check_blocked_upload, resolve_client_ip, move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let (_, _, translation, _) = get_config(&conn);
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            download::entries(
+                conn, file_id, state.config,
+                state.accessors, state.tokens, translation, db_backend).await
+        }}))
+        .get("/:file_id/events", (state(s.clone()), check_blocked_upload, resolve_client_ip, limit_connections, move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let (_, _, translation, _) = get_config(&conn);
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            events::handle(
+                conn, file_id, state.config, state.accessors, state.tokens,
+                state.shutdown, translation, db_backend).await
+        }}))
+        .get("/:file_id/dl", (state(s.clone()), check_blocked_upload, track_in_flight, resolve_client_ip, check_download_country, limit_connections, move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let (config, _, translation, _) = get_config(&conn);
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            let span = info_span!("download", id = %file_id);
+            download::handle(
+                conn, file_id, config, state.accessors, state.tokens, state.bandwidth,
+                translation, db_backend)
+                .instrument(span)
+                .await
         }}))
-        .get("/:file_id/dl", (state(s.clone()), move |mut conn: Conn| { async move {
+        // The download page submits the password in the request body so
+        // that it doesn't end up in access logs
+        .post("/:file_id/dl", (state(s.clone()), check_blocked_upload, track_in_flight, resolve_client_ip, check_download_country, limit_connections, move |mut conn: Conn| { async move {
             let file_id = conn.param("file_id").unwrap().to_owned();
             let (config, _, translation, _) = get_config(&conn);
             let state = conn.take_state::<TranspoState>().unwrap();
 
+            let span = info_span!("download", id = %file_id);
             download::handle(
-                conn, file_id, config, state.accessors, translation, db_backend).await
+                conn, file_id, config, state.accessors, state.tokens, state.bandwidth,
+                translation, db_backend)
+                .instrument(span)
+                .await
+        }}))
+        .post("/:file_id/token", (state(s.clone()), check_blocked_upload, resolve_client_ip, move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let (config, _, translation, _) = get_config(&conn);
+            let state = conn.take_state::<TranspoState>().unwrap();
+
+            download::token(
+                conn, file_id, config, state.accessors, state.tokens,
+                translation, db_backend).await
+        }}))
+        .post("/:file_id/report", (state(s.clone()), resolve_client_ip, move |mut conn: Conn| { async move {
+            let file_id = conn.param("file_id").unwrap().to_owned();
+            let (config, _, translation, _) = get_config(&conn);
+            let ClientIp(ip) = conn.take_state::<ClientIp>().unwrap();
+
+            reports::report(conn, file_id, config, translation, ip, db_backend).await
+        }}))
+        .get("/health", move |conn: Conn| { async move {
+            // Requests only get this far if the database is available
+            conn.with_status(200).with_body("OK").halt()
+        }})
+        .get("/version", (state(s.clone()), move |conn: Conn| { async move {
+            let (config, _, translation, _) = get_config(&conn);
+            version::handle(conn, config, translation)
         }}))
         .get("/clear-data", move |conn: Conn| { async move {
             conn
@@ -278,8 +1055,63 @@ fn trillium_main(
             http_errors::error_404(conn, config, translation)
         }}));
 
-    trillium_smol::config()
-        .with_host("0.0.0.0")
-        .with_port(config.port as u16)
-        .run(router);
+    let health_config = config.clone();
+    let db_health = move |conn: Conn| {
+        check_db_health(conn, db_backend, health_config.clone())
+    };
+
+    // Serve everything under the base path. Pages use relative links, so the
+    // base path itself needs a trailing slash for them to resolve correctly.
+    let router = if config.base_path.is_empty() {
+        router
+    } else {
+        Router::new().all(format!("{}/*", config.base_path), router)
+    };
+    let base_path = config.base_path.clone();
+    let base_path_redirect = move |conn: Conn| {
+        let base_path = base_path.clone();
+        async move {
+            if !base_path.is_empty() && conn.path() == base_path {
+                conn
+                    .with_status(301)
+                    .with_header("Location", format!("{}/", base_path))
+                    .halt()
+            } else {
+                conn
+            }
+        }
+    };
+
+    let onion_location = onion::OnionLocation::new(&config);
+
+    let handler = Arc::new(WithRequestId((onion_location, base_path_redirect, db_health, router)));
+    let mut addresses = config.listen_addresses();
+    let last_address = addresses.pop().unwrap();
+
+    // Each additional address gets its own listener thread
+    for address in addresses {
+        let handler = handler.clone();
+        let stopper = shutdown.stopper();
+        let config = config.clone();
+        std::thread::spawn(move || run_server(address, handler, &config, stopper));
+    }
+
+    run_server(last_address, handler, &config, shutdown.stopper());
+}
+
+// Signals are handled by `spawn_signal_thread`, which stops the server once
+// it has been drained
+fn run_server<H: trillium::Handler>(
+    address: SocketAddr, handler: H, config: &TranspoConfig, stopper: Stopper)
+{
+    if config.proxy_protocol {
+        proxy_protocol::run(address, handler, config.trusted_proxies.clone(), stopper);
+    } else {
+        trillium_smol::config()
+            .with_host(&address.ip().to_string())
+            .with_port(address.port())
+            .with_stopper(stopper)
+            .without_signals()
+            .run(handler);
+    }
 }
@@ -0,0 +1,310 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tracing::warn;
+
+
+// Upper bounds (in milliseconds) of the buckets of latency histograms. Values
+// above the last bound are counted in an extra bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [1, 5, 10, 25, 50, 100, 250, 1000, 5000, 15000];
+const NUM_BUCKETS: usize = LATENCY_BUCKETS_MS.len() + 1;
+
+// Queries slower than this are logged as they happen
+const SLOW_QUERY_MS: u128 = 1000;
+
+
+pub struct Histogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64
+}
+
+// A copy of the values of a histogram at some point in time
+pub struct HistogramSnapshot {
+    // number of values in each bucket (not cumulative)
+    pub buckets: [u64; NUM_BUCKETS],
+    pub count: u64,
+    pub sum_micros: u64
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; NUM_BUCKETS],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0)
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let millis = duration.as_millis();
+        let bucket = LATENCY_BUCKETS_MS.iter()
+            .position(|bound| millis <= *bound as u128)
+            .unwrap_or(NUM_BUCKETS - 1);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut buckets = [0; NUM_BUCKETS];
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            buckets[i] = bucket.load(Ordering::Relaxed);
+        }
+
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed)
+        }
+    }
+}
+
+
+// The kinds of database queries whose duration is measured
+#[derive(Clone, Copy, Debug)]
+pub enum QueryKind {
+    Insert,
+    SelectWithId,
+    Decrement,
+    Delete
+}
+
+pub const QUERY_KINDS: [QueryKind; 4] = [
+    QueryKind::Insert,
+    QueryKind::SelectWithId,
+    QueryKind::Decrement,
+    QueryKind::Delete
+];
+
+impl QueryKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueryKind::Insert => "insert",
+            QueryKind::SelectWithId => "select_with_id",
+            QueryKind::Decrement => "decrement",
+            QueryKind::Delete => "delete"
+        }
+    }
+}
+
+static QUERY_DURATIONS: [Histogram; QUERY_KINDS.len()] = [
+    Histogram::new(),
+    Histogram::new(),
+    Histogram::new(),
+    Histogram::new()
+];
+
+pub fn observe_query(kind: QueryKind, duration: Duration) {
+    QUERY_DURATIONS[kind as usize].observe(duration);
+
+    if duration.as_millis() > SLOW_QUERY_MS {
+        warn!(query = kind.name(), ms = duration.as_millis() as u64, "Slow database query");
+    }
+}
+
+pub fn query_durations() -> Vec<(QueryKind, HistogramSnapshot)> {
+    QUERY_KINDS.iter()
+        .map(|kind| (*kind, QUERY_DURATIONS[*kind as usize].snapshot()))
+        .collect()
+}
+
+
+// Uploads and downloads in progress
+static ACTIVE_UPLOADS: AtomicI64 = AtomicI64::new(0);
+static ACTIVE_DOWNLOADS: AtomicI64 = AtomicI64::new(0);
+
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+pub enum Transfer {
+    Upload,
+    Download
+}
+
+// An upload or download counted as active until this is dropped
+pub struct ActiveTransfer(Transfer);
+
+impl ActiveTransfer {
+    pub fn new(transfer: Transfer) -> Self {
+        active_transfers(transfer).fetch_add(1, Ordering::Relaxed);
+        Self(transfer)
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        active_transfers(self.0).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn active_transfers(transfer: Transfer) -> &'static AtomicI64 {
+    match transfer {
+        Transfer::Upload => &ACTIVE_UPLOADS,
+        Transfer::Download => &ACTIVE_DOWNLOADS
+    }
+}
+
+// Return the number of uploads or downloads in progress
+pub fn num_active(transfer: Transfer) -> i64 {
+    active_transfers(transfer).load(Ordering::Relaxed)
+}
+
+pub fn add_bytes_received(bytes: usize) {
+    BYTES_RECEIVED.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn add_bytes_sent(bytes: usize) {
+    BYTES_SENT.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+
+// The reasons for which uploads and downloads are refused
+#[derive(Clone, Copy)]
+pub enum Rejection {
+    Quota,
+    UploadCount,
+    Connections,
+    Storage,
+    AccountQuota,
+    Blocked,
+    Country,
+    SlowClient
+}
+
+const REJECTIONS: [Rejection; 8] = [
+    Rejection::Quota,
+    Rejection::UploadCount,
+    Rejection::Connections,
+    Rejection::Storage,
+    Rejection::AccountQuota,
+    Rejection::Blocked,
+    Rejection::Country,
+    Rejection::SlowClient
+];
+
+impl Rejection {
+    fn name(&self) -> &'static str {
+        match self {
+            Rejection::Quota => "quota",
+            Rejection::UploadCount => "upload_count",
+            Rejection::Connections => "connections",
+            Rejection::Storage => "storage",
+            Rejection::AccountQuota => "account_quota",
+            Rejection::Blocked => "blocked",
+            Rejection::Country => "country",
+            Rejection::SlowClient => "slow_client"
+        }
+    }
+}
+
+static REJECTION_COUNTS: [AtomicU64; REJECTIONS.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0)
+];
+
+pub fn count_rejection(rejection: Rejection) {
+    REJECTION_COUNTS[rejection as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+
+// Number of uploads deleted by cleanups (excluding dry runs) since the server
+// started, by kind (see `CleanupReport`)
+const CLEANUP_KINDS: [&'static str; 4] = ["expired", "purged", "broken", "missing"];
+
+static CLEANUP_COUNTS: [AtomicU64; CLEANUP_KINDS.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0)
+];
+
+pub fn count_cleanup(expired: usize, purged: usize, broken: usize, missing: usize) {
+    for (count, n) in CLEANUP_COUNTS.iter().zip([expired, purged, broken, missing]) {
+        count.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+
+// Return all metrics in the Prometheus text format. The storage usage is read
+// from the database, so it is None if that failed.
+pub fn render_prometheus(storage_used: Option<i64>, storage_capacity: usize) -> String {
+    let mut out = String::new();
+
+    // Writing to a String can't fail
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, value) in samples {
+            writeln!(out, "{}{} {}", name, labels, value).unwrap();
+        }
+    };
+    let sample = |value: String| vec![(String::new(), value)];
+
+    metric("transpo_active_uploads", "gauge", "Uploads in progress",
+        &sample(ACTIVE_UPLOADS.load(Ordering::Relaxed).to_string()));
+    metric("transpo_active_downloads", "gauge", "Downloads in progress",
+        &sample(ACTIVE_DOWNLOADS.load(Ordering::Relaxed).to_string()));
+    metric("transpo_received_bytes_total", "counter", "Bytes received from uploads",
+        &sample(BYTES_RECEIVED.load(Ordering::Relaxed).to_string()));
+    metric("transpo_sent_bytes_total", "counter", "Bytes sent to downloads",
+        &sample(BYTES_SENT.load(Ordering::Relaxed).to_string()));
+
+    if let Some(storage_used) = storage_used {
+        metric("transpo_storage_used_bytes", "gauge", "Size of all stored uploads",
+            &sample(storage_used.to_string()));
+    }
+    metric("transpo_storage_capacity_bytes", "gauge", "Maximum size of all stored uploads",
+        &sample(storage_capacity.to_string()));
+
+    let rejections: Vec<_> = REJECTIONS.iter()
+        .map(|r| (
+            format!("{{reason=\"{}\"}}", r.name()),
+            REJECTION_COUNTS[*r as usize].load(Ordering::Relaxed).to_string()))
+        .collect();
+    metric("transpo_rejections_total", "counter", "Uploads and downloads refused, by reason",
+        &rejections);
+
+    let cleanups: Vec<_> = CLEANUP_KINDS.iter().zip(&CLEANUP_COUNTS)
+        .map(|(kind, count)| (
+            format!("{{kind=\"{}\"}}", kind),
+            count.load(Ordering::Relaxed).to_string()))
+        .collect();
+    metric("transpo_cleanup_uploads_total", "counter", "Uploads deleted by cleanups, by kind",
+        &cleanups);
+
+    // Buckets are cumulative in the Prometheus format
+    let mut queries = Vec::new();
+    for (kind, snapshot) in query_durations() {
+        let mut cumulative = 0;
+        for (i, count) in snapshot.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match LATENCY_BUCKETS_MS.get(i) {
+                Some(bound) => (*bound as f64 / 1000.0).to_string(),
+                None => "+Inf".to_string()
+            };
+            queries.push((
+                format!("_bucket{{query=\"{}\",le=\"{}\"}}", kind.name(), bound),
+                cumulative.to_string()));
+        }
+        queries.push((
+            format!("_sum{{query=\"{}\"}}", kind.name()),
+            (snapshot.sum_micros as f64 / 1_000_000.0).to_string()));
+        queries.push((
+            format!("_count{{query=\"{}\"}}", kind.name()),
+            snapshot.count.to_string()));
+    }
+    metric("transpo_db_query_duration_seconds", "histogram", "Durations of database queries",
+        &queries);
+
+    out
+}
@@ -1,168 +1,315 @@
 // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Content-Disposition
 // https://developer.mozilla.org/en-US/docs/Web/HTTP/Methods/POST
 
+use crate::b64::Base64Decoder;
+use crate::constants::{FORM_READ_BUFFER_SIZE, MAX_FORM_BOUNDARY_LENGTH};
+
 use std::{cmp, str};
+use std::borrow::Cow;
+use std::ops::Range;
 
 const CD_PREFIX: &'static [u8] = b"Content-Disposition: ";
-const CD_PREFIX_BYTE_MAP: &'static [bool] = &cd_prefix_byte_map();
 const CT_PREFIX: &'static [u8] = b"Content-Type: ";
-const CT_PREFIX_BYTE_MAP: &'static [bool] = &ct_prefix_byte_map();
+const CTE_PREFIX: &'static [u8] = b"Content-Transfer-Encoding: ";
 const TERMINATOR: &'static [u8] = b"--"; // Come with me if you want to live.
 const NEWLINE: &'static [u8] = b"\r\n";
 const NEWLINE_BYTE_MAP: &'static [bool] = &newline_byte_map();
+// the blank line after the headers of a field
+const HEADERS_END: &'static [u8] = b"\r\n\r\n";
+// The headers of a field have to be held in memory all at once, so longer
+// headers are rejected
+const MAX_HEADERS_LENGTH: usize = FORM_READ_BUFFER_SIZE;
+// Content-Type of a field whose value is a nested form, i.e. several files
+// sent as one field (as in HTML 4)
+const NESTED_FORM_CT: &'static str = "multipart/mixed";
 
 pub enum ParseResult<'a> {
-    // There is a separate `bytes` field because the number of bytes parsed can
-    // differ from the size of the `value` that gets returned because of the
-    // additional leading data which prefixes the actual value.
-    //
-    //       bytes  c-disp   c-type   value
-    NewValue(usize, &'a str, &'a str, &'a [u8]),
-    //       value
-    Continue(&'a [u8]),
+    // The start of a new field in the form. Content-Type and
+    // Content-Transfer-Encoding are empty if the field doesn't have them.
+    //       c-disp   c-type   c-t-enc
+    NewField(&'a str, &'a str, &'a str),
+    // The start of a new field in a form nested in the value of the last
+    // `NewField`
+    //          c-disp   c-type   c-t-enc
+    NestedField(&'a str, &'a str, &'a str),
+    // A piece of the value of the current field
+    Value(&'a [u8]),
     NeedMoreData,
     Finished,
     Error
 }
 
-// This is a stateless parser for multipart POST requests.
-//
-// Returns the length of the data parsed, what was parsed and, if it is a new
-// form field, the Content-Disposition (and Content-Type if it has one).
-//
-// Subsequent calls to this function MUST guarantee that `buf` begins where
-// parsing last stopped, i.e. the elements of buf starting at the index where
-// parsing last stopped should be copied to the beginning of buf.
-//
-// Parsing is "stopped" when a conclusive result is returned (`NewValue` or
-// `Continue`). If `NeedMoreData` is returned, more data should be read, but
-// this data should be appended to the value passed as `buf` as the previous
-// parse attempt required more data in order to return a conclusive result.
+#[derive(Clone, Copy)]
+enum State {
+    // At a boundary, i.e. the start of a field or the end of the form
+    Boundary,
+    Headers,
+    Value,
+    // The rest of a value which held a nested form, after the form ended
+    Epilogue,
+    Finished,
+    Error
+}
+
+// What was parsed, as ranges of the parser's buffer, so that the state can
+// be updated before the buffer is borrowed
+enum Step {
+    NewField(Range<usize>, Range<usize>, Range<usize>),
+    Value(Range<usize>),
+    // (ranges of the buffer of the nested parser)
+    NestedField(Range<usize>, Range<usize>, Range<usize>),
+    NestedValue(Range<usize>),
+    NeedMoreData,
+    Finished,
+    Error
+}
+
+// A parser for multipart POST requests, which is given the body as it is
+// read and returns the fields in it piece by piece.
 //
-// Parsing is finished when `Finished` or `Error` is returned.
+// Whatever can't be parsed yet (headers which are cut off by the end of a
+// read, or the end of a value which may be the start of a boundary) is kept
+// until more data is pushed, so reads may be split anywhere.
 //
-// `boundary` MUST begin with "\r\n--"
-pub fn parse<'a, B>(buf: &'a [u8], boundary: B, boundary_byte_map: &[bool]) -> ParseResult<'a>
-where B: AsRef<[u8]>
-{
-    let boundary = boundary.as_ref();
+// A field whose value is a multipart/mixed form is parsed by a nested parser,
+// which is given the value. Its fields are returned as `NestedField`s, and
+// their values as the values of the outer field. Forms are only nested once.
+pub struct MultipartParser {
+    boundary: Vec<u8>,
+    boundary_byte_map: [bool; u8::MAX as usize + 1],
+    buf: Vec<u8>,
+    // index in `buf` up to which it has been parsed
+    start: usize,
+    state: State,
+    // parser for the form in the value of the current field, if it has one
+    nested: Option<Box<MultipartParser>>,
+    is_nested: bool
+}
 
-    if let Some(buf) = buf.strip_prefix(boundary) {
-        // This is either the end of the form or the start of a new form field
-        if buf.starts_with(TERMINATOR) {
-            // This is the end of the form
-            ParseResult::Finished
-        } else {
-            // Extract the content-disposition and content-type from the value,
-            // or return early if the form is malformed or potentially cut off
-            // by the end of the buffer, requiring another read.
-            let parse_result = try_strip_prefix(buf, NEWLINE, NEWLINE_BYTE_MAP)
-                .and_then(|buf| try_strip_prefix(buf, CD_PREFIX, CD_PREFIX_BYTE_MAP))
-                .and_then(|buf| Ok((buf, try_find_subslice(buf, NEWLINE, NEWLINE_BYTE_MAP)?)))
-                .and_then(|(buf, cd_len)| {
-                    let after_cd = &buf[(cd_len + NEWLINE.len())..];
-                    match try_strip_prefix(after_cd, CT_PREFIX, CT_PREFIX_BYTE_MAP) {
-                        Err(ParseResult::NeedMoreData) => {
-                            // There is possibly an incomplete Content-Type prefix
-                            return Err(ParseResult::NeedMoreData)
-                        },
-                        Err(ParseResult::Error) => {
-                            // There is no Content-Type
-                            Ok((buf, cd_len, false, 0))
-                        },
-                        Ok(after_cd) => {
-                            // There is a Content-Type
-                            let ct_len = try_find_subslice(after_cd, NEWLINE, NEWLINE_BYTE_MAP)?;
-                            Ok((buf, cd_len, true, ct_len))
-                        }
-                        // This case will not happen (see `try_strip_prefix`).
-                        // It is only here to appease the compiler.
-                        _ => Err(ParseResult::Error)
-                    }});
-
-            let (buf, cd_len, has_ct, ct_len) = match parse_result {
-                Ok(values) => values,
-                Err(result) => return result
-            };
+impl MultipartParser {
+    // `boundary` MUST begin with "\r\n--"
+    pub fn new(boundary: &[u8]) -> Self {
+        Self {
+            boundary: boundary.to_owned(),
+            boundary_byte_map: byte_map(boundary),
+            // Make the first boundary start with a newline to simplify parsing
+            buf: NEWLINE.to_vec(),
+            start: 0,
+            state: State::Boundary,
+            nested: None,
+            is_nested: false
+        }
+    }
 
-            // This is a new field in the form
+    // Add data which was read from the body of the request
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.drain(..self.start);
+        self.start = 0;
 
-            // New fields always have a Content-Disposition
-            let cd_str = match str::from_utf8(&buf[..cd_len]) {
-                Ok(cd_str) => cd_str,
-                Err(_) => return ParseResult::Error
-            };
-            let cd_total_len = CD_PREFIX.len() + cd_len + NEWLINE.len();
-
-            // New fields do *not* always have a Content-Type
-            //
-            // We *DON'T* use cd_total_len here because it includes the length
-            // of CD_PREFIX which is stripped off the value of `buf` in this
-            // scope!
-            let (ct_str, ct_total_len) = if has_ct {
-                let ct_total_len = CT_PREFIX.len() + ct_len + 2 * NEWLINE.len();
-                // Length of the contents of buf that come before the content type
-                let before_len = cd_len + NEWLINE.len() + CT_PREFIX.len();
-                let ct_str = match str::from_utf8(&buf[before_len..][..ct_len]) {
-                    Ok(ct_str) => ct_str,
-                    Err(_) => return ParseResult::Error
-                };
-                (ct_str, ct_total_len)
-            } else {
-                // When there is no Content-Type, there is still a blank line
-                ("", NEWLINE.len())
-            };
+        // (anything after the end of the form is discarded)
+        if let State::Boundary | State::Headers | State::Value | State::Epilogue = self.state {
+            self.buf.extend_from_slice(data);
+        }
+    }
 
-            let value_start_index = cd_len + NEWLINE.len() + ct_total_len;
-            if value_start_index < buf.len() {
-                let value = &buf[(cd_len + NEWLINE.len() + ct_total_len)..];
-                let value_len = find_value_len(value, boundary, boundary_byte_map);
-
-                let leading_len = boundary.len()
-                    + NEWLINE.len()
-                    + cd_total_len
-                    + ct_total_len;
-
-                ParseResult::NewValue(
-                    leading_len + value_len,
-                    cd_str, ct_str,
-                    &value[..value_len])
-            } else {
+    // Return what comes next in the form. If `NeedMoreData` is returned, more
+    // data should be pushed before calling this again. Parsing is finished
+    // when `Finished` or `Error` is returned.
+    pub fn next(&mut self) -> ParseResult<'_> {
+        // (a nested form is parsed while the value it is in is)
+        let step = match (&self.nested, self.state) {
+            (Some(_), State::Value) => self.step_nested(),
+            _ => self.step()
+        };
+        let nested_buf = self.nested.as_ref()
+            .map(|nested| nested.buf.as_slice())
+            .unwrap_or_default();
+
+        match step {
+            Step::NewField(cd, ct, cte) => ParseResult::NewField(
+                header(&self.buf, cd), header(&self.buf, ct), header(&self.buf, cte)),
+            Step::Value(value) => ParseResult::Value(&self.buf[value]),
+            Step::NestedField(cd, ct, cte) => ParseResult::NestedField(
+                header(nested_buf, cd), header(nested_buf, ct), header(nested_buf, cte)),
+            Step::NestedValue(value) => ParseResult::Value(&nested_buf[value]),
+            Step::NeedMoreData => ParseResult::NeedMoreData,
+            Step::Finished => ParseResult::Finished,
+            Step::Error => {
+                self.state = State::Error;
                 ParseResult::Error
             }
         }
-    } else {
-        // This is the continuation of the value of the previous field
-        let value_len = find_value_len(buf, boundary, boundary_byte_map);
-        ParseResult::Continue(&buf[..value_len])
     }
-}
 
-// Strip the given prefix off of buf. If buf does not start with the given
-// prefix, return a parse result of either NeedMoreData if buf could possibly
-// start with prefix if it were longer or Error if it does not and cannot
-fn try_strip_prefix<'a>(buf: &'a [u8], prefix: &[u8], prefix_byte_map: &[bool]) -> Result<&'a [u8], ParseResult<'a>> {
-    match buf.strip_prefix(prefix) {
-        Some(buf) => Ok(buf),
-        None => {
-            if buf.len() < prefix.len()
-                && ends_with_subslice(buf, prefix, prefix_byte_map)
-            {
-                Err(ParseResult::NeedMoreData)
-            } else {
-                Err(ParseResult::Error)
+    // Step through the nested form in the value of the current field, giving
+    // it the value as it is needed
+    fn step_nested(&mut self) -> Step {
+        loop {
+            let nested = match self.nested.as_mut() {
+                Some(nested) => nested,
+                None => return self.step()
+            };
+
+            match nested.step() {
+                Step::NewField(cd, ct, cte) => return Step::NestedField(cd, ct, cte),
+                Step::Value(value) => return Step::NestedValue(value),
+                Step::NeedMoreData => {
+                    let buf = &self.buf[self.start..];
+                    let value_len = find_value_len(buf, &self.boundary, &self.boundary_byte_map);
+
+                    if value_len > 0 {
+                        nested.push(&buf[..value_len]);
+                        self.start += value_len;
+                    } else if buf.starts_with(&self.boundary) {
+                        // The field ended before the nested form did
+                        return Step::Error;
+                    } else {
+                        return Step::NeedMoreData;
+                    }
+                },
+                Step::Finished => {
+                    // Anything after the nested form is skipped
+                    self.nested = None;
+                    self.state = State::Epilogue;
+                },
+                _ => return Step::Error
             }
         }
     }
-}
 
-fn try_find_subslice<'a>(buf: &'a [u8], prefix: &[u8], prefix_byte_map: &[bool]) -> Result<usize, ParseResult<'a>> {
-    match find_subslice(buf, prefix, prefix_byte_map) {
-        Some(index) => Ok(index),
-        None => Err(ParseResult::NeedMoreData)
+    fn step(&mut self) -> Step {
+        loop {
+            let buf = &self.buf[self.start..];
+
+            match self.state {
+                State::Boundary => {
+                    // This is either the end of the form or the start of a
+                    // new form field
+                    let boundary_len = self.boundary.len() + NEWLINE.len();
+                    if buf.len() < boundary_len {
+                        return Step::NeedMoreData;
+                    } else if !buf.starts_with(&self.boundary) {
+                        return Step::Error;
+                    }
+
+                    let after_boundary = &buf[self.boundary.len()..boundary_len];
+                    if after_boundary == TERMINATOR {
+                        self.state = State::Finished;
+                    } else if after_boundary == NEWLINE {
+                        self.start += boundary_len;
+                        self.state = State::Headers;
+                    } else {
+                        return Step::Error;
+                    }
+                },
+                State::Headers => {
+                    // (every field has at least a Content-Disposition)
+                    if buf.starts_with(NEWLINE) {
+                        return Step::Error;
+                    }
+
+                    let headers_len = match find_subslice(buf, HEADERS_END, NEWLINE_BYTE_MAP) {
+                        Some(headers_len) if headers_len <= MAX_HEADERS_LENGTH => headers_len,
+                        None if buf.len() < MAX_HEADERS_LENGTH + HEADERS_END.len() => {
+                            return Step::NeedMoreData
+                        },
+                        _ => return Step::Error
+                    };
+                    let headers = &buf[..headers_len];
+                    if str::from_utf8(headers).is_err() {
+                        return Step::Error;
+                    }
+
+                    // The Content-Disposition, Content-Type and
+                    // Content-Transfer-Encoding may come in any order. Other
+                    // headers are ignored.
+                    let mut cd = None;
+                    let mut ct = 0..0;
+                    let mut cte = 0..0;
+                    let mut line_start = 0;
+                    while line_start < headers_len {
+                        let line = &headers[line_start..];
+                        let line_len = find_subslice(line, NEWLINE, NEWLINE_BYTE_MAP)
+                            .unwrap_or(line.len());
+                        let line = &line[..line_len];
+                        let line_end = self.start + line_start + line_len;
+                        let value_start = |prefix: &[u8]| line_end - line_len + prefix.len();
+
+                        if has_header_name(line, CD_PREFIX) {
+                            cd = Some(value_start(CD_PREFIX)..line_end);
+                        } else if has_header_name(line, CT_PREFIX) {
+                            ct = value_start(CT_PREFIX)..line_end;
+                        } else if has_header_name(line, CTE_PREFIX) {
+                            cte = value_start(CTE_PREFIX)..line_end;
+                        }
+
+                        line_start += line_len + NEWLINE.len();
+                    }
+
+                    let cd = match cd {
+                        Some(cd) => cd,
+                        None => return Step::Error
+                    };
+
+                    // The value may be a nested form
+                    let ct_str = header(&self.buf, ct.clone());
+                    let is_nested_form = ct_str.split(';').next()
+                        .is_some_and(|ct| ct.trim().eq_ignore_ascii_case(NESTED_FORM_CT));
+                    if is_nested_form {
+                        let boundary = match get_header_param(ct_str, "boundary") {
+                            Some(boundary) if !self.is_nested && !boundary.is_empty() => {
+                                format!("\r\n--{}", boundary)
+                            },
+                            _ => return Step::Error
+                        };
+                        if boundary.len() > MAX_FORM_BOUNDARY_LENGTH {
+                            return Step::Error;
+                        }
+
+                        let mut nested = MultipartParser::new(boundary.as_bytes());
+                        nested.is_nested = true;
+                        self.nested = Some(Box::new(nested));
+                    }
+
+                    // This is a new field in the form
+                    self.start += headers_len + HEADERS_END.len();
+                    self.state = State::Value;
+                    return Step::NewField(cd, ct, cte);
+                },
+                State::Value | State::Epilogue => {
+                    let value_len = find_value_len(buf, &self.boundary, &self.boundary_byte_map);
+
+                    if value_len > 0 {
+                        let value = self.start..(self.start + value_len);
+                        self.start += value_len;
+                        if let State::Value = self.state {
+                            return Step::Value(value);
+                        }
+                    } else if buf.starts_with(&self.boundary) {
+                        self.state = State::Boundary;
+                    } else {
+                        // This may be the start of a boundary
+                        return Step::NeedMoreData;
+                    }
+                },
+                State::Finished => return Step::Finished,
+                State::Error => return Step::Error
+            }
+        }
     }
 }
 
+// Return the value of a header in a buffer of a parser (which was checked to be
+// UTF-8)
+fn header(buf: &[u8], range: Range<usize>) -> &str {
+    str::from_utf8(&buf[range]).unwrap_or_default()
+}
+
+// Return whether `line` starts with the header name `prefix`, ignoring case
+fn has_header_name(line: &[u8], prefix: &[u8]) -> bool {
+    line.len() >= prefix.len() && line[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
 // Return the index of the first instance of s2 in s1
 fn find_subslice(s1: &[u8], s2: &[u8], s2_byte_map: &[bool]) -> Option<usize>
 {
@@ -194,7 +341,7 @@ fn find_subslice(s1: &[u8], s2: &[u8], s2_byte_map: &[bool]) -> Option<usize>
 // Example: for s1 = "foobar" and s2 = "barnacle", the functioun should return 3
 fn find_ending_subslice_of(s1: &[u8], s2: &[u8], s2_byte_map: &[bool]) -> Option<usize>
 {
-    if s1.len() > 1 && s2.len() > 1 {
+    if !s1.is_empty() && !s2.is_empty() {
         for sub_len in 1..=cmp::min(s2.len(), s1.len()) {
             // If the first byte of the last `sub_len` bytes of s1 is not in s2
             if !s2_byte_map[s1[s1.len() - sub_len] as usize] {
@@ -208,11 +355,6 @@ fn find_ending_subslice_of(s1: &[u8], s2: &[u8], s2_byte_map: &[bool]) -> Option
     None
 }
 
-// Return whether or not s1 ends with a subslice of s2
-fn ends_with_subslice(s1: &[u8], s2: &[u8], s2_byte_map: &[bool]) -> bool {
-    find_ending_subslice_of(s1, s2, s2_byte_map).is_some()
-}
-
 // Return the possible ending for the current value, either because the
 // boundary is present in the current buffer, or a subslice of it is and it's
 // possible that it will be completed on the next parse. If the value is not
@@ -233,6 +375,180 @@ where B1: AsRef<[u8]>,
     }
 }
 
+// Return the file name in a Content-Disposition. The extended `filename*`
+// parameter (RFC 6266), which browsers send for names which aren't ASCII, is
+// preferred over `filename`.
+pub fn get_file_name(cd: &str) -> Option<Cow<'_, str>> {
+    let ext_name = get_header_param(cd, "filename*")
+        .and_then(decode_ext_value)
+        .filter(|name| !name.is_empty());
+
+    match ext_name {
+        Some(name) => Some(Cow::Owned(name)),
+        None => get_header_param(cd, "filename")
+            .filter(|name| !name.is_empty())
+            .map(Cow::Borrowed)
+    }
+}
+
+// Return the value of the parameter `name` in a header such as a
+// Content-Disposition, without quotes. Quoted values may contain ';' and
+// escaped quotes.
+fn get_header_param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    // (the first part is e.g. the disposition type, like "form-data")
+    let (_, mut rest) = header.split_once(';')?;
+
+    loop {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let mut is_escaped = false;
+                let end = quoted.bytes().position(|b| {
+                    let is_end = b == b'"' && !is_escaped;
+                    is_escaped = b == b'\\' && !is_escaped;
+                    is_end
+                })?;
+                (&quoted[..end], &quoted[end + 1..])
+            },
+            None => {
+                let end = value.find(';').unwrap_or(value.len());
+                (value[..end].trim_end(), &value[end..])
+            }
+        };
+
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+
+        rest = next.trim_start().strip_prefix(';')?;
+    }
+}
+
+// Decode an extended parameter value (RFC 5987), i.e.
+// charset'language'percent-encoded value
+fn decode_ext_value(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+    let bytes = urlencoding::decode_binary(encoded.as_bytes());
+
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes.into_owned()).ok()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        // (the first 256 code points of Unicode are ISO-8859-1)
+        Some(bytes.iter().map(|b| *b as char).collect())
+    } else {
+        None
+    }
+}
+
+// Decodes the value of a form field with a Content-Transfer-Encoding, which
+// some clients (e.g. email gateways) use, as it is parsed piece by piece
+pub enum TransferDecoder {
+    Identity,
+    Base64(Base64Decoder),
+    QuotedPrintable(QuotedPrintableDecoder)
+}
+
+impl TransferDecoder {
+    // Return the decoder for the Content-Transfer-Encoding `cte` (which is
+    // empty if there is none), or None if it isn't supported
+    pub fn new(cte: &str) -> Option<Self> {
+        match cte.trim().to_ascii_lowercase().as_str() {
+            "" | "7bit" | "8bit" | "binary" => Some(Self::Identity),
+            "base64" => Some(Self::Base64(Base64Decoder::lenient())),
+            "quoted-printable" => Some(Self::QuotedPrintable(QuotedPrintableDecoder::new())),
+            _ => None
+        }
+    }
+
+    // Return the decoded form of the next piece of the value, which is
+    // written to `out` unless the value isn't encoded. Some bytes may be held
+    // back until the next piece or `finish`.
+    pub fn decode<'a>(&mut self, val: &'a [u8], out: &'a mut Vec<u8>) -> &'a [u8] {
+        out.clear();
+        match self {
+            Self::Identity => { return val }
+            Self::Base64(decoder) => {
+                // (encoded values are split into lines)
+                for digits in val.split(|b| b.is_ascii_whitespace()) {
+                    decoder.update(digits, out);
+                }
+            },
+            Self::QuotedPrintable(decoder) => decoder.update(val, out)
+        }
+        out
+    }
+
+    // Write the bytes which were held back to `out`. Return None if the value
+    // wasn't encoded correctly.
+    pub fn finish(self, out: &mut Vec<u8>) -> Option<()> {
+        out.clear();
+        match self {
+            Self::Identity => Some(()),
+            Self::Base64(decoder) => decoder.finish(out),
+            Self::QuotedPrintable(decoder) => decoder.finish()
+        }
+    }
+}
+
+// Decodes quoted-printable (RFC 2045) over several calls. An escape sequence
+// ("=" and two hex digits, or a soft line break) which is cut off at the end
+// of one call is completed in the next.
+pub struct QuotedPrintableDecoder {
+    escape: [u8; 3],
+    escape_len: usize,
+    is_invalid: bool
+}
+
+impl QuotedPrintableDecoder {
+    pub fn new() -> Self {
+        Self { escape: [0; 3], escape_len: 0, is_invalid: false }
+    }
+
+    pub fn update(&mut self, val: &[u8], out: &mut Vec<u8>) {
+        for b in val {
+            if self.escape_len == 0 {
+                if *b == b'=' {
+                    self.escape[0] = *b;
+                    self.escape_len = 1;
+                } else {
+                    out.push(*b);
+                }
+                continue;
+            }
+
+            self.escape[self.escape_len] = *b;
+            self.escape_len += 1;
+            if self.escape_len == 3 {
+                match self.escape {
+                    // a soft line break, which isn't part of the value
+                    [_, b'\r', b'\n'] => {},
+                    [_, high, low] => match (hex_value(high), hex_value(low)) {
+                        (Some(high), Some(low)) => out.push(high << 4 | low),
+                        _ => self.is_invalid = true
+                    }
+                }
+                self.escape_len = 0;
+            }
+        }
+    }
+
+    // Return None if the value wasn't valid quoted-printable
+    pub fn finish(self) -> Option<()> {
+        if self.escape_len == 0 && !self.is_invalid {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
 // Return an array of bool where the value at index n (for any n: u8) represents
 // whether or not that byte is present in the given byte string.
 //
@@ -255,59 +571,10 @@ const fn newline_byte_map() -> [bool; u8::MAX as usize + 1] {
     map
 }
 
-// i really wish rust would let you iterate over fixed-size data in constant
-// functions. that would be really, really, really, really great.
-
-// So we can have CD_PREFIX_BYTE_MAP as a constant
-const fn cd_prefix_byte_map() -> [bool; u8::MAX as usize + 1] {
-    let mut map = [false; u8::MAX as usize + 1];
-    map[b'C' as usize] = true;
-    map[b'o' as usize] = true;
-    map[b'n' as usize] = true;
-    map[b't' as usize] = true;
-    map[b'e' as usize] = true;
-    map[b'n' as usize] = true;
-    map[b't' as usize] = true;
-    map[b'-' as usize] = true;
-    map[b'D' as usize] = true;
-    map[b'i' as usize] = true;
-    map[b's' as usize] = true;
-    map[b'p' as usize] = true;
-    map[b'o' as usize] = true;
-    map[b's' as usize] = true;
-    map[b'i' as usize] = true;
-    map[b't' as usize] = true;
-    map[b'i' as usize] = true;
-    map[b'o' as usize] = true;
-    map[b'n' as usize] = true;
-    map[b':' as usize] = true;
-    map[b' ' as usize] = true;
-    map
-}
-
-// So we can have CT_PREFIX_BYTE_MAP as a constant
-const fn ct_prefix_byte_map() -> [bool; u8::MAX as usize + 1] {
-    let mut map = [false; u8::MAX as usize + 1];
-    map[b'C' as usize] = true;
-    map[b'o' as usize] = true;
-    map[b'n' as usize] = true;
-    map[b't' as usize] = true;
-    map[b'e' as usize] = true;
-    map[b'n' as usize] = true;
-    map[b't' as usize] = true;
-    map[b'-' as usize] = true;
-    map[b'T' as usize] = true;
-    map[b'y' as usize] = true;
-    map[b'p' as usize] = true;
-    map[b'e' as usize] = true;
-    map[b':' as usize] = true;
-    map[b' ' as usize] = true;
-    map
-}
-
 #[cfg(test)]
 mod tests {
     use crate::multipart_form::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_find_subslice() {
@@ -329,47 +596,296 @@ mod tests {
         assert_eq!(find_ending_subslice_of(s1, b"foo", &byte_map(b"foo")), None);
     }
 
+    // content-disposition, content-type, content-transfer-encoding, value,
+    // whether the field is in a nested form
+    type Field = (String, String, String, Vec<u8>, bool);
+
+    // Parse `body`, which is pushed to the parser `read_size` bytes at a time
+    fn parse_in_reads(body: &[u8], boundary: &[u8], read_size: usize) -> Option<Vec<Field>> {
+        let mut parser = MultipartParser::new(boundary);
+        let mut reads = body.chunks(read_size);
+        let mut fields: Vec<Field> = Vec::new();
+
+        loop {
+            match parser.next() {
+                ParseResult::NewField(cd, ct, cte) => {
+                    fields.push((cd.to_owned(), ct.to_owned(), cte.to_owned(), Vec::new(), false));
+                },
+                ParseResult::NestedField(cd, ct, cte) => {
+                    fields.push((cd.to_owned(), ct.to_owned(), cte.to_owned(), Vec::new(), true));
+                },
+                ParseResult::Value(val) => fields.last_mut()?.3.extend_from_slice(val),
+                ParseResult::NeedMoreData => parser.push(reads.next()?),
+                ParseResult::Finished => return Some(fields),
+                ParseResult::Error => return None
+            }
+        }
+    }
+
+    fn field(cd: &str, ct: &str, cte: &str, value: &[u8]) -> Field {
+        (cd.to_owned(), ct.to_owned(), cte.to_owned(), value.to_owned(), false)
+    }
+
+    fn nested_field(cd: &str, ct: &str, value: &[u8]) -> Field {
+        (cd.to_owned(), ct.to_owned(), String::new(), value.to_owned(), true)
+    }
+
+    const BOUNDARY: &'static [u8] = b"\r\n--boundary";
+
     #[test]
     fn test_parse() {
         const FORM_BODY: &'static [u8] =
-b"\r
---boundary\r
+b"--boundary\r
 Content-Disposition: form-data; name=\"field1\"\r
 \r
 value1\r
 --boundary\r
 Content-Disposition: form-data; name=\"field2\"; filename=\"example.txt\"\r
 \r
-value2\r
+value2\r\n-\r\n--boundar\r
 --boundary--";
 
-        const BOUNDARY: &'static [u8] = b"\r\n--boundary";
-        let byte_map = byte_map(BOUNDARY);
-        const CD_1: &'static str = "form-data; name=\"field1\"";
-        const VALUE_1: &'static [u8] = b"value1";
-        const CD_2: &'static str = "form-data; name=\"field2\"; filename=\"example.txt\"";
-        const VALUE_2: &'static [u8] = b"value2";
+        let expected = vec![
+            field("form-data; name=\"field1\"", "", "", b"value1"),
+            field("form-data; name=\"field2\"; filename=\"example.txt\"", "", "", b"value2\r\n-\r\n--boundar")];
 
-        let mut i = 0;
-        let mut value = 0;
-        loop {
-            match parse(&FORM_BODY[i..], BOUNDARY, &byte_map) {
-                ParseResult::NewValue(len, cd, _ct, val) => {
-                    i += len;
+        // however the form is split into reads
+        for read_size in 1..=FORM_BODY.len() {
+            assert_eq!(parse_in_reads(FORM_BODY, BOUNDARY, read_size).unwrap(), expected);
+        }
+    }
 
-                    if value == 0 {
-                        assert_eq!(cd, CD_1);
-                        assert_eq!(val, VALUE_1);
-                    } else {
-                        assert_eq!(cd, CD_2);
-                        assert_eq!(val, VALUE_2);
-                    }
+    #[test]
+    fn test_parse_headers() {
+        // the Content-Type and Content-Transfer-Encoding may come in any
+        // order, and other headers are ignored
+        const FORM_BODIES: &[&[u8]] = &[
+b"--boundary\r
+Content-Disposition: form-data; name=\"files\"; filename=\"a.txt\"\r
+Content-Type: text/plain\r
+Content-Transfer-Encoding: base64\r
+\r
+dmFsdWU=\r
+--boundary--",
+b"--boundary\r
+content-transfer-encoding: base64\r
+Content-Length: 8\r
+Content-Type: text/plain\r
+Content-Disposition: form-data; name=\"files\"; filename=\"a.txt\"\r
+\r
+dmFsdWU=\r
+--boundary--"];
 
-                    value += 1;
-                },
-                ParseResult::Continue(val) => i += val.len(),
-                ParseResult::Finished | ParseResult::Error | ParseResult::NeedMoreData => break
+        let expected = vec![field(
+            "form-data; name=\"files\"; filename=\"a.txt\"", "text/plain", "base64", b"dmFsdWU=")];
+        for form_body in FORM_BODIES {
+            assert_eq!(parse_in_reads(form_body, BOUNDARY, 7).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        const FORM_BODIES: &[&[u8]] = &[
+            // no Content-Disposition
+            b"--boundary\r\nContent-Type: text/plain\r\n\r\nvalue\r\n--boundary--",
+            b"--boundary\r\n\r\nvalue\r\n--boundary--",
+            // not a boundary
+            b"--other\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--other--",
+            b"--boundaryX\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--boundary--",
+            // cut off
+            b"--boundary\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--bound",
+            b"--boundary\r\nContent-Disposition: form-data; name=\"a\"\r\nContent-Ty"];
+
+        for form_body in FORM_BODIES {
+            for read_size in [1, 5, form_body.len()] {
+                assert!(parse_in_reads(form_body, BOUNDARY, read_size).is_none());
+            }
+        }
+
+        // headers which are too long
+        let mut form_body = b"--boundary\r\nContent-Disposition: form-data; name=\"a\"\r\n".to_vec();
+        form_body.extend_from_slice(&[b'a'; MAX_HEADERS_LENGTH]);
+        form_body.extend_from_slice(b"\r\n\r\nvalue\r\n--boundary--");
+        assert!(parse_in_reads(&form_body, BOUNDARY, 1024).is_none());
+    }
+
+    #[test]
+    fn test_parse_nested() {
+        // (from the HTML 4 specification)
+        const FORM_BODY: &'static [u8] =
+b"--boundary\r
+Content-Disposition: form-data; name=\"days\"\r
+\r
+1\r
+--boundary\r
+Content-Disposition: form-data; name=\"files\"\r
+Content-Type: multipart/mixed; boundary=BbC04y\r
+\r
+--BbC04y\r
+Content-Disposition: file; filename=\"file1.txt\"\r
+Content-Type: text/plain\r
+\r
+contents of file1.txt\r\n--BbC04\r
+--BbC04y\r
+Content-Disposition: file; filename=\"file2.gif\"\r
+Content-Type: image/gif\r
+\r
+contents of file2.gif\r
+--BbC04y--\r
+epilogue\r
+--boundary\r
+Content-Disposition: form-data; name=\"hours\"\r
+\r
+2\r
+--boundary--";
+
+        let expected = vec![
+            field("form-data; name=\"days\"", "", "", b"1"),
+            field("form-data; name=\"files\"", "multipart/mixed; boundary=BbC04y", "", b""),
+            nested_field("file; filename=\"file1.txt\"", "text/plain", b"contents of file1.txt\r\n--BbC04"),
+            nested_field("file; filename=\"file2.gif\"", "image/gif", b"contents of file2.gif"),
+            field("form-data; name=\"hours\"", "", "", b"2")];
+
+        for read_size in 1..=FORM_BODY.len() {
+            assert_eq!(parse_in_reads(FORM_BODY, BOUNDARY, read_size).unwrap(), expected);
+        }
+
+        const INVALID_FORM_BODIES: &[&[u8]] = &[
+            // the field ends before the nested form
+            b"--boundary\r
+Content-Disposition: form-data; name=\"files\"\r
+Content-Type: multipart/mixed; boundary=BbC04y\r
+\r
+--BbC04y\r
+Content-Disposition: file; filename=\"file1.txt\"\r
+\r
+contents of file1.txt\r
+--boundary--",
+            // forms are only nested once
+            b"--boundary\r
+Content-Disposition: form-data; name=\"files\"\r
+Content-Type: multipart/mixed; boundary=BbC04y\r
+\r
+--BbC04y\r
+Content-Disposition: file; filename=\"file1.txt\"\r
+Content-Type: multipart/mixed; boundary=CcD05z\r
+\r
+--CcD05z--\r
+--BbC04y--\r
+--boundary--",
+            // no boundary
+            b"--boundary\r
+Content-Disposition: form-data; name=\"files\"\r
+Content-Type: multipart/mixed\r
+\r
+--BbC04y--\r
+--boundary--"];
+
+        for form_body in INVALID_FORM_BODIES {
+            for read_size in [1, 5, form_body.len()] {
+                assert!(parse_in_reads(form_body, BOUNDARY, read_size).is_none());
             }
         }
     }
+
+    proptest! {
+        // However a form is split into reads, the same values are parsed.
+        // Values are made of bytes which also start the boundary, so that
+        // they contain parts of it.
+        #[test]
+        fn prop_parse_any_reads(
+            values in prop::collection::vec(
+                prop::collection::vec(prop::sample::select(b"\r\n-bo".to_vec()), 0..100), 1..5),
+            read_size in 1usize..64)
+        {
+            let mut form_body = Vec::new();
+            for value in &values {
+                form_body.extend_from_slice(b"--boundary\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n");
+                form_body.extend_from_slice(value);
+                form_body.extend_from_slice(b"\r\n");
+            }
+            form_body.extend_from_slice(b"--boundary--");
+
+            let fields = parse_in_reads(&form_body, BOUNDARY, read_size).unwrap();
+            let parsed_values: Vec<_> = fields.into_iter().map(|field| field.3).collect();
+            prop_assert_eq!(parsed_values, values);
+        }
+
+        // Arbitrary data after the first boundary is parsed the same whether
+        // it is read at once or not (and doesn't panic)
+        #[test]
+        fn prop_parse_arbitrary(
+            data in prop::collection::vec(any::<u8>(), 0..512),
+            read_size in 1usize..64)
+        {
+            let mut form_body = b"--boundary\r\n".to_vec();
+            form_body.extend_from_slice(&data);
+
+            prop_assert_eq!(
+                parse_in_reads(&form_body, BOUNDARY, read_size),
+                parse_in_reads(&form_body, BOUNDARY, form_body.len()));
+        }
+    }
+
+    fn decode_in_pieces(cte: &str, val: &[u8], piece_size: usize) -> Option<Vec<u8>> {
+        let mut decoder = TransferDecoder::new(cte)?;
+        let mut out = Vec::new();
+        let mut decoded = Vec::new();
+        for piece in val.chunks(piece_size) {
+            decoded.extend_from_slice(decoder.decode(piece, &mut out));
+        }
+        decoder.finish(&mut out)?;
+        decoded.extend_from_slice(&out);
+        Some(decoded)
+    }
+
+    #[test]
+    fn test_transfer_decoder() {
+        for piece_size in [1, 2, 3, 5, 100] {
+            assert_eq!(
+                decode_in_pieces("base64", b"YSBzaW1wbGUg\r\ndGVzdA==", piece_size).unwrap(),
+                b"a simple test");
+            assert_eq!(
+                decode_in_pieces("Quoted-Printable", b"na=C3=AFve =\r\ntest=3D\r\nline", piece_size).unwrap(),
+                "naïve test=\r\nline".as_bytes());
+            assert_eq!(
+                decode_in_pieces("binary", b"a=b", piece_size).unwrap(),
+                b"a=b");
+
+            assert!(decode_in_pieces("base64", b"YS*z", piece_size).is_none());
+            assert!(decode_in_pieces("quoted-printable", b"a=XYb", piece_size).is_none());
+            assert!(decode_in_pieces("quoted-printable", b"a=4", piece_size).is_none());
+        }
+
+        assert!(TransferDecoder::new("x-uuencode").is_none());
+    }
+
+    #[test]
+    fn test_get_file_name() {
+        let cd = "form-data; name=\"files\"; filename=\"example.txt\"";
+        assert_eq!(get_file_name(cd).unwrap(), "example.txt");
+
+        // quoted values may contain separators
+        let cd = "form-data; name=\"files\"; filename=\"a; b=c.txt\"";
+        assert_eq!(get_file_name(cd).unwrap(), "a; b=c.txt");
+
+        // the extended parameter is preferred, wherever it is
+        let cd = "form-data; name=\"files\"; filename=\"na-ve.txt\"; filename*=UTF-8''na%C3%AFve.txt";
+        assert_eq!(get_file_name(cd).unwrap(), "naïve.txt");
+        let cd = "form-data; name=\"files\"; filename*=utf-8'en'na%C3%AFve.txt; filename=\"na-ve.txt\"";
+        assert_eq!(get_file_name(cd).unwrap(), "naïve.txt");
+        let cd = "form-data; name=\"files\"; filename*=UTF-8''%E2%82%AC%20rates.txt";
+        assert_eq!(get_file_name(cd).unwrap(), "€ rates.txt");
+        let cd = "form-data; name=\"files\"; filename*=ISO-8859-1''na%EFve.txt";
+        assert_eq!(get_file_name(cd).unwrap(), "naïve.txt");
+
+        // unknown charsets and invalid UTF-8 fall back to `filename`
+        let cd = "form-data; name=\"files\"; filename=\"a.txt\"; filename*=KOI8-R''%C1.txt";
+        assert_eq!(get_file_name(cd).unwrap(), "a.txt");
+        let cd = "form-data; name=\"files\"; filename=\"a.txt\"; filename*=UTF-8''%FF.txt";
+        assert_eq!(get_file_name(cd).unwrap(), "a.txt");
+
+        assert!(get_file_name("form-data; name=\"files\"; filename=\"\"").is_none());
+        assert!(get_file_name("form-data; name=\"files\"").is_none());
+    }
 }
@@ -0,0 +1,120 @@
+use crate::b64::*;
+use crate::cluster::Leader;
+use crate::config::*;
+use crate::db::*;
+use crate::mail;
+
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, Duration as ChronoDuration};
+use serde_json::json;
+use tracing::{info_span, warn};
+
+
+// Number of seconds between checks for uploads which are about to expire
+const NOTIFY_DELAY_SECS: u64 = 5 * 60;
+// Uploaders are told that their upload expires when it has this long left
+const EXPIRY_WARNING_MINUTES: i64 = 60;
+const MAX_TOPIC_LENGTH: usize = 64;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+
+pub enum Event {
+    Downloaded,
+    ExpiresSoon
+}
+
+// ntfy topics are made of letters, digits, `-` and `_`
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= MAX_TOPIC_LENGTH
+        && topic.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn is_enabled(config: &TranspoConfig) -> bool {
+    config.ntfy_server.is_some() || config.notify_url.is_some()
+}
+
+fn send(url: &str, service: NotifyService, title: &str, message: &str) {
+    let request = ureq::post(url).timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS));
+    let result = match service {
+        NotifyService::Ntfy => request
+            .set("Title", title)
+            .send_string(message),
+        NotifyService::Gotify => request
+            .send_json(json!({ "title": title, "message": message }))
+    };
+
+    if let Err(e) = result {
+        warn!("Sending notification: {}", e);
+    }
+}
+
+// Tell the uploader (if they gave a topic) and the operator (if they set a
+// URL) about the upload. The notifications are sent in the background.
+pub fn notify(upload: &Upload, event: Event, config: &TranspoConfig) {
+    let mut targets = Vec::new();
+    if let (Some(server), Some(topic)) = (&config.ntfy_server, &upload.notify_topic) {
+        targets.push((format!("{}/{}", server, topic), NotifyService::Ntfy));
+    }
+    if let Some(url) = &config.notify_url {
+        targets.push((url.clone(), config.notify_service));
+    }
+    if targets.is_empty() {
+        return;
+    }
+
+    let id = String::from_utf8(i64_to_b64_bytes(upload.id)).unwrap();
+    let (title, message) = match event {
+        Event::Downloaded => (
+            "Upload downloaded",
+            format!("Upload {} was downloaded", id)),
+        Event::ExpiresSoon => (
+            "Upload expires soon",
+            format!(
+                "Upload {} expires at {} UTC",
+                id, upload.expire_after.format("%Y-%m-%d %H:%M")))
+    };
+
+    thread::spawn(move || for (url, service) in targets {
+        send(&url, service, title, &message);
+    });
+}
+
+pub fn spawn_notify_thread(config: TranspoConfig, db_backend: DbBackend, leader: Leader) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(NOTIFY_DELAY_SECS));
+
+        // Otherwise each replica would notify about the same uploads
+        if !leader.is_leader() {
+            continue;
+        }
+
+        let _span = info_span!("notify").entered();
+
+        // Try again next time if the database is unavailable
+        match establish_connection(db_backend, &config.db_url) {
+            Some(db_connection) => notify_expiring(&config, &db_connection),
+            None => warn!("Skipping expiry notifications, the database is unavailable")
+        }
+    });
+}
+
+// Notify about each upload which is about to expire (and remind its owner by
+// mail), once
+fn notify_expiring(config: &TranspoConfig, db_connection: &DbConnection) {
+    let deadline = Local::now().naive_utc()
+        + ChronoDuration::minutes(EXPIRY_WARNING_MINUTES);
+
+    for upload in Upload::select_expiring(deadline, db_connection).unwrap_or_default() {
+        // Too late for a warning
+        if !upload.is_expired() {
+            notify(&upload, Event::ExpiresSoon, config);
+            if mail::is_enabled(config) {
+                mail::queue_expiry_mail(&upload, config, db_connection);
+            }
+        }
+        Upload::set_expiry_notified(upload.id, db_connection);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::accounts::*;
+use crate::b64::*;
+use crate::config::*;
+use crate::db::*;
+use crate::http_errors::*;
+use crate::random_bytes::*;
+use crate::translations::*;
+
+use std::sync::Arc;
+
+use blocking::unblock;
+use chrono::Local;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use trillium::Conn;
+use urlencoding::{decode as url_decode, encode};
+
+
+// Holds the state, nonce and PKCE verifier of a login in progress
+const LOGIN_COOKIE: &'static str = "transpo_oidc";
+const LOGIN_TIMEOUT_SECS: u64 = 10 * 60;
+const RANDOM_TOKEN_LENGTH: usize = 32;
+
+
+// The parts of the provider's discovery document which are needed to log in
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>
+}
+
+// The claims of an ID token which are used (the rest are checked by
+// `Validation`)
+#[derive(Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>
+}
+
+fn random_token() -> String {
+    let mut bytes = [0; RANDOM_TOKEN_LENGTH];
+    random_bytes(&mut bytes);
+    String::from_utf8(base64_encode(&bytes)).unwrap()
+}
+
+fn discover(issuer: &str) -> Option<ProviderMetadata> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    let metadata: ProviderMetadata = match ureq::get(&url).call() {
+        Ok(response) => response.into_json().ok()?,
+        Err(e) => {
+            warn!("Fetching OpenID Connect provider metadata: {}", e);
+            return None;
+        }
+    };
+
+    // Otherwise, another issuer's tokens could be accepted
+    if metadata.issuer != issuer {
+        warn!(issuer = %metadata.issuer, "The OpenID Connect provider reports a different issuer");
+        return None;
+    }
+
+    Some(metadata)
+}
+
+// Check the signature and claims of an ID token issued for the login with
+// the given nonce
+fn verify_id_token(
+    id_token: &str, metadata: &ProviderMetadata, client_id: &str,
+    nonce: &str) -> Option<IdClaims>
+{
+    let header = decode_header(id_token).ok()?;
+    let jwks: JwkSet = ureq::get(&metadata.jwks_uri).call().ok()?.into_json().ok()?;
+    let jwk = jwks.keys.iter()
+        .filter(|k| k.kty == "RSA")
+        .find(|k| header.kid.is_none() || k.kid == header.kid)?;
+    let key = DecodingKey::from_rsa_components(jwk.n.as_ref()?, jwk.e.as_ref()?).ok()?;
+
+    // Every provider supports RS256
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_audience(&[client_id]);
+    validation.set_issuer(&[&metadata.issuer]);
+
+    let claims = decode::<IdClaims>(id_token, &key, &validation).ok()?.claims;
+    if claims.nonce.as_deref() == Some(nonce) {
+        Some(claims)
+    } else {
+        None
+    }
+}
+
+// Return the ID of the user the subject is logged in as, creating the user
+// the first time they log in
+fn find_or_create_user(
+    issuer: &str, claims: &IdClaims, db_connection: &DbConnection) -> Option<i64>
+{
+    if let Some(identity) = OidcIdentity::select(issuer, &claims.sub, db_connection) {
+        return Some(identity.user_id);
+    }
+
+    // The provider's username is used if it is free, so that the user is
+    // recognisable in `user` commands
+    let preferred_username = claims.preferred_username.clone()
+        .filter(|u| is_valid_username(u))
+        .filter(|u| User::select_with_username(u, db_connection).is_none());
+    let username = preferred_username.unwrap_or_else(|| {
+        let random = random_token();
+        format!("oidc-{}", &random[..8])
+    });
+
+    let user = User {
+        id: thread_rng().gen(),
+        username,
+        // Never matches a password, so the user can only log in through the
+        // provider
+        password_hash: String::new(),
+        created_at: Local::now().naive_utc(),
+        // Expiry reminders are only sent to addresses the provider vouches for
+        email: claims.email.clone().filter(|_| claims.email_verified == Some(true))
+    };
+    user.insert(db_connection)?;
+
+    let identity = OidcIdentity {
+        issuer: issuer.to_string(),
+        subject: claims.sub.clone(),
+        user_id: user.id
+    };
+    identity.insert(db_connection)?;
+
+    info!(username = %user.username, "Created user for OpenID Connect subject");
+    Some(user.id)
+}
+
+// `GET /oidc/login`: send the browser to the provider to log in
+pub async fn login(conn: Conn, config: Arc<TranspoConfig>, translation: Translation) -> Conn {
+    let issuer = match &config.oidc_issuer {
+        Some(issuer) => issuer.clone(),
+        None => return error_404(conn, config, translation)
+    };
+
+    let metadata = match unblock(move || discover(&issuer)).await {
+        Some(metadata) => metadata,
+        None => return conn.with_status(502).with_body("Login is unavailable").halt()
+    };
+
+    let state = random_token();
+    let nonce = random_token();
+    let verifier = random_token();
+    let challenge = String::from_utf8(
+        base64_encode(&Sha256::digest(verifier.as_bytes()))).unwrap();
+
+    let separator = if metadata.authorization_endpoint.contains('?') { '&' } else { '?' };
+    let location = format!(
+        "{}{}response_type=code&scope=openid%20profile&client_id={}&redirect_uri={}\
+        &state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        metadata.authorization_endpoint, separator,
+        encode(&config.oidc_client_id), encode(&config.oidc_redirect_url),
+        state, nonce, challenge);
+    let cookie = format!(
+        "{}={}.{}.{}; Path={}/oidc/; Max-Age={}; HttpOnly; SameSite=Lax",
+        LOGIN_COOKIE, state, nonce, verifier, config.base_path, LOGIN_TIMEOUT_SECS);
+
+    conn
+        .with_status(302)
+        .with_header("Location", location)
+        .with_header("Set-Cookie", cookie)
+        .halt()
+}
+
+// `GET /oidc/callback`: finish logging in once the provider sends the
+// browser back
+pub async fn callback(
+    conn: Conn, config: Arc<TranspoConfig>, translation: Translation,
+    db_backend: DbBackend) -> Conn
+{
+    let issuer = match &config.oidc_issuer {
+        Some(issuer) => issuer.clone(),
+        None => return error_404(conn, config, translation)
+    };
+
+    let mut code = None;
+    let mut state = None;
+    for field in conn.querystring().split('&') {
+        if let Some((key, value)) = field.split_once('=') {
+            let value = url_decode(value).ok().map(|v| v.into_owned());
+            match key {
+                "code" => code = value,
+                "state" => state = value,
+                _ => {}
+            }
+        }
+    }
+
+    let login = cookie_from_headers(conn.headers(), LOGIN_COOKIE);
+    let login = login.as_ref().and_then(|login| {
+        let mut parts = login.split('.');
+        Some((parts.next()?.to_owned(), parts.next()?.to_owned(), parts.next()?.to_owned()))
+    });
+
+    // The state ties the response to the login which this browser started
+    let (code, nonce, verifier) = match (code, state, login) {
+        (Some(code), Some(state), Some((expected_state, nonce, verifier)))
+            if state == expected_state => (code, nonce, verifier),
+        _ => return error_400(conn, config, translation)
+    };
+
+    let config_ = config.clone();
+    let token = unblock(move || {
+        let metadata = discover(&issuer)?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", config_.oidc_redirect_url.as_str()),
+            ("client_id", config_.oidc_client_id.as_str()),
+            ("code_verifier", verifier.as_str())
+        ];
+        if let Some(secret) = &config_.oidc_client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        let token_response: TokenResponse = match ureq::post(&metadata.token_endpoint).send_form(&form) {
+            Ok(response) => response.into_json().ok()?,
+            Err(e) => {
+                warn!("Exchanging OpenID Connect authorization code: {}", e);
+                return None;
+            }
+        };
+
+        let claims = verify_id_token(
+            &token_response.id_token, &metadata, &config_.oidc_client_id, &nonce);
+        let claims = match claims {
+            Some(claims) => claims,
+            None => {
+                warn!("Rejected an invalid OpenID Connect ID token");
+                return None;
+            }
+        };
+
+        let db_connection = establish_connection(db_backend, &config_.db_url)?;
+        let user_id = find_or_create_user(&metadata.issuer, &claims, &db_connection)?;
+        create_session(user_id, &db_connection)
+    }).await;
+
+    match token {
+        // (the login cookie expires by itself)
+        Some(token) => conn
+            .with_status(302)
+            .with_header("Location", format!("{}/", config.base_path))
+            .with_header("Set-Cookie", session_cookie(&config, &token))
+            .halt(),
+        None => error_400(conn, config, translation)
+    }
+}
@@ -0,0 +1,61 @@
+use crate::config::*;
+
+use trillium::{async_trait, Conn, Handler};
+
+
+const ONION_LOCATION_HEADER: &'static str = "Onion-Location";
+
+
+// Return the link to the given path (relative to the base path) on the onion
+// service, if there is one
+pub fn onion_link(config: &TranspoConfig, path: &str) -> Option<String> {
+    config.onion_url.as_ref().map(|onion_url| format!("{}/{}", onion_url, path))
+}
+
+// Whether the request was made through the onion service
+pub fn is_onion_request(conn: &Conn) -> bool {
+    conn.headers().get_str("Host")
+        .map(|host| host.split(':').next().unwrap_or(host).ends_with(".onion"))
+        .unwrap_or(false)
+}
+
+// Tell Tor Browser where each page can be found on the onion service. The
+// header is only added to HTML responses, which is all Tor Browser looks at.
+pub struct OnionLocation {
+    onion_url: String,
+    base_path: String
+}
+
+impl OnionLocation {
+    pub fn new(config: &TranspoConfig) -> Option<Self> {
+        Some(Self {
+            onion_url: config.onion_url.clone()?,
+            base_path: config.base_path.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl Handler for OnionLocation {
+    async fn run(&self, conn: Conn) -> Conn {
+        conn
+    }
+
+    async fn before_send(&self, mut conn: Conn) -> Conn {
+        let is_html = conn.inner().response_headers().get_str("Content-Type")
+            .map(|t| t.starts_with("text/html"))
+            .unwrap_or(false);
+        if !is_html || is_onion_request(&conn) {
+            return conn;
+        }
+
+        let path = conn.path().strip_prefix(&self.base_path).unwrap_or(conn.path());
+        let mut location = format!("{}{}", self.onion_url, path);
+        if !conn.querystring().is_empty() {
+            location = format!("{}?{}", location, conn.querystring());
+        }
+
+        conn.headers_mut().insert(ONION_LOCATION_HEADER, location);
+        conn
+    }
+}
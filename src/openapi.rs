@@ -0,0 +1,817 @@
+use crate::accounts::SESSION_COOKIE;
+use crate::api_keys::*;
+use crate::config::*;
+use crate::download::{PASSWORD_HEADER, DEFAULT_TOKEN_AGE_MINUTES, MAX_TOKEN_AGE_MINUTES};
+use crate::files::FORMAT_VERSION;
+use crate::http_errors::*;
+use crate::templates::html_escape;
+use crate::translations::*;
+use crate::upload::*;
+use crate::version::VERSION;
+
+use std::sync::Arc;
+
+use serde_json::{json, Map, Value};
+use trillium::Conn;
+
+
+// Loaded by the page served at `/api/docs`
+const SWAGGER_UI_URL: &'static str = "https://unpkg.com/swagger-ui-dist@5";
+
+
+fn query_param(name: &str, description: &str, schema: Value) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": schema
+    })
+}
+
+fn file_id_param() -> Value {
+    json!({
+        "name": "file_id",
+        "in": "path",
+        "required": true,
+        "description": "ID of the upload (the part of the download link before `?` and `#`)",
+        "schema": { "type": "string" }
+    })
+}
+
+fn password_params() -> Vec<Value> {
+    vec![
+        query_param("password", "Password of the upload", json!({ "type": "string" })),
+        query_param("token", "Download token returned by `POST /{file_id}/token`, \
+            used in place of the password", json!({ "type": "string" })),
+        json!({
+            "name": PASSWORD_HEADER,
+            "in": "header",
+            "required": false,
+            "description": "Password of the upload, percent-encoded. It may also be sent \
+                as `Authorization: Bearer <password>`.",
+            "schema": { "type": "string" }
+        })
+    ]
+}
+
+fn upload_params(config: &TranspoConfig) -> Vec<Value> {
+    let mut params = vec![
+        query_param(MINUTES_QUERY, "Number of minutes after which the upload expires",
+            json!({ "type": "integer", "minimum": 1, "maximum": config.max_upload_age_minutes })),
+        query_param(MAX_DOWNLOADS_QUERY, "Number of downloads after which the upload expires",
+            json!({ "type": "integer", "minimum": 1 })),
+        query_param(PASSWORD_QUERY, "Password required to download the upload",
+            json!({ "type": "string" })),
+        query_param(DERIVE_KEY_QUERY, "Derive the key from the password, so download links \
+            don't contain the key and the key isn't returned (server-side encryption only)",
+            json!({ "type": "boolean" })),
+        query_param(RECIPIENTS_QUERY, "age public keys (`age1...`) of recipients, separated by \
+            commas, who can download the upload with their secret key instead of the key \
+            (server-side encryption only)", json!({ "type": "string" })),
+        query_param(AGE_QUERY, "Store the upload as an age file for the recipients, so that \
+            they can also decrypt it with `age`", json!({ "type": "boolean" })),
+        query_param(FILE_NAME_QUERY, "Name of the file, if it is uploaded as a single file \
+            (websocket only)", json!({ "type": "string" })),
+        query_param(MIME_TYPE_QUERY, "MIME type of the file, if it is uploaded as a single file \
+            (websocket only)", json!({ "type": "string" })),
+        query_param(TITLE_QUERY, "Title of the upload, encrypted with its key like the file \
+            name (client-side encryption only)", json!({ "type": "string" })),
+        query_param(DOWNLOAD_SPEED_LIMIT_QUERY, "Maximum download speed in bytes per second",
+            json!({ "type": "integer", "minimum": 1 })),
+        query_param(SIZE_QUERY, "Total size of the files being uploaded, used to reserve space",
+            json!({ "type": "integer", "minimum": 0 })),
+        query_param(FORMAT_VERSION_QUERY, "Version of the format in which the client encrypted \
+            the upload (1 if not given). Uploads encrypted by the server are always in the \
+            latest version.", json!({ "type": "integer", "minimum": 1, "maximum": FORMAT_VERSION })),
+        query_param(API_KEY_QUERY, &format!("API key, for clients which can't set the `{}` header",
+                API_KEY_HEADER), json!({ "type": "string" })),
+    ];
+
+    if config.ntfy_server.is_some() {
+        params.push(query_param(NOTIFY_TOPIC_QUERY,
+            "ntfy topic on which to be notified when the upload is downloaded or about to expire",
+            json!({ "type": "string", "pattern": "^[A-Za-z0-9_-]{1,64}$" })));
+    }
+
+    if config.smtp_url.is_some() {
+        params.push(query_param(SHARE_EMAIL_QUERY,
+            "Address to which the link is mailed once the upload completes (only for users who \
+            are logged in). The key is only included if the server encrypted the upload.",
+            json!({ "type": "string", "format": "email" })));
+    }
+
+    if config.gallery {
+        params.push(query_param(PUBLIC_QUERY,
+            "Whether to list the upload in the public gallery once it completes (unless it is \
+            password-protected)",
+            json!({ "type": "boolean" })));
+        params.push(query_param(PUBLIC_KEY_QUERY,
+            "Key of an upload encrypted by the client, which is needed to list it in the gallery",
+            json!({ "type": "string" })));
+    }
+
+    params
+}
+
+// Uploads may be made without an API key, unless the instance requires one
+fn upload_security(config: &TranspoConfig) -> Value {
+    if config.require_api_key {
+        json!([{ "apiKey": [] }])
+    } else {
+        json!([{ "apiKey": [] }, {}])
+    }
+}
+
+fn error_responses() -> Value {
+    json!({
+        "400": { "description": "The request is invalid, or the password is wrong" },
+        "404": { "description": "The upload does not exist or has expired" }
+    })
+}
+
+// Describe the HTTP API of this instance as an OpenAPI 3 document
+pub fn spec(config: &TranspoConfig) -> Value {
+    let version = if config.hide_version { "unknown" } else { VERSION };
+
+    let mut download_params = vec![
+        file_id_param(),
+        query_param("key", "Key of the upload, from the part of the download link after `#`. \
+            If it is omitted, the encrypted upload is returned as is (as `{file_id}.age` if it \
+            is stored in the age format).", json!({ "type": "string" })),
+        query_param("identity", "Secret key (`AGE-SECRET-KEY-1...`) of a recipient of the upload, \
+            used to unwrap its key instead", json!({ "type": "string" })),
+        query_param("start_index", "Offset in bytes to resume the download from",
+            json!({ "type": "integer", "minimum": 0 })),
+    ];
+    download_params.extend(password_params());
+
+    let mut info_params = vec![file_id_param()];
+    info_params.extend(password_params());
+
+    let mut entries_params = vec![
+        file_id_param(),
+        query_param("key", "Key of the upload. If it is omitted, the entries are returned \
+            encrypted.", json!({ "type": "string" })),
+        query_param("identity", "Secret key (`AGE-SECRET-KEY-1...`) of a recipient of the upload, \
+            used to unwrap its key instead", json!({ "type": "string" })),
+    ];
+    entries_params.extend(password_params());
+
+    let token_params = vec![
+        file_id_param(),
+        query_param("minutes", &format!(
+                "Number of minutes the token is valid for (default {}, at most {})",
+                DEFAULT_TOKEN_AGE_MINUTES, MAX_TOKEN_AGE_MINUTES),
+            json!({ "type": "integer", "minimum": 1, "maximum": MAX_TOKEN_AGE_MINUTES })),
+    ];
+
+    let paths = vec![
+        ("/upload", json!({
+            "post": {
+                "summary": "Upload files",
+                "description": "Several files are stored as a single zip archive. The response \
+                    is the ID of the upload followed by `#` and its key, as a JSON string.",
+                "security": upload_security(config),
+                "parameters": upload_params(config),
+                "requestBody": { "$ref": "#/components/requestBodies/UploadForm" },
+                "responses": {
+                    "200": {
+                        "description": "The upload was stored",
+                        "content": { "application/json": { "schema": { "type": "string" } } }
+                    },
+                    "400": { "description": "The request is invalid, or the upload is too large" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "429": { "description": "A quota was exceeded. See the `Retry-After` header." },
+                    "503": { "description": "The server is shutting down" }
+                }
+            },
+            "get": {
+                "summary": "Upload a file over a websocket",
+                "description": "Each binary message is a chunk of the file, and an empty message \
+                    ends the upload. The server replies with the ID and key of the upload, then \
+                    with a message for each chunk received.",
+                "security": upload_security(config),
+                "parameters": upload_params(config),
+                "responses": {
+                    "101": { "description": "Switching to the websocket protocol" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "429": { "description": "A quota was exceeded. See the `Retry-After` header." }
+                }
+            }
+        })),
+        ("/api/v1/uploads", json!({
+            "post": {
+                "summary": "Upload files",
+                "description": "Takes the same parameters and form as `POST /upload`, but always \
+                    responds with JSON.",
+                "security": upload_security(config),
+                "parameters": upload_params(config),
+                "requestBody": { "$ref": "#/components/requestBodies/UploadForm" },
+                "responses": {
+                    "201": {
+                        "description": "The upload was stored. `key` and `url` are null if \
+                            the client encrypted the upload itself.",
+                        "content": { "application/json": {
+                            "schema": { "$ref": "#/components/schemas/UploadCreated" }
+                        } }
+                    },
+                    "400": { "$ref": "#/components/responses/Error" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "429": { "$ref": "#/components/responses/Error" }
+                }
+            },
+            "get": {
+                "summary": "List the uploads of the user who is logged in",
+                "security": [{ "session": [] }],
+                "responses": {
+                    "200": {
+                        "description": "The uploads which have not expired, newest first",
+                        "content": { "application/json": {
+                            "schema": {
+                                "type": "array",
+                                "items": {
+                                    "allOf": [
+                                        { "type": "object", "properties": { "id": { "type": "string" } } },
+                                        { "$ref": "#/components/schemas/UploadInfo" }
+                                    ]
+                                }
+                            }
+                        } }
+                    },
+                    "401": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/uploads/{file_id}", json!({
+            "get": {
+                "summary": "Get the metadata of an upload",
+                "parameters": info_params.clone(),
+                "responses": {
+                    "200": {
+                        "description": "The metadata of the upload",
+                        "content": { "application/json": {
+                            "schema": { "$ref": "#/components/schemas/UploadInfo" }
+                        } }
+                    },
+                    "400": { "$ref": "#/components/responses/Error" },
+                    "404": { "$ref": "#/components/responses/Error" }
+                }
+            },
+            "delete": {
+                "summary": "Delete an upload owned by the user who is logged in (or any \
+                    upload, with an API key which has the `admin` scope)",
+                "security": [{ "session": [] }, { "apiKey": [] }],
+                "parameters": [file_id_param()],
+                "responses": {
+                    "204": { "description": "The upload was deleted" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "404": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/reports", json!({
+            "get": {
+                "summary": "List the abuse reports of uploads which still exist",
+                "security": [{ "apiKey": [] }],
+                "responses": {
+                    "200": {
+                        "description": "The reports",
+                        "content": { "application/json": {
+                            "schema": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "properties": {
+                                        "upload_id": { "type": "string" },
+                                        "reason": { "type": "string" },
+                                        "reporter_ip": { "type": "string", "nullable": true },
+                                        "created_at": { "type": "integer" },
+                                        "upload_disabled": { "type": "boolean" }
+                                    }
+                                }
+                            }
+                        } }
+                    },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/reports/{file_id}", json!({
+            "delete": {
+                "summary": "Dismiss the reports of an upload, and allow it to be \
+                    downloaded again",
+                "security": [{ "apiKey": [] }],
+                "parameters": [file_id_param()],
+                "responses": {
+                    "204": { "description": "The reports were dismissed" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "404": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/blocklist", json!({
+            "get": {
+                "summary": "List the blocked networks and uploads",
+                "security": [{ "apiKey": [] }],
+                "responses": {
+                    "200": {
+                        "description": "The blocklists",
+                        "content": { "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "networks": { "type": "array", "items": {
+                                        "type": "object",
+                                        "properties": {
+                                            "id": { "type": "string" },
+                                            "network": { "type": "string" },
+                                            "reason": { "type": "string", "nullable": true },
+                                            "created_at": { "type": "integer" }
+                                        }
+                                    } },
+                                    "uploads": { "type": "array", "items": {
+                                        "type": "object",
+                                        "properties": {
+                                            "id": { "type": "string" },
+                                            "reason": { "type": "string", "nullable": true },
+                                            "created_at": { "type": "integer" }
+                                        }
+                                    } }
+                                }
+                            }
+                        } }
+                    },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/blocklist/networks", json!({
+            "post": {
+                "summary": "Refuse uploads from a network",
+                "security": [{ "apiKey": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/x-www-form-urlencoded": {
+                        "schema": {
+                            "type": "object",
+                            "required": ["network"],
+                            "properties": {
+                                "network": { "type": "string", "example": "192.0.2.0/24" },
+                                "reason": { "type": "string" }
+                            }
+                        }
+                    } }
+                },
+                "responses": {
+                    "201": {
+                        "description": "The network was blocked",
+                        "content": { "application/json": {
+                            "schema": { "type": "object", "properties": { "id": { "type": "string" } } }
+                        } }
+                    },
+                    "400": { "$ref": "#/components/responses/Error" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/blocklist/networks/{id}", json!({
+            "delete": {
+                "summary": "Unblock a network",
+                "security": [{ "apiKey": [] }],
+                "parameters": [{
+                    "name": "id", "in": "path", "required": true,
+                    "schema": { "type": "string" }
+                }],
+                "responses": {
+                    "204": { "description": "The network was unblocked" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "404": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/blocklist/uploads", json!({
+            "post": {
+                "summary": "Make an upload expire now, and keep it from being downloaded \
+                    or restored",
+                "security": [{ "apiKey": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/x-www-form-urlencoded": {
+                        "schema": {
+                            "type": "object",
+                            "required": ["id"],
+                            "properties": {
+                                "id": { "type": "string" },
+                                "reason": { "type": "string" }
+                            }
+                        }
+                    } }
+                },
+                "responses": {
+                    "204": { "description": "The upload was blocked" },
+                    "400": { "$ref": "#/components/responses/Error" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/api/v1/blocklist/uploads/{file_id}", json!({
+            "delete": {
+                "summary": "Remove an upload from the blocklist",
+                "security": [{ "apiKey": [] }],
+                "parameters": [file_id_param()],
+                "responses": {
+                    "204": { "description": "The upload was unblocked" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "404": { "$ref": "#/components/responses/Error" }
+                }
+            }
+        })),
+        ("/{file_id}/info", json!({
+            "get": {
+                "summary": "Get the metadata of an upload",
+                "parameters": info_params.clone(),
+                "responses": {
+                    "200": {
+                        "description": "The metadata of the upload",
+                        "content": { "application/json": {
+                            "schema": { "$ref": "#/components/schemas/UploadInfo" }
+                        } }
+                    },
+                    "400": { "description": "The request is invalid, or the password is wrong" },
+                    "404": { "description": "The upload does not exist or has expired" }
+                }
+            }
+        })),
+        ("/{file_id}/entries", json!({
+            "get": {
+                "summary": "List the entries of an archive created by the server",
+                "description": "Each entry is an object with a `name`, the uncompressed `size` and \
+                    the UNIX timestamp (`mtime`) at which it was added. Without a key, the JSON \
+                    array is returned encrypted with the key of the upload (see CRYPTO.md).",
+                "parameters": entries_params,
+                "responses": {
+                    "200": {
+                        "description": "The entries of the archive",
+                        "content": {
+                            "application/json": { "schema": { "type": "array", "items": {
+                                "$ref": "#/components/schemas/ArchiveEntry"
+                            } } },
+                            "application/octet-stream": {}
+                        }
+                    },
+                    "404": { "description": "The upload does not exist, is not an archive created \
+                        by the server, or the password is wrong" }
+                }
+            }
+        })),
+        ("/{file_id}/events", json!({
+            "get": {
+                "summary": "Stream changes to an upload as server-sent events",
+                "description": "Sends `progress` (`{\"size\": ...}`, while the upload is in progress), \
+                    `completed` (`{\"size\": ...}`) and `downloads` (`{\"remaining_downloads\": ..., \
+                    \"downloads\": ..., \"completed_downloads\": ...}`) events with the current state \
+                    first and then whenever it changes, and a `gone` event once the upload has expired \
+                    or was deleted. The stream ends after a few minutes, after which `EventSource` \
+                    reconnects.",
+                "parameters": info_params,
+                "responses": {
+                    "200": {
+                        "description": "The stream of events",
+                        "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                    },
+                    "400": { "description": "The request is invalid, or the password is wrong" },
+                    "404": { "description": "The upload does not exist or has expired" }
+                }
+            }
+        })),
+        ("/{file_id}/dl", json!({
+            "get": {
+                "summary": "Download an upload",
+                "parameters": download_params,
+                "responses": {
+                    "200": {
+                        "description": "The contents of the upload",
+                        "content": { "application/octet-stream": {
+                            "schema": { "type": "string", "format": "binary" }
+                        } }
+                    },
+                    "400": { "description": "The request is invalid, or the password is wrong" },
+                    "404": { "description": "The upload does not exist or has expired" }
+                }
+            },
+            "post": {
+                "summary": "Download an upload, with the parameters in the request body",
+                "parameters": [file_id_param()],
+                "requestBody": {
+                    "content": { "application/x-www-form-urlencoded": {
+                        "schema": {
+                            "type": "object",
+                            "properties": {
+                                "key": { "type": "string" },
+                                "password": { "type": "string" },
+                                "token": { "type": "string" },
+                                "identity": { "type": "string" },
+                                "start_index": { "type": "integer" }
+                            }
+                        }
+                    } }
+                },
+                "responses": {
+                    "200": { "description": "The contents of the upload" },
+                    "400": { "description": "The request is invalid, or the password is wrong" },
+                    "404": { "description": "The upload does not exist or has expired" }
+                }
+            }
+        })),
+        ("/{file_id}/token", json!({
+            "post": {
+                "summary": "Create a token to download an upload once without its password \
+                    (requires being logged in as its owner, or an API key with the `admin` scope)",
+                "security": [{ "session": [] }, { "apiKey": [] }],
+                "parameters": token_params,
+                "responses": {
+                    "200": {
+                        "description": "The token, as a JSON string",
+                        "content": { "application/json": { "schema": { "type": "string" } } }
+                    },
+                    "400": { "description": "The request is invalid" },
+                    "401": { "$ref": "#/components/responses/Error" },
+                    "403": { "$ref": "#/components/responses/Error" },
+                    "404": { "description": "The upload does not exist or has expired" }
+                }
+            }
+        })),
+        ("/zip", json!({
+            "post": {
+                "summary": "Download several uploads as a single zip archive",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/x-www-form-urlencoded": {
+                        "schema": {
+                            "type": "object",
+                            "required": ["upload"],
+                            "properties": {
+                                "upload": {
+                                    "type": "array",
+                                    "items": { "type": "string" },
+                                    "description": "`file_id:key` or `file_id:key:password` \
+                                        for each upload"
+                                }
+                            }
+                        },
+                        "encoding": { "upload": { "explode": true } }
+                    } }
+                },
+                "responses": error_responses()
+            }
+        })),
+        ("/api/v1/quota", json!({
+            "get": {
+                "summary": "Get how much of each quota the client has used",
+                "security": upload_security(config),
+                "responses": {
+                    "200": {
+                        "description": "Quotas which are disabled are `null`",
+                        "content": { "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "bytes": { "$ref": "#/components/schemas/QuotaUsage" },
+                                    "uploads": { "$ref": "#/components/schemas/QuotaUsage" }
+                                }
+                            }
+                        } }
+                    }
+                }
+            }
+        })),
+        ("/api/admin/cleanup", json!({
+            "post": {
+                "summary": "Delete expired uploads (requires an API key with the `admin` scope)",
+                "security": [{ "apiKey": [] }],
+                "parameters": [
+                    query_param("dry_run", "Report what would be deleted without deleting it",
+                        json!({ "type": "boolean", "allowEmptyValue": true }))
+                ],
+                "responses": {
+                    "200": { "description": "What was deleted", "content": { "application/json": {} } },
+                    "401": { "description": "The API key is invalid or lacks the `admin` scope" }
+                }
+            }
+        })),
+        ("/stats", json!({
+            "get": {
+                "summary": "Get daily usage statistics (requires an API key with the `admin` scope)",
+                "security": [{ "apiKey": [] }],
+                "parameters": [
+                    query_param("days", "Number of days to report", json!({ "type": "integer" }))
+                ],
+                "responses": {
+                    "200": { "description": "Usage for each day", "content": { "application/json": {} } },
+                    "401": { "description": "The API key is invalid or lacks the `admin` scope" }
+                }
+            }
+        })),
+        ("/metrics", json!({
+            "get": {
+                "summary": "Get metrics in the Prometheus text format",
+                "security": [{ "bearer": [] }],
+                "responses": {
+                    "200": { "description": "The metrics", "content": { "text/plain": {} } },
+                    "401": { "description": "The token is missing or wrong" },
+                    "404": { "description": "Metrics are disabled" }
+                }
+            }
+        })),
+        ("/api/sharex", json!({
+            "get": {
+                "summary": "Get a ShareX custom uploader for this instance",
+                "responses": {
+                    "200": { "description": "A `.sxcu` file", "content": { "application/json": {} } }
+                }
+            }
+        })),
+        ("/api/ishare", json!({
+            "get": {
+                "summary": "Get an ishare custom uploader for this instance",
+                "responses": {
+                    "200": { "description": "An `.iscu` file", "content": { "application/json": {} } }
+                }
+            }
+        })),
+        ("/version", json!({
+            "get": {
+                "summary": "Get the version and features of this build",
+                "responses": {
+                    "200": { "description": "The build information", "content": { "application/json": {} } },
+                    "404": { "description": "The version is hidden" }
+                }
+            }
+        })),
+        ("/health", json!({
+            "get": {
+                "summary": "Check whether the server and its database are available",
+                "responses": {
+                    "200": { "description": "The server is available" },
+                    "503": { "description": "The database is unavailable" }
+                }
+            }
+        }))
+    ];
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": config.app_name,
+            "version": version,
+            "description": "Files are encrypted by the server with a key which is returned \
+                when they are uploaded and never stored. Download links have the form \
+                `/{file_id}?nopass#{key}` (`nopass` is omitted if the upload has a password), \
+                or `/{file_id}?derived` if the key is derived from the password. If the key \
+                is wrapped for recipients, `recipient` is added instead of the key."
+        },
+        "components": {
+            "securitySchemes": {
+                "apiKey": { "type": "apiKey", "in": "header", "name": API_KEY_HEADER },
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "session": { "type": "apiKey", "in": "cookie", "name": SESSION_COOKIE }
+            },
+            "responses": {
+                "Error": {
+                    "description": "An error",
+                    "content": { "application/json": {
+                        "schema": {
+                            "type": "object",
+                            "properties": { "error": { "type": "string" } }
+                        }
+                    } }
+                }
+            },
+            "requestBodies": {
+                "UploadForm": {
+                    "content": {
+                        "multipart/form-data": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "files": { "type": "array", "items": { "type": "string", "format": "binary" } },
+                                    "days": { "type": "integer" },
+                                    "hours": { "type": "integer" },
+                                    "minutes": { "type": "integer" },
+                                    "expiry": { "type": "integer", "description": "Total number of minutes, instead of days, hours and minutes" },
+                                    "enable-max-downloads": { "type": "string", "enum": ["on"] },
+                                    "max-downloads": { "type": "integer" },
+                                    "enable-password": { "type": "string", "enum": ["on"] },
+                                    "password": { "type": "string" },
+                                    "recipients": { "type": "string", "description": "age public keys of recipients, separated by whitespace or commas" },
+                                    "age": { "type": "string", "enum": ["on"], "description": "Store the upload as an age file for the recipients (they have to come before the files)" },
+                                    "title": { "type": "string", "maxLength": 256, "description": "Title of the upload, which the server encrypts with its key" },
+                                    "download-speed-limit": { "type": "integer" }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "schemas": {
+                "UploadCreated": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string" },
+                        "key": { "type": "string", "nullable": true },
+                        "url": { "type": "string", "nullable": true, "description": "Path of the download link, relative to the server" }
+                    }
+                },
+                "UploadInfo": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string", "description": "Encrypted file name, base64-encoded" },
+                        "mime": { "type": "string", "description": "Encrypted MIME type, base64-encoded" },
+                        "size": { "type": "integer", "description": "Size of the encrypted upload (0 while it is in progress)" },
+                        "plaintext_size": { "type": "integer", "description": "Size of the decrypted upload, including the padding of padded uploads (0 while it is in progress)" },
+                        "expire_after": { "type": "integer", "description": "UNIX timestamp after which the upload expires" },
+                        "remaining_downloads": { "type": "integer", "nullable": true },
+                        "is_completed": { "type": "boolean" },
+                        "downloads": { "type": "integer" },
+                        "completed_downloads": { "type": "integer" },
+                        "bytes_downloaded": { "type": "integer" },
+                        "format_version": { "type": "integer", "description": "Version of the format in which the upload is encrypted" },
+                        "derived_key": { "type": "boolean", "description": "Whether the key is derived from the password, so the server decrypts the upload" },
+                        "recipients": { "type": "boolean", "description": "Whether the key is wrapped for recipients, so the server decrypts the upload with the secret key of one of them" },
+                        "title": { "type": "string", "description": "Encrypted title, base64-encoded (only present if the upload has one)" },
+                        "checksum": { "type": "string" }
+                    }
+                },
+                "ArchiveEntry": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "size": { "type": "integer", "description": "Size of the uncompressed entry" },
+                        "mtime": { "type": "integer", "description": "UNIX timestamp at which the entry was added" }
+                    }
+                },
+                "QuotaUsage": {
+                    "type": "object",
+                    "nullable": true,
+                    "properties": {
+                        "used": { "type": "integer" },
+                        "limit": { "type": "integer" },
+                        "remaining": { "type": "integer" },
+                        "reset_secs": { "type": "integer", "description": "Seconds until the quota is refilled completely" }
+                    }
+                }
+            }
+        },
+        "paths": paths.into_iter()
+            .map(|(path, item)| (path.to_string(), item))
+            .collect::<Map<String, Value>>()
+    })
+}
+
+pub fn handle(conn: Conn, config: Arc<TranspoConfig>) -> Conn {
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "application/json")
+        .with_body(spec(&config).to_string())
+        .halt()
+}
+
+// Serve a Swagger UI page for the document at `/api/openapi.json`
+pub fn handle_docs(conn: Conn, config: Arc<TranspoConfig>, translation: Translation) -> Conn {
+    if !config.api_docs {
+        return error_404(conn, config, translation);
+    }
+
+    let page = format!(r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{app_name} API</title>
+<link rel="stylesheet" href="{ui}/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="{ui}/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({{ url: "openapi.json", dom_id: "#swagger-ui" }});</script>
+</body>
+</html>
+"##, app_name = html_escape(&config.app_name), ui = SWAGGER_UI_URL);
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(page)
+        .halt()
+}
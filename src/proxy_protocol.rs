@@ -0,0 +1,295 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use smol::io::AsyncReadExt;
+use smol::net::{TcpListener, TcpStream};
+use smol_timeout::TimeoutExt;
+use tracing::{debug, error};
+use trillium::Handler;
+use trillium_http::Stopper;
+use trillium_http::transport::BoxedTransport;
+
+
+// The longest possible v1 header, including the trailing "\r\n"
+const MAX_V1_HEADER_LENGTH: usize = 107;
+const V2_SIGNATURE: &'static [u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+// Only the addresses are read from the header, so anything past them (TLVs)
+// is skipped. Proxies won't send more than this.
+const MAX_V2_ADDRESSES_LENGTH: usize = 512;
+// Proxies send the header right after connecting
+const HEADER_TIMEOUT_SECS: u64 = 5;
+
+
+fn invalid_header() -> Error {
+    Error::new(ErrorKind::InvalidData, "Invalid PROXY protocol header")
+}
+
+// Parse a v1 (text) header, e.g. "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".
+// Return the address of the client, or None if the proxy doesn't know it.
+fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>> {
+    let header = str::from_utf8(header)
+        .ok()
+        .and_then(|h| h.strip_suffix("\r\n"))
+        .ok_or_else(invalid_header)?;
+    let fields = header.split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid_header())?;
+            let port: u16 = port.parse().map_err(|_| invalid_header())?;
+
+            match (*protocol, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) =>
+                    Ok(Some(SocketAddr::new(ip, port))),
+                _ => Err(invalid_header())
+            }
+        },
+        _ => Err(invalid_header())
+    }
+}
+
+// Parse the addresses following a v2 (binary) header. Return the address of
+// the client, or None if the connection was made by the proxy itself (e.g. a
+// health check) or its address family is not supported.
+fn parse_v2(header: &[u8; V2_HEADER_LENGTH], addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0F;
+    let family = header[13];
+
+    if &header[..12] != V2_SIGNATURE || version != 2 {
+        return Err(invalid_header());
+    }
+
+    match command {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {},
+        _ => return Err(invalid_header())
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        },
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        },
+        0x11 | 0x21 => Err(invalid_header()),
+        _ => Ok(None)
+    }
+}
+
+// Read the PROXY protocol header at the start of `stream`, without reading
+// any of the request after it. Return the address of the client, if the
+// proxy sent one.
+async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    // Shorter than any valid header, so this never reads past one
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; V2_HEADER_LENGTH];
+        header[..12].copy_from_slice(&start);
+        stream.read_exact(&mut header[12..]).await?;
+
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        if length > MAX_V2_ADDRESSES_LENGTH {
+            return Err(invalid_header());
+        }
+        let mut addresses = vec![0; length];
+        stream.read_exact(&mut addresses).await?;
+
+        parse_v2(&header, &addresses)
+    } else if start.starts_with(b"PROXY ") {
+        let mut header = start.to_vec();
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= MAX_V1_HEADER_LENGTH {
+                return Err(invalid_header());
+            }
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await?;
+            header.push(byte[0]);
+        }
+
+        parse_v1(&header)
+    } else {
+        Err(invalid_header())
+    }
+}
+
+async fn handle_stream<H: Handler>(
+    mut stream: TcpStream, handler: Arc<H>, trusted_proxies: Arc<Vec<IpNet>>,
+    stopper: Stopper)
+{
+    let peer_ip = match stream.peer_addr() {
+        Ok(address) => address.ip(),
+        Err(_) => return
+    };
+
+    // Anyone else could claim to be connecting on behalf of any address
+    if !trusted_proxies.iter().any(|net| net.contains(&peer_ip)) {
+        debug!(%peer_ip, "Refusing connection from an untrusted proxy");
+        return;
+    }
+
+    let client_address = match read_header(&mut stream)
+        .timeout(Duration::from_secs(HEADER_TIMEOUT_SECS)).await
+    {
+        Some(Ok(address)) => address,
+        Some(Err(e)) => {
+            debug!(%peer_ip, "Reading PROXY protocol header: {}", e);
+            return;
+        },
+        None => return
+    };
+    let client_ip = client_address.map(|a| a.ip()).unwrap_or(peer_ip);
+
+    let result = trillium_http::Conn::map(
+        stream, stopper, |mut conn| {
+            let handler = handler.clone();
+            async move {
+                conn.set_peer_ip(Some(client_ip));
+                let conn = handler.run(conn.into()).await;
+                let conn = handler.before_send(conn).await;
+                conn.into_inner()
+            }
+        }).await;
+
+    match result {
+        Ok(Some(upgrade)) => {
+            let upgrade = upgrade.map_transport(BoxedTransport::new);
+            if handler.has_upgrade(&upgrade) {
+                handler.upgrade(upgrade).await;
+            }
+        },
+        Ok(None) => {},
+        Err(e) => debug!(%client_ip, "Handling connection: {}", e)
+    }
+}
+
+// Like `trillium_smol::run`, except that every connection must start with a
+// PROXY protocol (v1 or v2) header from one of `trusted_proxies`, and the
+// client address it contains is used as the address of the peer
+pub fn run<H: Handler>(
+    address: SocketAddr, handler: H, trusted_proxies: Vec<IpNet>, stopper: Stopper)
+{
+    let handler = Arc::new(handler);
+    let trusted_proxies = Arc::new(trusted_proxies);
+
+    smol::block_on(async move {
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(%address, "Binding listener: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            match stopper.stop_future(listener.accept()).await {
+                Some(Ok((stream, _))) => {
+                    smol::spawn(handle_stream(
+                        stream, handler.clone(), trusted_proxies.clone(),
+                        stopper.clone())).detach();
+                },
+                Some(Err(e)) => error!("Accepting connection: {}", e),
+                None => break
+            }
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_tcp4() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        assert_eq!(
+            parse_v1(header).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(
+            parse_v1(header).unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_invalid() {
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").is_err());
+        assert!(parse_v1(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    fn v2_header(command: u8, family: u8, length: u16) -> [u8; V2_HEADER_LENGTH] {
+        let mut header = [0; V2_HEADER_LENGTH];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = 0x20 | command;
+        header[13] = family;
+        header[14..].copy_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        assert_eq!(
+            parse_v2(&v2_header(1, 0x11, 12), &addresses).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let mut addresses = [0; 36];
+        addresses[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[16..32].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        addresses[34..].copy_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(&v2_header(1, 0x21, 36), &addresses).unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local() {
+        assert_eq!(parse_v2(&v2_header(0, 0x00, 0), &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn v2_invalid() {
+        // truncated addresses
+        assert!(parse_v2(&v2_header(1, 0x11, 4), &[192, 0, 2, 1]).is_err());
+        // unknown command
+        assert!(parse_v2(&v2_header(2, 0x11, 0), &[]).is_err());
+        // wrong version
+        let mut header = v2_header(1, 0x11, 0);
+        header[12] = 0x11;
+        assert!(parse_v2(&header, &[0; 12]).is_err());
+    }
+}
@@ -1,67 +1,483 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use crate::config::TranspoConfig;
+use crate::logging::{log_security_event, SecurityEvent};
+use crate::metrics::{count_rejection, Rejection};
+
+
+// Length of the interval over which uploads are counted
+const UPLOAD_COUNT_INTERVAL_SECS: u64 = 60 * 60;
+
+// Storage for the token bucket of each address. Buckets are kept in memory by
+// default, but may be shared by several Transpo processes.
+//
+// Each bucket holds up to `quota_bytes_total` bytes and is refilled
+// continuously at `quota_bytes_per_minute`. Refilling happens lazily whenever
+// a bucket is used, so there is no need to visit every bucket periodically.
+pub trait QuotaStore: Send + Sync {
+    // Take the given number of bytes from the bucket for the given address.
+    // Return whether there were enough along with the number of bytes taken
+    // from the bucket afterwards (or None if the bucket is unavailable).
+    // Nothing is taken if there weren't enough.
+    fn take(&self, addr: &IpAddr, bytes: usize) -> Option<(bool, f64)>;
+
+    // Forget buckets which have been refilled completely
+    fn prune(&self);
+}
+
+// Milliseconds since the UNIX epoch
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Bucket {
+    // Bytes taken from the bucket, i.e. capacity minus the bytes remaining.
+    // This is a float so that refills smaller than a byte aren't lost when
+    // the bucket is used in quick succession.
+    used: f64,
+    // milliseconds since the UNIX epoch
+    updated_at: u64
+}
+
+pub struct MemoryQuotaStore {
+    capacity: usize,
+    bytes_per_minute: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    // file to which the buckets are saved, so that they survive restarts
+    state_path: Option<PathBuf>
+}
+
+impl MemoryQuotaStore {
+    // Create a store which saves its buckets to the given file every minute,
+    // starting with the buckets previously saved there (if any)
+    pub fn with_state_file(
+        capacity: usize, bytes_per_minute: usize, state_path: PathBuf) -> Self
+    {
+        let buckets = fs::read(&state_path).ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        Self {
+            capacity,
+            bytes_per_minute,
+            buckets: Mutex::new(buckets),
+            state_path: Some(state_path)
+        }
+    }
+
+    // Give back the bytes refilled since the bucket was last updated
+    fn refill(&self, bucket: &mut Bucket, now: u64) {
+        let elapsed_ms = now.saturating_sub(bucket.updated_at) as f64;
+        let refill = elapsed_ms * self.bytes_per_minute as f64 / 60000.0;
+
+        bucket.used = (bucket.used - refill).max(0.0);
+        bucket.updated_at = now;
+    }
+
+    fn save(&self, buckets: &HashMap<IpAddr, Bucket>) {
+        if let Some(state_path) = &self.state_path {
+            // Write to a temporary file first so that a crash can't leave a
+            // truncated state file behind
+            let tmp_path = state_path.with_extension("tmp");
+            let result = fs::write(&tmp_path, serde_json::to_vec(buckets).unwrap())
+                .and_then(|_| fs::rename(&tmp_path, state_path));
+
+            if let Err(e) = result {
+                warn!("Saving quotas: {}", e);
+            }
+        }
+    }
+}
+
+impl QuotaStore for MemoryQuotaStore {
+    fn take(&self, addr: &IpAddr, bytes: usize) -> Option<(bool, f64)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = unix_time_ms();
+
+        let bucket = buckets.entry(*addr)
+            .or_insert(Bucket { used: 0.0, updated_at: now });
+        self.refill(bucket, now);
+
+        if bucket.used + bytes as f64 > self.capacity as f64 {
+            Some((false, bucket.used))
+        } else {
+            bucket.used += bytes as f64;
+            Some((true, bucket.used))
+        }
+    }
+
+    fn prune(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = unix_time_ms();
+
+        for bucket in buckets.values_mut() {
+            self.refill(bucket, now);
+        }
+        buckets.retain(|_, bucket| bucket.used > 0.0);
+
+        self.save(&buckets);
+    }
+}
+
+
+// The state of a quota after trying to use it
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaStatus {
+    pub is_exceeded: bool,
+    pub limit: usize,
+    pub remaining: usize,
+    // seconds until the quota is refilled completely
+    pub reset_secs: u64,
+    // seconds until the bytes which were asked for are available
+    pub retry_after_secs: u64
+}
+
+impl QuotaStatus {
+    // Headers telling the client how to back off
+    pub fn headers(&self) -> [(&'static str, String); 4] {
+        [
+            ("Retry-After", self.retry_after_secs.to_string()),
+            ("X-RateLimit-Limit", self.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            ("X-RateLimit-Reset", self.reset_secs.to_string())
+        ]
+    }
+}
+
+// Lets an exceeded quota be returned as an `io::Error` from upload parsing
+impl fmt::Display for QuotaStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quota exceeded, retry after {} seconds", self.retry_after_secs)
+    }
+}
 
+impl std::error::Error for QuotaStatus {}
+
+// How much of a quota has been used, as reported to clients
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    pub used: usize,
+    pub limit: usize,
+    pub remaining: usize,
+    // seconds until the quota is refilled completely
+    pub reset_secs: u64
+}
+
+impl From<QuotaStatus> for QuotaUsage {
+    fn from(status: QuotaStatus) -> Self {
+        Self {
+            used: status.limit - status.remaining,
+            limit: status.limit,
+            remaining: status.remaining,
+            reset_secs: status.reset_secs
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Quotas {
     max_bytes: usize,
     bytes_per_minute: usize,
-    quotas: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    // addresses in the same network of this size share a quota
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    store: Arc<dyn QuotaStore>
 }
 
-impl From<&TranspoConfig> for Quotas {
-    fn from(config: &TranspoConfig) -> Self {
+impl Quotas {
+    pub fn new(config: &TranspoConfig, store: Arc<dyn QuotaStore>) -> Self {
         Self {
             max_bytes: config.quota_bytes_total,
             bytes_per_minute: config.quota_bytes_per_minute,
-            quotas: Arc::new(Mutex::new(HashMap::new()))
+            ipv4_prefix: config.quota_ipv4_prefix,
+            ipv6_prefix: config.quota_ipv6_prefix,
+            store
         }
     }
-}
 
-impl Quotas {
-    // Return whether or not writing the given amount of bytes would exceed
-    // the quota for the given address
-    pub fn exceeds_quota(&self, addr: &IpAddr, bytes: usize) -> bool {
-        let mut quotas = self.quotas.lock().unwrap();
-
-        let count = match quotas.get_mut(addr) {
-            Some(count) => {
-                *count += bytes;
-                *count
-            },
-            None => {
-                quotas.insert(addr.to_owned(), bytes);
-                bytes
-            }
+    // Number of seconds it takes to refill the given number of bytes
+    fn refill_secs(&self, bytes: f64) -> u64 {
+        if bytes <= 0.0 {
+            0
+        } else if self.bytes_per_minute == 0 {
+            // never
+            u32::MAX as u64
+        } else {
+            (bytes * 60.0 / self.bytes_per_minute as f64).ceil() as u64
+        }
+    }
+
+    // Use the given amount of bytes from the quota for the given address and
+    // return the state of the quota (or None if it can't be checked)
+    pub fn use_quota(&self, addr: &IpAddr, bytes: usize) -> Option<QuotaStatus> {
+        let network = quota_network(addr, self.ipv4_prefix, self.ipv6_prefix);
+        let (is_available, used) = self.store.take(&network, bytes)?;
+        let missing = if is_available {
+            0.0
+        } else {
+            used + bytes as f64 - self.max_bytes as f64
         };
 
-        count > self.max_bytes
+        Some(QuotaStatus {
+            is_exceeded: !is_available,
+            limit: self.max_bytes,
+            remaining: self.max_bytes.saturating_sub(used.ceil() as usize),
+            reset_secs: self.refill_secs(used),
+            retry_after_secs: self.refill_secs(missing)
+        })
+    }
+
+    // Return the state of the quota for the given address if it has been
+    // used up completely, otherwise None. Uploads are not blocked if the
+    // quota can't be checked.
+    pub fn exceeded_quota(&self, addr: &IpAddr, bytes: usize) -> Option<QuotaStatus> {
+        let status = self.use_quota(addr, bytes).filter(|status| status.is_exceeded);
+        if status.is_some() {
+            count_rejection(Rejection::Quota);
+            log_security_event(SecurityEvent::QuotaExceeded, Some(*addr));
+        }
+        status
+    }
+
+    fn prune(&self) {
+        self.store.prune();
+    }
+}
+
+// Return the address of the network whose quota applies to `addr`
+fn quota_network(addr: &IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    // IPv4 clients of dual-stack sockets show up as IPv4-mapped addresses
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*addr),
+        IpAddr::V4(_) => *addr
+    };
+    let prefix = match addr {
+        IpAddr::V4(_) => ipv4_prefix,
+        IpAddr::V6(_) => ipv6_prefix
+    };
+
+    IpNet::new(addr, prefix)
+        .map(|net| net.network())
+        .unwrap_or(addr)
+}
+
+
+// Whoever an upload is counted against
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Uploader {
+    Address(IpAddr),
+    // uploads made with an API key are counted against the key, wherever
+    // they come from
+    ApiKey(i64)
+}
+
+// Count the number of uploads started by each uploader, so that many small
+// uploads can be limited as well as large ones. Uploads are counted in fixed
+// intervals of an hour starting with each uploader's first upload.
+#[derive(Clone)]
+pub struct UploadCounts {
+    max_uploads: usize,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    // start of the current interval (seconds since the UNIX epoch) and the
+    // number of uploads started during it
+    counts: Arc<Mutex<HashMap<Uploader, (u64, usize)>>>
+}
+
+impl UploadCounts {
+    pub fn new(config: &TranspoConfig) -> Self {
+        Self {
+            max_uploads: config.quota_uploads,
+            ipv4_prefix: config.quota_ipv4_prefix,
+            ipv6_prefix: config.quota_ipv6_prefix,
+            counts: Arc::new(Mutex::new(HashMap::new()))
+        }
     }
 
-    fn replenish(&self) {
-        let mut quotas = self.quotas.lock().unwrap();
+    // Count `uploads` (0 or 1) more uploads by the given uploader and return
+    // the state of their quota. Nothing is counted if they have already
+    // started the maximum number of uploads in the current interval.
+    fn count(&self, uploader: Uploader, uploads: usize) -> QuotaStatus {
+        let uploader = match uploader {
+            Uploader::Address(addr) => Uploader::Address(
+                quota_network(&addr, self.ipv4_prefix, self.ipv6_prefix)),
+            Uploader::ApiKey(_) => uploader
+        };
 
-        quotas.retain(|_, count| *count > self.bytes_per_minute);
+        let now = unix_time_ms() / 1000;
+        let mut counts = self.counts.lock().unwrap();
+        let (start, count) = match counts.get(&uploader) {
+            Some(&(start, count)) if now < start + UPLOAD_COUNT_INTERVAL_SECS => (start, count),
+            // The interval starts with the first upload
+            _ => (now, 0)
+        };
 
-        for count in quotas.values_mut() {
-            *count -= self.bytes_per_minute;
+        let is_exceeded = count + uploads > self.max_uploads;
+        let count = if is_exceeded { count } else { count + uploads };
+        if count > 0 {
+            counts.insert(uploader, (start, count));
         }
+
+        let reset_secs = if count == 0 {
+            0
+        } else {
+            start + UPLOAD_COUNT_INTERVAL_SECS - now
+        };
+
+        QuotaStatus {
+            is_exceeded,
+            limit: self.max_uploads,
+            remaining: self.max_uploads.saturating_sub(count),
+            reset_secs,
+            retry_after_secs: if is_exceeded { reset_secs } else { 0 }
+        }
+    }
+
+    // Count an upload by the given uploader. Return the state of their quota
+    // if they have already started the maximum number of uploads in the
+    // current interval (in which case the upload is not counted), otherwise
+    // None.
+    pub fn count_upload(&self, uploader: Uploader) -> Option<QuotaStatus> {
+        Some(self.count(uploader, 1)).filter(|status| status.is_exceeded)
+    }
+
+    // Return the state of the given uploader's quota without counting anything
+    pub fn status(&self, uploader: Uploader) -> QuotaStatus {
+        self.count(uploader, 0)
+    }
+
+    // Forget uploaders whose interval is over
+    fn prune(&self) {
+        let now = unix_time_ms() / 1000;
+        self.counts.lock().unwrap()
+            .retain(|_, (start, _)| now < *start + UPLOAD_COUNT_INTERVAL_SECS);
     }
 }
 
-pub fn spawn_quotas_thread(quotas: Quotas) {
-    thread::spawn(move || quotas_thread(quotas));
+
+pub fn spawn_quotas_thread(quotas: Option<Quotas>, upload_counts: Option<UploadCounts>) {
+    if quotas.is_some() || upload_counts.is_some() {
+        thread::spawn(move || quotas_thread(quotas, upload_counts));
+    }
 }
 
-fn quotas_thread(quotas: Quotas) {
+fn quotas_thread(quotas: Option<Quotas>, upload_counts: Option<UploadCounts>) {
     loop {
         thread::sleep(Duration::from_secs(60));
-        quotas.replenish();
+        if let Some(quotas) = &quotas {
+            quotas.prune();
+        }
+        if let Some(upload_counts) = &upload_counts {
+            upload_counts.prune();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_store(capacity: usize, bytes_per_minute: usize) -> Arc<MemoryQuotaStore> {
+        Arc::new(MemoryQuotaStore {
+            capacity,
+            bytes_per_minute,
+            buckets: Mutex::new(HashMap::new()),
+            state_path: None
+        })
+    }
+
+    fn test_quotas(max_bytes: usize, bytes_per_minute: usize) -> Quotas {
+        Quotas {
+            max_bytes,
+            bytes_per_minute,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            store: memory_store(max_bytes, bytes_per_minute)
+        }
+    }
+
+    #[test]
+    fn buckets_refuse_more_than_their_capacity() {
+        let store = memory_store(100, 60);
+        let addr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(store.take(&addr, 60), Some((true, 60.0)));
+        // Nothing is taken if there isn't enough left
+        let (is_available, used) = store.take(&addr, 50).unwrap();
+        assert!(!is_available);
+        assert!(used <= 60.0 && used > 59.0);
+        let (is_available, _) = store.take(&addr, 40).unwrap();
+        assert!(is_available);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let store = memory_store(100, 60);
+        let mut bucket = Bucket { used: 60.0, updated_at: 0 };
+
+        // 60 bytes per minute is one per second
+        store.refill(&mut bucket, 30_000);
+        assert_eq!(bucket.used, 30.0);
+        assert_eq!(bucket.updated_at, 30_000);
+        store.refill(&mut bucket, 30_500);
+        assert_eq!(bucket.used, 29.5);
+        // ...but never past empty
+        store.refill(&mut bucket, 1_000_000);
+        assert_eq!(bucket.used, 0.0);
+    }
+
+    #[test]
+    fn networks_share_a_quota() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert_eq!(quota_network(&ip("192.0.2.77"), 24, 64), ip("192.0.2.0"));
+        assert_eq!(quota_network(&ip("192.0.2.77"), 32, 64), ip("192.0.2.77"));
+        assert_eq!(quota_network(&ip("2001:db8::1:2:3:4"), 24, 64), ip("2001:db8::"));
+        // IPv4-mapped addresses share the quota of their IPv4 network
+        assert_eq!(quota_network(&ip("::ffff:192.0.2.77"), 24, 64), ip("192.0.2.0"));
+
+        let quotas = test_quotas(100, 60);
+        assert!(!quotas.use_quota(&ip("192.0.2.1"), 60).unwrap().is_exceeded);
+        assert!(quotas.use_quota(&ip("::ffff:192.0.2.2"), 60).unwrap().is_exceeded);
+        assert!(!quotas.use_quota(&ip("192.0.3.1"), 60).unwrap().is_exceeded);
+    }
+
+    #[test]
+    fn retry_after_waits_for_the_missing_bytes() {
+        let addr = "192.0.2.1".parse().unwrap();
+
+        let quotas = test_quotas(100, 60);
+        let status = quotas.use_quota(&addr, 80).unwrap();
+        assert!(!status.is_exceeded);
+        assert_eq!(status.remaining, 20);
+        assert_eq!(status.reset_secs, 80);
+        assert_eq!(status.retry_after_secs, 0);
+
+        // 30 bytes are missing, which are refilled in 30 seconds
+        let status = quotas.use_quota(&addr, 50).unwrap();
+        assert!(status.is_exceeded);
+        assert_eq!(status.retry_after_secs, 30);
+        assert_eq!(status.headers()[0], ("Retry-After", "30".to_string()));
+
+        // A quota which is never refilled can't be retried
+        let quotas = test_quotas(100, 0);
+        quotas.use_quota(&addr, 100).unwrap();
+        assert_eq!(quotas.use_quota(&addr, 1).unwrap().retry_after_secs, u32::MAX as u64);
     }
 }
@@ -0,0 +1,137 @@
+use std::io::{Error, ErrorKind, Result};
+
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, NewAead};
+use bech32::{FromBase32, Variant};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+use crate::b64;
+use crate::random_bytes::*;
+
+
+// The key of an upload can be wrapped for recipients, who unwrap it with their
+// own secret key instead of getting it in the link. Recipients are given as
+// age X25519 public keys (`age1...`) and unwrap with the matching secret key
+// (`AGE-SECRET-KEY-1...`), so keys made with `age-keygen` can be used. The key
+// is wrapped like age wraps file keys, but not in age's format (see CRYPTO.md).
+
+const RECIPIENT_HRP: &'static str = "age";
+const IDENTITY_HRP: &'static str = "age-secret-key-";
+const WRAP_INFO: &'static [u8] = b"transpo2 recipient";
+// separates the keys wrapped for each recipient when they are stored
+const SEPARATOR: &'static str = ",";
+
+pub const MAX_RECIPIENTS: usize = 16;
+
+fn other_error(message: &'static str) -> Error {
+    Error::new(ErrorKind::Other, message)
+}
+
+fn decode_key(encoded: &str, hrp: &str) -> Option<[u8; 32]> {
+    let (decoded_hrp, data, variant) = bech32::decode(encoded).ok()?;
+    if decoded_hrp != hrp || variant != Variant::Bech32 {
+        return None;
+    }
+
+    Vec::<u8>::from_base32(&data).ok()?.try_into().ok()
+}
+
+// Parse public keys separated by whitespace or commas. Return None if any of
+// them is invalid or there are too many.
+pub fn parse_recipients(list: &str) -> Option<Vec<[u8; 32]>> {
+    let recipients = list
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|r| !r.is_empty())
+        .map(|r| decode_key(r, RECIPIENT_HRP))
+        .collect::<Option<Vec<_>>>()?;
+
+    if recipients.len() > MAX_RECIPIENTS {
+        None
+    } else {
+        Some(recipients)
+    }
+}
+
+// Parse the secret key of a recipient
+pub fn parse_identity(identity: &str) -> Option<[u8; 32]> {
+    decode_key(identity.trim(), IDENTITY_HRP)
+}
+
+// The cipher for the key of an upload wrapped for `recipient`, derived from
+// the secret shared with the ephemeral key
+fn wrapping_cipher(
+    shared_secret: &SharedSecret, ephemeral: &PublicKey,
+    recipient: &PublicKey) -> Result<Aes256Gcm>
+{
+    // (low order points would give a secret anyone can work out)
+    if !shared_secret.was_contributory() {
+        return Err(other_error("Invalid public key"));
+    }
+
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key_slice = [0; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes())
+        .expand(WRAP_INFO, &mut key_slice)
+        .or(Err(other_error("expand")))?;
+
+    Ok(Aes256Gcm::new(Key::from_slice(&key_slice)))
+}
+
+// Wrap the b64 encoded key of an upload for each of `recipients`. Return the
+// wrapped keys as they are stored: for each recipient, the b64 encoded
+// ephemeral public key followed by the encrypted key.
+pub fn wrap_key_for_recipients(key: &[u8], recipients: &[[u8; 32]]) -> Result<String> {
+    let key_slice = b64::base64_decode_lenient(key).ok_or(other_error("base64_decode"))?;
+    let mut wrapped_keys = Vec::with_capacity(recipients.len());
+
+    for recipient in recipients {
+        let recipient = PublicKey::from(*recipient);
+        let mut ephemeral_bytes = [0; 32];
+        random_bytes(&mut ephemeral_bytes);
+        let ephemeral_secret = StaticSecret::from(ephemeral_bytes);
+        let ephemeral = PublicKey::from(&ephemeral_secret);
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient);
+
+        // Every ephemeral key gives a new key, so the nonce doesn't need to
+        // change
+        let wrapped_key = wrapping_cipher(&shared_secret, &ephemeral, &recipient)?
+            .encrypt(Nonce::from_slice(&[0; 12]), key_slice.as_slice())
+            .or(Err(other_error("encrypt")))?;
+
+        let mut stanza = ephemeral.as_bytes().to_vec();
+        stanza.extend_from_slice(&wrapped_key);
+        wrapped_keys.push(String::from_utf8(b64::base64_encode(&stanza)).unwrap());
+    }
+
+    Ok(wrapped_keys.join(SEPARATOR))
+}
+
+// Return the b64 encoded key wrapped by `wrap_key_for_recipients` for the
+// recipient whose secret key is `identity`
+pub fn unwrap_key_for_recipient(wrapped_keys: &str, identity: &[u8; 32]) -> Result<Vec<u8>> {
+    let secret = StaticSecret::from(*identity);
+    let recipient = PublicKey::from(&secret);
+
+    for stanza in wrapped_keys.split(SEPARATOR) {
+        let stanza = match b64::base64_decode(stanza.as_bytes()) {
+            Some(stanza) if stanza.len() > 32 => stanza,
+            _ => continue
+        };
+        let (ephemeral_bytes, wrapped_key) = stanza.split_at(32);
+        let ephemeral = PublicKey::from(<[u8; 32]>::try_from(ephemeral_bytes).unwrap());
+
+        let key_slice = wrapping_cipher(&secret.diffie_hellman(&ephemeral), &ephemeral, &recipient)
+            .ok()
+            .and_then(|cipher| cipher.decrypt(Nonce::from_slice(&[0; 12]), wrapped_key).ok());
+        if let Some(key_slice) = key_slice {
+            return Ok(b64::base64_encode(&key_slice));
+        }
+    }
+
+    Err(other_error("The key is not wrapped for this recipient"))
+}
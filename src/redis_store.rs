@@ -0,0 +1,158 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redis::{Client, Connection, RedisResult, Script};
+use tracing::error;
+
+use crate::concurrency::AccessCounter;
+use crate::quotas::QuotaStore;
+
+
+const QUOTA_KEY_PREFIX: &'static str = "transpo:quota:";
+const ACCESSORS_KEY_PREFIX: &'static str = "transpo:accessors:";
+
+// Take ARGV[1] bytes from the token bucket in KEYS[1] with a capacity of
+// ARGV[4] bytes, after refilling ARGV[2] bytes per minute since it was last
+// updated at ARGV[3] (milliseconds). Return 1 if there were enough bytes
+// (otherwise take nothing and return 0) along with the number of bytes taken
+// from the bucket as a string, since Redis truncates numbers returned by
+// scripts to integers. The key expires once the bucket would have been
+// refilled completely.
+const QUOTA_SCRIPT: &'static str = "
+local used = tonumber(redis.call('HGET', KEYS[1], 'used') or '0')
+local now = tonumber(ARGV[3])
+local updated = tonumber(redis.call('HGET', KEYS[1], 'updated') or ARGV[3])
+local bytes_per_minute = tonumber(ARGV[2])
+local refill = math.max(0, now - updated) * bytes_per_minute / 60000
+used = math.max(0, used - refill)
+local is_available = used + tonumber(ARGV[1]) <= tonumber(ARGV[4])
+if is_available then
+    used = used + tonumber(ARGV[1])
+end
+redis.call('HSET', KEYS[1], 'used', tostring(used), 'updated', now)
+if bytes_per_minute > 0 then
+    redis.call('PEXPIRE', KEYS[1], math.max(1, math.ceil(used * 60000 / bytes_per_minute)))
+end
+if is_available then
+    return {1, tostring(used)}
+else
+    return {0, tostring(used)}
+end
+";
+
+
+// A connection which is re-established if a command fails
+struct RedisConnection {
+    client: Client,
+    connection: Mutex<Option<Connection>>
+}
+
+impl RedisConnection {
+    fn new(redis_url: &str) -> RedisResult<Self> {
+        let client = Client::open(redis_url)?;
+        let connection = client.get_connection()?;
+
+        Ok(Self {
+            client,
+            connection: Mutex::new(Some(connection))
+        })
+    }
+
+    fn run<T, F>(&self, f: F) -> Option<T>
+    where F: FnOnce(&mut Connection) -> RedisResult<T>
+    {
+        let mut connection = self.connection.lock().unwrap();
+
+        if connection.is_none() {
+            *connection = self.client.get_connection().ok();
+        }
+
+        let result = f(connection.as_mut()?);
+        if let Err(e) = &result {
+            error!("Redis command failed: {}", e);
+            // Reconnect on the next command
+            *connection = None;
+        }
+
+        result.ok()
+    }
+}
+
+
+pub struct RedisQuotaStore {
+    capacity: usize,
+    bytes_per_minute: usize,
+    connection: RedisConnection,
+    script: Script
+}
+
+impl RedisQuotaStore {
+    pub fn new(
+        redis_url: &str, capacity: usize, bytes_per_minute: usize) -> RedisResult<Self>
+    {
+        Ok(Self {
+            capacity,
+            bytes_per_minute,
+            connection: RedisConnection::new(redis_url)?,
+            script: Script::new(QUOTA_SCRIPT)
+        })
+    }
+}
+
+impl QuotaStore for RedisQuotaStore {
+    fn take(&self, addr: &IpAddr, bytes: usize) -> Option<(bool, f64)> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+        let key = format!("{}{}", QUOTA_KEY_PREFIX, addr);
+
+        self.connection.run(|c| self.script
+            .key(&key)
+            .arg(bytes)
+            .arg(self.bytes_per_minute)
+            .arg(now)
+            .arg(self.capacity)
+            .invoke::<(i64, String)>(c))
+            .map(|(is_available, used)| (is_available == 1, used.parse().unwrap_or(0.0)))
+    }
+
+    // Full buckets expire on their own
+    fn prune(&self) {}
+}
+
+
+pub struct RedisAccessCounter {
+    connection: RedisConnection
+}
+
+impl RedisAccessCounter {
+    pub fn new(redis_url: &str) -> RedisResult<Self> {
+        Ok(Self {
+            connection: RedisConnection::new(redis_url)?
+        })
+    }
+}
+
+impl AccessCounter for RedisAccessCounter {
+    fn increment(&self, id: i64) -> Option<()> {
+        let key = format!("{}{}", ACCESSORS_KEY_PREFIX, id);
+        self.connection.run(|c| redis::cmd("INCR").arg(&key).query::<i64>(c))
+            .map(|_| ())
+    }
+
+    fn decrement(&self, id: i64) -> Option<()> {
+        let key = format!("{}{}", ACCESSORS_KEY_PREFIX, id);
+        let count = self.connection.run(|c| redis::cmd("DECR").arg(&key).query::<i64>(c))?;
+
+        if count <= 0 {
+            self.connection.run(|c| redis::cmd("DEL").arg(&key).query::<i64>(c));
+        }
+
+        Some(())
+    }
+
+    fn count(&self, id: i64) -> Option<i64> {
+        let key = format!("{}{}", ACCESSORS_KEY_PREFIX, id);
+        self.connection.run(|c| redis::cmd("GET").arg(&key).query::<Option<i64>>(c))
+            .map(|count| count.unwrap_or(0))
+    }
+}
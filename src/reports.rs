@@ -0,0 +1,213 @@
+use crate::api_keys::*;
+use crate::b64::*;
+use crate::config::*;
+use crate::constants::*;
+use crate::db::*;
+use crate::http_errors::*;
+use crate::templates::*;
+use crate::translations::*;
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use blocking::unblock;
+use chrono::Local;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use smol::io::AsyncReadExt;
+use trillium::Conn;
+use trillium_askama::AskamaConnExt;
+use tracing::{info, warn};
+use urlencoding::decode;
+
+
+const MAX_FORM_BODY_SIZE: u64 = 4096;
+const MAX_REASON_LENGTH: usize = 1000;
+
+
+// A report, as listed by `GET /api/v1/reports`
+#[derive(Serialize)]
+struct ReportInfo {
+    upload_id: String,
+    reason: String,
+    reporter_ip: Option<String>,
+    // Unix timestamp
+    created_at: i64,
+    // whether downloads of the upload are disabled
+    upload_disabled: bool
+}
+
+fn parse_id(id_string: &str) -> Option<i64> {
+    match i64_from_b64_bytes(id_string.as_bytes()) {
+        Some(id) if id_string.len() == base64_encode_length(ID_LENGTH) => Some(id),
+        _ => None
+    }
+}
+
+// Parse an `application/x-www-form-urlencoded` request body containing
+// `reason`
+async fn parse_reason(conn: &mut Conn) -> Option<String> {
+    let mut body = String::new();
+    conn.request_body().await
+        .take(MAX_FORM_BODY_SIZE)
+        .read_to_string(&mut body).await
+        .ok()?;
+
+    let reason = body.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| *key == "reason")
+        .and_then(|(_, value)| decode(&value.replace('+', "%20")).ok().map(Cow::into_owned))?;
+    let reason = reason.trim();
+
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_LENGTH {
+        None
+    } else {
+        Some(reason.to_string())
+    }
+}
+
+// `POST /:file_id/report`: record a report of the upload. Once enough
+// addresses have reported it, it can't be downloaded until it is reviewed.
+pub async fn report(
+    mut conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    translation: Translation, reporter_ip: Option<IpAddr>, db_backend: DbBackend) -> Conn
+{
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return error_404(conn, config, translation)
+    };
+    let reason = match parse_reason(&mut conn).await {
+        Some(reason) => reason,
+        None => return error_400(conn, config, translation)
+    };
+
+    let config_ = config.clone();
+    let recorded = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config_.db_url)?;
+
+        match Upload::select_with_id(id, &db_connection) {
+            Some(upload) if !upload.is_deleted() => {},
+            _ => return None
+        }
+
+        // Each address counts once, so that nobody can disable an upload
+        // on their own
+        let reporter_ip = reporter_ip.map(|ip| ip.to_string());
+        let reports = Report::select_with_upload(id, &db_connection)?;
+        if reporter_ip.is_some() && reports.iter().any(|r| r.reporter_ip == reporter_ip) {
+            return Some(());
+        }
+
+        let report = Report {
+            id: thread_rng().gen(),
+            upload_id: id,
+            reason,
+            reporter_ip,
+            created_at: Local::now().naive_utc()
+        };
+        report.insert(&db_connection)?;
+
+        let num_reports = reports.len() + 1;
+        info!(id = %id_string, num_reports, "Upload reported");
+        if config_.report_threshold > 0 && num_reports >= config_.report_threshold {
+            warn!(id = %id_string, "Disabling downloads of reported upload until it is reviewed");
+            Upload::set_disabled(id, true, &db_connection)?;
+        }
+
+        Some(())
+    }).await;
+
+    match recorded {
+        Some(()) => {
+            let template = ReportSentTemplate {
+                app_name: &config.app_name,
+                path_prefix: path_prefix(conn.path()),
+                t: translation
+            };
+            conn.render(template).halt()
+        },
+        None => error_404(conn, config, translation)
+    }
+}
+
+// `GET /api/v1/reports`: list the reports of uploads which still exist
+pub async fn list(conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn {
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let reports = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+
+        let mut disabled = HashSet::new();
+        let mut deleted = HashSet::new();
+        let mut reports = Vec::new();
+        for report in Report::select_all(&db_connection)? {
+            if !disabled.contains(&report.upload_id) && !deleted.contains(&report.upload_id) {
+                match Upload::select_with_id(report.upload_id, &db_connection) {
+                    Some(upload) if !upload.is_deleted() => if upload.is_disabled {
+                        disabled.insert(upload.id);
+                    },
+                    _ => {
+                        deleted.insert(report.upload_id);
+                    }
+                }
+            }
+            if deleted.contains(&report.upload_id) {
+                continue;
+            }
+
+            reports.push(ReportInfo {
+                upload_id: String::from_utf8(i64_to_b64_bytes(report.upload_id)).unwrap(),
+                reason: report.reason,
+                reporter_ip: report.reporter_ip,
+                created_at: report.created_at.timestamp(),
+                upload_disabled: disabled.contains(&report.upload_id)
+            });
+        }
+
+        serde_json::to_string(&reports).ok()
+    }).await;
+
+    match reports {
+        Some(reports) => conn
+            .with_status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(reports)
+            .halt(),
+        None => api_error(conn, 500, "Listing reports failed")
+    }
+}
+
+// `DELETE /api/v1/reports/:file_id`: dismiss the reports of an upload, and
+// allow it to be downloaded again. (Uploads which break the rules are
+// deleted with `DELETE /api/v1/uploads/:file_id` instead.)
+pub async fn dismiss(
+    conn: Conn, id_string: String, config: Arc<TranspoConfig>,
+    db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return api_error(conn, status, "An API key with the admin scope is required");
+    }
+
+    let id = match parse_id(&id_string) {
+        Some(id) => id,
+        None => return api_error(conn, 404, "The upload does not exist")
+    };
+
+    let dismissed = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        Report::delete_with_upload(id, &db_connection)?;
+        Upload::set_disabled(id, false, &db_connection)
+    }).await;
+
+    match dismissed {
+        Some(_) => {
+            info!(id = %id_string, "Reports dismissed");
+            conn.with_status(204).halt()
+        },
+        None => api_error(conn, 500, "Dismissing the reports failed")
+    }
+}
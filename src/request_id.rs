@@ -0,0 +1,72 @@
+use crate::b64::*;
+use crate::random_bytes::*;
+
+use std::borrow::Cow;
+
+use tracing::{info_span, Instrument};
+use trillium::{async_trait, Conn, Handler, Info, Upgrade};
+
+
+pub const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+const REQUEST_ID_LENGTH: usize = 12;
+
+
+// A random ID for each request, so that what a user reports (from an error
+// page or a response header) can be found in the logs
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn new() -> Self {
+        let mut bytes = [0; REQUEST_ID_LENGTH];
+        random_bytes(&mut bytes);
+        Self(String::from_utf8(base64_encode(&bytes)).unwrap())
+    }
+}
+
+// Return the ID of the request, if it has one
+pub fn get_request_id(conn: &Conn) -> Option<String> {
+    conn.state::<RequestId>().map(|RequestId(id)| id.clone())
+}
+
+// Give each request an ID, send it back in a header and run the rest of the
+// request in a span carrying it
+pub struct WithRequestId<H>(pub H);
+
+#[async_trait]
+impl<H: Handler> Handler for WithRequestId<H> {
+    async fn run(&self, conn: Conn) -> Conn {
+        let request_id = RequestId::new();
+        let span = info_span!(
+            "request",
+            request_id = %request_id.0,
+            method = %conn.method(),
+            path = %conn.path());
+
+        let conn = conn
+            .with_header(REQUEST_ID_HEADER, request_id.0.clone())
+            .with_state(request_id);
+
+        self.0.run(conn).instrument(span).await
+    }
+
+    async fn init(&mut self, info: &mut Info) {
+        self.0.init(info).await
+    }
+
+    async fn before_send(&self, conn: Conn) -> Conn {
+        self.0.before_send(conn).await
+    }
+
+    fn has_upgrade(&self, upgrade: &Upgrade) -> bool {
+        self.0.has_upgrade(upgrade)
+    }
+
+    async fn upgrade(&self, upgrade: Upgrade) {
+        self.0.upgrade(upgrade).await
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        self.0.name()
+    }
+}
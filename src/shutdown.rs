@@ -0,0 +1,140 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+use trillium_http::Stopper;
+
+use crate::metrics::{num_active, Transfer};
+
+
+// How often to check whether the transfers in progress have finished
+const DRAIN_POLL_INTERVAL_MS: u64 = 100;
+// How often to log the transfers still in progress while draining
+const DRAIN_REPORT_INTERVAL_SECS: u64 = 5;
+
+
+// Shut down gracefully: once shutdown starts, new uploads and downloads are
+// refused while the ones in progress are given time to finish before the
+// listeners are stopped.
+#[derive(Clone)]
+pub struct Shutdown {
+    is_draining: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+    stopper: Stopper
+}
+
+// An upload or download in progress, counted until this is dropped
+pub struct InFlight(Shutdown);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            is_draining: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            stopper: Stopper::new()
+        }
+    }
+
+    // Stops every listener which was given this stopper
+    pub fn stopper(&self) -> Stopper {
+        self.stopper.clone()
+    }
+
+    // Count a new upload or download as in progress. Return None if the
+    // server is shutting down, in which case it should be refused.
+    pub fn begin(&self) -> Option<InFlight> {
+        // Count first, so that draining can't miss a transfer which starts
+        // at the same time
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(self.clone());
+
+        if self.is_draining.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(in_flight)
+        }
+    }
+
+    // Whether the server is shutting down, so that long-lived responses
+    // should end
+    pub fn is_draining(&self) -> bool {
+        self.is_draining.load(Ordering::SeqCst)
+    }
+
+    // Refuse new transfers, wait up to `timeout` for the ones in progress to
+    // finish and then stop the listeners
+    fn drain(&self, timeout: Duration) {
+        self.is_draining.store(true, Ordering::SeqCst);
+        info!(
+            active_uploads = num_active(Transfer::Upload),
+            active_downloads = num_active(Transfer::Download),
+            drain_timeout_secs = timeout.as_secs(),
+            "Shutting down, waiting for uploads and downloads to finish");
+
+        let start = Instant::now();
+        let mut last_report = start;
+        loop {
+            let in_flight = self.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 {
+                break;
+            }
+
+            let active_uploads = num_active(Transfer::Upload);
+            let active_downloads = num_active(Transfer::Download);
+            if start.elapsed() >= timeout {
+                warn!(
+                    active_uploads, active_downloads,
+                    "Drain timeout reached, abandoning transfers in progress");
+                break;
+            }
+            if last_report.elapsed() >= Duration::from_secs(DRAIN_REPORT_INTERVAL_SECS) {
+                let remaining_secs = timeout.saturating_sub(start.elapsed()).as_secs();
+                info!(
+                    active_uploads, active_downloads, remaining_secs,
+                    "Waiting for transfers to finish");
+                last_report = Instant::now();
+            }
+
+            thread::sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MS));
+        }
+
+        self.stopper.stop();
+    }
+}
+
+// Start shutting down on the first SIGTERM or SIGINT. A second signal exits
+// immediately.
+#[cfg(unix)]
+pub fn spawn_signal_thread(shutdown: Shutdown, drain_timeout: Duration) {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT])
+        .expect("Registering signal handlers");
+
+    thread::spawn(move || {
+        let mut is_shutting_down = false;
+
+        for signal in signals.forever() {
+            if is_shutting_down {
+                warn!(signal, "Received another signal, exiting immediately");
+                std::process::exit(1);
+            }
+
+            is_shutting_down = true;
+            let shutdown = shutdown.clone();
+            thread::spawn(move || shutdown.drain(drain_timeout));
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_signal_thread(_shutdown: Shutdown, _drain_timeout: Duration) {}
@@ -0,0 +1,156 @@
+use crate::api_keys::*;
+use crate::config::*;
+use crate::db::*;
+use crate::metrics::*;
+
+use std::sync::Arc;
+
+use blocking::unblock;
+use trillium::Conn;
+use serde::Serialize;
+
+
+const DEFAULT_STATS_DAYS: i64 = 30;
+const MAX_STATS_DAYS: i64 = 366;
+
+
+// The usage of the instance on a single day, as returned by `/stats`
+#[derive(Serialize)]
+struct DayInfo {
+    // formatted as YYYY-MM-DD
+    day: String,
+    uploads_created: i64,
+    bytes_stored: i64,
+    downloads_served: i64,
+    bytes_served: i64
+}
+
+impl From<DailyStats> for DayInfo {
+    fn from(stats: DailyStats) -> Self {
+        DayInfo {
+            day: stats.day.format("%Y-%m-%d").to_string(),
+            uploads_created: stats.uploads_created,
+            bytes_stored: stats.bytes_stored,
+            downloads_served: stats.downloads_served,
+            bytes_served: stats.bytes_served
+        }
+    }
+}
+
+// The durations of one kind of database query, as returned by `/stats/db`
+#[derive(Serialize)]
+struct QueryInfo {
+    kind: &'static str,
+    count: u64,
+    total_milliseconds: f64,
+    // upper bound of each bucket in milliseconds (`null` for the last bucket)
+    // along with the number of queries in it
+    buckets: Vec<(Option<u64>, u64)>
+}
+
+impl From<(QueryKind, HistogramSnapshot)> for QueryInfo {
+    fn from((kind, snapshot): (QueryKind, HistogramSnapshot)) -> Self {
+        let bounds = LATENCY_BUCKETS_MS.iter().map(|b| Some(*b)).chain([None]);
+
+        QueryInfo {
+            kind: kind.name(),
+            count: snapshot.count,
+            total_milliseconds: snapshot.sum_micros as f64 / 1000.0,
+            buckets: bounds.zip(snapshot.buckets).collect()
+        }
+    }
+}
+
+fn parse_days(query: &str) -> i64 {
+    let days = query.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| *key == "days")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(DEFAULT_STATS_DAYS);
+
+    days.clamp(1, MAX_STATS_DAYS)
+}
+
+// Respond with the statistics of the most recent days (newest first). Only
+// available with an API key which has the admin scope.
+pub async fn handle(
+    conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config.clone(), db_backend).await {
+        return conn.with_status(status).halt();
+    }
+
+    let days = parse_days(conn.querystring());
+
+    let stats = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        DailyStats::select_recent(days, &db_connection)
+    }).await;
+
+    match stats {
+        Some(stats) => {
+            let stats: Vec<DayInfo> = stats.into_iter().map(DayInfo::from).collect();
+            conn
+                .with_status(200)
+                .with_header("Content-Type", "application/json")
+                .with_body(serde_json::to_string(&stats).unwrap())
+                .halt()
+        },
+        None => conn.with_status(500).halt()
+    }
+}
+
+// Respond with the durations of database queries made since the server
+// started. Only available with an API key which has the admin scope.
+pub async fn handle_db(
+    conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn
+{
+    if let Some(status) = check_admin(conn.headers(), config, db_backend).await {
+        return conn.with_status(status).halt();
+    }
+
+    let queries: Vec<QueryInfo> = query_durations().into_iter().map(QueryInfo::from).collect();
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "application/json")
+        .with_body(serde_json::to_string(&queries).unwrap())
+        .halt()
+}
+
+// Respond with all metrics in the Prometheus text format. Only available if a
+// metrics token is configured, and only to requests which present it as a
+// bearer token.
+pub async fn handle_metrics(
+    conn: Conn, config: Arc<TranspoConfig>, db_backend: DbBackend) -> Conn
+{
+    let token = match &config.metrics_token {
+        Some(token) => token,
+        None => return conn.with_status(404).halt()
+    };
+
+    // Compare hashes, so the comparison takes the same time however much of
+    // the token is right
+    let is_authorized = conn.headers().get_str("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|presented| hash_key(presented.trim()) == hash_key(token))
+        .unwrap_or(false);
+    if !is_authorized {
+        return conn
+            .with_status(401)
+            .with_header("WWW-Authenticate", "Bearer")
+            .halt();
+    }
+
+    let storage_capacity = config.max_storage_size_bytes;
+    let storage_used = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        StorageUsage::select(&db_connection)
+    }).await;
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "text/plain; version=0.0.4")
+        .with_body(render_prometheus(storage_used, storage_capacity))
+        .halt()
+}
@@ -5,6 +5,16 @@ use crate::translations::*;
 use std::cmp;
 
 
+// Escape text to be put into HTML (or XML) which isn't escaped by the
+// templates
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
 // return (max_days, max_hours, max_minutes, max_upload_size)
 fn get_limits(config: &TranspoConfig) -> (usize, usize, usize, usize) {
     let max_days = cmp::max(config.max_upload_age_minutes / (24 * 60) - 1, 0);
@@ -24,6 +34,48 @@ fn get_limits(config: &TranspoConfig) -> (usize, usize, usize, usize) {
     (max_days, max_hours, max_minutes, config.max_upload_size_bytes)
 }
 
+// A time limit offered in the upload form
+#[derive(Clone)]
+pub struct ExpiryPreset {
+    minutes: usize,
+    label: String,
+    selected: bool
+}
+
+// return (expiry presets, default days, default hours, default minutes)
+fn get_expiry_settings(
+    config: &TranspoConfig, translation: &Translation) -> (Vec<ExpiryPreset>, usize, usize, usize)
+{
+    let presets = config.expiry_presets.iter()
+        .map(|minutes| {
+            let units = [
+                (minutes / (24 * 60), "index/days-short"),
+                (minutes % (24 * 60) / 60, "index/hours-short"),
+                (minutes % 60, "index/minutes-short")
+            ];
+            let label = units.iter()
+                .filter(|(n, _)| *n > 0)
+                .map(|(n, unit)| format!("{} {}", n, translation.get(unit)))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            ExpiryPreset {
+                minutes: *minutes,
+                label,
+                selected: *minutes == config.default_expiry_minutes
+            }
+        })
+        .collect();
+
+    let default_minutes = cmp::max(
+        cmp::min(config.default_expiry_minutes, config.max_upload_age_minutes), 1);
+
+    (presets,
+     default_minutes / (24 * 60),
+     default_minutes % (24 * 60) / 60,
+     default_minutes % 60)
+}
+
 #[derive(Template, Clone)]
 #[template(path = "index.html", escape = "none")]
 pub struct IndexTemplate<'a> {
@@ -34,6 +86,19 @@ pub struct IndexTemplate<'a> {
     max_hours: usize,
     max_minutes: usize,
     max_upload_size: usize,
+    expiry_presets: Vec<ExpiryPreset>,
+    default_days: usize,
+    default_hours: usize,
+    default_minutes: usize,
+    // whether uploaders may give an ntfy topic
+    notify_topics: bool,
+    // whether uploaders may have the link mailed to someone
+    share_by_mail: bool,
+    // whether uploaders may list their uploads in the public gallery
+    gallery: bool,
+    // whether the key may be derived from the password (this needs the
+    // server to encrypt the upload)
+    password_keys: bool,
     t: Translation
 }
 
@@ -47,6 +112,8 @@ impl<'a> IndexTemplate<'a> {
         let app_name = &config.app_name;
 
         let (max_days, max_hours, max_minutes, max_upload_size) = get_limits(config);
+        let (expiry_presets, default_days, default_hours, default_minutes) =
+            get_expiry_settings(config, &translation);
 
         Self {
             app_name,
@@ -56,6 +123,14 @@ impl<'a> IndexTemplate<'a> {
             max_hours,
             max_minutes,
             max_upload_size,
+            expiry_presets,
+            default_days,
+            default_hours,
+            default_minutes,
+            notify_topics: config.ntfy_server.is_some(),
+            share_by_mail: config.smtp_url.is_some(),
+            gallery: config.gallery,
+            password_keys: true,
             t: translation
         }
     }
@@ -71,6 +146,19 @@ pub struct PasteTemplate<'a> {
     max_hours: usize,
     max_minutes: usize,
     max_upload_size: usize,
+    expiry_presets: Vec<ExpiryPreset>,
+    default_days: usize,
+    default_hours: usize,
+    default_minutes: usize,
+    // whether uploaders may give an ntfy topic
+    notify_topics: bool,
+    // whether uploaders may have the link mailed to someone
+    share_by_mail: bool,
+    // whether uploaders may list their uploads in the public gallery
+    gallery: bool,
+    // whether the key may be derived from the password (pastes are always
+    // encrypted by the client)
+    password_keys: bool,
     t: Translation
 }
 
@@ -83,6 +171,8 @@ impl<'a> PasteTemplate<'a> {
     {
         let app_name = &config.app_name;
         let (max_days, max_hours, max_minutes, max_upload_size) = get_limits(config);
+        let (expiry_presets, default_days, default_hours, default_minutes) =
+            get_expiry_settings(config, &translation);
 
         Self {
             app_name,
@@ -92,6 +182,14 @@ impl<'a> PasteTemplate<'a> {
             max_hours,
             max_minutes,
             max_upload_size,
+            expiry_presets,
+            default_days,
+            default_hours,
+            default_minutes,
+            notify_topics: config.ntfy_server.is_some(),
+            share_by_mail: config.smtp_url.is_some(),
+            gallery: config.gallery,
+            password_keys: false,
             t: translation
         }
     }
@@ -102,10 +200,36 @@ impl<'a> PasteTemplate<'a> {
 pub struct UploadLinkTemplate {
     pub app_name: String,
     pub upload_url: String,
+    // the same link on the onion service, if there is one
+    pub onion_upload_url: Option<String>,
     pub upload_id: String,
     pub t: Translation
 }
 
+// Sent to the recipient an uploader gave when uploading
+#[derive(Template)]
+#[template(path = "mail/share.txt", escape = "none")]
+pub struct ShareMailTemplate {
+    pub app_name: String,
+    pub url: String,
+    // the same link on the onion service, if there is one
+    pub onion_url: Option<String>,
+    // whether the key was left out of the link (i.e. the upload was
+    // encrypted by the client)
+    pub without_key: bool,
+    pub expire_after: String
+}
+
+// Sent to a registered user whose upload is about to expire
+#[derive(Template)]
+#[template(path = "mail/expiry.txt", escape = "none")]
+pub struct ExpiryMailTemplate {
+    pub app_name: String,
+    pub username: String,
+    pub upload_id: String,
+    pub expire_after: String
+}
+
 #[derive(Template, Clone)]
 #[template(path = "about.html", escape = "none")]
 pub struct AboutTemplate<'a> {
@@ -137,6 +261,12 @@ pub struct DownloadTemplate<'a> {
     pub file_id: String,
     pub app_name: &'a String,
     pub has_password: bool,
+    // whether the key is derived from the password, so the page has no key
+    // and the server decrypts the upload
+    pub derived_key: bool,
+    // whether the key is wrapped for recipients, so the page asks for the
+    // secret key of a recipient instead
+    pub recipient_key: bool,
     pub t: Translation
 }
 
@@ -155,5 +285,37 @@ pub struct ErrorTemplate<'a> {
     pub error_code: usize,
     pub app_name: &'a String,
     pub path_prefix: String,
+    pub request_id: Option<String>,
+    pub t: Translation
+}
+
+#[derive(Template)]
+#[template(path = "report_sent.html", escape = "none")]
+pub struct ReportSentTemplate<'a> {
+    pub app_name: &'a String,
+    pub path_prefix: String,
+    pub t: Translation
+}
+
+// An upload as it is listed in the public gallery
+pub struct GalleryEntry {
+    // (escaped)
+    pub name: String,
+    // path of the download link (including the key) relative to the gallery
+    pub path: String,
+    pub size: String,
+    pub expire_after: String
+}
+
+#[derive(Template)]
+#[template(path = "gallery.html", escape = "none")]
+pub struct GalleryTemplate<'a> {
+    pub app_name: &'a String,
+    pub selected_lang: &'a str,
+    pub lang_names: &'a [(String, String)],
+    pub entries: Vec<GalleryEntry>,
+    // the number of the next and previous pages, if there are any
+    pub previous_page: Option<i64>,
+    pub next_page: Option<i64>,
     pub t: Translation
 }
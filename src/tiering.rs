@@ -0,0 +1,129 @@
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, Duration as ChronoDuration};
+use tracing::{error, info, info_span, warn};
+
+use crate::b64::*;
+use crate::cluster::Leader;
+use crate::db::*;
+
+
+// Number of seconds between checks for uploads which have gone cold
+const TIERING_DELAY_SECS: u64 = 60 * 60;
+
+
+fn id_string(id: i64) -> String {
+    String::from_utf8(i64_to_b64_bytes(id)).unwrap()
+}
+
+pub fn spawn_tiering_thread(
+    cold_after_hours: usize, storage_path: PathBuf, cold_storage_path: PathBuf,
+    db_backend: DbBackend, db_url: String, leader: Leader)
+{
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(TIERING_DELAY_SECS));
+
+        if !leader.is_leader() {
+            continue;
+        }
+
+        let _span = info_span!("tiering").entered();
+
+        // Try again next time if the database is unavailable
+        match establish_connection(db_backend, &db_url) {
+            Some(db_connection) => move_cold_uploads(
+                cold_after_hours, &storage_path, &cold_storage_path, &db_connection),
+            None => warn!("Skipping moving cold uploads, the database is unavailable")
+        }
+    });
+}
+
+// Move the uploads which haven't been used in the last `cold_after_hours`
+// hours into the cold storage directory. The file of each upload in the
+// storage directory is replaced with a link to where it was moved, so it is
+// read (and deleted) the same way as before.
+pub fn move_cold_uploads(
+    cold_after_hours: usize, storage_path: &PathBuf, cold_storage_path: &PathBuf,
+    db_connection: &DbConnection)
+{
+    // The links must still point at the moved files when Transpo is run
+    // from a different working directory
+    let cold_storage_path = match fs::canonicalize(cold_storage_path) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Resolving cold storage directory: {}", e);
+            return;
+        }
+    };
+
+    let used_before = Local::now().naive_utc()
+        - ChronoDuration::hours(cold_after_hours as i64);
+
+    if let Some(ids) = Upload::select_cold(used_before, db_connection) {
+        for id in ids {
+            let id_string = id_string(id);
+            let upload_path = storage_path.join(&id_string).join("upload");
+
+            // Skip uploads which have already been moved
+            match fs::symlink_metadata(&upload_path) {
+                Ok(metadata) if metadata.file_type().is_file() => {},
+                _ => continue
+            }
+
+            let cold_dir = cold_storage_path.join(&id_string);
+            match move_upload(&upload_path, &cold_dir) {
+                Ok(()) => info!(id, "Moved cold upload"),
+                Err(e) => {
+                    error!(id, "Moving cold upload: {}", e);
+                    drop(fs::remove_file(upload_path.with_file_name("upload.link")));
+                    drop(fs::remove_dir_all(cold_dir));
+                }
+            }
+        }
+    }
+
+    // Remove files left behind in the cold storage directory, e.g. by an
+    // upload which was deleted while it was being moved
+    if let Ok(dir_entries) = fs::read_dir(&cold_storage_path) {
+        for entry in dir_entries.filter_map(|e| e.ok()) {
+            let id = entry.file_name().to_str()
+                .and_then(|name| i64_from_b64_bytes(name.as_bytes()));
+
+            if let Some(id) = id {
+                if Upload::select_with_id(id, db_connection).is_none() {
+                    warn!(id, "Deleting cold upload without a database record");
+                    drop(fs::remove_dir_all(entry.path()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn move_upload(upload_path: &Path, cold_dir: &Path) -> Result<()> {
+    let cold_path = cold_dir.join("upload");
+    let partial_cold_path = cold_dir.join("upload.partial");
+    let link_path = upload_path.with_file_name("upload.link");
+
+    fs::create_dir_all(cold_dir)?;
+    fs::copy(upload_path, &partial_cold_path)?;
+    fs::File::open(&partial_cold_path)?.sync_all()?;
+    fs::rename(&partial_cold_path, &cold_path)?;
+
+    // Replacing the file with the link is atomic, so the upload can always be
+    // read from one tier or the other. Downloads which already opened the
+    // file keep reading it until they finish.
+    std::os::unix::fs::symlink(&cold_path, &link_path)?;
+    fs::rename(&link_path, upload_path)
+}
+
+#[cfg(not(unix))]
+fn move_upload(_upload_path: &Path, _cold_dir: &Path) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Cold storage is only supported on Unix"))
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Local, NaiveDateTime};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::b64::*;
+use crate::random_bytes::*;
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Signed download tokens let the owner of an upload hand out links which only
+// work once and only for a short time, without sharing the password itself.
+//
+// A token has the form `<expiry>.<signature>` where `expiry` is a UNIX
+// timestamp and `signature` is the base64-encoded HMAC of the upload ID and
+// the expiry under a secret which only lives as long as the server process.
+//
+// Once redeemed, a token can still be used to resume the download it started
+// (until it expires), but not to start another one. Only one download can be
+// made with a token at a time, and after the download has reached the end of
+// the file, the token can't be used at all.
+#[derive(Clone)]
+pub struct DownloadTokens {
+    secret: Arc<[u8; 32]>,
+    // Tokens which have been redeemed, mapped to their expiry and the state
+    // of the download they were redeemed for
+    redeemed: Arc<Mutex<HashMap<String, (i64, TokenState)>>>
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TokenState {
+    // A download made with the token is in progress
+    InFlight,
+    // The download was interrupted and can be resumed
+    Interrupted,
+    // The download reached the end of the file
+    Finished
+}
+
+// A token which was redeemed for a download. If it is dropped without being
+// finished or interrupted (i.e. nothing was sent), the token goes back to the
+// state it was in before.
+pub struct RedeemedToken {
+    tokens: DownloadTokens,
+    token: String,
+    is_resumed: bool
+}
+
+impl RedeemedToken {
+    fn set_state(&self, state: TokenState) {
+        let mut redeemed = self.tokens.redeemed.lock().unwrap();
+        if let Some((_, s)) = redeemed.get_mut(&self.token) {
+            *s = state;
+        }
+    }
+
+    // Record that the download reached the end of the file, after which the
+    // token can't be used to resume it anymore
+    pub fn finish(self) {
+        self.set_state(TokenState::Finished);
+    }
+
+    // Record that the download ended part way through the file, after which
+    // the token can be used to resume it
+    pub fn interrupt(self) {
+        self.set_state(TokenState::Interrupted);
+    }
+}
+
+impl Drop for RedeemedToken {
+    fn drop(&mut self) {
+        let mut redeemed = self.tokens.redeemed.lock().unwrap();
+        match redeemed.get_mut(&self.token) {
+            Some((_, state)) if *state == TokenState::InFlight && self.is_resumed => {
+                *state = TokenState::Interrupted;
+            },
+            Some((_, TokenState::InFlight)) => {
+                redeemed.remove(&self.token);
+            },
+            _ => {}
+        }
+    }
+}
+
+impl DownloadTokens {
+    pub fn new() -> Self {
+        let mut secret = [0; 32];
+        random_bytes(&mut secret);
+
+        Self {
+            secret: Arc::new(secret),
+            redeemed: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    fn mac(&self, id: i64, expiry: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_ref())
+            .expect("HMAC accepts keys of any size");
+        mac.update(&id.to_be_bytes());
+        mac.update(&expiry.to_be_bytes());
+        mac
+    }
+
+    // Return a token for the upload with the given ID which is valid until
+    // `expire_after`
+    pub fn mint(&self, id: i64, expire_after: NaiveDateTime) -> String {
+        let expiry = expire_after.timestamp();
+        let signature = self.mac(id, expiry).finalize().into_bytes();
+
+        format!("{}.{}", expiry, String::from_utf8(base64_encode(&signature)).unwrap())
+    }
+
+    // Return the expiry of the token if it is valid for the given ID
+    fn verify_expiry(&self, id: i64, token: &str) -> Option<i64> {
+        let (expiry, signature) = token.split_once('.')?;
+        let expiry: i64 = expiry.parse().ok()?;
+        let signature = base64_decode(signature.as_bytes())?;
+
+        if Local::now().naive_utc().timestamp() > expiry {
+            return None;
+        }
+
+        self.mac(id, expiry).verify_slice(&signature).ok()?;
+
+        Some(expiry)
+    }
+
+    // Return whether or not the token is valid for the given ID without
+    // using it up
+    pub fn verify(&self, id: i64, token: &str) -> bool {
+        self.verify_expiry(id, token).is_some()
+            && !matches!(self.redeemed.lock().unwrap().get(token), Some((_, TokenState::Finished)))
+    }
+
+    // Redeem the token for a download of the upload with the given ID, if it
+    // is valid for it. A token can only be redeemed once, except to resume
+    // the download it was redeemed for once that was interrupted.
+    pub fn redeem(&self, id: i64, token: &str, is_resumed: bool) -> Option<RedeemedToken> {
+        let expiry = self.verify_expiry(id, token)?;
+        let now = Local::now().naive_utc().timestamp();
+        let mut redeemed = self.redeemed.lock().unwrap();
+
+        // Expired tokens are rejected anyway, so stop tracking them
+        redeemed.retain(|_, (expiry, _)| *expiry >= now);
+        match redeemed.get(token).map(|(_, state)| *state) {
+            None if !is_resumed => {},
+            Some(TokenState::Interrupted) if is_resumed => {},
+            // (a download which was never started can't be resumed, and one
+            // which is still in progress can't be made again)
+            _ => return None
+        }
+        redeemed.insert(token.to_owned(), (expiry, TokenState::InFlight));
+
+        Some(RedeemedToken {
+            tokens: self.clone(),
+            token: token.to_owned(),
+            is_resumed
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::thread;
+
+    fn in_minutes(minutes: i64) -> NaiveDateTime {
+        Local::now().naive_utc() + Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn signing() {
+        let tokens = DownloadTokens::new();
+        let token = tokens.mint(1, in_minutes(10));
+        assert!(tokens.verify(1, &token));
+
+        // Tokens are bound to the upload and to the server which minted them
+        assert!(!tokens.verify(2, &token));
+        assert!(!DownloadTokens::new().verify(1, &token));
+
+        // The expiry is covered by the signature
+        let (_, signature) = token.split_once('.').unwrap();
+        let later = format!("{}.{}", in_minutes(20).timestamp(), signature);
+        assert!(!tokens.verify(1, &later));
+        assert!(!tokens.verify(1, "garbage"));
+    }
+
+    #[test]
+    fn expiry() {
+        let tokens = DownloadTokens::new();
+        let token = tokens.mint(1, in_minutes(-1));
+        assert!(!tokens.verify(1, &token));
+        assert!(tokens.redeem(1, &token, false).is_none());
+    }
+
+    #[test]
+    fn single_use() {
+        let tokens = DownloadTokens::new();
+        let token = tokens.mint(1, in_minutes(10));
+
+        let redeemed = tokens.redeem(1, &token, false).unwrap();
+        assert!(tokens.redeem(1, &token, false).is_none());
+        // (the download is still in progress)
+        assert!(tokens.redeem(1, &token, true).is_none());
+
+        // The interrupted download can be resumed until it finishes
+        redeemed.interrupt();
+        let resumed = tokens.redeem(1, &token, true).unwrap();
+        resumed.interrupt();
+        let resumed = tokens.redeem(1, &token, true).unwrap();
+        resumed.finish();
+        assert!(tokens.redeem(1, &token, true).is_none());
+        assert!(!tokens.verify(1, &token));
+    }
+
+    #[test]
+    fn resuming_needs_a_started_download() {
+        let tokens = DownloadTokens::new();
+        let token = tokens.mint(1, in_minutes(10));
+        assert!(tokens.redeem(1, &token, true).is_none());
+
+        // A download which failed before anything was sent doesn't use up
+        // the token, and can't be resumed either
+        drop(tokens.redeem(1, &token, false).unwrap());
+        assert!(tokens.redeem(1, &token, true).is_none());
+        assert!(tokens.redeem(1, &token, false).is_some());
+    }
+
+    #[test]
+    fn concurrent_resumes() {
+        let tokens = DownloadTokens::new();
+        let token = tokens.mint(1, in_minutes(10));
+        tokens.redeem(1, &token, false).unwrap().interrupt();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let tokens = tokens.clone();
+                let token = token.clone();
+                thread::spawn(move || tokens.redeem(1, &token, true))
+            })
+            .collect();
+        let redeemed: Vec<_> = handles.into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        assert_eq!(redeemed.iter().filter(|r| r.is_some()).count(), 1);
+    }
+}
@@ -1,4 +1,5 @@
-use crate::multipart_form::{self, *};
+use crate::multipart_form::*;
+use crate::at_rest;
 use crate::b64;
 use crate::files::*;
 use crate::constants::*;
@@ -8,8 +9,17 @@ use crate::http_errors::*;
 use crate::templates::*;
 use crate::translations::*;
 use crate::quotas::*;
-
-use std::{cmp, fs, str};
+use crate::concurrency::*;
+use crate::cleanup::evict_uploads;
+use crate::metrics::*;
+use crate::api_keys::API_KEY_QUERY;
+use crate::notify::is_valid_topic;
+use crate::mail::{self, is_valid_address};
+use crate::onion::{is_onion_request, onion_link};
+use crate::federation::mirror_to_peers;
+use crate::recipients::*;
+
+use std::{cmp, fs, mem, str};
 use std::io::{Result, Error, ErrorKind};
 use std::sync::Arc;
 use std::path::PathBuf;
@@ -36,38 +46,86 @@ use urlencoding::decode;
 use argon2::{Argon2, PasswordHasher};
 use argon2::password_hash::{rand_core::OsRng, SaltString};
 
+use tracing::{info, warn, Span};
+
 
 // Make sure storage capacity is not exceeded after reading this many bytes
-const STORAGE_CHECK_INTERVAL: usize = 1024 * 1024 * 10;
+pub const STORAGE_CHECK_INTERVAL: usize = 1024 * 1024 * 10;
 
-const EXPECTED_BOUNDARY_START: &'static str = "\r\n-----------------------";
+// Browsers and curl start their boundaries with more dashes than this; ShareX
+// uses 20
+const EXPECTED_BOUNDARY_START: &'static str = "\r\n----------------------";
 
 // Content-Disposition for valid form fields
 const SERVER_SIDE_PROCESSING_CD: &'static str = "form-data; name=\"server-side-processing\"";
 const ENABLE_MULTIPLE_FILES_CD: &'static str = "form-data; name=\"enable-multiple-files\"";
-const FILES_CD_PREFIX: &'static str = "form-data; name=\"files\"; filename=";
+// (followed by `filename` and/or `filename*`)
+const FILES_CD_PREFIX: &'static str = "form-data; name=\"files\"; filename";
+// several files in a nested multipart/mixed form (as in HTML 4), each with a
+// Content-Disposition starting with NESTED_FILE_CD_PREFIX
+const NESTED_FILES_CD: &'static str = "form-data; name=\"files\"";
+const NESTED_FILE_CD_PREFIX: &'static str = "file; filename";
 const DAYS_CD: &'static str = "form-data; name=\"days\"";
 const HOURS_CD: &'static str = "form-data; name=\"hours\"";
 const MINUTES_CD: &'static str = "form-data; name=\"minutes\"";
+// total number of minutes, sent instead of days/hours/minutes by expiry presets
+const EXPIRY_CD: &'static str = "form-data; name=\"expiry\"";
 const ENABLE_MAX_DOWNLOADS_CD: &'static str = "form-data; name=\"enable-max-downloads\"";
 const MAX_DOWNLOADS_CD: &'static str = "form-data; name=\"max-downloads\"";
 const ENABLE_PASSWORD_CD: &'static str = "form-data; name=\"enable-password\"";
 const PASSWORD_CD: &'static str = "form-data; name=\"password\"";
+const DOWNLOAD_SPEED_LIMIT_CD: &'static str = "form-data; name=\"download-speed-limit\"";
+const NOTIFY_TOPIC_CD: &'static str = "form-data; name=\"notify-topic\"";
+const SHARE_EMAIL_CD: &'static str = "form-data; name=\"share-email\"";
+const PUBLIC_CD: &'static str = "form-data; name=\"public\"";
+const DERIVE_KEY_CD: &'static str = "form-data; name=\"derive-key\"";
+const RECIPIENTS_CD: &'static str = "form-data; name=\"recipients\"";
+const AGE_CD: &'static str = "form-data; name=\"age\"";
+const TITLE_CD: &'static str = "form-data; name=\"title\"";
 
 const VALUE_ON: &'static str = "on";
 
 
-const MINUTES_QUERY: &'static str = "minutes";
-const PASSWORD_QUERY: &'static str = "password";
-const MAX_DOWNLOADS_QUERY: &'static str = "max-downloads";
-const FILE_NAME_QUERY: &'static str = "file-name";
-const MIME_TYPE_QUERY: &'static str = "mime-type";
-
+pub const MINUTES_QUERY: &'static str = "minutes";
+pub const PASSWORD_QUERY: &'static str = "password";
+pub const MAX_DOWNLOADS_QUERY: &'static str = "max-downloads";
+pub const FILE_NAME_QUERY: &'static str = "file-name";
+pub const MIME_TYPE_QUERY: &'static str = "mime-type";
+pub const DOWNLOAD_SPEED_LIMIT_QUERY: &'static str = "download-speed-limit";
+pub const SIZE_QUERY: &'static str = "size";
+pub const NOTIFY_TOPIC_QUERY: &'static str = "notify-topic";
+pub const SHARE_EMAIL_QUERY: &'static str = "share-email";
+pub const PUBLIC_QUERY: &'static str = "public";
+// the key of an upload encrypted by the client, so that it can be listed in
+// the gallery
+pub const PUBLIC_KEY_QUERY: &'static str = "public-key";
+// the format in which the client encrypts the upload (see `files.rs`)
+pub const FORMAT_VERSION_QUERY: &'static str = "format-version";
+// whether the key of an upload encrypted by the server is derived from its
+// password, instead of being part of the link
+pub const DERIVE_KEY_QUERY: &'static str = "derive-key";
+// public keys of the recipients for whom the key of an upload encrypted by the
+// server is wrapped, instead of being part of the link
+pub const RECIPIENTS_QUERY: &'static str = "recipients";
+// whether an upload encrypted by the server for recipients is stored in the
+// age format (see `age.rs`)
+pub const AGE_QUERY: &'static str = "age";
+// the title of the upload, encrypted by the client like the file name (see
+// `files::seal_title`)
+pub const TITLE_QUERY: &'static str = "title";
+
+// Codes sent to websocket clients when an upload fails
+#[derive(Debug)]
 enum UploadError {
     FileSize = 1,
-    Quota = 2,
+    // (2 was sent for exceeded quotas before they carried a retry time)
     Storage = 3,
     Protocol = 4,
+    // followed by the number of seconds after which the client may try
+    // again, as a big-endian u32
+    RateLimited = 5,
+    // the storage or transfer limit of the uploader's account
+    AccountQuota = 6,
 
     Other = 0
 }
@@ -85,7 +143,21 @@ struct UploadQuery {
     max_downloads: Option<u32>,
     password: Option<String>,
     file_name: Option<Vec<u8>>,
-    mime_type: Option<Vec<u8>>
+    mime_type: Option<Vec<u8>>,
+    download_speed_limit: Option<u64>,
+    // total size of the files being uploaded, if the client declared it
+    size: Option<u64>,
+    notify_topic: Option<String>,
+    // address to which the link is mailed once the upload completes
+    share_email: Option<String>,
+    // whether to list the upload in the public gallery
+    public: Option<bool>,
+    public_key: Option<String>,
+    format_version: Option<i16>,
+    derive_key: Option<bool>,
+    recipients: Option<Vec<[u8; 32]>>,
+    age: Option<bool>,
+    title: Option<String>
 }
 
 impl UploadQuery {
@@ -110,6 +182,33 @@ impl UploadQuery {
                     MAX_DOWNLOADS_QUERY => upload_query.max_downloads = Some(value.parse().ok()?),
                     FILE_NAME_QUERY => upload_query.file_name = Some(value.to_owned().into_bytes()),
                     MIME_TYPE_QUERY => upload_query.mime_type = Some(value.to_owned().into_bytes()),
+                    DOWNLOAD_SPEED_LIMIT_QUERY => upload_query.download_speed_limit = Some(value.parse().ok()?),
+                    SIZE_QUERY => upload_query.size = Some(value.parse().ok()?),
+                    NOTIFY_TOPIC_QUERY if is_valid_topic(value) =>
+                        upload_query.notify_topic = Some(value.to_owned()),
+                    SHARE_EMAIL_QUERY => {
+                        let value = decode(value).ok()?;
+                        if !is_valid_address(&value) {
+                            return None;
+                        }
+                        upload_query.share_email = Some(value.into_owned());
+                    },
+                    PUBLIC_QUERY => upload_query.public = Some(value == "true"),
+                    PUBLIC_KEY_QUERY => upload_query.public_key = Some(value.to_owned()),
+                    DERIVE_KEY_QUERY => upload_query.derive_key = Some(value == "true"),
+                    RECIPIENTS_QUERY => upload_query.recipients = Some(
+                        parse_recipients(&decode(value).ok()?)?),
+                    AGE_QUERY => upload_query.age = Some(value == "true"),
+                    TITLE_QUERY => upload_query.title = Some(value.to_owned()),
+                    FORMAT_VERSION_QUERY => {
+                        let version = value.parse().ok()?;
+                        if !(1..=FORMAT_VERSION).contains(&version) {
+                            return None;
+                        }
+                        upload_query.format_version = Some(version);
+                    },
+                    // (checked before the upload starts)
+                    API_KEY_QUERY => {},
                     _ => return None
                 }
             }
@@ -125,17 +224,30 @@ impl UploadQuery {
             MAX_DOWNLOADS_QUERY => self.max_downloads.is_some(),
             FILE_NAME_QUERY => self.file_name.is_some(),
             MIME_TYPE_QUERY => self.mime_type.is_some(),
+            DOWNLOAD_SPEED_LIMIT_QUERY => self.download_speed_limit.is_some(),
+            SIZE_QUERY => self.size.is_some(),
+            NOTIFY_TOPIC_QUERY => self.notify_topic.is_some(),
+            SHARE_EMAIL_QUERY => self.share_email.is_some(),
+            PUBLIC_QUERY => self.public.is_some(),
+            PUBLIC_KEY_QUERY => self.public_key.is_some(),
+            FORMAT_VERSION_QUERY => self.format_version.is_some(),
+            DERIVE_KEY_QUERY => self.derive_key.is_some(),
+            RECIPIENTS_QUERY => self.recipients.is_some(),
+            AGE_QUERY => self.age.is_some(),
+            TITLE_QUERY => self.title.is_some(),
             _ => false
         }
     }
 
-    fn get_values(self) -> Option<(u32, Option<u32>, Option<String>, Option<Vec<u8>>, Option<Vec<u8>>)> {
+    fn get_values(self) -> Option<(u32, Option<u32>, Option<String>, Option<Vec<u8>>, Option<Vec<u8>>, Option<u64>, Option<String>)> {
         Some((
                 self.minutes?,
                 self.max_downloads,
                 self.password,
                 self.file_name,
-                self.mime_type
+                self.mime_type,
+                self.download_speed_limit,
+                self.notify_topic
         ))
     }
 }
@@ -146,13 +258,25 @@ enum FormField {
     ServerSideProcessing,
     EnableMultipleFiles,
     Files,
+    NestedFiles,
     Days,
     Hours,
     Minutes,
+    Expiry,
     EnableMaxDownloads,
     MaxDownloads,
     EnablePassword,
     Password,
+    DownloadSpeedLimit,
+    NotifyTopic,
+    ShareEmail,
+    Public,
+    DeriveKey,
+    Recipients,
+    Age,
+    Title,
+    // a field which isn't known, and whose value is discarded
+    Unknown,
     Invalid
 }
 
@@ -166,17 +290,37 @@ fn match_content_disposition(cd: &str) -> FormField {
             DAYS_CD => FormField::Days,
             HOURS_CD => FormField::Hours,
             MINUTES_CD => FormField::Minutes,
+            EXPIRY_CD => FormField::Expiry,
             ENABLE_MAX_DOWNLOADS_CD => FormField::EnableMaxDownloads,
             MAX_DOWNLOADS_CD => FormField::MaxDownloads,
             ENABLE_PASSWORD_CD => FormField::EnablePassword,
             PASSWORD_CD => FormField::Password,
+            DOWNLOAD_SPEED_LIMIT_CD => FormField::DownloadSpeedLimit,
+            NOTIFY_TOPIC_CD => FormField::NotifyTopic,
+            SHARE_EMAIL_CD => FormField::ShareEmail,
+            PUBLIC_CD => FormField::Public,
+            DERIVE_KEY_CD => FormField::DeriveKey,
+            RECIPIENTS_CD => FormField::Recipients,
+            AGE_CD => FormField::Age,
+            TITLE_CD => FormField::Title,
+            NESTED_FILES_CD => FormField::NestedFiles,
             _ => FormField::Invalid
         }
     }
 }
 
+// Return the type of a field in a form nested in NestedFiles, which can only
+// be a file
+fn match_nested_content_disposition(cd: &str) -> FormField {
+    if cd.starts_with(NESTED_FILE_CD_PREFIX) {
+        FormField::Files
+    } else {
+        FormField::Invalid
+    }
+}
+
 #[derive(Default)]
-struct UploadForm {
+pub struct UploadForm {
     server_side_processing: Option<bool>,
     enable_multiple_files: Option<bool>,
     days: Option<u16>,
@@ -185,24 +329,35 @@ struct UploadForm {
     enable_max_downloads: Option<bool>,
     max_downloads: Option<u32>,
     enable_password: Option<bool>,
-    password: Option<String>
+    password: Option<String>,
+    download_speed_limit: Option<u64>,
+    notify_topic: Option<String>,
+    share_email: Option<String>,
+    public: Option<bool>,
+    derive_key: Option<bool>,
+    recipients: Option<Vec<[u8; 32]>>,
+    age: Option<bool>,
+    // plaintext title, encrypted by the server once the key is known
+    title: Option<String>,
+    // (not a form field, the title encrypted by the client)
+    title_cipher: Option<String>,
+    // (not a form field, uploads encrypted by the client without declaring
+    // a format are in the first one)
+    format_version: Option<i16>,
+    // (not a form field, recipients given in the query string for whom the
+    // upload is stored in the age format)
+    query_age_recipients: Option<Vec<[u8; 32]>>
 }
 
 impl UploadForm {
-    fn new(
+    pub fn new(
         server_side_processing: bool, minutes: u32, max_downloads: Option<u32>,
-        password: Option<String>) -> Self
+        password: Option<String>, download_speed_limit: Option<u64>,
+        notify_topic: Option<String>) -> Self
     {
         let mut form = Self::default();
         form.server_side_processing = Some(server_side_processing);
-
-        let days = minutes / (60 * 24);
-        let hours = (minutes % (60 * 24)) / 60;
-        let minutes = minutes % 60;
-
-        form.days = Some(days as u16);
-        form.hours = Some(hours as u8);
-        form.minutes = Some(minutes as u8);
+        form.set_expiry(minutes);
 
         if let Some(max_downloads) = max_downloads {
             form.enable_max_downloads = Some(true);
@@ -214,9 +369,41 @@ impl UploadForm {
             form.password = Some(password);
         }
 
+        form.download_speed_limit = download_speed_limit;
+        form.notify_topic = notify_topic;
+
         form
     }
 
+    // Record that the upload is encrypted by the server, which always uses
+    // the current format (unless it is stored in the age format)
+    pub fn set_encrypted_by_server(&mut self, is_padded: bool, is_age: bool) {
+        self.format_version = Some(if is_age {
+            AGE_FORMAT_VERSION
+        } else {
+            server_format_version(is_padded)
+        });
+    }
+
+    // Return the recipients for whom the upload is stored in the age format,
+    // which have to be known before the files start
+    fn age_recipients(&self) -> Option<Vec<[u8; 32]>> {
+        self.query_age_recipients.clone()
+            .or_else(|| self.recipients.clone().filter(|_| self.age.unwrap_or(false)))
+            .filter(|r| !r.is_empty())
+    }
+
+    // Split a total number of minutes into days, hours and minutes
+    fn set_expiry(&mut self, minutes: u32) {
+        let days = minutes / (60 * 24);
+        let hours = (minutes % (60 * 24)) / 60;
+        let minutes = minutes % 60;
+
+        self.days = Some(cmp::min(days, u16::MAX as u32) as u16);
+        self.hours = Some(hours as u8);
+        self.minutes = Some(minutes as u8);
+    }
+
     fn is_valid_field(&self, field: &FormField) -> bool {
         match field {
             FormField::ServerSideProcessing => self.server_side_processing.is_none(),
@@ -224,10 +411,19 @@ impl UploadForm {
             FormField::Days => self.days.is_none(),
             FormField::Hours => self.hours.is_none(),
             FormField::Minutes => self.minutes.is_none(),
+            FormField::Expiry => !self.has_time_limit(),
             FormField::EnableMaxDownloads => self.enable_max_downloads.is_none(),
             FormField::MaxDownloads => self.max_downloads.is_none(),
             FormField::EnablePassword => self.enable_password.is_none(),
             FormField::Password => self.password.is_none(),
+            FormField::DownloadSpeedLimit => self.download_speed_limit.is_none(),
+            FormField::NotifyTopic => self.notify_topic.is_none(),
+            FormField::ShareEmail => self.share_email.is_none(),
+            FormField::Public => self.public.is_none(),
+            FormField::DeriveKey => self.derive_key.is_none(),
+            FormField::Recipients => self.recipients.is_none(),
+            FormField::Age => self.age.is_none(),
+            FormField::Title => self.title.is_none(),
             _ => false
         }
     }
@@ -242,10 +438,39 @@ impl UploadForm {
                     FormField::Days => Self::parse_from_str(value, &mut self.days),
                     FormField::Hours => Self::parse_from_str(value, &mut self.hours),
                     FormField::Minutes => Self::parse_from_str(value, &mut self.minutes),
+                    FormField::Expiry => match value.parse() {
+                        Ok(minutes) => {
+                            self.set_expiry(minutes);
+                            true
+                        },
+                        Err(_) => false
+                    },
                     FormField::EnableMaxDownloads => Self::parse_bool_value(value, &mut self.enable_max_downloads),
                     FormField::MaxDownloads => Self::parse_from_str(value, &mut self.max_downloads),
                     FormField::EnablePassword => Self::parse_bool_value(value, &mut self.enable_password),
                     FormField::Password => Self::parse_string_value(value, &mut self.password),
+                    FormField::DownloadSpeedLimit => Self::parse_from_str(value, &mut self.download_speed_limit),
+                    // (left empty when no notifications are wanted)
+                    FormField::NotifyTopic if value.is_empty() => true,
+                    FormField::NotifyTopic if is_valid_topic(value) =>
+                        Self::parse_string_value(value, &mut self.notify_topic),
+                    FormField::ShareEmail if value.is_empty() => true,
+                    FormField::ShareEmail if is_valid_address(value) =>
+                        Self::parse_string_value(value, &mut self.share_email),
+                    FormField::Public => Self::parse_bool_value(value, &mut self.public),
+                    FormField::DeriveKey => Self::parse_bool_value(value, &mut self.derive_key),
+                    FormField::Age => Self::parse_bool_value(value, &mut self.age),
+                    // (left empty when the upload has no title)
+                    FormField::Title if value.is_empty() => true,
+                    FormField::Title if value.len() <= MAX_TITLE_LENGTH =>
+                        Self::parse_string_value(value, &mut self.title),
+                    FormField::Recipients => match parse_recipients(value) {
+                        Some(recipients) if self.recipients.is_none() => {
+                            self.recipients = Some(recipients);
+                            true
+                        },
+                        _ => false
+                    },
                     _ => false
                 }
             },
@@ -328,7 +553,10 @@ impl Writer {
     }
 }
 
-fn create_upload_storage_dir(storage_path: PathBuf) -> (i64, String, PathBuf) {
+pub fn create_upload_storage_dir(
+    storage_path: PathBuf, client: UploadClient,
+    declared_size: Option<u64>) -> (i64, String, PathBuf)
+{
     // Note: we check the filesystem to avoid duplicate upload IDs.
     let mut rng = thread_rng();
     loop {
@@ -338,56 +566,105 @@ fn create_upload_storage_dir(storage_path: PathBuf) -> (i64, String, PathBuf) {
         let dir = storage_path.join(&id_string);
         // This will fail if the directory already exists
         if fs::create_dir(&dir).is_ok() {
+            // Nothing can be stored unencrypted if a storage key is set
+            if let Err(e) = at_rest::mark_new_dir(&dir) {
+                warn!(id = %id_string, "Marking upload directory as encrypted: {}", e);
+                let _ = fs::remove_dir_all(&dir);
+                continue;
+            }
+
+            let metadata = UploadMetadata {
+                declared_size,
+                started_at: Local::now().naive_utc(),
+                client,
+                completed: false
+            };
+            // Without metadata, the upload is treated like one stored before
+            // metadata was written
+            if let Err(e) = write_upload_metadata(&dir, &metadata) {
+                warn!(id = %id_string, "Writing upload metadata: {}", e);
+            }
+
             return (id, id_string, dir);
         }
     }
 }
 
 pub async fn handle_websocket(
-    mut conn: WebSocketConn, config: Arc<TranspoConfig>,
-    db_backend: DbBackend, quotas_data: Option<(Quotas, IpAddr)>) -> Result<()>
+    mut conn: WebSocketConn, config: Arc<TranspoConfig>, accessors: Accessors,
+    db_backend: DbBackend, quotas_data: Option<(Quotas, IpAddr)>,
+    uploader_ip: Option<IpAddr>, owner_id: Option<i64>) -> Result<()>
 {
+    let _active = ActiveTransfer::new(Transfer::Upload);
     let query = UploadQuery::new(conn.querystring());
-
-    if let Some((minutes, max_downloads, password, file_name, mime_type)) =
+    let size_hint = query.as_ref().and_then(|q| q.size);
+    let share_email = query.as_ref().and_then(|q| q.share_email.clone());
+    let is_public = query.as_ref().and_then(|q| q.public).unwrap_or(false);
+    let public_key = query.as_ref().and_then(|q| q.public_key.clone());
+    let format_version = query.as_ref().and_then(|q| q.format_version);
+    let title_cipher = query.as_ref().and_then(|q| q.title.clone());
+
+    if let Some((minutes, max_downloads, password, file_name, mime_type, download_speed_limit, notify_topic)) =
         query.and_then(|q| q.get_values())
     {
         let (upload_id, upload_id_string, upload_dir) = {
             let storage_path = config.storage_dir.clone();
-            unblock(|| create_upload_storage_dir(storage_path))
+            unblock(move || create_upload_storage_dir(
+                storage_path, UploadClient::Websocket, size_hint))
         }.await;
+        Span::current().record("id", upload_id_string.as_str());
 
         let upload_path = upload_dir.join("upload");
 
-        let form = UploadForm::new(true, minutes, max_downloads, password);
+        let mut form = UploadForm::new(
+            true, minutes, max_downloads, password, download_speed_limit, notify_topic);
+        form.format_version = format_version;
+        form.title_cipher = title_cipher;
 
         let db_write_succeeded = write_to_db(
-            form, upload_id, file_name, mime_type,
+            form, upload_id, file_name, mime_type, uploader_ip, owner_id,
             db_backend, config.clone()).await.is_some();
 
         if db_write_succeeded {
             conn.send_string(upload_id_string.clone()).await;
 
+            let mut quota_status = None;
             let upload_result = websocket_read_loop(
-                &mut conn, &upload_path, config.clone(), quotas_data).await;
+                &mut conn, &upload_path, size_hint, config.clone(), &accessors,
+                db_backend, quotas_data, owner_id, &mut quota_status).await;
 
             match upload_result {
                 Ok(()) => {
-                    let write_is_completed_success =
-                        write_is_completed(upload_id, db_backend, config.clone()).await.is_some();
+                    let write_is_completed_success = write_is_completed(
+                        upload_id, upload_path, None, db_backend, config.clone()).await.is_some();
 
                     if write_is_completed_success {
+                        info!("Upload completed");
+                        mirror_to_peers(upload_id, config.clone(), db_backend);
+                        // The key never leaves the client
+                        share_by_mail(
+                            share_email, upload_id, None, owner_id, db_backend,
+                            config.clone()).await;
+                        // ...unless the uploader lists the upload publicly
+                        publish(is_public, upload_id, public_key, db_backend, config.clone()).await;
                         // Don't handle error, since client may have already closed its
                         // end in which case closing here will return an error, but
                         // this error should *not* cause the upload to fail.
                         drop(conn.send(Message::Close(None)).await);
                         return Ok(()); // return early
                     } else {
+                        warn!("Upload failed: could not mark it as completed");
                         drop(conn.send(Message::Binary(vec![UploadError::Other as u8])).await);
                     }
                 },
                 Err(e) => {
-                    drop(conn.send(Message::Binary(vec![e as u8])).await);
+                    warn!(error = ?e, "Upload failed");
+                    let mut message = vec![e as u8];
+                    if let Some(status) = quota_status {
+                        let retry_after = cmp::min(status.retry_after_secs, u32::MAX as u64) as u32;
+                        message.extend(retry_after.to_be_bytes());
+                    }
+                    drop(conn.send(Message::Binary(message)).await);
                 }
             }
         }
@@ -395,8 +672,10 @@ pub async fn handle_websocket(
 
         unblock(move || {
             if upload_dir.exists() {
-                let db_connection = establish_connection(db_backend, &config.db_url);
-                Upload::delete_with_id(upload_id, &db_connection);
+                release_storage(&upload_dir.join("upload"), db_backend, &config);
+                if let Some(db_connection) = establish_connection(db_backend, &config.db_url) {
+                    Upload::delete_with_id(upload_id, &db_connection);
+                }
                 std::fs::remove_dir_all(upload_dir)
                     .expect("Deleting failed upload");
             }
@@ -408,39 +687,56 @@ pub async fn handle_websocket(
 }
 
 async fn websocket_read_loop(
-    conn: &mut WebSocketConn, upload_path: &PathBuf, config: Arc<TranspoConfig>,
-    quotas_data: Option<(Quotas, IpAddr)>) -> std::result::Result<(), UploadError>
+    conn: &mut WebSocketConn, upload_path: &PathBuf, size_hint: Option<u64>,
+    config: Arc<TranspoConfig>, accessors: &Accessors, db_backend: DbBackend, quotas_data: Option<(Quotas, IpAddr)>,
+    owner_id: Option<i64>,
+    quota_status: &mut Option<QuotaStatus>) -> std::result::Result<(), UploadError>
 {
-    if is_storage_full(config.clone()).await? {
+    if charge_storage(0, accessors, db_backend, config.clone()).await? {
         return Err(UploadError::Storage);
     }
+    if charge_account(owner_id, 0, 0, db_backend, config.clone()).await? {
+        return Err(UploadError::AccountQuota);
+    }
 
     let timeout_duration = time::Duration::from_millis(config.read_timeout_milliseconds as u64);
-    let inner_writer = FileWriter::new(&upload_path, config.max_upload_size_bytes)?;
+    let inner_writer = FileWriter::new(&upload_path, config.max_upload_size_bytes, size_hint)
+        .map_err(|e| if is_out_of_space(&e) { UploadError::Storage } else { UploadError::Other })?;
     let mut writer = Unblock::with_capacity(FORM_READ_BUFFER_SIZE, inner_writer);
     let mut bytes_read_interval = 0;
+    let mut bytes_read_total = 0;
+    let mut transfer_rate = MinTransferRate::new(
+        config.min_transfer_bytes_per_second, config.upload_deadline_minutes);
 
-    while let Some(Ok(msg)) = conn
-        .next()
+    while let Some((Some(Ok(msg)), waited)) = timed(conn.next())
         .timeout(timeout_duration).await
-        .flatten()
     {
         match msg {
             Message::Binary(b) => {
-                if let Some(true) = quotas_data.as_ref().map(
-                    |(q, a)| q.exceeds_quota(a, b.len()))
+                transfer_rate.record(b.len(), waited)?;
+
+                if let Some(status) = quotas_data.as_ref().and_then(
+                    |(q, a)| q.exceeded_quota(a, b.len()))
                 {
-                    return Err(UploadError::Quota);
+                    *quota_status = Some(status);
+                    return Err(UploadError::RateLimited);
                 } else if b.len() > FORM_READ_BUFFER_SIZE * 2 {
                     return Err(UploadError::Protocol);
                 } else {
                     bytes_read_interval += b.len();
+                    bytes_read_total += b.len();
                     if bytes_read_interval > STORAGE_CHECK_INTERVAL {
+                        let bytes = bytes_read_interval;
                         bytes_read_interval = 0;
 
-                        if is_storage_full(config.clone()).await? {
+                        if charge_storage(bytes, accessors, db_backend, config.clone()).await? {
                             return Err(UploadError::Storage);
                         }
+                        if charge_account(
+                            owner_id, bytes, bytes_read_total, db_backend, config.clone()).await?
+                        {
+                            return Err(UploadError::AccountQuota);
+                        }
 
                         if !upload_path.exists() {
                             return Err(UploadError::Other);
@@ -457,6 +753,11 @@ async fn websocket_read_loop(
             },
             Message::Close(_) => {
                 writer.flush().await?;
+                // Charge the rest of the upload
+                charge_storage(bytes_read_interval, accessors, db_backend, config.clone()).await?;
+                charge_account(
+                    owner_id, bytes_read_interval, bytes_read_total,
+                    db_backend, config.clone()).await?;
                 return Ok(());
             },
             _ => {
@@ -470,14 +771,166 @@ async fn websocket_read_loop(
     Err(UploadError::Protocol)
 }
 
+// How the result of a form upload is sent to the client
+#[derive(Clone, Copy, PartialEq)]
+pub enum UploadResponse {
+    // an HTML page for browsers, or a JSON string for tools like curl
+    Page,
+    // a JSON object, for `/api/v1`
+    Api
+}
+
+fn upload_failed(
+    conn: Conn, response: UploadResponse, config: Arc<TranspoConfig>,
+    translation: Translation) -> Conn
+{
+    match response {
+        UploadResponse::Page => error_400(conn, config, translation),
+        UploadResponse::Api => api_error(conn, 400, "The upload is invalid or too large")
+    }
+}
+
+// Mail the link to the upload to the address which the uploader gave. Only
+// users who are logged in may do so, so that nobody can have the server send
+// mail to arbitrary addresses.
+async fn share_by_mail(
+    recipient: Option<String>, id: i64, key: Option<String>, owner_id: Option<i64>,
+    db_backend: DbBackend, config: Arc<TranspoConfig>)
+{
+    let recipient = match recipient {
+        Some(recipient) if mail::is_enabled(&config) => recipient,
+        _ => return
+    };
+    if owner_id.is_none() {
+        info!("Not mailing the link to an upload by someone who isn't logged in");
+        return;
+    }
+
+    let queued = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        mail::queue_share_mail(&recipient, id, key.as_deref(), &config, &db_connection)
+    }).await;
+
+    if queued.is_none() {
+        warn!("Queueing the link to be mailed failed");
+    }
+}
+
+// Return the path of the download link of an upload encrypted by the server
+fn upload_link(
+    id_string: &str, key: &str, is_password_protected: bool, is_key_derived: bool,
+    has_recipients: bool) -> String
+{
+    if is_key_derived || has_recipients {
+        // The download page asks for the password or the secret key of a
+        // recipient, and the server decrypts the upload with the key
+        // unwrapped with it
+        let mut flags = Vec::new();
+        if !is_password_protected {
+            flags.push("nopass");
+        }
+        if is_key_derived {
+            flags.push("derived");
+        }
+        if has_recipients {
+            flags.push("recipient");
+        }
+        format!("{}?{}", id_string, flags.join("&"))
+    } else if is_password_protected {
+        format!("{}#{}", id_string, key)
+    } else {
+        format!("{}?nopass#{}", id_string, key)
+    }
+}
+
+// Store the key of an upload whose key is derived from its password wrapped
+// with a key derived from the password. Return the number of modified rows.
+async fn wrap_upload_key(
+    id: i64, key: Vec<u8>, password: String, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<usize>
+{
+    unblock(move || {
+        let (key_salt, wrapped_key) = wrap_key(&key, password.as_bytes()).ok()?;
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        Upload::set_wrapped_key(id, &key_salt, &wrapped_key, &db_connection)
+    }).await
+}
+
+// Store the key of an upload wrapped for each of its recipients. Return the
+// number of modified rows.
+async fn wrap_key_for_upload_recipients(
+    id: i64, key: Vec<u8>, recipients: Vec<[u8; 32]>, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<usize>
+{
+    unblock(move || {
+        let recipient_keys = wrap_key_for_recipients(&key, &recipients).ok()?;
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        Upload::set_recipient_keys(id, &recipient_keys, &db_connection)
+    }).await
+}
+
+// Encrypt the title of an upload with its key and store it. Return the number
+// of modified rows.
+async fn seal_upload_title(
+    id: i64, key: Vec<u8>, title: String, format: ChunkFormat, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<usize>
+{
+    unblock(move || {
+        let title_cipher = seal_title(&key, &title, format).ok()?;
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        Upload::set_title(id, &title_cipher, &db_connection)
+    }).await
+}
+
+// List the upload in the public gallery if the uploader asked for it. The
+// key is checked against the encrypted file name, so that the gallery only
+// links to uploads which can be downloaded.
+async fn publish(
+    is_public: bool, id: i64, key: Option<String>, db_backend: DbBackend,
+    config: Arc<TranspoConfig>)
+{
+    if !is_public || !config.gallery {
+        return;
+    }
+    let key = match key {
+        Some(key) => key,
+        None => {
+            info!("Not listing an upload whose key is unknown in the gallery");
+            return;
+        }
+    };
+
+    let published = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+        let upload = Upload::select_with_id(id, &db_connection)?;
+        decrypt_file_name(
+            key.as_bytes(), upload.file_name.as_bytes(),
+            ChunkFormat::new(upload.id, upload.format_version)).ok()?;
+        // (there is no way to give the password in the gallery)
+        if upload.password_hash.is_some() {
+            info!("Not listing a password-protected upload in the gallery");
+            return Some(0);
+        }
+
+        Upload::publish(id, &key, &db_connection)
+    }).await;
+
+    if published.is_none() {
+        warn!("Listing the upload in the gallery failed");
+    }
+}
+
 pub async fn handle_post(
-    mut conn: Conn, config: Arc<TranspoConfig>, translation: Translation,
-    db_backend: DbBackend, quotas_data: Option<(Quotas, IpAddr)>) -> Conn
+    mut conn: Conn, response: UploadResponse, config: Arc<TranspoConfig>,
+    translation: Translation, accessors: Accessors, db_backend: DbBackend, quotas_data: Option<(Quotas, IpAddr)>,
+    uploader_ip: Option<IpAddr>, owner_id: Option<i64>) -> Conn
 {
+    let _active = ActiveTransfer::new(Transfer::Upload);
+
     // Get the boundary of the multi-part form
     let boundary = match get_boundary(&conn) {
         Some(boundary) => boundary,
-        None => return error_400(conn, config, translation)
+        None => return upload_failed(conn, response, config, translation)
     };
     let boundary = format!("\r\n--{}", boundary);
     if boundary.len() > MAX_FORM_BOUNDARY_LENGTH
@@ -486,13 +939,20 @@ pub async fn handle_post(
         // This is unlikely to happen unless someone is trying to abuse the
         // slowest path in the parser: a long boundary that contains every
         // possible byte value.
-        return error_400(conn, config, translation);
+        return upload_failed(conn, response, config, translation);
     }
 
+    // The body also contains the rest of the form, but that's small enough
+    // that reserving space for it doesn't matter
+    let size_hint = conn.headers().get_str("Content-Length")
+        .and_then(|l| l.parse().ok());
+
     let (upload_id, upload_id_string, upload_dir) = {
         let storage_path = config.storage_dir.clone();
-        unblock(|| create_upload_storage_dir(storage_path))
+        unblock(move || create_upload_storage_dir(
+            storage_path, UploadClient::Form, size_hint))
     }.await;
+    Span::current().record("id", upload_id_string.as_str());
 
     let upload_path = upload_dir.join("upload");
 
@@ -500,17 +960,32 @@ pub async fn handle_post(
     let mut key: Option<Vec<u8>> = None;
 
     let query = UploadQuery::new(conn.querystring());
+    let mut share_email = query.as_ref().and_then(|q| q.share_email.clone());
+    let query_is_public = query.as_ref().and_then(|q| q.public).unwrap_or(false);
+    let public_key = query.as_ref().and_then(|q| q.public_key.clone());
+    let format_version = query.as_ref().and_then(|q| q.format_version);
+    let query_derive_key = query.as_ref().and_then(|q| q.derive_key).unwrap_or(false);
+    let query_password = query.as_ref().and_then(|q| q.password.clone());
+    let query_recipients = query.as_ref().and_then(|q| q.recipients.clone());
+    let query_age = query.as_ref().and_then(|q| q.age).unwrap_or(false);
+    let query_title = query.as_ref().and_then(|q| q.title.clone());
 
     let (mut form, mut file_name, mut mime_type) = if let Some(
-        (minutes, max_downloads, password, file_name, mime_type))
+        (minutes, max_downloads, password, file_name, mime_type, download_speed_limit, notify_topic))
         = query.and_then(|q| q.get_values())
     {
-        (UploadForm::new(true, minutes, max_downloads, password), file_name, mime_type)
+        let mut form = UploadForm::new(
+            true, minutes, max_downloads, password, download_speed_limit, notify_topic);
+        form.format_version = format_version;
+        (form, file_name, mime_type)
     } else {
         (UploadForm::default(), None, None)
     };
+    form.title_cipher = query_title;
 
     let mut db_write_success = false;
+    // (the format version which is written to the DB along with the form)
+    let mut stored_format_version = None;
 
     // If a time limit has already been provided via the query string, write
     // the current data in the form to the DB to allow the file to be downloaded
@@ -518,58 +993,154 @@ pub async fn handle_post(
     // in the query string, it must provide it in the form body which will be
     // read by `parse_upload_form`.
     if form.has_time_limit() {
+        stored_format_version = form.format_version;
         db_write_success = write_to_db(
-            form, upload_id, file_name, mime_type,
+            form, upload_id, file_name, mime_type, uploader_ip, owner_id,
             db_backend, config.clone()).await.is_some();
         file_name = None;
         mime_type = None;
         form = UploadForm::default();
     }
 
+    // Recipients given in the query string are known before the files start,
+    // so the upload can be stored in the age format for them
+    if query_age {
+        form.query_age_recipients = query_recipients.clone();
+    }
+
     let req_body = conn.request_body().await;
     let parse_result = parse_upload_form(
-        req_body, boundary, &upload_path, &mut form, &mut file_writer, &mut key,
-        &mut file_name, &mut mime_type, config.clone(), quotas_data).await;
-    let parse_success = match parse_result {
-        Ok(result) => result,
-        Err(_) => false
+        req_body, boundary, upload_id, &upload_path, size_hint, &mut form, &mut file_writer, &mut key,
+        &mut file_name, &mut mime_type, config.clone(), &accessors, db_backend,
+        quotas_data, owner_id).await;
+    let (parse_success, quota_status) = match parse_result {
+        Ok(result) => (result, None),
+        Err(e) => (false, e.get_ref()
+            .and_then(|e| e.downcast_ref::<QuotaStatus>())
+            .copied())
     };
 
     let is_password_protected = form.is_password_protected();
+    share_email = share_email.or(form.share_email.take());
+    let is_public = query_is_public || form.public.unwrap_or(false);
+    // Only a key generated by the server can be wrapped, and only with a
+    // password
+    let password = query_password
+        .or_else(|| form.password.clone().filter(|_| is_password_protected))
+        .filter(|p| !p.is_empty() && key.is_some()
+            && (query_derive_key || form.derive_key.unwrap_or(false)));
+    let is_key_derived = password.is_some();
+    // Likewise, only a key generated by the server can be wrapped for
+    // recipients
+    let recipients = query_recipients
+        .or(form.recipients.take())
+        .filter(|r| !r.is_empty() && key.is_some());
+    let has_recipients = recipients.is_some();
+    // Likewise, only the server can encrypt a plaintext title
+    let title = form.title.take().filter(|_| key.is_some());
+    // The key is left out of links if it can be unwrapped without them
+    let is_key_hidden = is_key_derived || has_recipients;
 
     // If a DB entry has not yet been written for the upload, and parsing the
     // upload body succeeded, try to write one now.
     if parse_success && !db_write_success {
+        stored_format_version = form.format_version;
         db_write_success = write_to_db(
-            form, upload_id, file_name, mime_type,
+            form, upload_id, file_name, mime_type, uploader_ip, owner_id,
             db_backend, config.clone()).await.is_some();
     }
 
+    let chunk_format = ChunkFormat::new(upload_id, stored_format_version.unwrap_or(1));
+
+    // The key and title are stored before the upload is marked as completed,
+    // so that it can't be downloaded without them
+    let wrap_key_success = match (&key, password) {
+        (Some(key), Some(password)) if parse_success => wrap_upload_key(
+            upload_id, key.clone(), password, db_backend, config.clone()).await.is_some(),
+        _ => true
+    };
+
+    let wrap_recipient_keys_success = match (&key, recipients) {
+        (Some(key), Some(recipients)) if parse_success => wrap_key_for_upload_recipients(
+            upload_id, key.clone(), recipients, db_backend, config.clone()).await.is_some(),
+        _ => true
+    };
+
+    let seal_title_success = match (&key, title) {
+        (Some(key), Some(title)) if parse_success => seal_upload_title(
+            upload_id, key.clone(), title, chunk_format, db_backend, config.clone())
+            .await.is_some(),
+        _ => true
+    };
+
     // write that the upload is completed into the db
-    let write_is_completed_success =
-        write_is_completed(upload_id, db_backend, config.clone()).await.is_some();
+    let write_is_completed_success = parse_success
+        && db_write_success
+        && wrap_key_success
+        && wrap_recipient_keys_success
+        && seal_title_success
+        && write_is_completed(
+            upload_id, upload_path, key.clone(), db_backend, config.clone()).await.is_some();
 
     let upload_success =
         parse_success
         && db_write_success
-        && write_is_completed_success;
+        && write_is_completed_success
+        && wrap_key_success
+        && wrap_recipient_keys_success
+        && seal_title_success;
 
     // Respond to the client
     if upload_success {
-        if let Some(key) = key {
+        info!("Upload completed");
+        mirror_to_peers(upload_id, config.clone(), db_backend);
+        // Only a key generated by the server is known, and it is left out of
+        // links if it is derived from the password or wrapped for recipients
+        let key_string = key.as_ref()
+            .map(|k| String::from_utf8(k.clone()).unwrap())
+            .filter(|_| !is_key_hidden);
+        share_by_mail(
+            share_email, upload_id, key_string.clone(), owner_id, db_backend, config.clone()).await;
+        publish(
+            is_public, upload_id, key_string.or(public_key), db_backend, config.clone()).await;
+
+        if response == UploadResponse::Api {
+            let url = key.as_ref().map(|key| upload_link(
+                &upload_id_string, &String::from_utf8_lossy(key), is_password_protected,
+                is_key_derived, has_recipients));
+            let key = key.map(|k| String::from_utf8(k).unwrap()).filter(|_| !is_key_hidden);
+            let body = serde_json::json!({
+                "id": upload_id_string,
+                "key": key,
+                "url": url
+            });
+
+            conn
+                .with_status(201)
+                .with_header("Content-Type", "application/json")
+                .with_body(body.to_string())
+                .halt()
+        } else if let Some(key) = key {
             // If the server handled encryption + archiving
             let key_string = String::from_utf8(key).unwrap();
             if conn.headers().has_header("User-Agent") {
                 // If the client is probably a browser
-                let upload_url = if is_password_protected {
-                    format!("{}#{}", upload_id_string, key_string)
+                let upload_url = upload_link(
+                    &upload_id_string, &key_string, is_password_protected, is_key_derived,
+                    has_recipients);
+
+                // Links on the page already point at the onion service if it
+                // is used
+                let onion_upload_url = if is_onion_request(&conn) {
+                    None
                 } else {
-                    format!("{}?nopass#{}", upload_id_string, key_string)
+                    onion_link(&config, &upload_url)
                 };
 
                 let template = UploadLinkTemplate {
                     app_name: config.app_name.clone(),
                     upload_url: upload_url,
+                    onion_upload_url,
                     upload_id: upload_id_string,
                     t: translation
                 };
@@ -579,7 +1150,11 @@ pub async fn handle_post(
                 conn
                     .with_status(200)
                     .with_header("Content-Type", "application/json")
-                    .with_body(format!("\"{}#{}\"", upload_id_string, key_string))
+                    .with_body(if is_key_hidden {
+                        format!("\"{}\"", upload_id_string)
+                    } else {
+                        format!("\"{}#{}\"", upload_id_string, key_string)
+                    })
                     .halt()
             }
         } else {
@@ -591,100 +1166,252 @@ pub async fn handle_post(
                 .halt()
         }
     } else {
+        warn!(parse_success, db_write_success, write_is_completed_success, "Upload failed");
+        let release_config = config.clone();
         unblock(move || {
             if upload_dir.exists() {
+                release_storage(&upload_dir.join("upload"), db_backend, &release_config);
+                if let Some(db_connection) = establish_connection(
+                    db_backend, &release_config.db_url)
+                {
+                    Upload::delete_with_id(upload_id, &db_connection);
+                }
                 std::fs::remove_dir_all(upload_dir)
                     .expect("Deleting failed upload");
             }
         }).await;
 
-        error_400(conn, config, translation)
+        match (quota_status, response) {
+            (Some(status), UploadResponse::Page) => quota_exceeded(conn, status),
+            (Some(status), UploadResponse::Api) => {
+                let conn = status.headers().into_iter()
+                    .fold(conn, |conn, (name, value)| conn.with_header(name, value));
+                api_error(conn, 429, &status.to_string())
+            },
+            (None, _) => upload_failed(conn, response, config, translation)
+        }
+    }
+}
+
+// Respond with 429 and tell the client when it can upload again
+pub fn quota_exceeded(conn: Conn, status: QuotaStatus) -> Conn {
+    let conn = status.headers().into_iter()
+        .fold(conn, |conn, (name, value)| conn.with_header(name, value));
+
+    conn
+        .with_status(429)
+        .with_body(status.to_string())
+        .halt()
+}
+
+// Add the given number of bytes to the storage usage. Return whether or not
+// the storage capacity is now exceeded (after evicting old uploads, if
+// enabled).
+pub async fn charge_storage(
+    bytes: usize, accessors: &Accessors, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Result<bool>
+{
+    add_bytes_received(bytes);
+
+    let accessors = accessors.clone();
+    let is_full = unblock(move || {
+        let db_connection = establish_connection(db_backend, &config.db_url)
+            .ok_or(Error::new(ErrorKind::Other, "Reading storage usage"))?;
+        let storage_size = StorageUsage::add(bytes as i64, &db_connection)
+            .ok_or(Error::new(ErrorKind::Other, "Reading storage usage"))?;
+
+        let excess = storage_size - config.max_storage_size_bytes as i64;
+        if excess > 0 && config.evict_when_full {
+            let bytes_freed = evict_uploads(
+                excess, config.eviction_protected_minutes,
+                &config.storage_dir, &accessors, &db_connection);
+            return Ok(bytes_freed < excess);
+        }
+
+        Ok(excess > 0)
+    }).await;
+
+    if let Ok(true) = is_full {
+        count_rejection(Rejection::Storage);
     }
+    is_full
 }
 
-async fn is_storage_full(config: Arc<TranspoConfig>) -> Result<bool> {
-    unblock(move || {
-        Ok(get_storage_size(&config.storage_dir)? > config.max_storage_size_bytes)
-    }).await
+// Add the given number of bytes to this month's transfer of the account making
+// an upload (if the uploader is logged in). Return whether or not the account
+// has now exceeded its transfer limit, or would exceed its storage limit once
+// the `upload_size` bytes uploaded so far are stored.
+async fn charge_account(
+    owner_id: Option<i64>, bytes: usize, upload_size: usize,
+    db_backend: DbBackend, config: Arc<TranspoConfig>) -> Result<bool>
+{
+    let owner_id = match owner_id {
+        Some(owner_id) => owner_id,
+        None => return Ok(false)
+    };
+    if config.account_storage_bytes == 0 && config.account_transfer_bytes_per_month == 0 {
+        return Ok(false);
+    }
+
+    let is_exceeded = unblock(move || {
+        let error = || Error::new(ErrorKind::Other, "Reading account usage");
+        let db_connection = establish_connection(db_backend, &config.db_url)
+            .ok_or_else(error)?;
+
+        if config.account_transfer_bytes_per_month > 0 {
+            let transfer = AccountTransfer::add(owner_id, bytes as i64, &db_connection)
+                .ok_or_else(error)?;
+            if transfer > config.account_transfer_bytes_per_month as i64 {
+                return Ok(true);
+            }
+        }
+
+        if config.account_storage_bytes > 0 {
+            // The upload in progress isn't completed yet, so it isn't
+            // counted here
+            let stored = Upload::bytes_stored_by_owner(owner_id, &db_connection)
+                .ok_or_else(error)?;
+            if stored + upload_size as i64 > config.account_storage_bytes as i64 {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }).await;
+
+    if let Ok(true) = is_exceeded {
+        count_rejection(Rejection::AccountQuota);
+    }
+    is_exceeded
+}
+
+// Remove the bytes of a failed upload from the storage usage
+pub fn release_storage(upload_path: &PathBuf, db_backend: DbBackend, config: &TranspoConfig) {
+    if let Ok(size) = get_file_size(upload_path) {
+        if let Some(db_connection) = establish_connection(db_backend, &config.db_url) {
+            StorageUsage::add(-(size as i64), &db_connection);
+        }
+    }
 }
 
 async fn parse_upload_form<R>(
-    mut req_body: R, boundary: String, upload_path: &PathBuf,
-    form: &mut UploadForm, file_writer: &mut Option<Writer>,
+    mut req_body: R, boundary: String, upload_id: i64, upload_path: &PathBuf,
+    size_hint: Option<u64>, form: &mut UploadForm, file_writer: &mut Option<Writer>,
     key: &mut Option<Vec<u8>>, file_name: &mut Option<Vec<u8>>,
     mime_type: &mut Option<Vec<u8>>, config: Arc<TranspoConfig>,
-    quotas_data: Option<(Quotas, IpAddr)>) -> Result<bool>
+    accessors: &Accessors, db_backend: DbBackend, quotas_data: Option<(Quotas, IpAddr)>,
+    owner_id: Option<i64>) -> Result<bool>
 where R: AsyncReadExt + Unpin
 {
-    if is_storage_full(config.clone()).await? {
+    if charge_storage(0, accessors, db_backend, config.clone()).await? {
         return Err(Error::new(ErrorKind::Other, "Storage capacity exceeded"));
     }
+    if charge_account(owner_id, 0, 0, db_backend, config.clone()).await? {
+        return Err(Error::new(ErrorKind::Other, "Account quota exceeded"));
+    }
 
     let timeout_duration = time::Duration::from_millis(
         config.read_timeout_milliseconds as u64);
     let mut upload_success = false;
     let mut buf = [0; FORM_READ_BUFFER_SIZE];
-    let boundary_byte_map = byte_map(boundary.as_bytes());
-    // Make the first boundary start with a newline to simplify parsing
-    (&mut buf[..2]).copy_from_slice(b"\r\n");
-    let mut read_start = 2;
+    let mut parser = MultipartParser::new(boundary.as_bytes());
 
     let mut field_type = FormField::Invalid;
+    // whether the last field of the form (not of a nested form) was NestedFiles
+    let mut is_in_nested_files = false;
     // Form fields other than files are expected to fit in this buffer.
     // If they do not, error 400 will be returned.
     let mut field_buf = [0; FORM_FIELD_BUFFER_SIZE];
     let mut field_write_start = 0;
+    // Decodes the value of the current field if it has a
+    // Content-Transfer-Encoding, into `decode_buf`
+    let mut transfer_decoder = TransferDecoder::Identity;
+    let mut decode_buf = Vec::new();
 
     let mut bytes_read_interval = 0;
+    let mut bytes_read_total = 0;
+    // number of files in a multi-file upload so far
+    let mut file_count = 0;
+
+    let mut transfer_rate = MinTransferRate::new(
+        config.min_transfer_bytes_per_second, config.upload_deadline_minutes);
 
-    'outer: while let Some(Ok(bytes_read)) = req_body
-        .read(&mut buf[read_start..])
+    'outer: while let Some((Ok(bytes_read), waited)) = timed(req_body
+        .read(&mut buf))
         .timeout(timeout_duration).await
     {
         if bytes_read == 0 {
             break 'outer;
         }
 
-        if let Some(true) = quotas_data.as_ref().map(
-            |(q, a)| q.exceeds_quota(a, bytes_read))
+        transfer_rate.record(bytes_read, waited)?;
+
+        if let Some(status) = quotas_data.as_ref().and_then(
+            |(q, a)| q.exceeded_quota(a, bytes_read))
         {
-            return Err(Error::new(ErrorKind::Other, "Quota exceeded"));
+            return Err(Error::new(ErrorKind::Other, status));
         }
 
         bytes_read_interval += bytes_read;
+        bytes_read_total += bytes_read;
         if bytes_read_interval > STORAGE_CHECK_INTERVAL {
+            let bytes = bytes_read_interval;
             bytes_read_interval = 0;
-            if is_storage_full(config.clone()).await? {
+            if charge_storage(bytes, accessors, db_backend, config.clone()).await? {
                 return Err(Error::new(ErrorKind::Other, "Storage capacity exceeded"));
             }
+            if charge_account(
+                owner_id, bytes, bytes_read_total, db_backend, config.clone()).await?
+            {
+                return Err(Error::new(ErrorKind::Other, "Account quota exceeded"));
+            }
         }
 
-        // Make sure buf does not contain data from the previous read
-        let buf = &mut buf[..(bytes_read + read_start)];
+        parser.push(&buf[..bytes_read]);
+
+        // Parse until either parsing ends, or we run out of data, i.e. we hit
+        // either the end of what was read or a string of bytes that may or
+        // may not be a boundary and we can't be sure until we read more data
+        loop {
+            let parse_result = parser.next();
+            let is_nested = matches!(parse_result, ParseResult::NestedField(..));
 
-        // Parse over the buffer until either parsing ends, or we run out of data
-        // i.e. we hit either the end of the buffer or a string of bytes that may
-        // or may not be a boundary and we can't be sure until we read more data
-        let mut parse_start = 0;
-        while buf.len() - parse_start > boundary.len() {
-            let parse_result = multipart_form::parse(
-                &buf[parse_start..], &boundary, &boundary_byte_map);
             match parse_result {
-                // The start of a new field in the form
-                ParseResult::NewValue(b, cd, ct, val) => {
-                    parse_start += b;
+                // The start of a new field in the form, or in a form nested
+                // in one of its fields
+                ParseResult::NewField(cd, ct, cte) | ParseResult::NestedField(cd, ct, cte) => {
+                    // write what is left of the value of the previous field
+                    let decoder = mem::replace(&mut transfer_decoder, TransferDecoder::Identity);
+                    finish_field_value(
+                        decoder, &mut decode_buf, &field_type, file_writer,
+                        &mut field_buf, &mut field_write_start).await?;
 
                     // parse the value of the previous field
-                    if field_type != FormField::Files && field_type != FormField::Invalid {
-                        if !form.parse_field(&field_type, &field_buf[..field_write_start]) {
-                            return Err(Error::new(
-                                    ErrorKind::InvalidData,
-                                    "Error parsing form field"));
-                        }
+                    if !matches!(field_type,
+                        FormField::Files | FormField::NestedFiles | FormField::Unknown | FormField::Invalid)
+                    && !form.parse_field(&field_type, &field_buf[..field_write_start])
+                    {
+                        return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                "Error parsing form field"));
                     }
 
                     // handle the new field
-                    let new_field_type = match_content_disposition(cd);
+                    let new_field_type = if !is_nested {
+                        match_content_disposition(cd)
+                    } else if is_in_nested_files {
+                        match_nested_content_disposition(cd)
+                    } else {
+                        FormField::Invalid
+                    };
+                    let new_field_type = match new_field_type {
+                        FormField::Invalid if config.ignore_unknown_fields => FormField::Unknown,
+                        new_field_type => new_field_type
+                    };
+                    if !is_nested {
+                        is_in_nested_files = new_field_type == FormField::NestedFiles;
+                    }
+
                     match new_field_type {
                         FormField::Invalid => {
                             return Err(Error::new(
@@ -697,21 +1424,28 @@ where R: AsyncReadExt + Unpin
                                 Some(true) => true
                             };
 
-                            let enable_multiple_files = match form.enable_multiple_files {
+                            // (the files of a nested form are archived together)
+                            let enable_multiple_files = is_nested || match form.enable_multiple_files {
                                 None | Some(false) => false,
                                 Some(true) => true
                             };
 
                             let is_first_file = file_writer.is_none();
+                            let age_recipients = form.age_recipients();
+                            let is_age = age_recipients.is_some();
 
-                            match handle_file_start(cd, ct, &upload_path, file_writer,
+                            match handle_file_start(cd, ct, upload_id, &upload_path, size_hint, file_writer,
                                                     server_side_processing,
                                                     enable_multiple_files,
-                                                    config.max_upload_size_bytes,
-                                                    config.compression_level).await
+                                                    age_recipients,
+                                                    &mut file_count,
+                                                    &config).await
                             {
                                 Ok((k, f, m)) => {
                                     if is_first_file {
+                                        if k.is_some() {
+                                            form.set_encrypted_by_server(config.pad_uploads, is_age);
+                                        }
                                         *key = k;
                                         *file_name = f;
                                         *mime_type = m;
@@ -723,25 +1457,11 @@ where R: AsyncReadExt + Unpin
                                             "File upload started when not allowed"));
                                 }
                             }
-
-                            match file_writer {
-                                Some(writer) => {
-                                    writer.write(val).await?;
-                                },
-                                None => {
-                                    return Err(Error::new(
-                                            ErrorKind::InvalidData,
-                                            "Cannot write file contents without writer"));
-                                }
-                            }
                         },
+                        FormField::NestedFiles | FormField::Unknown => {},
                         _ => {
-                            if form.is_valid_field(&new_field_type)
-                            && val.len() <= field_buf.len()
-                            {
-                                // copy new data into the field buffer
-                                (&mut field_buf[..val.len()]).copy_from_slice(val);
-                                field_write_start = val.len();
+                            if form.is_valid_field(&new_field_type) {
+                                field_write_start = 0;
                             } else {
                                 return Err(Error::new(
                                         ErrorKind::InvalidData,
@@ -751,47 +1471,37 @@ where R: AsyncReadExt + Unpin
                     }
 
                     field_type = new_field_type;
-                },
-                // The continuation of the value of the previous field
-                ParseResult::Continue(val) => {
-                    parse_start += val.len();
-
-                    match field_type {
-                        FormField::Invalid => {
+                    transfer_decoder = match TransferDecoder::new(cte) {
+                        Some(decoder) => decoder,
+                        // (the value of an unknown field isn't decoded)
+                        None if field_type == FormField::Unknown => TransferDecoder::Identity,
+                        None => {
                             return Err(Error::new(
                                     ErrorKind::InvalidData,
-                                    "Error invalid form field type"));
-                        },
-                        FormField::Files => match file_writer {
-                            Some(writer) => {
-                                writer.write(val).await?;
-                            },
-                            None => {
-                                return Err(Error::new(
-                                        ErrorKind::InvalidData,
-                                        "Cannot write file contents without writer"));
-                            }
-                        },
-                        _ => {
-                            if field_write_start + val.len() <= field_buf.len() {
-                                // copy new data into the field buffer
-                                (&mut field_buf[field_write_start..][..val.len()])
-                                    .copy_from_slice(val);
-                                field_write_start += val.len();
-                            } else {
-                                return Err(Error::new(
-                                        ErrorKind::Other,
-                                        "Form field is too big"));
-                            }
+                                    "Unsupported Content-Transfer-Encoding"));
                         }
-                    }
+                    };
+                },
+                // A piece of the value of the current field
+                ParseResult::Value(val) => {
+                    let val = transfer_decoder.decode(val, &mut decode_buf);
+                    write_field_value(
+                        val, &field_type, file_writer,
+                        &mut field_buf, &mut field_write_start).await?;
                 },
                 // The end of the form
                 ParseResult::Finished => {
                     if field_type != FormField::Invalid {
+                        let decoder = mem::replace(&mut transfer_decoder, TransferDecoder::Identity);
+                        finish_field_value(
+                            decoder, &mut decode_buf, &field_type, file_writer,
+                            &mut field_buf, &mut field_write_start).await?;
+
                         // parse the value of the previous field, if it wasn't
-                        // the contents of the upload
-                        if field_type != FormField::Files {
+                        // the contents of the upload or unknown
+                        if !matches!(field_type,
+                            FormField::Files | FormField::NestedFiles | FormField::Unknown)
+                        {
                             upload_success = form.parse_field(&field_type, &field_buf[..field_write_start]);
                         } else {
                             upload_success = true;
@@ -819,27 +1529,21 @@ where R: AsyncReadExt + Unpin
                             }
                         }
 
-                        if is_storage_full(config.clone()).await? {
+                        // Charge the rest of the upload
+                        let bytes = bytes_read_interval;
+                        if charge_storage(bytes, accessors, db_backend, config.clone()).await? {
                             return Err(Error::new(ErrorKind::Other, "Storage capacity exceeded"));
                         }
+                        if charge_account(
+                            owner_id, bytes, bytes_read_total, db_backend, config.clone()).await?
+                        {
+                            return Err(Error::new(ErrorKind::Other, "Account quota exceeded"));
+                        }
                     }
 
                     break 'outer;
                 },
-                ParseResult::NeedMoreData => {
-                    if parse_start == 0 && buf.len() == FORM_READ_BUFFER_SIZE {
-                        // The buffer is not big enough for another read without
-                        // discarding any data. This is *very* unlikely to
-                        // happen for a legitimate upload and not possible to
-                        // handle without allocating arbitrary amounts of
-                        // memory.
-                        return Err(Error::new(
-                                ErrorKind::Other,
-                                "Form field is too big"));
-                    } else {
-                        break;
-                    }
-                },
+                ParseResult::NeedMoreData => break,
                 // An error
                 ParseResult::Error => {
                     return Err(Error::new(
@@ -848,19 +1552,63 @@ where R: AsyncReadExt + Unpin
                 }
             }
         }
-
-        // The buffer may contain incomplete data at the end, so we copy it to
-        // the front of the buffer and make sure it doesn't get read over
-        if parse_start != 0 {
-            buf.copy_within(parse_start.., 0);
-        }
-        read_start = buf.len() - parse_start;
     }
 
     Ok(upload_success)
 }
 
 
+// Write a piece of the (decoded) value of the current form field to the file
+// being uploaded or to the field buffer
+async fn write_field_value(
+    val: &[u8], field_type: &FormField, file_writer: &mut Option<Writer>,
+    field_buf: &mut [u8], field_write_start: &mut usize) -> Result<()>
+{
+    match field_type {
+        // (the value of NestedFiles is a form, which the parser goes through)
+        FormField::Invalid | FormField::NestedFiles => {
+            Err(Error::new(ErrorKind::InvalidData, "Error invalid form field type"))
+        },
+        FormField::Unknown => Ok(()),
+        FormField::Files => match file_writer {
+            Some(writer) => writer.write(val).await,
+            None => {
+                Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Cannot write file contents without writer"))
+            }
+        },
+        _ => {
+            if *field_write_start + val.len() <= field_buf.len() {
+                // copy new data into the field buffer
+                field_buf[*field_write_start..][..val.len()].copy_from_slice(val);
+                *field_write_start += val.len();
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::Other, "Form field is too big"))
+            }
+        }
+    }
+}
+
+// Write what the decoder of the current form field held back, once its value
+// has ended
+async fn finish_field_value(
+    decoder: TransferDecoder, decode_buf: &mut Vec<u8>, field_type: &FormField,
+    file_writer: &mut Option<Writer>, field_buf: &mut [u8],
+    field_write_start: &mut usize) -> Result<()>
+{
+    if decoder.finish(decode_buf).is_none() {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid Content-Transfer-Encoding"));
+    }
+
+    if decode_buf.is_empty() {
+        Ok(())
+    } else {
+        write_field_value(decode_buf, field_type, file_writer, field_buf, field_write_start).await
+    }
+}
+
 // Read the multipart form boundary out of the headers
 fn get_boundary<'a>(conn: &'a Conn) -> Option<&'a str> {
     conn.headers()
@@ -882,28 +1630,35 @@ fn get_boundary<'a>(conn: &'a Conn) -> Option<&'a str> {
         })
 }
 
-fn get_file_name(cd: &str) -> Option<&str> {
-    let (_, name) = cd.split_once("filename=")?;
-    let name = name.trim();
-    if name.len() > 2 && name.starts_with('"') && name.ends_with('"') {
-        Some(&name[1..(name.len() - 1)])
-    } else {
-        None
-    }
-}
-
 // Return writer, key, file name, mime type
 async fn handle_file_start(
-    cd: &str, ct: &str, upload_path: &PathBuf, file_writer: &mut Option<Writer>,
+    cd: &str, ct: &str, upload_id: i64, upload_path: &PathBuf, size_hint: Option<u64>,
+    file_writer: &mut Option<Writer>,
     server_side_processing: bool,
     enable_multiple_files: bool,
-    max_upload_size: usize,
-    compression_level: usize) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>)>
+    age_recipients: Option<Vec<[u8; 32]>>,
+    file_count: &mut usize,
+    config: &TranspoConfig) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>)>
 {
-    let file_name_str = match get_file_name(cd) {
+    let max_upload_size = config.max_upload_size_bytes;
+    let compression_level = config.compression_level;
+
+    let file_name = match get_file_name(cd) {
         Some(file_name) => Ok(file_name),
         None => Err(Error::from(ErrorKind::InvalidInput))
     }?;
+    let file_name_str: &str = &file_name;
+
+    // Limit the shape of archives created on the server, so that they can't
+    // be stuffed with huge numbers of entries or huge entry names
+    if server_side_processing && enable_multiple_files {
+        *file_count += 1;
+        if config.max_archive_files > 0 && *file_count > config.max_archive_files {
+            return Err(Error::new(ErrorKind::InvalidInput, "Too many files"));
+        } else if file_name_str.len() > config.max_archive_name_length {
+            return Err(Error::new(ErrorKind::InvalidInput, "File name is too long"));
+        }
+    }
 
     let mime_type_str = ct;
     // https://datatracker.ietf.org/doc/html/rfc4288#section-4.2
@@ -934,7 +1689,8 @@ async fn handle_file_start(
                     // Multi-file upload with server-side processing on
                     let (mut inner_writer, key, file_name, mime_type)
                         = EncryptedZipWriter::new(
-                            &upload_path, max_upload_size,
+                            &upload_path, max_upload_size, size_hint, upload_id,
+                            config.pad_uploads, age_recipients.as_deref(),
                             compression_level as u8)?;
                     let file_name_str = file_name_str.to_owned();
 
@@ -949,8 +1705,9 @@ async fn handle_file_start(
                     // Single file upload with server-side processing on
                     let (inner_writer, key, file_name, mime_type)
                         = EncryptedFileWriter::new(
-                            &upload_path, max_upload_size,
-                            file_name_str, mime_type_str)?;
+                            &upload_path, max_upload_size, size_hint, upload_id,
+                            config.pad_uploads, age_recipients.as_deref(), file_name_str,
+                            mime_type_str)?;
                     let inner_writer = Unblock::with_capacity(FORM_READ_BUFFER_SIZE, inner_writer);
 
                     *file_writer = Some(Writer::Encrypted(inner_writer));
@@ -960,7 +1717,7 @@ async fn handle_file_start(
                 // Single file upload with client-side processing
                 let file_name = Some(file_name_str.as_bytes().to_owned());
                 let mime_type = Some(mime_type_str.as_bytes().to_owned());
-                let inner_writer = FileWriter::new(&upload_path, max_upload_size)?;
+                let inner_writer = FileWriter::new(&upload_path, max_upload_size, size_hint)?;
                 let inner_writer = Unblock::with_capacity(FORM_READ_BUFFER_SIZE, inner_writer);
 
                 *file_writer = Some(Writer::Basic(inner_writer));
@@ -975,9 +1732,10 @@ async fn handle_file_start(
 
 // Insert the metadata for an upload into the database. Return the number of
 // affected rows (or None if there was an error)
-async fn write_to_db(
+pub async fn write_to_db(
     form: UploadForm, id: i64, file_name: Option<Vec<u8>>, mime_type: Option<Vec<u8>>,
-    db_backend: DbBackend, config: Arc<TranspoConfig>) -> Option<usize>
+    uploader_ip: Option<IpAddr>, owner_id: Option<i64>, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<usize>
 {
 
     let time_limit_minutes = 
@@ -1011,6 +1769,19 @@ async fn write_to_db(
     let expire_after = Local::now().naive_utc()
         + Duration::minutes(time_limit_minutes as i64);
 
+    // Audit data is only recorded if the operator has enabled it
+    let (uploader_ip, created_at) = if config.audit_retention_minutes > 0 {
+        (uploader_ip.map(|a| a.to_string()), Some(Local::now().naive_utc()))
+    } else {
+        (None, None)
+    };
+
+    let max_download_bytes_per_second = form.download_speed_limit
+        .map(|l| cmp::min(l, i64::MAX as u64) as i64);
+
+    // Topics are only used if the operator has set an ntfy server
+    let notify_topic = form.notify_topic.filter(|_| config.ntfy_server.is_some());
+
     let upload = Upload {
         id: id,
         file_name: file_name,
@@ -1019,23 +1790,79 @@ async fn write_to_db(
         remaining_downloads: remaining_downloads,
         num_accessors: 0,
         expire_after: expire_after,
-        is_completed: false
+        is_completed: false,
+        max_download_bytes_per_second,
+        num_downloads: 0,
+        num_completed_downloads: 0,
+        bytes_downloaded: 0,
+        plaintext_size: None,
+        ciphertext_size: None,
+        uploader_ip,
+        created_at,
+        owner_id,
+        deleted_at: None,
+        last_used_at: None,
+        notify_topic,
+        expiry_notified: false,
+        is_disabled: false,
+        is_mirror: false,
+        public_key: None,
+        published_at: None,
+        format_version: form.format_version.unwrap_or(1),
+        // (set once the upload is completed, see `wrap_upload_key`)
+        key_salt: None,
+        wrapped_key: None,
+        // (set once the upload is completed, see `wrap_key_for_upload_recipients`)
+        recipient_keys: None,
+        // (set once the upload is completed if the server encrypts it, see
+        // `seal_upload_title`)
+        title: form.title_cipher
     };
 
     unblock(move || {
-        let db_connection = establish_connection(db_backend, &config.db_url);
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
         let num_modified_rows = upload.insert(&db_connection)?;
 
         Some(num_modified_rows)
     }).await
 }
 
-async fn write_is_completed(
-    id: i64, db_backend: DbBackend, config: Arc<TranspoConfig>) -> Option<usize>
+// Record that the upload is completed (along with its size) in the database.
+// Return the number of affected rows (or None if there was an error)
+//
+// If uploads are verified, the file is checked first (including its first
+// chunk, if the server knows `key`).
+pub async fn write_is_completed(
+    id: i64, upload_path: PathBuf, key: Option<Vec<u8>>, db_backend: DbBackend,
+    config: Arc<TranspoConfig>) -> Option<usize>
 {
     unblock(move || {
-        let db_connection = establish_connection(db_backend, &config.db_url);
-        let num_modified_rows = Upload::set_is_completed(id, true, &db_connection)?;
+        let db_connection = establish_connection(db_backend, &config.db_url)?;
+
+        let (plaintext_size, ciphertext_size) = if config.verify_uploads {
+            let format_version = Upload::select_with_id(id, &db_connection)?.format_version;
+            let sample = key.as_ref()
+                .map(|key| (ChunkFormat::new(id, format_version), key.as_slice()));
+
+            match check_file(&upload_path, sample) {
+                Ok(check) => (check.plaintext_size, check.file_size),
+                Err(e) => {
+                    warn!(id, "Upload failed verification: {}", e);
+                    return None;
+                }
+            }
+        } else {
+            (get_plaintext_size(&upload_path).ok()?, get_file_size(&upload_path).ok()?)
+        };
+
+        let num_modified_rows = Upload::set_completed(
+            id, plaintext_size, ciphertext_size, &db_connection)?;
+
+        if let Some(upload_dir) = upload_path.parent() {
+            if let Err(e) = mark_upload_metadata_completed(upload_dir) {
+                warn!(id, "Updating upload metadata: {}", e);
+            }
+        }
 
         Some(num_modified_rows)
     }).await
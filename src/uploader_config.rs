@@ -0,0 +1,89 @@
+use crate::api_keys::*;
+use crate::config::*;
+
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use trillium::Conn;
+
+
+// Put in place of the API key, for the user to replace
+const API_KEY_PLACEHOLDER: &'static str = "<your API key>";
+
+
+// The URL of this instance: `--public-url` if it is set, otherwise guessed
+// from the request
+pub fn instance_url(conn: &Conn, config: &TranspoConfig) -> String {
+    if !config.public_url.is_empty() {
+        return config.public_url.clone();
+    }
+
+    let headers = conn.headers();
+    let scheme = headers.get_str("X-Forwarded-Proto").unwrap_or("http");
+    let host = headers.get_str("Host").unwrap_or("localhost");
+    format!("{}://{}{}", scheme, host, config.base_path)
+}
+
+// The fields sent along with the file: the server encrypts the upload, since
+// screenshot tools can't
+fn upload_arguments(config: &TranspoConfig) -> Value {
+    json!({
+        "server-side-processing": "on",
+        "expiry": config.default_expiry_minutes.to_string()
+    })
+}
+
+fn upload_headers(config: &TranspoConfig) -> Value {
+    if config.require_api_key {
+        json!({ API_KEY_HEADER: API_KEY_PLACEHOLDER })
+    } else {
+        json!({})
+    }
+}
+
+fn config_response(conn: Conn, file_name: &str, body: Value) -> Conn {
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "application/json")
+        .with_header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", file_name))
+        .with_body(serde_json::to_string_pretty(&body).unwrap())
+        .halt()
+}
+
+// `GET /api/sharex`: a ShareX (14 or later) custom uploader
+pub fn sharex(conn: Conn, config: Arc<TranspoConfig>) -> Conn {
+    let url = instance_url(&conn, &config);
+    let body = json!({
+        "Version": "14.0.0",
+        "Name": config.app_name,
+        "DestinationType": "ImageUploader, TextUploader, FileUploader",
+        "RequestMethod": "POST",
+        "RequestURL": format!("{}/api/v1/uploads", url),
+        "Headers": upload_headers(&config),
+        "Body": "MultipartFormData",
+        "Arguments": upload_arguments(&config),
+        "FileFormName": "files",
+        "URL": format!("{}/{{json:url}}", url),
+        "ErrorMessage": "{json:error}"
+    });
+
+    config_response(conn, "transpo.sxcu", body)
+}
+
+// `GET /api/ishare`: an ishare (macOS) custom uploader
+pub fn ishare(conn: Conn, config: Arc<TranspoConfig>) -> Conn {
+    let url = instance_url(&conn, &config);
+    let body = json!({
+        "name": config.app_name,
+        "requestURL": format!("{}/api/v1/uploads", url),
+        "headers": upload_headers(&config),
+        "formData": upload_arguments(&config),
+        "fileFormName": "files",
+        "requestBodyType": "multipartFormData",
+        "responseURL": format!("{}/{{{{url}}}}", url)
+    });
+
+    config_response(conn, "transpo.iscu", body)
+}
@@ -0,0 +1,73 @@
+use crate::config::*;
+use crate::http_errors::*;
+use crate::translations::*;
+
+use std::sync::Arc;
+
+use trillium::Conn;
+use serde::Serialize;
+
+
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+// empty if the commit could not be determined when building
+const GIT_COMMIT: &'static str = env!("TRANSPO_GIT_COMMIT");
+
+// What this build of Transpo supports, as returned by `/version`
+#[derive(Serialize)]
+pub struct BuildInfo {
+    version: &'static str,
+    commit: Option<&'static str>,
+    db_backends: Vec<&'static str>,
+    // cipher used for uploads and hash used for passwords
+    crypto: Vec<&'static str>,
+    features: Vec<&'static str>
+}
+
+pub fn build_info() -> BuildInfo {
+    let mut db_backends = Vec::new();
+    if cfg!(feature = "sqlite") {
+        db_backends.push("sqlite");
+    }
+    if cfg!(feature = "postgres") {
+        db_backends.push("postgres");
+    }
+    if cfg!(feature = "mysql") {
+        db_backends.push("mysql");
+    }
+
+    let mut features = vec![
+        "server-side-crypto", "download-tokens", "resumable-downloads",
+        "compressed-downloads", "accounts", "api-keys"
+    ];
+    if cfg!(feature = "redis") {
+        features.push("redis");
+    }
+
+    BuildInfo {
+        version: VERSION,
+        commit: Some(GIT_COMMIT).filter(|c| !c.is_empty()),
+        db_backends,
+        crypto: vec!["aes-256-gcm", "argon2"],
+        features
+    }
+}
+
+pub fn print_version() {
+    let info = build_info();
+    println!("Transpo {} ({})", info.version, info.commit.unwrap_or("unknown commit"));
+    println!("Database backends: {}", info.db_backends.join(", "));
+    println!("Crypto: {}", info.crypto.join(", "));
+    println!("Features: {}", info.features.join(", "));
+}
+
+pub fn handle(conn: Conn, config: Arc<TranspoConfig>, translation: Translation) -> Conn {
+    if config.hide_version {
+        return error_404(conn, config, translation);
+    }
+
+    conn
+        .with_status(200)
+        .with_header("Content-Type", "application/json")
+        .with_body(serde_json::to_string(&build_info()).unwrap())
+        .halt()
+}
@@ -76,7 +76,7 @@ upload() {
         minutes=${time_rev[0]:-0}
     fi
 
-    curlcmd="curl -X POST -H 'User-Agent:' -F server-side-processing=on -F days=$days -F hours=$hours -F minutes=$minutes"
+    curlcmd="curl -sS --fail-with-body -X POST -F server-side-processing=on -F days=$days -F hours=$hours -F minutes=$minutes"
 
     if ! [ -z "$max_downloads" ]; then
         shift; shift
@@ -100,15 +100,17 @@ upload() {
         curlcmd+=" -F files=@$file"
     done
 
-    curlcmd+=" $host/upload"
+    curlcmd+=" $host/api/v1/uploads"
 
     echo "$curlcmd"
     response=`eval "$curlcmd"`
     if [ $? = 0 ]; then
+        url=`echo "$response" | sed -n 's/.*"url":"\([^"]*\)".*/\1/p'`
         echo
-        echo "$host/`eval echo $response`"
+        echo "$host/$url"
         echo
     else
+        echo "$response" >&2
         exit 1
     fi
 }
@@ -135,7 +137,7 @@ download() {
     url=${parts[0]}
     key=${parts[1]}
 
-    curlcmd="curl -X GET -O -J -L $url/dl?key=$key&password=$password"
+    curlcmd="curl -X POST -O -J -L --data-urlencode key=$key --data-urlencode password=\"$password\" $url/dl"
     echo "$curlcmd"
     eval "$curlcmd"
 }
@@ -25,21 +25,20 @@ async function downloadEventHandlerNoSW(e) {
     let password = "";
     const passwordInput = document.getElementById("password-input");
     if (passwordInput) {
-        password = encodeURIComponent(passwordInput.value);
+        password = passwordInput.value;
     }
 
     const url = new URL(
-        location.origin + location.pathname + "/dl"
-        + "?password=" + password + location.hash);
+        location.origin + location.pathname + "/dl" + location.hash);
 
-    if (!await transpoDownload(url)) {
-        window.location = url;
+    if (!await transpoDownload(url, password)) {
+        downloadForm.submit();
     }
 }
 
 
 async function setupWorker() {
-    let registration = await navigator.serviceWorker.register("/download_worker.js");
+    let registration = await navigator.serviceWorker.register("download_worker.js");
 
     await navigator.serviceWorker.ready;
     navigator.serviceWorker.startMessages();
@@ -93,14 +92,44 @@ if ("serviceWorker" in navigator) {
     eventListener = downloadEventHandlerNoSW;
 }
 
-downloadForm.addEventListener("submit", async e => {
-    setButtonDisabled(true);
-
-    try {
-        await eventListener(e);
-    } catch (error) {
-        console.error(error);
+// List the contents of archives created by the server. Password-protected
+// uploads can't be listed before the password is given, so they aren't.
+async function showArchiveEntries() {
+    const url = new URL(location.origin + location.pathname + location.hash);
+    const entries = await transpoArchiveEntries(url, "");
+    if (entries == null) {
+        return;
     }
 
-    setButtonDisabled(false);
+    const entriesList = document.getElementById("entries-list");
+    entries.forEach(entry => {
+        const item = document.createElement("LI");
+        item.textContent = entry.name + " (" + sizeString(entry.size) + ")";
+        item.title = new Date(entry.mtime * 1000).toLocaleString();
+        entriesList.appendChild(item);
+    });
+    document.getElementById("entries").hidden = false;
+}
+
+window.addEventListener("load", () => {
+    if (!derivedKey && !recipientKey) {
+        showArchiveEntries().catch(console.error);
+    }
 });
+
+// If the key is derived from the password or wrapped for recipients, there is
+// no key in the URL to decrypt the upload with, so the form is sent as is and
+// the server decrypts the upload instead
+if (!derivedKey && !recipientKey) {
+    downloadForm.addEventListener("submit", async e => {
+        setButtonDisabled(true);
+
+        try {
+            await eventListener(e);
+        } catch (error) {
+            console.error(error);
+        }
+
+        setButtonDisabled(false);
+    });
+}
@@ -28,6 +28,12 @@ self.addEventListener("fetch", e => {
     const noServiceWorker = url.searchParams.get("nosw") != null;
 
     if (isDownloadPath && !noServiceWorker) {
-        e.respondWith(decryptedResponse(url));
+        if (e.request.method == "POST") {
+            // The download form sends the password in the request body
+            e.respondWith(e.request.formData()
+                .then(formData => decryptedResponse(url, formData.get("password"))));
+        } else {
+            e.respondWith(decryptedResponse(url));
+        }
     }
 });
@@ -20,6 +20,8 @@ function showError(err) {
             fileSizeError.showModal();
             break;
         case "2":
+        case "5":
+        case "6":
             // quota
             quotaError.showModal();
             break;
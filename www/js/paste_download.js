@@ -2,23 +2,34 @@ const downloadForm = document.getElementById("download-form");
 const downloadButton = document.getElementById("download-button");
 const pasteTextOutput = document.getElementById("paste-text-output");
 const passwordDialog = document.getElementById("password-dialog");
+const pasteTitle = document.getElementById("paste-title");
 
 async function downloadPaste(replaceUrl) {
     let password = "";
     const passwordInput = document.getElementById("password-input");
     if (passwordInput) {
-        password = encodeURIComponent(passwordInput.value);
+        password = passwordInput.value;
     }
 
     const url = new URL(
         location.origin + location.pathname + "/dl"
-        + "?nosw&password=" + password + location.hash);
-    const r = await transpoDecryptedResponse(url);
+        + "?nosw" + location.hash);
+    const r = await transpoDecryptedResponse(url, password);
 
     if (r.ok) {
+        const title = r.headers.get("Transpo-Title");
+        if (title) {
+            pasteTitle.textContent = decodeURIComponent(title);
+            pasteTitle.hidden = false;
+            document.title = appName + " | " + pasteTitle.textContent;
+        }
         pasteTextOutput.value = await r.text();
         return true;
     } else {
+        // The query string is still accepted for compatibility, so fall back
+        // to it when navigating to the raw download.
+        url.searchParams.append("password", password);
+
         if (replaceUrl) {
             window.location.replace(url);
         } else {
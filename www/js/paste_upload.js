@@ -1,4 +1,5 @@
 const textArea = document.getElementById("paste-text-input");
+const titleInput = document.getElementById("paste-title-input");
 const textEncoder = new TextEncoder();
 
 function getFilesToUpload() {
@@ -11,7 +12,9 @@ function getFilesToUpload() {
 }
 
 async function getListItemText(files) {
-    let name = (await files[0].text()).replace("\\w+", " ").substring(0, 500);
+    // Pastes are listed by their title if they have one
+    let name = titleInput.value
+        || (await files[0].text()).replace("\\w+", " ").substring(0, 500);
 
     return {
         name: name,
@@ -15,6 +15,12 @@ const PARAMS = {
 const maxPlaintextSegmentSize = 10240;
 // Maximum length of ciphertext to be decrypted at once
 const maxCiphertextSegmentSize = maxPlaintextSegmentSize + 16;
+// Version of the format in which the browser encrypts uploads (see
+// `files.rs`). It can't hash a stream, so it doesn't write the manifest of
+// version 3.
+const formatVersion = 2;
+// Count with which the title of an upload is encrypted (see `files.rs`)
+const titleCount = 2 ** 48;
 
 
 function nonceFromCount(count, nonce) {
@@ -24,6 +30,30 @@ function nonceFromCount(count, nonce) {
     }
 }
 
+// Return the associated data of the chunk with the given count of an upload
+// in the given format version: the upload's ID followed by the count, both
+// in big-endian byte order, and a byte equal to 1 if the chunk is the
+// manifest. Chunks of uploads in version 1 (and the file name and mime type
+// in any version) have none.
+function chunkAdditionalData(uploadID, count, version, isManifest) {
+    if (version < 2) {
+        return new Uint8Array(0);
+    }
+
+    const data = new Uint8Array(isManifest ? 17 : 16);
+    if (isManifest) {
+        data[16] = 1;
+    }
+    // The ID is the base64 encoding of its big-endian bytes
+    data.set(stringToBytes(b64Decode(uploadID)).subarray(0, 8));
+    for (let i = 15; i >= 8; i--) {
+        data[i] = count % 256;
+        count = Math.floor(count / 256);
+    }
+
+    return data;
+}
+
 function stringToBytes(string) {
     const bytes = new Uint8Array(string.length);
     for (let i = 0; i < bytes.length; i++) {
@@ -96,13 +126,15 @@ async function decodeKey(b64) {
 }
 
 // Encrypt plaintext with the given key
-async function encrypt(key, count, plaintext) {
+async function encrypt(key, count, plaintext, additionalData) {
     nonceFromCount(count, PARAMS.iv);
+    PARAMS.additionalData = additionalData || new Uint8Array(0);
     return new Uint8Array(await crypto.subtle.encrypt(PARAMS, key, plaintext));
 }
 
 // Decrypt ciphertext using the given key
-async function decrypt(key, count, ciphertext) {
+async function decrypt(key, count, ciphertext, additionalData) {
     nonceFromCount(count, PARAMS.iv);
+    PARAMS.additionalData = additionalData || new Uint8Array(0);
     return new Uint8Array(await crypto.subtle.decrypt(PARAMS, key, ciphertext));
 }
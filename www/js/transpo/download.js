@@ -1,4 +1,4 @@
-import { maxCiphertextSegmentSize, b64Decode, stringToBytes, decrypt, decodeKey } from "./crypto.js";
+import { maxCiphertextSegmentSize, titleCount, archiveEntriesCount, chunkAdditionalData, b64Decode, stringToBytes, decrypt, decodeKey } from "./crypto.js";
 
 const textDecoder = new TextDecoder("utf-8");
 
@@ -37,10 +37,21 @@ function generateFileName(uploadID, mime) {
     return name;
 }
 
+// Return the length of the plaintext stored in a manifest, which starts with it
+// as a big-endian u64
+function manifestPlaintextLength(manifest) {
+    const view = new DataView(manifest.buffer, manifest.byteOffset, 8);
+    return view.getUint32(0) * 2 ** 32 + view.getUint32(4);
+}
+
 // `state` is an object with the following fields:
 // - `segment` buffer into which ciphertext is written
 // - `segmentWriteStart` index into segment where next read should be inserted
 // - `count` number of decryptions so far
+// - `uploadID` and `formatVersion` of the upload, which the associated data of
+//   each segment depends on
+// - `plaintextLength` number of bytes decrypted so far
+// - `manifestVerified` whether the manifest (since version 3) was checked
 // Returns whether or not the full download has been decrypted
 async function decryptBufferAndEnqueue(buffer, controller, key, state) {
     const EMPTY = new Uint8Array(0);
@@ -63,9 +74,16 @@ async function decryptBufferAndEnqueue(buffer, controller, key, state) {
         bytesRead += iterLen;
 
         while (state.segmentWriteStart >= 2) {
-            const segmentSize = state.segment[0] * 256 + state.segment[1];
+            // The manifest is marked by the highest bit of its length prefix
+            const isManifest = (state.segment[0] & 0x80) != 0;
+            const segmentSize = (state.segment[0] & 0x7f) * 256 + state.segment[1];
+
+            if (segmentSize == 0 && !isManifest) {
+                if (state.formatVersion >= 3 && !state.manifestVerified) {
+                    controller.error(new Error("Missing manifest"));
+                    return false;
+                }
 
-            if (segmentSize == 0) {
                 if (typeof controller.terminate == typeof undefined) {
                     controller.close();
                 } else {
@@ -79,10 +97,28 @@ async function decryptBufferAndEnqueue(buffer, controller, key, state) {
 
             if (state.segmentWriteStart >= segmentSize + 2) {
                 const segmentCiphertext = state.segment.subarray(2, segmentSize + 2);
-                const segmentPlaintext = await decrypt(key, state.count, segmentCiphertext);
+                const additionalData = chunkAdditionalData(
+                    state.uploadID, state.count, state.formatVersion, isManifest);
+                const segmentPlaintext = await decrypt(
+                    key, state.count, segmentCiphertext, additionalData);
                 state.count++;
-                controller.enqueue(segmentPlaintext);
-                chunksEnqueued++;
+
+                if (isManifest) {
+                    // The browser can't hash a stream, so only the length of
+                    // the plaintext is checked
+                    if (
+                        state.formatVersion < 3
+                        || manifestPlaintextLength(segmentPlaintext) != state.plaintextLength
+                    ) {
+                        controller.error(new Error("Invalid manifest"));
+                        return false;
+                    }
+                    state.manifestVerified = true;
+                } else {
+                    state.plaintextLength += segmentPlaintext.byteLength;
+                    controller.enqueue(segmentPlaintext);
+                    chunksEnqueued++;
+                }
 
                 const segmentEnd = segmentSize + 2;
                 const leftover = state.segmentWriteStart - segmentEnd;
@@ -107,7 +143,7 @@ async function decryptBufferAndEnqueue(buffer, controller, key, state) {
     return false;
 }
 
-async function decryptedStream(r, key) {
+async function decryptedStream(r, key, uploadID, formatVersion) {
     let segment = new Uint8Array(2 + maxCiphertextSegmentSize);
     let segmentWriteStart = 0;
     // count starts at 2 since we first decrypt file name and mime type
@@ -116,7 +152,11 @@ async function decryptedStream(r, key) {
     let state = {
         'segment': segment,
         'segmentWriteStart': segmentWriteStart,
-        'count': count
+        'count': count,
+        'uploadID': uploadID,
+        'formatVersion': formatVersion,
+        'plaintextLength': 0,
+        'manifestVerified': false
     };
 
     let stream;
@@ -159,16 +199,41 @@ async function decryptedStream(r, key) {
     return stream;
 }
 
-async function decryptedResponse(url) {
+// Return the options for a fetch request which sends the given password in a
+// header, so that it doesn't need to be included in the URL.
+function passwordRequestInit(password) {
+    const headers = new Headers();
+    if (typeof password == typeof "" && password.length > 0) {
+        headers.append("X-Transpo-Password", encodeURIComponent(password));
+    }
+
+    return { "headers": headers };
+}
+
+async function decryptedResponse(url, password) {
     const key = await getKeyFromURL(url);
     const uploadID = getUploadIDFromURL(url);
+    const init = passwordRequestInit(password);
 
-    let r = await fetch(uploadID + "/info" + url.search);
+    let r = await fetch(uploadID + "/info" + url.search, init);
     if (!r.ok) {
         return r;
     }
 
     const info = await r.json();
+
+    // Padded uploads (since version 4) can't be decrypted as a stream without
+    // knowing where the padding starts, so the server decrypts them instead
+    // (they were encrypted by the server in the first place). Neither can
+    // uploads in later versions, which are age files or encrypted with
+    // ChaCha20-Poly1305.
+    if (info.format_version >= 4) {
+        return await fetch(url.origin + url.pathname + url.search, {
+            "method": "POST",
+            "headers": init.headers,
+            "body": new URLSearchParams({ "key": url.hash.substring(1) })
+        });
+    }
     const nameCipherBytes = stringToBytes(b64Decode(info.name));
     const mimeCipherBytes = stringToBytes(b64Decode(info.mime));
 
@@ -189,13 +254,23 @@ async function decryptedResponse(url) {
     const headers = new Headers();
     headers.append("Content-Type", mime);
     headers.append("Content-Disposition", "attachment; filename=\"" + name + "\"");
-    if (info.size > 0) {
-        headers.append("Content-Length", String(info.size));
+    // (percent-encoded like the file name, as the server sends it when it
+    // decrypts the upload)
+    if (info.title) {
+        const titleBytes = await decrypt(
+            key, titleCount, stringToBytes(b64Decode(info.title)));
+        headers.append("Transpo-Title", encodeURIComponent(textDecoder.decode(titleBytes)));
+    }
+    // (the size of the decrypted stream, not of the ciphertext)
+    if (info.plaintext_size > 0) {
+        headers.append("Content-Length", String(info.plaintext_size));
     }
 
-    r = await fetch(url);
+    r = await fetch(url, init);
     if (r.ok) {
-        const stream = await decryptedStream(r, key);
+        // (uploads from before the format version was reported are in the
+        // first one)
+        const stream = await decryptedStream(r, key, uploadID, info.format_version || 1);
 
         const init = {
             "status": 200,
@@ -235,8 +310,8 @@ async function downloadResponse(response, url) {
     a.remove();
 }
 
-async function download(url) {
-    const response = await decryptedResponse(url);
+async function download(url, password) {
+    const response = await decryptedResponse(url, password);
 
     if (response.ok) {
         await downloadResponse(response, url);
@@ -246,11 +321,31 @@ async function download(url) {
     }
 }
 
+// Return the entries of an archive created by the server (objects with a
+// `name`, a `size` and an `mtime`), or null if the upload isn't one
+async function archiveEntries(url, password) {
+    const key = await getKeyFromURL(url);
+    const uploadID = getUploadIDFromURL(url);
+    if (key == null) {
+        return null;
+    }
+
+    const r = await fetch(uploadID + "/entries" + url.search, passwordRequestInit(password));
+    if (!r.ok) {
+        return null;
+    }
+
+    const entriesCipher = new Uint8Array(await r.arrayBuffer());
+    const entriesBytes = await decrypt(key, archiveEntriesCount, entriesCipher);
+    return JSON.parse(textDecoder.decode(entriesBytes));
+}
+
 if (typeof window != typeof undefined) {
     window.transpoDownload = download;
     window.transpoGetKeyFromURL = getKeyFromURL;
     window.transpoGetUploadIDFromURL = getUploadIDFromURL;
     window.transpoDecryptedResponse = decryptedResponse;
+    window.transpoArchiveEntries = archiveEntries;
 }
 
-export { getKeyFromURL, getUploadIDFromURL, decryptedResponse, download, downloadResponse };
+export { getKeyFromURL, getUploadIDFromURL, decryptedResponse, archiveEntries, download, downloadResponse };
@@ -1,4 +1,4 @@
-import { maxPlaintextSegmentSize, b64Encode, encrypt, genKey, encodeKey } from "./crypto.js";
+import { maxPlaintextSegmentSize, formatVersion, titleCount, chunkAdditionalData, b64Encode, encrypt, genKey, encodeKey } from "./crypto.js";
 import { downloadZip } from "./client-zip/index.js";
 
 const textEncoder = new TextEncoder("utf-8");
@@ -7,7 +7,7 @@ const MAX_BUFFERED_AMOUNT = 10_000_000;
 const MAX_SEND_WAIT_MS = 100;
 
 
-async function encryptStream(files, key) {
+async function encryptStream(files, key, id) {
     let fileStream;
     if (files.length == 1) {
         fileStream = files[0].stream();
@@ -43,7 +43,9 @@ async function encryptStream(files, key) {
             segmentEnd = Math.min(segmentStart + maxPlaintextSegmentSize, filePlaintext.length);
 
             const segmentPlaintext = filePlaintext.subarray(segmentStart, segmentEnd);
-            const segmentCiphertext = await encrypt(key, count, segmentPlaintext);
+            const additionalData = chunkAdditionalData(id, count, formatVersion);
+            const segmentCiphertext = await encrypt(
+                key, count, segmentPlaintext, additionalData);
             count++;
 
             segmentPrefix[0] = segmentCiphertext.byteLength / 256;
@@ -123,8 +125,16 @@ async function readToSocket(
 // `minutes` is the number of minutes before the upload expires
 // `maxDownloads` is the number of downloads to permit before the upload expires
 // `password` is the password required to download the file
+// `notifyTopic` is the ntfy topic on which to be notified about the upload
+// `shareEmail` is an address to which the server mails the link (without the
+// key)
+// `isPublic` is whether to list the upload in the public gallery, in which case
+// the key is sent to the server
+// `title` is the title of the upload (used by pastes), encrypted like the file
+// name
 //
-// Set `maxDownloads` and `password` to `null` if they aren't to be used.
+// Set `maxDownloads`, `password`, `notifyTopic`, `shareEmail` and `title` to
+// `null` if they aren't to be used.
 //
 // The various callback parameters are called in response to changes in the
 // progress of the upload.
@@ -172,7 +182,7 @@ async function readToSocket(
 //  NOTE: the callbacks will ONLY be called if their respective events are fired
 //  AFTER idCallback is triggered.
 async function upload(
-    url, files, minutes, maxDownloads, password, obj, progressCallback,
+    url, files, minutes, maxDownloads, password, notifyTopic, shareEmail, isPublic, title, obj, progressCallback,
     completionCallback, idCallback, errorCallback, closeCallback)
 {
     const key = await genKey();
@@ -202,6 +212,10 @@ async function upload(
     url = url.concat("?file-name=", name);
     url = url.concat("&mime-type=", mime);
     url = url.concat("&minutes=", minutes.toString());
+    // Lets the server reserve space for the upload
+    const size = Array.from(files).reduce((total, file) => total + file.size, 0);
+    url = url.concat("&size=", size.toString());
+    url = url.concat("&format-version=", formatVersion.toString());
 
     if (typeof maxDownloads !== typeof undefined && maxDownloads != null) {
         url = url.concat("&max-downloads=", maxDownloads.toString());
@@ -211,6 +225,23 @@ async function upload(
         url = url.concat("&password=", encodeURIComponent(password));
     }
 
+    if (typeof notifyTopic !== typeof undefined && notifyTopic != null) {
+        url = url.concat("&notify-topic=", encodeURIComponent(notifyTopic));
+    }
+
+    if (typeof shareEmail !== typeof undefined && shareEmail != null) {
+        url = url.concat("&share-email=", encodeURIComponent(shareEmail));
+    }
+
+    if (isPublic) {
+        url = url.concat("&public=true&public-key=", await encodeKey(key));
+    }
+
+    if (typeof title !== typeof undefined && title != null) {
+        const titleCipher = await encrypt(key, titleCount, textEncoder.encode(title));
+        url = url.concat("&title=", b64Encode(String.fromCharCode(...titleCipher)));
+    }
+
 
     const socket = new WebSocket(url);
     socket.binaryType = "arraybuffer";
@@ -241,11 +272,16 @@ async function upload(
     const messageEventHandler = async e => {
         socket.removeEventListener('message', messageEventHandler);
         // if the server sends another message, it will be to report an error
-        // code to the client.
+        // code to the client. Rate limit errors (code 5) are followed by the
+        // number of seconds to wait before retrying as a big-endian u32.
         socket.addEventListener('message', msg => {
             if (typeof errorCallback !== typeof undefined) {
                 const msgArr = new Uint8Array(msg.data);
-                errorCallback(msg, obj, msgArr[0]);
+                let retryAfter = null;
+                if (msgArr.length >= 5) {
+                    retryAfter = new DataView(msgArr.buffer).getUint32(1);
+                }
+                errorCallback(msg, obj, msgArr[0], retryAfter);
             }
         });
 
@@ -256,7 +292,9 @@ async function upload(
             idCallback(id, encodedKey, maxDownloads, password, obj);
         }
 
-        const stream = await encryptStream(files, key);
+        // The ID is sent before the upload starts, since each segment is
+        // bound to it
+        const stream = await encryptStream(files, key, id);
         const reader = stream.getReader();
 
         await readToSocket(
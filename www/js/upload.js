@@ -17,9 +17,12 @@ function closeCallback(close, obj) {
     delete sockets[obj.uploadNum];
 }
 
-function errorCallback(error, obj, errorCode) {
+function errorCallback(error, obj, errorCode, retryAfter) {
     if (typeof obj.listItem.dataset.errorCode == typeof undefined) {
         obj.listItem.dataset.errorCode = errorCode;
+        if (typeof retryAfter === typeof 0) {
+            obj.listItem.dataset.retryAfter = retryAfter;
+        }
     }
     obj.listItem.dataset.completed = true;
     obj.listItem.classList.add("failed");
@@ -49,6 +52,24 @@ function idCallback(id, key, maxDownloads, password, obj) {
     setUploadedListItemData(obj.listItem, id, key, password !== null, isPaste_);
 }
 
+function submitForServerSideProcessing(hasMultipleFiles) {
+    const fields = ["server-side-processing"];
+    if (hasMultipleFiles) {
+        fields.push("enable-multiple-files");
+    }
+
+    // These fields have to come before the files in the form
+    fields.reverse().forEach(name => {
+        const input = document.createElement("INPUT");
+        input.type = "hidden";
+        input.name = name;
+        input.value = "on";
+        uploadForm.prepend(input);
+    });
+
+    uploadForm.submit();
+}
+
 async function upload(e) {
     e.preventDefault();
 
@@ -70,10 +91,12 @@ async function upload(e) {
         return false;
     }
 
-    const minutes =
-        (~~formData.get("days")) * (24 * 60)
-        + (~~formData.get("hours")) * 60
-        + (~~formData.get("minutes"));
+    // Expiry presets send the total number of minutes
+    const minutes = formData.has("expiry")
+        ? ~~formData.get("expiry")
+        : (~~formData.get("days")) * (24 * 60)
+            + (~~formData.get("hours")) * 60
+            + (~~formData.get("minutes"));
 
     let maxDownloads;
     if (formData.get("enable-max-downloads")) {
@@ -89,6 +112,35 @@ async function upload(e) {
         password = null;
     }
 
+    // The key can't be derived from the password in the browser, so the form
+    // is sent as is and the server encrypts the upload instead
+    if (password && formData.get("derive-key") == "on") {
+        submitForServerSideProcessing(filesToUpload.length > 1);
+        return false;
+    }
+
+    // Likewise, the key can't be wrapped for recipients in the browser
+    if ((formData.get("recipients") || "").trim()) {
+        // The recipients have to come before the files for the upload to be
+        // stored in the age format for them
+        if (formData.get("age") == "on") {
+            uploadForm.prepend(
+                document.getElementById("recipients-input"),
+                document.getElementById("age-input"));
+        }
+        submitForServerSideProcessing(filesToUpload.length > 1);
+        return false;
+    }
+
+    // Only present if the server accepts topics
+    const notifyTopic = formData.get("notify-topic") || null;
+    // Only present if the server can send mail
+    const shareEmail = formData.get("share-email") || null;
+    // Only present if the server has a public gallery
+    const isPublic = formData.get("public") == "on";
+    // Only present on the paste page
+    const title = formData.get("title") || null;
+
     let obj = {
         bytesUploaded: 0,
         uploadSize: uploadSize,
@@ -108,7 +160,7 @@ async function upload(e) {
     url = new URL("upload", urlPrefix + location.host + location.pathname).toString();
 
     obj.socket = await transpoUpload(
-        url, filesToUpload, minutes, maxDownloads, password, obj,
+        url, filesToUpload, minutes, maxDownloads, password, notifyTopic, shareEmail, isPublic, title, obj,
         progressCallback, completionCallback, idCallback, errorCallback, closeCallback);
 
     sockets[uploadNum] = obj.socket;